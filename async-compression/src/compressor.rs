@@ -12,6 +12,8 @@ use std::result;
 
 use bzip2;
 use bzip2::write::BzEncoder;
+use flate2;
+use flate2::write::ZlibEncoder;
 use futures::Poll;
 use tokio_io::AsyncWrite;
 
@@ -56,10 +58,10 @@ where
             c_type: ct,
             inner: match ct {
                 CompressorType::Bzip2(level) => Box::new(BzEncoder::new(w, level)),
-                // TODO: need
-                // https://github.com/alexcrichton/flate2-rs/issues/62 to be
-                // fixed
-                CompressorType::Gzip => unimplemented!(),
+                // Mercurial calls this gzip, but it's really the zlib format.
+                CompressorType::Gzip => {
+                    Box::new(ZlibEncoder::new(w, flate2::Compression::Default))
+                }
                 CompressorType::Zstd { level } => Box::new(AsyncZstdEncoder::new(w, level)),
                 CompressorType::Uncompressed => Box::new(NoopEncoder::new(w)),
             },
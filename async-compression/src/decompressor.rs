@@ -4,13 +4,14 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-//! Non-blocking, buffered compression and decompression for bzip2
+//! Non-blocking, buffered decompression
 
 use std::fmt::{self, Debug, Formatter};
 use std::io;
 use std::io::Read;
 
 use bzip2::read::BzDecoder;
+use flate2::read::ZlibDecoder;
 use tokio_io::AsyncRead;
 use zstd::Decoder as ZstdDecoder;
 
@@ -42,10 +43,8 @@ where
             d_type: dt,
             inner: match dt {
                 DecompressorType::Bzip2 => Box::new(BzDecoder::new(r)),
-                // TODO: need
-                // https://github.com/alexcrichton/flate2-rs/issues/62 to be
-                // fixed
-                DecompressorType::Gzip => unimplemented!(),
+                // Mercurial calls this gzip, but it's really the zlib format.
+                DecompressorType::Gzip => Box::new(ZlibDecoder::new(r)),
                 // ZstdDecoder::new() should only fail on OOM, so just call unwrap here.
                 DecompressorType::Zstd => Box::new(ZstdDecoder::new(r).unwrap()),
                 DecompressorType::Uncompressed => Box::new(NoopDecoder::new(r)),
@@ -14,6 +14,8 @@ use tokio_io::AsyncWrite;
 
 use bzip2::read::BzDecoder;
 use bzip2::write::BzEncoder;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
 use zstd::{Decoder as ZstdDecoder, Encoder as ZstdEncoder};
 
 pub trait RawDecoder<R: Read>: Read {
@@ -39,6 +41,23 @@ impl<R: Read> RawDecoder<R> for BzDecoder<R> {
     }
 }
 
+impl<R: Read> RawDecoder<R> for ZlibDecoder<R> {
+    #[inline]
+    fn get_ref(&self) -> &R {
+        ZlibDecoder::get_ref(self)
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> &mut R {
+        ZlibDecoder::get_mut(self)
+    }
+
+    #[inline]
+    fn into_inner(self: Box<Self>) -> R {
+        ZlibDecoder::into_inner(*self)
+    }
+}
+
 impl<R: Read> RawDecoder<R> for ZstdDecoder<R> {
     #[inline]
     fn get_ref(&self) -> &R {
@@ -78,6 +97,21 @@ where
     }
 }
 
+impl<W> RawEncoder<W> for ZlibEncoder<W>
+where
+    W: AsyncWrite + Send + 'static,
+{
+    #[inline]
+    fn try_finish(
+        mut self: Box<Self>,
+    ) -> result::Result<W, (Box<RawEncoder<W> + Send>, io::Error)> {
+        match ZlibEncoder::try_finish(&mut self) {
+            Ok(()) => Ok(ZlibEncoder::finish(*self).unwrap()),
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
 /// A wrapper around ZstdEncoder which depends on and implements AsyncWrite.
 ///
 /// The sole purpose of this struct is to work around the orphan rule: you
@@ -26,6 +26,10 @@ quickcheck! {
         roundtrip(CompressorType::Bzip2(bzip2::Compression::Default), &input)
     }
 
+    fn test_gzip_roundtrip(input: Vec<u8>) -> TestResult {
+        roundtrip(CompressorType::Gzip, &input)
+    }
+
     fn test_noop_roundtrip(input: Vec<u8>) -> TestResult {
         roundtrip(CompressorType::Uncompressed, &input)
     }
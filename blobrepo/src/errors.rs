@@ -17,6 +17,7 @@ pub enum StateOpenError {
     Bookmarks,
     Blobstore,
     Linknodes,
+    Phases,
 }
 
 impl fmt::Display for StateOpenError {
@@ -28,6 +29,7 @@ impl fmt::Display for StateOpenError {
             Bookmarks => write!(f, "bookmarks"),
             Blobstore => write!(f, "blob store"),
             Linknodes => write!(f, "linknodes"),
+            Phases => write!(f, "phases"),
         }
     }
 }
@@ -44,6 +46,12 @@ error_chain! {
         Bookmarks {
             description("Bookmarks error")
         }
+        Linknodes {
+            description("Linknodes error")
+        }
+        Phases {
+            description("Phases error")
+        }
         StateOpen(kind: StateOpenError) {
             description("Error while opening state")
             display("Error while opening state for {}", kind)
@@ -91,3 +99,11 @@ pub fn blobstore_err<E: error::Error + Send + 'static>(err: E) -> Error {
 pub fn bookmarks_err<E: error::Error + Send + 'static>(err: E) -> Error {
     ChainedError::with_chain(err, ErrorKind::Bookmarks)
 }
+
+pub fn linknodes_err<E: error::Error + Send + 'static>(err: E) -> Error {
+    ChainedError::with_chain(err, ErrorKind::Linknodes)
+}
+
+pub fn phases_err<E: error::Error + Send + 'static>(err: E) -> Error {
+    ChainedError::with_chain(err, ErrorKind::Phases)
+}
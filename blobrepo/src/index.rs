@@ -0,0 +1,110 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Index of changesets by hash prefix.
+//!
+//! Blobstores can't enumerate their keys, so changesets are listed in index blobs, one for each
+//! value of the first three hex digits of their hashes. That keeps each blob small, and a lookup
+//! by a prefix of three or more digits only has to read one of them.
+
+use std::collections::BTreeMap;
+
+use bincode;
+use futures::future::{self, Future, IntoFuture};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobstore::Blobstore;
+use mercurial_types::{NodeHash, NodeHashPrefix};
+
+use errors::*;
+
+// How many index blobs are read or updated at once
+const CONCURRENCY: usize = 16;
+
+fn bucket(nodeid: &NodeHash) -> u16 {
+    let bytes = nodeid.as_ref();
+    (bytes[0] as u16) << 4 | (bytes[1] as u16) >> 4
+}
+
+fn bucket_key(bucket: u16) -> String {
+    format!("changesetindex-{:03x}.bincode", bucket)
+}
+
+fn load_bucket<B>(blobstore: &B, bucket: u16) -> BoxFuture<Vec<NodeHash>, Error>
+where
+    B: Blobstore<Key = String>,
+{
+    blobstore
+        .get(&bucket_key(bucket))
+        .map_err(blobstore_err)
+        .and_then(|got| match got {
+            None => Ok(Vec::new()),
+            Some(blob) => bincode::deserialize(blob.as_ref()).map_err(Error::from),
+        })
+        .boxify()
+}
+
+/// Add changesets to the index.
+///
+/// Each index blob which gains changesets is read, updated and written back, so concurrent
+/// calls can lose each other's additions. Callers have to make sure they don't overlap.
+pub fn index_changesets<B>(blobstore: B, nodes: Vec<NodeHash>) -> BoxFuture<(), Error>
+where
+    B: Blobstore<Key = String> + Clone,
+    B::ValueIn: From<Vec<u8>>,
+{
+    let mut buckets = BTreeMap::new();
+    for node in nodes {
+        buckets
+            .entry(bucket(&node))
+            .or_insert_with(Vec::new)
+            .push(node);
+    }
+
+    stream::iter_ok(buckets)
+        .map(move |(bucket, nodes)| {
+            let blobstore = blobstore.clone();
+            load_bucket(&blobstore, bucket).and_then(move |mut indexed| {
+                let before = indexed.len();
+                indexed.extend(nodes);
+                indexed.sort();
+                indexed.dedup();
+                if indexed.len() == before {
+                    return future::ok(()).boxify();
+                }
+
+                bincode::serialize(&indexed, bincode::Infinite)
+                    .map_err(Error::from)
+                    .into_future()
+                    .and_then(move |blob| {
+                        blobstore
+                            .put(bucket_key(bucket), blob.into())
+                            .map_err(blobstore_err)
+                    })
+                    .boxify()
+            })
+        })
+        .buffer_unordered(CONCURRENCY)
+        .for_each(|()| Ok(()))
+        .boxify()
+}
+
+/// The indexed changesets whose hashes start with `prefix`, in order.
+pub fn find_changesets<B>(blobstore: B, prefix: &NodeHashPrefix) -> BoxStream<NodeHash, Error>
+where
+    B: Blobstore<Key = String> + Clone,
+{
+    let prefix = *prefix;
+
+    stream::iter_ok(bucket(prefix.min())..(bucket(prefix.max()) + 1))
+        .map(move |bucket| load_bucket(&blobstore, bucket))
+        .buffered(CONCURRENCY)
+        .map(stream::iter_ok::<_, Error>)
+        .flatten()
+        .filter(move |node| prefix.matches(node))
+        .boxify()
+}
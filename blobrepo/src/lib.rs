@@ -29,6 +29,7 @@ extern crate fileblob;
 extern crate filebookmarks;
 extern crate fileheads;
 extern crate filelinknodes;
+extern crate filephases;
 extern crate futures_ext;
 extern crate heads;
 extern crate linknodes;
@@ -37,9 +38,12 @@ extern crate memblob;
 extern crate membookmarks;
 extern crate memheads;
 extern crate memlinknodes;
+extern crate memphases;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate phases;
 extern crate rocksblob;
+extern crate storage_types;
 
 mod repo;
 mod changeset;
@@ -47,11 +51,13 @@ mod manifest;
 mod state;
 mod file;
 mod errors;
+mod index;
 mod utils;
 
 pub use errors::*;
 
 pub use changeset::BlobChangeset;
+pub use index::index_changesets;
 pub use manifest::BlobManifest;
 pub use repo::BlobRepo;
 pub use state::{BlobState, FilesBlobState, MemBlobState, RocksBlobState, TestManifoldBlobState};
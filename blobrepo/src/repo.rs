@@ -4,24 +4,33 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::sync::Arc;
 
+use bincode;
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{self, Future, IntoFuture, Loop};
 use futures::stream::{self, Stream};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
-use bookmarks::BoxedBookmarks;
+use blobstore::Blobstore;
+use bookmarks::{Bookmarks, BookmarksMut, BoxedBookmarks};
 use heads::Heads;
-use mercurial_types::{repo, Changeset, Manifest, NodeHash, Repo};
+use linknodes::Linknodes;
+use mercurial::revlogrepo::RevlogChangeset;
+use mercurial_types::{repo, BlobHash, BlobNode, Changeset, Manifest, NodeHash, NodeHashPrefix,
+                      Parents, Repo, RepoPath};
+use phases::{Phase, Phases};
+use storage_types::Version;
 
 use BlobChangeset;
 use BlobManifest;
 use BlobState;
 use errors::*;
 use file::fetch_file_blob_from_blobstore;
+use index::{find_changesets, index_changesets};
+use utils::{get_node, RawNodeBlob};
 
 pub struct BlobRepo<State> {
     inner: Arc<State>,
@@ -42,6 +51,285 @@ where
     pub fn get_file_blob(&self, key: &NodeHash) -> BoxFuture<Vec<u8>, Error> {
         fetch_file_blob_from_blobstore(self.inner.blobstore().clone(), *key)
     }
+
+    /// Get the full text of a manifest or file node, including any file metadata. This is the
+    /// text the node hash is computed over.
+    pub fn get_node_text(&self, nodeid: &NodeHash) -> BoxFuture<Vec<u8>, Error> {
+        let nodeid = *nodeid;
+        let blobstore = self.inner.blobstore();
+
+        get_node(blobstore, nodeid)
+            .and_then({
+                let blobstore = blobstore.clone();
+                move |node| {
+                    let key = format!("sha1-{}", node.blob.sha1());
+
+                    blobstore
+                        .get(&key)
+                        .map_err(blobstore_err)
+                        .and_then(move |blob| {
+                            blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
+                        })
+                        .map(|blob| Vec::from(blob.as_ref()))
+                }
+            })
+            .boxify()
+    }
+
+    /// Get the parents of a manifest or file node.
+    pub fn get_node_parents(&self, nodeid: &NodeHash) -> BoxFuture<Parents, Error> {
+        get_node(self.inner.blobstore(), *nodeid)
+            .map(|node| node.parents)
+            .boxify()
+    }
+
+    pub fn add_head(&self, nodeid: &NodeHash) -> BoxFuture<(), Error> {
+        self.inner.heads().add(nodeid).map_err(heads_err).boxify()
+    }
+
+    pub fn remove_head(&self, nodeid: &NodeHash) -> BoxFuture<(), Error> {
+        self.inner.heads().remove(nodeid).map_err(heads_err).boxify()
+    }
+
+    /// Add and remove heads in one step, so the heads are never seen with only some of the
+    /// changes applied.
+    pub fn update_heads(&self, add: &[NodeHash], remove: &[NodeHash]) -> BoxFuture<(), Error> {
+        self.inner
+            .heads()
+            .update(add, remove)
+            .map_err(heads_err)
+            .boxify()
+    }
+
+    /// The version of the heads, which changes whenever they do. Heads read after this are at
+    /// least as new as it.
+    pub fn heads_version(&self) -> BoxFuture<Version, Error> {
+        self.inner.heads().version().map_err(heads_err).boxify()
+    }
+
+    /// Like `update_heads`, but only if the heads are still at `version`. Returns the new
+    /// version, or `None` if the heads have changed since, in which case nothing is updated.
+    pub fn update_heads_if(
+        &self,
+        add: &[NodeHash],
+        remove: &[NodeHash],
+        version: &Version,
+    ) -> BoxFuture<Option<Version>, Error> {
+        self.inner
+            .heads()
+            .update_if(add, remove, version)
+            .map_err(heads_err)
+            .boxify()
+    }
+
+    /// Move bookmark `key` from `old` to `new`, where `None` means the bookmark doesn't exist.
+    ///
+    /// The update only happens if the bookmark still has the value `old` when it's written, so
+    /// concurrent updates can't overwrite each other, and only if `new` is a changeset in the
+    /// repo. Returns whether the bookmark now has the value `new`, which is the case if it
+    /// already did. Once it has, `new` and its ancestors are public.
+    pub fn update_bookmark(
+        &self,
+        key: Vec<u8>,
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<bool, Error> {
+        let repo = self.clone();
+        let bookmarks = self.inner.bookmarks().clone();
+
+        let new_exists = match new {
+            Some(ref new) => self.changeset_exists(new),
+            None => future::ok(true).boxify(),
+        };
+        new_exists
+            .and_then(move |new_exists| {
+                if !new_exists {
+                    return future::ok(None).boxify();
+                }
+                bookmarks
+                    .get(&key)
+                    .map_err(bookmarks_err)
+                    .map(move |current| Some((bookmarks, key, current)))
+                    .boxify()
+            })
+            .and_then(move |found| {
+                let (bookmarks, key, current) = match found {
+                    Some(found) => found,
+                    None => return future::ok(false).boxify(),
+                };
+                let version = match (current, old) {
+                    // Nothing to do, perhaps because this is a retry of an update which happened
+                    (Some((ref value, _)), _) if Some(*value) == new => None,
+                    (None, None) => Some(Version::absent()),
+                    (Some((ref value, ref version)), Some(ref old)) if value == old => {
+                        Some(*version)
+                    }
+                    // The bookmark has moved since the client last saw it
+                    _ => return future::ok(false).boxify(),
+                };
+
+                let update = match (version, new) {
+                    (None, _) => future::ok(true).boxify(),
+                    (Some(version), Some(new)) => bookmarks
+                        .set(&key, &new, &version)
+                        .map(|version| version.is_some())
+                        .map_err(bookmarks_err)
+                        .boxify(),
+                    (Some(version), None) => bookmarks
+                        .delete(&key, &version)
+                        .map(|version| version.is_some())
+                        .map_err(bookmarks_err)
+                        .boxify(),
+                };
+                update
+                    .and_then(move |updated| match new {
+                        Some(ref new) if updated => {
+                            repo.make_public(new).map(move |()| updated).boxify()
+                        }
+                        _ => future::ok(updated).boxify(),
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    pub fn get_phase(&self, nodeid: &NodeHash) -> BoxFuture<Phase, Error> {
+        self.inner
+            .phases()
+            .get_phase(nodeid)
+            .map_err(phases_err)
+            .boxify()
+    }
+
+    /// Make a changeset and all its ancestors public.
+    ///
+    /// Ancestors are made public before their descendants, so that the public changesets stay
+    /// closed under ancestry even if this is interrupted. That lets the search for changesets to
+    /// update stop at the first public one on each path.
+    pub fn make_public(&self, nodeid: &NodeHash) -> BoxFuture<(), Error> {
+        let repo = self.clone();
+        let inner = self.inner.clone();
+
+        // Collect the draft ancestors, along with their parents
+        let draft = future::loop_fn(
+            (vec![*nodeid], HashMap::new()),
+            move |(mut pending, mut draft)| match pending.pop() {
+                None => future::ok(Loop::Break(draft)).boxify(),
+                Some(node) => if draft.contains_key(&node) {
+                    future::ok(Loop::Continue((pending, draft))).boxify()
+                } else {
+                    let repo = repo.clone();
+                    repo.get_phase(&node)
+                        .and_then(move |phase| match phase {
+                            Phase::Public => future::ok(Loop::Continue((pending, draft))).boxify(),
+                            Phase::Draft => repo.get_changeset_by_nodeid(&node)
+                                .map(move |cs| {
+                                    let parents: Vec<_> = cs.parents().into_iter().collect();
+                                    pending.extend(parents.iter().cloned());
+                                    draft.insert(node, parents);
+                                    Loop::Continue((pending, draft))
+                                })
+                                .boxify(),
+                        })
+                        .boxify()
+                },
+            },
+        );
+
+        draft
+            .and_then(move |draft| {
+                stream::iter_ok(parents_first(draft)).for_each(move |node| {
+                    inner.phases().add_public(&node).map_err(phases_err)
+                })
+            })
+            .boxify()
+    }
+
+    /// Record the linknode for a manifest or file node. A node which already has one keeps it,
+    /// as in Mercurial, where a revision's linkrev is the first changeset to introduce it. So
+    /// pushing a node again, whether in a retried push or a changeset which reintroduces an
+    /// existing file revision, succeeds.
+    pub fn add_linknode(
+        &self,
+        path: RepoPath,
+        nodeid: &NodeHash,
+        linknode: &NodeHash,
+    ) -> BoxFuture<(), Error> {
+        self.inner
+            .linknodes()
+            .add(path, nodeid, linknode)
+            .or_else(|err| {
+                let exists = match err.kind() {
+                    &linknodes::ErrorKind::AlreadyExists(..) => true,
+                    _ => false,
+                };
+                if exists {
+                    Ok(())
+                } else {
+                    Err(err)
+                }
+            })
+            .map_err(linknodes_err)
+            .boxify()
+    }
+}
+
+impl<State> BlobRepo<State>
+where
+    State: BlobState,
+    <State::Blobstore as Blobstore>::ValueIn: From<Vec<u8>>,
+{
+    /// Store a changeset, and add it to the index used to look changesets up by prefix. `node`
+    /// holds the changeset's text and parents, and `nodeid` is expected to be its hash.
+    ///
+    /// The index is shared with other changesets, and updating it can lose their entries if
+    /// they're being stored at the same time. So changesets should be stored one at a time.
+    pub fn put_changeset(&self, nodeid: &NodeHash, node: BlobNode) -> BoxFuture<(), Error> {
+        let nodeid = *nodeid;
+        let blobstore = self.inner.blobstore().clone();
+
+        RevlogChangeset::new(node)
+            .map_err(Error::from)
+            .into_future()
+            .and_then({
+                let blobstore = blobstore.clone();
+                move |cs| BlobChangeset::new(&nodeid, cs).save(blobstore)
+            })
+            .and_then(move |()| index_changesets(blobstore, vec![nodeid]))
+            .boxify()
+    }
+
+    /// Store a manifest or file node. `data` is the full text, including any file metadata.
+    pub fn put_node(
+        &self,
+        nodeid: &NodeHash,
+        parents: Parents,
+        data: Vec<u8>,
+    ) -> BoxFuture<(), Error> {
+        let blobstore = self.inner.blobstore().clone();
+        let nodekey = format!("node-{}.bincode", nodeid);
+
+        let nodeblob = RawNodeBlob {
+            parents: parents,
+            blob: BlobHash::from(data.as_slice()),
+        };
+        let blobkey = format!("sha1-{}", nodeblob.blob.sha1());
+
+        bincode::serialize(&nodeblob, bincode::Bounded(4096))
+            .map_err(Error::from)
+            .into_future()
+            .and_then(move |nodeblob| {
+                // Store the content before the node, so that a node is never visible without
+                // its content.
+                let put_content = blobstore.put(blobkey, data.into()).map_err(blobstore_err);
+                put_content.and_then(move |()| {
+                    blobstore
+                        .put(nodekey, nodeblob.into())
+                        .map_err(blobstore_err)
+                })
+            })
+            .boxify()
+    }
 }
 
 impl<State> Repo for BlobRepo<State>
@@ -52,15 +340,20 @@ where
 
     fn get_changesets(&self) -> BoxStream<NodeHash, Self::Error> {
         BlobChangesetStream {
-            repo: BlobRepo {
-                inner: self.inner.clone(),
-            },
+            repo: self.clone(),
             heads: self.inner.heads().heads().map_err(heads_err).boxify(),
             state: BCState::Idle,
             seen: HashSet::new(),
         }.boxify()
     }
 
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error> {
+        find_changesets(self.inner.blobstore().clone(), prefix)
+    }
+
     fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
         self.inner.heads().heads().map_err(heads_err).boxify()
     }
@@ -101,6 +394,37 @@ where
     }
 }
 
+// Order nodes, given with their parents, so that each comes after those of its parents which are
+// also in `nodes`
+fn parents_first(nodes: HashMap<NodeHash, Vec<NodeHash>>) -> Vec<NodeHash> {
+    let mut order = Vec::with_capacity(nodes.len());
+    let mut visited = HashSet::new();
+
+    for start in nodes.keys() {
+        // Depth-first, emitting each node once all its parents have been. This uses an explicit
+        // stack since draft histories can be arbitrarily long.
+        let mut stack = vec![(*start, false)];
+        while let Some((node, parents_done)) = stack.pop() {
+            if parents_done {
+                order.push(node);
+                continue;
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+
+            stack.push((node, true));
+            for parent in &nodes[&node] {
+                if nodes.contains_key(parent) && !visited.contains(parent) {
+                    stack.push((*parent, false));
+                }
+            }
+        }
+    }
+
+    order
+}
+
 impl<State> Clone for BlobRepo<State> {
     fn clone(&self) -> Self {
         Self {
@@ -8,12 +8,13 @@ use std::path::Path;
 use std::sync::Arc;
 
 use blobstore::Blobstore;
-use bookmarks::Bookmarks;
+use bookmarks::BookmarksMut;
 use bytes::Bytes;
 use fileblob::Fileblob;
 use filebookmarks::FileBookmarks;
 use fileheads::FileHeads;
 use filelinknodes::FileLinknodes;
+use filephases::FilePhases;
 use heads::Heads;
 use linknodes::Linknodes;
 use manifoldblob::ManifoldBlob;
@@ -21,7 +22,9 @@ use memblob::Memblob;
 use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
+use memphases::MemPhases;
 use mercurial_types::NodeHash;
+use phases::Phases;
 use rocksblob::Rocksblob;
 use tokio_core::reactor::Remote;
 
@@ -30,14 +33,16 @@ use errors::*;
 /// Represents all the state used by a blob store.
 pub trait BlobState: 'static + Send + Sync {
     type Heads: Heads<Key = NodeHash> + Sync;
-    type Bookmarks: Bookmarks<Value = NodeHash> + Clone + Sync;
+    type Bookmarks: BookmarksMut<Value = NodeHash> + Clone + Sync;
     type Blobstore: Blobstore<Key = String> + Clone + Sync;
     type Linknodes: Linknodes + Clone;
+    type Phases: Phases<Key = NodeHash> + Sync;
 
     fn heads(&self) -> &Self::Heads;
     fn bookmarks(&self) -> &Self::Bookmarks;
     fn blobstore(&self) -> &Self::Blobstore;
     fn linknodes(&self) -> &Self::Linknodes;
+    fn phases(&self) -> &Self::Phases;
 }
 
 macro_rules! impl_blob_state {
@@ -47,6 +52,7 @@ macro_rules! impl_blob_state {
             bookmarks: $book_type: ty,
             blobstore: $blob_type: ty,
             linknodes: $link_type: ty,
+            phases: $phase_type: ty,
         }
     } => {
         pub struct $struct_type {
@@ -54,6 +60,7 @@ macro_rules! impl_blob_state {
             bookmarks: $book_type,
             blobstore: $blob_type,
             linknodes: $link_type,
+            phases: $phase_type,
         }
 
         impl BlobState for $struct_type {
@@ -61,6 +68,7 @@ macro_rules! impl_blob_state {
             type Bookmarks = $book_type;
             type Blobstore = $blob_type;
             type Linknodes = $link_type;
+            type Phases = $phase_type;
 
             #[inline]
             fn heads(&self) -> &Self::Heads {
@@ -81,6 +89,11 @@ macro_rules! impl_blob_state {
             fn linknodes(&self) -> &Self::Linknodes {
                 &self.linknodes
             }
+
+            #[inline]
+            fn phases(&self) -> &Self::Phases {
+                &self.phases
+            }
         }
     }
 }
@@ -91,6 +104,7 @@ impl_blob_state! {
         bookmarks: Arc<FileBookmarks<NodeHash>>,
         blobstore: Fileblob<String, Vec<u8>>,
         linknodes: Arc<FileLinknodes>,
+        phases: FilePhases<NodeHash>,
     }
 }
 
@@ -108,12 +122,17 @@ impl FilesBlobState {
             FileLinknodes::open(path.join("linknodes"))
                 .chain_err(|| ErrorKind::StateOpen(StateOpenError::Linknodes))?,
         );
+        // Repos imported before phases were tracked don't have a phase store yet. Their
+        // changesets are draft until something makes them public, like an incremental import.
+        let phases = FilePhases::create(path.join("phases"))
+            .chain_err(|| ErrorKind::StateOpen(StateOpenError::Phases))?;
 
         Ok(FilesBlobState {
             heads,
             bookmarks,
             blobstore,
             linknodes,
+            phases,
         })
     }
 }
@@ -124,6 +143,7 @@ impl_blob_state! {
         bookmarks: Arc<FileBookmarks<NodeHash>>,
         blobstore: Rocksblob<String>,
         linknodes: Arc<FileLinknodes>,
+        phases: FilePhases<NodeHash>,
     }
 }
 
@@ -141,13 +161,17 @@ impl RocksBlobState {
             FileLinknodes::open(path.join("linknodes"))
                 .chain_err(|| ErrorKind::StateOpen(StateOpenError::Linknodes))?,
         );
-
+        // Repos imported before phases were tracked don't have a phase store yet. Their
+        // changesets are draft until something makes them public, like an incremental import.
+        let phases = FilePhases::create(path.join("phases"))
+            .chain_err(|| ErrorKind::StateOpen(StateOpenError::Phases))?;
 
         Ok(RocksBlobState {
             heads,
             bookmarks,
             blobstore,
             linknodes,
+            phases,
         })
     }
 }
@@ -158,6 +182,7 @@ impl_blob_state! {
         bookmarks: Arc<MemBookmarks<NodeHash>>,
         blobstore: Memblob,
         linknodes: Arc<MemLinknodes>,
+        phases: MemPhases<NodeHash>,
     }
 }
 
@@ -167,12 +192,14 @@ impl MemBlobState {
         bookmarks: MemBookmarks<NodeHash>,
         blobstore: Memblob,
         linknodes: MemLinknodes,
+        phases: MemPhases<NodeHash>,
     ) -> Self {
         MemBlobState {
             heads,
             bookmarks: Arc::new(bookmarks),
             blobstore,
             linknodes: Arc::new(linknodes),
+            phases,
         }
     }
 }
@@ -183,6 +210,7 @@ impl_blob_state! {
         bookmarks: Arc<MemBookmarks<NodeHash>>,
         blobstore: ManifoldBlob<String, Bytes>,
         linknodes: Arc<MemLinknodes>,
+        phases: MemPhases<NodeHash>,
     }
 }
 
@@ -192,11 +220,13 @@ impl TestManifoldBlobState {
         let bookmarks = Arc::new(MemBookmarks::new());
         let blobstore = ManifoldBlob::new_may_panic("mononoke", remote);
         let linknodes = Arc::new(MemLinknodes::new());
+        let phases = MemPhases::new();
         Ok(TestManifoldBlobState {
             heads,
             bookmarks,
             blobstore,
             linknodes,
+            phases,
         })
     }
 }
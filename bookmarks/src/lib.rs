@@ -187,6 +187,22 @@ pub trait BookmarksMut: Bookmarks {
     }
 }
 
+// Implement BookmarksMut for Arc-wrapped BookmarksMut type
+impl<B> BookmarksMut for Arc<B>
+where
+    B: BookmarksMut + Sync,
+{
+    type Set = B::Set;
+
+    fn set(&self, key: &AsRef<[u8]>, value: &Self::Value, version: &Version) -> Self::Set {
+        (**self).set(key, value, version)
+    }
+
+    fn delete(&self, key: &AsRef<[u8]>, version: &Version) -> Self::Set {
+        (**self).delete(key, version)
+    }
+}
+
 /// Ensure that trait objects can be created from the traits here.
 fn _assert_objects() {
     use std::io;
@@ -0,0 +1,591 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{BTreeMap, HashSet};
+use std::error;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, Future, IntoFuture};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use mercurial::changeset::serialize_cs;
+use mercurial_bundles::changegroup::{CgDeltaChunk, CgVersion, Part, RevFlags, Section};
+use mercurial_types::{delta, Blob, Changeset, Delta, Entry, MPath, Manifest, NodeHash, Repo,
+                      NULL_HASH};
+use mercurial_types::delta::Fragment;
+use repoinfo::RepoGenCache;
+use revset::{AncestorsNodeStream, NodeStream, SetDifferenceNodeStream, UnionNodeStream};
+
+use errors::*;
+
+// Number of changesets whose contents are fetched concurrently
+pub const FETCH_CONCURRENCY: usize = 100;
+
+type BoxManifest<E> = Box<Manifest<Error = E> + Sync>;
+type BoxEntry<E> = Box<Entry<Error = E> + Sync>;
+
+/// Compute the changesets which are ancestors of `heads` but not ancestors of `common`.
+///
+/// The result is in topological order (every changeset comes after its parents), which is the
+/// order Mercurial expects to receive them in.
+pub fn missing_changesets<R>(
+    repo: &Arc<R>,
+    repo_generation: RepoGenCache<R>,
+    heads: Vec<NodeHash>,
+    common: Vec<NodeHash>,
+) -> BoxFuture<Vec<NodeHash>, Error>
+where
+    R: Repo,
+{
+    let keep = ancestors_of(repo, &repo_generation, heads);
+    let remove = ancestors_of(repo, &repo_generation, common);
+
+    SetDifferenceNodeStream::new(repo, repo_generation, keep, remove)
+        .collect()
+        .map(|mut nodes| {
+            // Revset streams produce nodes in decreasing generation order, so reversing them
+            // puts every parent before its children.
+            nodes.reverse();
+            nodes
+        })
+        .from_err()
+        .boxify()
+}
+
+/// Compute the changesets which are ancestors of one of `heads` but not of the parents of any
+/// of `roots`. This is what Mercurial sends a client which is missing `roots` and everything
+/// after them, so each root and head is included in the result.
+///
+/// The result is in topological order.
+pub fn changesets_between<R>(
+    repo: &Arc<R>,
+    repo_generation: RepoGenCache<R>,
+    roots: Vec<NodeHash>,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<Vec<NodeHash>, Error>
+where
+    R: Repo,
+{
+    // The null revision has no parents, so it doesn't rule anything out
+    let parents: Vec<_> = roots
+        .into_iter()
+        .filter(|root| root != &NULL_HASH)
+        .map(|root| {
+            repo.get_changeset_by_nodeid(&root)
+                .map(|cs| cs.parents().into_iter().collect::<Vec<_>>())
+                .map_err(repo_err)
+        })
+        .collect();
+
+    let repo = repo.clone();
+    future::join_all(parents)
+        .and_then(move |parents| {
+            let common = parents.into_iter().flat_map(|parents| parents).collect();
+            missing_changesets(&repo, repo_generation, heads, common)
+        })
+        .boxify()
+}
+
+fn ancestors_of<R>(
+    repo: &Arc<R>,
+    repo_generation: &RepoGenCache<R>,
+    nodes: Vec<NodeHash>,
+) -> Box<NodeStream>
+where
+    R: Repo,
+{
+    // Clients send the null hash to say they have nothing in common with us
+    let inputs: Vec<_> = nodes
+        .into_iter()
+        .filter(|node| node != &NULL_HASH)
+        .map(|node| AncestorsNodeStream::new(repo, repo_generation.clone(), node).boxed())
+        .collect();
+
+    UnionNodeStream::new(repo, repo_generation.clone(), inputs).boxed()
+}
+
+/// Generate a changegroup which brings a client that has `common` up to date with `heads`, to
+/// be encoded as a `version` changegroup.
+///
+/// Every revision is sent as a full text. From version 02 on that's a delta against the null
+/// revision, which is always valid though not the most compact encoding.
+pub fn create_changegroup<R>(
+    repo: Arc<R>,
+    repo_generation: RepoGenCache<R>,
+    heads: Vec<NodeHash>,
+    common: Vec<NodeHash>,
+    version: CgVersion,
+) -> BoxStream<Part, Error>
+where
+    R: Repo,
+{
+    let nodes = missing_changesets(&repo, repo_generation, heads, common);
+    let parts = changegroup_parts(repo.clone(), nodes);
+    match version {
+        CgVersion::Cg1 => cg1_deltas(repo, parts),
+        CgVersion::Cg2 | CgVersion::Cg3 => parts,
+    }
+}
+
+/// Generate a version 01 changegroup of the changesets between `roots` and `heads`, which is
+/// what the legacy `changegroup` and `changegroupsubset` commands send.
+///
+/// Version 01 changegroups don't name delta bases: each delta applies to the previous revision
+/// in its section, or to its p1 for the first revision of a section. Revisions are sent as
+/// deltas which replace all of their base's text.
+pub fn create_cg1_changegroup<R>(
+    repo: Arc<R>,
+    repo_generation: RepoGenCache<R>,
+    roots: Vec<NodeHash>,
+    heads: Vec<NodeHash>,
+) -> BoxStream<Part, Error>
+where
+    R: Repo,
+{
+    let nodes = changesets_between(&repo, repo_generation, roots, heads);
+    let parts = changegroup_parts(repo.clone(), nodes);
+    cg1_deltas(repo, parts)
+}
+
+fn changegroup_parts<R>(
+    repo: Arc<R>,
+    nodes: BoxFuture<Vec<NodeHash>, Error>,
+) -> BoxStream<Part, Error>
+where
+    R: Repo,
+{
+    // The other sections can only be laid out once every changeset is known, so the changeset
+    // section records what each changeset introduced as it goes. Those are only hashes and
+    // unfetched entries: every text is fetched just before it's sent.
+    let infos = Arc::new(Mutex::new(Vec::new()));
+
+    let changesets = {
+        let repo = repo.clone();
+        let infos = infos.clone();
+        nodes
+            .map(move |nodes| {
+                stream::iter_ok(nodes)
+                    .map(move |node| changeset_info(repo.clone(), node))
+                    .buffered(FETCH_CONCURRENCY)
+            })
+            .flatten_stream()
+            .map(move |(chunk, info)| {
+                infos.lock().expect("lock poisoned").push(info);
+                Part::CgChunk(Section::Changeset, chunk)
+            })
+    };
+
+    let rest = future::lazy(move || {
+        let infos = mem::replace(&mut *infos.lock().expect("lock poisoned"), Vec::new());
+        Ok(manifest_and_filelog_parts(repo, infos))
+    }).flatten_stream();
+
+    changesets
+        .chain(stream::once(Ok(Part::SectionEnd(Section::Changeset))))
+        .chain(rest)
+        .boxify()
+}
+
+// Rebase the full texts in `parts` onto the implicit delta bases of a version 01 changegroup
+fn cg1_deltas<R>(repo: Arc<R>, parts: BoxStream<Part, Error>) -> BoxStream<Part, Error>
+where
+    R: Repo,
+{
+    // The length of the previous revision in the section is known by the time the next one is
+    // reached. Only the first revision of a section can have a base outside the changegroup, so
+    // those are the only texts which need fetching.
+    let mut prev = None;
+
+    parts
+        .map(move |part| -> BoxFuture<Part, Error> {
+            let (section, mut chunk) = match part {
+                Part::CgChunk(section, chunk) => (section, chunk),
+                part => {
+                    prev = None;
+                    return future::ok(part).boxify();
+                }
+            };
+
+            let base = match prev {
+                Some(prev) => future::ok(prev).boxify(),
+                None if chunk.p1 == NULL_HASH => future::ok((NULL_HASH, 0)).boxify(),
+                None => {
+                    let p1 = chunk.p1;
+                    parent_text(repo.clone(), &section, &chunk)
+                        .map(move |text| (p1, text.len()))
+                        .boxify()
+                }
+            };
+
+            let text = delta::apply(&[], mem::replace(&mut chunk.delta, Delta::default()));
+            prev = Some((chunk.node, text.len()));
+
+            base.and_then(move |(base, base_len)| -> Result<Part> {
+                chunk.base = base;
+                chunk.delta = Delta::new(vec![
+                    Fragment {
+                        start: 0,
+                        end: base_len,
+                        content: text,
+                    },
+                ])?;
+                Ok(Part::CgChunk(section, chunk))
+            }).boxify()
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .boxify()
+}
+
+// Fetch the text of the p1 of `chunk`, which is a revision in `section`
+fn parent_text<R>(
+    repo: Arc<R>,
+    section: &Section,
+    chunk: &CgDeltaChunk,
+) -> BoxFuture<Vec<u8>, Error>
+where
+    R: Repo,
+{
+    let node = chunk.node;
+    let p1 = chunk.p1;
+
+    match section {
+        &Section::Changeset => repo.get_changeset_by_nodeid(&p1)
+            .map_err(repo_err)
+            .and_then(|cs| {
+                let mut data = Vec::new();
+                serialize_cs(&*cs, &mut data)?;
+                Ok(data)
+            })
+            .boxify(),
+        &Section::Manifest => repo.get_manifest_by_nodeid(&p1)
+            .map_err(repo_err)
+            .and_then(|manifest| manifest_text(&manifest))
+            .boxify(),
+        // Only flat manifests are stored
+        &Section::Treemanifest(_) => {
+            future::err(ErrorKind::UnsupportedTreemanifest(node).into()).boxify()
+        }
+        &Section::Filelog(ref path) => {
+            // Filelog parents come from the manifests of the parents of the changeset which
+            // introduced the revision
+            let path = path.clone();
+            repo.get_changeset_by_nodeid(&chunk.linknode)
+                .map_err(repo_err)
+                .and_then(move |cs| {
+                    future::join_all(
+                        cs.parents()
+                            .into_iter()
+                            .map(|p| parent_manifest(repo.clone(), p))
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .and_then(move |parents| {
+                    future::join_all(
+                        parents
+                            .iter()
+                            .map(|&(_, ref mf)| mf.lookup(&path).map_err(repo_err))
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .and_then(move |entries| {
+                    let entry = entries
+                        .into_iter()
+                        .filter_map(|entry| entry)
+                        .find(|entry| *entry.get_hash() == p1);
+                    match entry {
+                        Some(entry) => entry
+                            .get_raw_content()
+                            .map_err(repo_err)
+                            .and_then(move |blob| fulltext_bytes(p1, blob))
+                            .boxify(),
+                        None => future::err(ErrorKind::UnknownParent(node, p1).into()).boxify(),
+                    }
+                })
+                .boxify()
+        }
+    }
+}
+
+// What a changeset introduced besides itself. Nothing here holds a text.
+struct ChangesetInfo<E> {
+    node: NodeHash,
+    // The changeset's manifest and its parents, if the changeset introduced it
+    manifest: Option<(NodeHash, Vec<NodeHash>)>,
+    filelogs: Vec<(MPath, BoxEntry<E>)>,
+}
+
+fn changeset_info<R>(
+    repo: Arc<R>,
+    node: NodeHash,
+) -> BoxFuture<(CgDeltaChunk, ChangesetInfo<R::Error>), Error>
+where
+    R: Repo,
+{
+    repo.get_changeset_by_nodeid(&node)
+        .map_err(repo_err)
+        .and_then(move |cs| {
+            let parents: Vec<_> = cs.parents()
+                .into_iter()
+                .map(|p| parent_manifest(repo.clone(), p))
+                .collect();
+            let manifest = repo.get_manifest_by_nodeid(cs.manifestid())
+                .map_err(repo_err);
+
+            manifest
+                .join(future::join_all(parents))
+                .map(move |(manifest, parents)| (cs, manifest, parents))
+        })
+        .and_then(move |(cs, manifest, parents)| {
+            let (parent_mfids, parent_manifests): (Vec<_>, Vec<_>) = parents.into_iter().unzip();
+
+            let mfid = *cs.manifestid();
+            // A manifest shared with a parent was introduced by an earlier changeset
+            let manifest_info = if mfid == NULL_HASH || parent_mfids.contains(&mfid) {
+                None
+            } else {
+                Some((mfid, parent_mfids))
+            };
+
+            let filelogs = future::join_all(
+                cs.files()
+                    .iter()
+                    .map(|path| filelog_entry(&manifest, &parent_manifests, path))
+                    .collect::<Vec<_>>(),
+            ).map(|entries| entries.into_iter().filter_map(|entry| entry).collect());
+
+            changeset_chunk(node, &*cs)
+                .into_future()
+                .join(filelogs)
+                .map(move |(changeset, filelogs)| {
+                    let info = ChangesetInfo {
+                        node,
+                        manifest: manifest_info,
+                        filelogs,
+                    };
+                    (changeset, info)
+                })
+        })
+        .boxify()
+}
+
+// The manifest and filelog sections for the changesets in `infos`, followed by the end of the
+// changegroup. Each section fetches at most `FETCH_CONCURRENCY` texts at once.
+fn manifest_and_filelog_parts<R>(
+    repo: Arc<R>,
+    infos: Vec<ChangesetInfo<R::Error>>,
+) -> BoxStream<Part, Error>
+where
+    R: Repo,
+{
+    let mut manifests = Vec::new();
+    let mut filelogs: BTreeMap<MPath, Vec<(BoxEntry<R::Error>, NodeHash)>> = BTreeMap::new();
+
+    let mut seen_manifests = HashSet::new();
+    let mut seen_filenodes = HashSet::new();
+
+    for info in infos {
+        let linknode = info.node;
+        if let Some((mfid, parents)) = info.manifest {
+            if seen_manifests.insert(mfid) {
+                manifests.push((mfid, parents, linknode));
+            }
+        }
+        for (path, entry) in info.filelogs {
+            if seen_filenodes.insert((path.clone(), *entry.get_hash())) {
+                filelogs
+                    .entry(path)
+                    .or_insert_with(Vec::new)
+                    .push((entry, linknode));
+            }
+        }
+    }
+
+    let manifests = stream::iter_ok(manifests)
+        .map(move |(mfid, parents, linknode)| {
+            repo.get_manifest_by_nodeid(&mfid)
+                .map_err(repo_err)
+                .and_then(move |manifest| manifest_chunk(&manifest, mfid, &parents, linknode))
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .map(|chunk| Part::CgChunk(Section::Manifest, chunk))
+        .chain(stream::once(Ok(Part::SectionEnd(Section::Manifest))));
+
+    let filelogs = stream::iter_ok::<_, Error>(filelogs)
+        .map(|(path, entries)| {
+            let section = Section::Filelog(path);
+            let end = Part::SectionEnd(section.clone());
+            stream::iter_ok(entries)
+                .map(|(entry, linknode)| entry_chunk(entry, linknode))
+                .buffered(FETCH_CONCURRENCY)
+                .map(move |chunk| Part::CgChunk(section.clone(), chunk))
+                .chain(stream::once(Ok(end)))
+        })
+        .flatten();
+
+    manifests
+        .chain(filelogs)
+        .chain(stream::once(Ok(Part::End)))
+        .boxify()
+}
+
+fn parent_manifest<R>(
+    repo: Arc<R>,
+    parent: NodeHash,
+) -> BoxFuture<(NodeHash, BoxManifest<R::Error>), Error>
+where
+    R: Repo,
+{
+    repo.get_changeset_by_nodeid(&parent)
+        .and_then(move |cs| {
+            let mfid = *cs.manifestid();
+            repo.get_manifest_by_nodeid(&mfid).map(move |mf| (mfid, mf))
+        })
+        .map_err(repo_err)
+        .boxify()
+}
+
+pub fn changeset_chunk(node: NodeHash, cs: &Changeset) -> Result<CgDeltaChunk> {
+    let mut data = Vec::new();
+    serialize_cs(cs, &mut data)?;
+
+    let (p1, p2) = cs.parents().get_nodes();
+    Ok(CgDeltaChunk {
+        node: node,
+        p1: *p1.unwrap_or(&NULL_HASH),
+        p2: *p2.unwrap_or(&NULL_HASH),
+        base: NULL_HASH,
+        linknode: node,
+        flags: RevFlags::empty(),
+        delta: Delta::new_fulltext(data),
+    })
+}
+
+fn manifest_chunk<E>(
+    manifest: &BoxManifest<E>,
+    node: NodeHash,
+    parents: &[NodeHash],
+    linknode: NodeHash,
+) -> BoxFuture<CgDeltaChunk, Error>
+where
+    E: error::Error + Send + 'static,
+{
+    let p1 = parents.get(0).cloned().unwrap_or(NULL_HASH);
+    let p2 = parents.get(1).cloned().unwrap_or(NULL_HASH);
+
+    manifest_text(manifest)
+        .map(move |data| {
+            CgDeltaChunk {
+                node: node,
+                p1: p1,
+                p2: p2,
+                base: NULL_HASH,
+                linknode: linknode,
+                flags: RevFlags::empty(),
+                delta: Delta::new_fulltext(data),
+            }
+        })
+        .boxify()
+}
+
+// Serialize a manifest the way Mercurial stores it in the manifest revlog
+fn manifest_text<E>(manifest: &BoxManifest<E>) -> BoxFuture<Vec<u8>, Error>
+where
+    E: error::Error + Send + 'static,
+{
+    manifest
+        .list()
+        .map(|entry| {
+            let details = format!("{}{}", entry.get_hash(), entry.get_type());
+            (entry.get_mpath().to_vec(), details)
+        })
+        .collect()
+        .map_err(repo_err)
+        .map(|mut lines| {
+            // Mercurial orders manifest lines by the raw bytes of the path
+            lines.sort();
+
+            let mut data = Vec::new();
+            for (path, details) in lines {
+                data.extend_from_slice(&path);
+                data.push(b'\0');
+                data.extend_from_slice(details.as_bytes());
+                data.push(b'\n');
+            }
+            data
+        })
+        .boxify()
+}
+
+// The entry for the revision of `path` which a changeset introduced, if any
+fn filelog_entry<E>(
+    manifest: &BoxManifest<E>,
+    parent_manifests: &[BoxManifest<E>],
+    path: &MPath,
+) -> BoxFuture<Option<(MPath, BoxEntry<E>)>, Error>
+where
+    E: error::Error + Send + 'static,
+{
+    let parent_hashes = future::join_all(
+        parent_manifests
+            .iter()
+            .map(|mf| {
+                mf.lookup(path)
+                    .map(|entry| entry.map(|entry| *entry.get_hash()))
+            })
+            .collect::<Vec<_>>(),
+    );
+    let path = path.clone();
+
+    manifest
+        .lookup(&path)
+        .join(parent_hashes)
+        .map_err(repo_err)
+        .map(move |(entry, parent_hashes)| match entry {
+            // The file was deleted by this changeset
+            None => None,
+            // The file is unchanged from one of the parents (eg. a merge), so this revision
+            // was introduced by an earlier changeset
+            Some(ref entry) if parent_hashes.contains(&Some(*entry.get_hash())) => None,
+            Some(entry) => Some((path, entry)),
+        })
+        .boxify()
+}
+
+fn entry_chunk<E>(entry: BoxEntry<E>, linknode: NodeHash) -> BoxFuture<CgDeltaChunk, Error>
+where
+    E: error::Error + Send + 'static,
+{
+    let node = *entry.get_hash();
+
+    entry
+        .get_raw_content()
+        .join(entry.get_parents())
+        .map_err(repo_err)
+        .and_then(move |(blob, parents)| {
+            let (p1, p2) = parents.get_nodes();
+            Ok(CgDeltaChunk {
+                node: node,
+                p1: *p1.unwrap_or(&NULL_HASH),
+                p2: *p2.unwrap_or(&NULL_HASH),
+                base: NULL_HASH,
+                linknode: linknode,
+                flags: RevFlags::empty(),
+                delta: fulltext(node, blob)?,
+            })
+        })
+        .boxify()
+}
+
+fn fulltext(node: NodeHash, blob: Blob<Vec<u8>>) -> Result<Delta> {
+    fulltext_bytes(node, blob).map(Delta::new_fulltext)
+}
+
+fn fulltext_bytes(node: NodeHash, blob: Blob<Vec<u8>>) -> Result<Vec<u8>> {
+    blob.into_inner()
+        .ok_or_else(|| ErrorKind::MissingData(node).into())
+}
@@ -0,0 +1,54 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::error;
+
+use error_chain::ChainedError;
+
+use mercurial_types::NodeHash;
+
+error_chain! {
+    errors {
+        Repo {
+            description("repo error")
+        }
+        MissingData(node: NodeHash) {
+            description("node has no data")
+            display("no data for node {}", node)
+        }
+        UnknownLinknode(node: NodeHash, linknode: NodeHash) {
+            description("node is linked to an unknown changeset")
+            display("node {} is linked to unknown changeset {}", node, linknode)
+        }
+        UnknownParent(node: NodeHash, parent: NodeHash) {
+            description("node has a parent which can't be found")
+            display("can't find parent {} of node {}", parent, node)
+        }
+        UnsupportedTreemanifest(node: NodeHash) {
+            description("tree manifests can't be generated")
+            display("can't generate tree manifest revision {}", node)
+        }
+        UnmeasuredRevlog(name: String) {
+            description("revlog was not measured before being sent")
+            display("revlog {} was not measured before being sent", name)
+        }
+    }
+
+    links {
+        Mercurial(::mercurial::Error, ::mercurial::ErrorKind);
+        MercurialTypes(::mercurial_types::Error, ::mercurial_types::ErrorKind);
+        Revset(::revset::Error, ::revset::ErrorKind);
+    }
+
+    foreign_links {
+        Io(::std::io::Error);
+    }
+}
+
+// The repo we're generating from can have any error type, so chain it onto a local ErrorKind.
+pub fn repo_err<E: error::Error + Send + 'static>(err: E) -> Error {
+    ChainedError::with_chain(err, ErrorKind::Repo)
+}
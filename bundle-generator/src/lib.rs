@@ -0,0 +1,31 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Generate the contents of bundles and stream clones sent to Mercurial clients
+//!
+//! This works over any `Repo`, so the same code serves pulls from both revlog and blob repos.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+
+extern crate futures_ext;
+extern crate mercurial;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+extern crate repoinfo;
+extern crate revset;
+
+mod changegroup;
+mod errors;
+mod streamclone;
+
+pub use changegroup::{changesets_between, create_cg1_changegroup, create_changegroup,
+                      missing_changesets};
+pub use errors::*;
+pub use streamclone::create_stream_clone;
@@ -0,0 +1,229 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use futures::future::Future;
+use futures::stream::{self, Stream};
+use futures_ext::{BoxStream, StreamExt};
+
+use mercurial::revlog::{RevIdx, RevlogBuilder};
+use mercurial_bundles::changegroup::{CgVersion, Part, Section};
+use mercurial_types::{delta, MPath, NodeHash, Repo};
+use repoinfo::RepoGenCache;
+
+use changegroup::{changeset_chunk, create_changegroup, FETCH_CONCURRENCY};
+use errors::*;
+
+/// Generate a stream clone of `repo` in Mercurial's `stream_out` format.
+///
+/// The store's revlogs are synthesized from a changegroup of the whole repo, so this works even
+/// for repos which don't keep revlogs. Every revision is stored as a full text, which means
+/// clients can write the files straight to disk without applying any deltas.
+///
+/// The format starts with the size of every revlog, so the changegroup is generated twice: once
+/// to measure the revlogs, and again to encode each revision as it's sent. Only the revlogs'
+/// indexes of nodes are kept in memory.
+pub fn create_stream_clone<R>(
+    repo: Arc<R>,
+    repo_generation: RepoGenCache<R>,
+) -> BoxStream<Vec<u8>, Error>
+where
+    R: Repo,
+{
+    repo.get_heads()
+        .collect()
+        .map_err(repo_err)
+        .and_then(move |heads| {
+            let parts = create_changegroup(
+                repo.clone(),
+                repo_generation.clone(),
+                heads.clone(),
+                vec![],
+                CgVersion::Cg2,
+            );
+            parts
+                .fold(StoreLayout::new(), |mut layout, part| -> Result<_> {
+                    layout.add(part)?;
+                    Ok(layout)
+                })
+                .map(move |layout| encode_stream_out(repo, repo_generation, heads, layout))
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+// Encodes the revlogs making up a store from the parts of a changegroup, a revision at a time
+struct StoreBuilder {
+    changelog: RevlogBuilder,
+    manifest: RevlogBuilder,
+    filelogs: BTreeMap<MPath, RevlogBuilder>,
+    nchangesets: usize,
+}
+
+impl StoreBuilder {
+    fn new() -> Self {
+        StoreBuilder {
+            changelog: RevlogBuilder::new(),
+            manifest: RevlogBuilder::new(),
+            filelogs: BTreeMap::new(),
+            nchangesets: 0,
+        }
+    }
+
+    // Add the revision in `part` to its revlog, returning the revision's section and the bytes
+    // it adds to the revlog
+    fn add(&mut self, part: Part) -> Result<Option<(Section, Vec<u8>)>> {
+        let (section, chunk) = match part {
+            Part::CgChunk(section, chunk) => (section, chunk),
+            Part::SectionEnd(_) | Part::End => return Ok(None),
+        };
+
+        let node = chunk.node;
+        let (p1, p2) = (chunk.p1, chunk.p2);
+        let text = delta::apply(&[], chunk.delta);
+
+        if let Section::Changeset = section {
+            // Changesets are their own linkrevs
+            self.changelog
+                .add_rev(node, &p1, &p2, RevIdx::from(self.nchangesets), &text)?;
+            self.nchangesets += 1;
+            let data = self.changelog.take_encoded();
+            return Ok(Some((section, data)));
+        }
+
+        let linkrev = match self.changelog.get_idx_by_nodeid(&chunk.linknode) {
+            Some(linkrev) => linkrev,
+            None => bail!(ErrorKind::UnknownLinknode(node, chunk.linknode)),
+        };
+
+        let data = {
+            let revlog = match section {
+                Section::Filelog(ref path) => self.filelogs
+                    .entry(path.clone())
+                    .or_insert_with(RevlogBuilder::new),
+                _ => &mut self.manifest,
+            };
+            revlog.add_rev(node, &p1, &p2, linkrev, &text)?;
+            revlog.take_encoded()
+        };
+        Ok(Some((section, data)))
+    }
+}
+
+// The first pass over the changegroup: the size of each revlog, and the changesets in the order
+// they're stored in the changelog
+struct StoreLayout {
+    store: StoreBuilder,
+    sizes: BTreeMap<Vec<u8>, usize>,
+    changesets: Vec<NodeHash>,
+}
+
+impl StoreLayout {
+    fn new() -> Self {
+        StoreLayout {
+            store: StoreBuilder::new(),
+            sizes: BTreeMap::new(),
+            changesets: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, part: Part) -> Result<()> {
+        if let Part::CgChunk(Section::Changeset, ref chunk) = part {
+            self.changesets.push(chunk.node);
+        }
+        if let Some((section, data)) = self.store.add(part)? {
+            *self.sizes.entry(revlog_name(&section)).or_insert(0) += data.len();
+        }
+        Ok(())
+    }
+}
+
+// The path of the revlog holding `section` in the store
+fn revlog_name(section: &Section) -> Vec<u8> {
+    match section {
+        &Section::Changeset => b"00changelog.i".to_vec(),
+        // Only flat manifests are ever generated
+        &Section::Manifest | &Section::Treemanifest(_) => b"00manifest.i".to_vec(),
+        &Section::Filelog(ref path) => {
+            let mut name = b"data/".to_vec();
+            name.extend_from_slice(&path.encodedir());
+            name.extend_from_slice(b".i");
+            name
+        }
+    }
+}
+
+// The format is a status line, a line with the number of files and their total size, and then
+// each file as a header line (`name\0size`) followed by its contents.
+fn encode_stream_out<R>(
+    repo: Arc<R>,
+    repo_generation: RepoGenCache<R>,
+    heads: Vec<NodeHash>,
+    layout: StoreLayout,
+) -> BoxStream<Vec<u8>, Error>
+where
+    R: Repo,
+{
+    let StoreLayout { sizes, changesets, .. } = layout;
+    let total: usize = sizes.values().sum();
+    let header = format!("0\n{} {}\n", sizes.len(), total).into_bytes();
+
+    // Like Mercurial, send the changelog last so that a client never sees changesets whose
+    // contents it doesn't have yet. The rest of the store is sent in changegroup order, which
+    // puts the changelog first, so its revisions are fetched again at the end.
+    let mut store = StoreBuilder::new();
+    let revlogs = create_changegroup(repo.clone(), repo_generation, heads, vec![], CgVersion::Cg2)
+        .and_then(move |part| store.add(part))
+        .filter_map(|revision| revision)
+        .filter(|&(ref section, _)| *section != Section::Changeset);
+
+    let mut changelog_store = StoreBuilder::new();
+    let changelog = stream::iter_ok(changesets)
+        .map(move |node| {
+            repo.get_changeset_by_nodeid(&node)
+                .map_err(repo_err)
+                .and_then(move |cs| changeset_chunk(node, &*cs))
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .and_then(move |chunk| changelog_store.add(Part::CgChunk(Section::Changeset, chunk)))
+        .filter_map(|revision| revision);
+
+    let files = with_file_headers(sizes, revlogs.chain(changelog));
+
+    stream::once(Ok(header)).chain(files).boxify()
+}
+
+// Put the header line of each revlog in front of its first revision
+fn with_file_headers<S>(sizes: BTreeMap<Vec<u8>, usize>, revisions: S) -> BoxStream<Vec<u8>, Error>
+where
+    S: Stream<Item = (Section, Vec<u8>), Error = Error> + Send + 'static,
+{
+    let mut current = None;
+
+    revisions
+        .and_then(move |(section, data)| -> Result<_> {
+            let mut out = Vec::with_capacity(2);
+            if current.as_ref() != Some(&section) {
+                let mut file_header = revlog_name(&section);
+                let size = match sizes.get(&file_header) {
+                    Some(size) => *size,
+                    None => bail!(ErrorKind::UnmeasuredRevlog(
+                        String::from_utf8_lossy(&file_header).into_owned()
+                    )),
+                };
+                write!(file_header, "\0{}\n", size).expect("writes to a Vec can't fail");
+                out.push(file_header);
+                current = Some(section);
+            }
+            out.push(data);
+            Ok(stream::iter_ok::<_, Error>(out))
+        })
+        .flatten()
+        .boxify()
+}
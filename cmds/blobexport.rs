@@ -0,0 +1,408 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Write a blob repo back out as a Mercurial revlog repo. This is the reverse of blobimport.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+
+extern crate blobrepo;
+extern crate mercurial;
+extern crate mercurial_types;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg};
+use futures::{Future, Stream};
+
+use blobrepo::{BlobRepo, BlobState, FilesBlobState, RocksBlobState};
+use mercurial::changeset::serialize_cs;
+use mercurial::manifest::revlog::{self, Details};
+use mercurial::revlog::{Features, RevIdx, RevlogWriter};
+use mercurial_types::{fsencode, BlobNode, Changeset, MPath, MPathElement, NodeHash, Parents,
+                      Repo, NULL_HASH};
+
+mod errors {
+    error_chain! {
+        links {
+            Blobrepo(::blobrepo::Error, ::blobrepo::ErrorKind);
+            Mercurial(::mercurial::Error, ::mercurial::ErrorKind);
+        }
+        foreign_links {
+            Io(::std::io::Error);
+        }
+    }
+}
+
+use errors::*;
+
+// What a fresh `hg init` would write, for a repo with flat manifests
+const REQUIREMENTS: &[&str] = &["dotencode", "fncache", "generaldelta", "revlogv1", "store"];
+
+// Revisions are held in memory until they're committed, so commit every so often
+const COMMIT_INTERVAL: usize = 1000;
+
+/// Writes changesets into the revlogs of a new repo, along with the manifests and file
+/// revisions they introduce.
+struct Exporter<State> {
+    repo: BlobRepo<State>,
+    store: PathBuf,
+    changelog: RevlogWriter,
+    manifest: RevlogWriter,
+    // Filelogs written to since the last commit
+    filelogs: HashMap<MPath, RevlogWriter>,
+    // Store paths of all the filelogs, unencoded, as listed in `fncache`
+    fncache: BTreeSet<Vec<u8>>,
+}
+
+impl<State> Exporter<State>
+where
+    State: BlobState,
+{
+    fn new(repo: BlobRepo<State>, store: PathBuf) -> Result<Self> {
+        // Like Mercurial, the changelog doesn't use general delta. Neither it nor the manifest
+        // is inline, since they soon grow too big for that to make sense.
+        let changelog = RevlogWriter::open(store.join("00changelog.i"), Features::empty())?;
+        let manifest = RevlogWriter::open(store.join("00manifest.i"), Features::GENERAL_DELTA)?;
+        Ok(Exporter {
+            repo: repo,
+            changelog: changelog,
+            manifest: manifest,
+            store: store,
+            filelogs: HashMap::new(),
+            fncache: BTreeSet::new(),
+        })
+    }
+
+    // Add the changeset `csid` as revision `linkrev`. Its parents must have been added already.
+    fn add_changeset(&mut self, csid: &NodeHash, linkrev: RevIdx) -> Result<()> {
+        let cs = self.repo
+            .get_changeset_by_nodeid(csid)
+            .wait()
+            .chain_err(|| format!("failed to fetch changeset {}", csid))?;
+
+        let mut text = Vec::new();
+        serialize_cs(&*cs, &mut text)?;
+        check_hash("changeset", csid, &text, cs.parents())?;
+
+        self.add_manifest(cs.manifestid(), linkrev)
+            .chain_err(|| format!("failed to export changeset {}", csid))?;
+
+        let (p1, p2) = cs.parents().get_nodes();
+        self.changelog.add_rev(
+            *csid,
+            p1.unwrap_or(&NULL_HASH),
+            p2.unwrap_or(&NULL_HASH),
+            linkrev,
+            &text,
+        )?;
+        Ok(())
+    }
+
+    fn add_manifest(&mut self, mfid: &NodeHash, linkrev: RevIdx) -> Result<()> {
+        // Changesets can share a manifest, and one with no files at all has the null manifest
+        if *mfid == NULL_HASH || self.manifest.get_idx_by_nodeid(mfid).is_some() {
+            return Ok(());
+        }
+
+        let parents = self.repo.get_node_parents(mfid).wait()?;
+        let text = self.repo.get_node_text(mfid).wait()?;
+        check_hash("manifest", mfid, &text, &parents)?;
+
+        // Everything in a parent manifest was exported along with it, so only the entries that
+        // differ from both parents are new.
+        let mut parent_entries = Vec::new();
+        for parent in &parents {
+            let text = self.repo.get_node_text(&parent).wait()?;
+            parent_entries.push(revlog::parse(&text)?);
+        }
+
+        for (path, details) in revlog::parse(&text)? {
+            if details.is_tree() {
+                bail!("{}: tree manifests are not supported", path);
+            }
+            if !is_new(&parent_entries, &path, &details) {
+                continue;
+            }
+            self.add_file(&path, details.nodeid(), linkrev)
+                .chain_err(|| format!("failed to export {} revision {}", path, details.nodeid()))?;
+        }
+
+        let (p1, p2) = parents.get_nodes();
+        self.manifest.add_rev(
+            *mfid,
+            p1.unwrap_or(&NULL_HASH),
+            p2.unwrap_or(&NULL_HASH),
+            linkrev,
+            &text,
+        )?;
+        Ok(())
+    }
+
+    fn add_file(&mut self, path: &MPath, nodeid: &NodeHash, linkrev: RevIdx) -> Result<()> {
+        // The same file revision can appear in unrelated manifests, and belongs to the first
+        if self.filelog(path)?.get_idx_by_nodeid(nodeid).is_some() {
+            return Ok(());
+        }
+
+        let parents = self.repo.get_node_parents(nodeid).wait()?;
+        let text = self.repo.get_node_text(nodeid).wait()?;
+        check_hash("file", nodeid, &text, &parents)?;
+
+        let (p1, p2) = parents.get_nodes();
+        self.filelog(path)?.add_rev(
+            *nodeid,
+            p1.unwrap_or(&NULL_HASH),
+            p2.unwrap_or(&NULL_HASH),
+            linkrev,
+            &text,
+        )?;
+        Ok(())
+    }
+
+    fn filelog(&mut self, path: &MPath) -> Result<&mut RevlogWriter> {
+        if !self.filelogs.contains_key(path) {
+            let mut elements = vec![MPathElement::new(b"data".to_vec())];
+            elements.extend(path.into_iter().cloned());
+            if let Some(last) = elements.last_mut() {
+                last.extend(b".i");
+            }
+
+            // Filelogs are always inline, since a non-inline one with a hashed path would need
+            // its data file's path hashed separately.
+            let idxpath = self.store.join(fsencode(&elements, true));
+            let writer = RevlogWriter::open(idxpath, Features::INLINE | Features::GENERAL_DELTA)?;
+            self.filelogs.insert(path.clone(), writer);
+
+            let mut entry = b"data/".to_vec();
+            entry.extend(path.to_vec());
+            entry.extend_from_slice(b".i");
+            self.fncache.insert(entry);
+        }
+        Ok(self.filelogs.get_mut(path).expect("filelog was just opened"))
+    }
+
+    // Filelogs go first and the changelog last, so that nothing refers to revisions which
+    // haven't been written yet.
+    fn commit(&mut self) -> Result<()> {
+        for (_, mut filelog) in self.filelogs.drain() {
+            filelog.commit()?;
+        }
+        self.manifest.commit()?;
+        self.changelog.commit()?;
+        Ok(())
+    }
+
+    fn write_fncache(&self) -> Result<()> {
+        let mut out = Vec::new();
+        for entry in &self.fncache {
+            out.extend_from_slice(entry);
+            out.push(b'\n');
+        }
+        File::create(self.store.join("fncache"))?.write_all(&out)?;
+        Ok(())
+    }
+}
+
+// Whether the manifest entry for `path` differs from its entry in each of the parent manifests
+fn is_new(parent_entries: &[BTreeMap<MPath, Details>], path: &MPath, details: &Details) -> bool {
+    parent_entries.iter().all(|entries| {
+        entries
+            .get(path)
+            .map(|parent| parent.nodeid() != details.nodeid())
+            .unwrap_or(true)
+    })
+}
+
+// Check that `text` and `parents` are what `nodeid` was computed from, so that the exported repo
+// has the same hashes as the blob repo.
+fn check_hash(kind: &str, nodeid: &NodeHash, text: &[u8], parents: &Parents) -> Result<()> {
+    let (p1, p2) = parents.get_nodes();
+    let computed = BlobNode::new(text.to_vec(), p1, p2)
+        .nodeid()
+        .expect("revision text is always present");
+    if computed != *nodeid {
+        bail!("{} {} hashes to {} when exported", kind, nodeid, computed);
+    }
+    Ok(())
+}
+
+// All the changesets reachable from the heads, each after its parents
+fn changesets_in_order<State>(repo: &BlobRepo<State>) -> Result<Vec<NodeHash>>
+where
+    State: BlobState,
+{
+    let mut heads = repo.get_heads()
+        .collect()
+        .wait()
+        .chain_err(|| "failed to get repo heads")?;
+    heads.sort();
+
+    let mut parents: HashMap<NodeHash, Vec<NodeHash>> = HashMap::new();
+    let mut pending = heads.clone();
+    while let Some(node) = pending.pop() {
+        if parents.contains_key(&node) {
+            continue;
+        }
+        let cs = repo.get_changeset_by_nodeid(&node)
+            .wait()
+            .chain_err(|| format!("failed to fetch changeset {}", node))?;
+        let node_parents: Vec<_> = cs.parents().into_iter().collect();
+        pending.extend(node_parents.iter().cloned());
+        parents.insert(node, node_parents);
+    }
+
+    // Depth-first from each head, emitting a changeset once its parents have been. This uses an
+    // explicit stack since histories can be arbitrarily long.
+    let mut order = Vec::with_capacity(parents.len());
+    let mut visited = HashSet::new();
+    let mut stack: Vec<_> = heads.iter().rev().map(|head| (*head, false)).collect();
+    while let Some((node, parents_done)) = stack.pop() {
+        if parents_done {
+            order.push(node);
+            continue;
+        }
+        if !visited.insert(node) {
+            continue;
+        }
+
+        stack.push((node, true));
+        // Push p2 first, so that p1's history is numbered first like Mercurial would
+        for parent in parents[&node].iter().rev() {
+            if !visited.contains(parent) {
+                stack.push((*parent, false));
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+fn write_bookmarks<State>(
+    repo: &BlobRepo<State>,
+    exported: &HashSet<NodeHash>,
+    path: &Path,
+) -> Result<()>
+where
+    State: BlobState,
+{
+    let bookmarks = repo.get_bookmarks()?;
+    let names = bookmarks
+        .keys()
+        .collect()
+        .wait()
+        .chain_err(|| "failed to list bookmarks")?;
+
+    let mut out = Vec::new();
+    for name in names {
+        // The bookmark may have been deleted since it was listed
+        if let Some((node, _)) = bookmarks.get(&name).wait()? {
+            if !exported.contains(&node) {
+                println!(
+                    "skipping bookmark {}: {} is not reachable from the heads",
+                    String::from_utf8_lossy(&name),
+                    node
+                );
+                continue;
+            }
+            write!(out, "{} ", node)?;
+            out.extend_from_slice(&name);
+            out.push(b'\n');
+        }
+    }
+
+    File::create(path)?.write_all(&out)?;
+    Ok(())
+}
+
+fn export<State>(repo: BlobRepo<State>, output: &Path) -> Result<()>
+where
+    State: BlobState,
+{
+    let dothg = output.join(".hg");
+    if dothg.exists() {
+        bail!("{} already exists", dothg.display());
+    }
+    let store = dothg.join("store");
+    fs::create_dir_all(&store).chain_err(|| format!("failed to create {}", store.display()))?;
+
+    let order = changesets_in_order(&repo)?;
+    println!("exporting {} changesets", order.len());
+
+    let mut exporter = Exporter::new(repo.clone(), store)?;
+    for (rev, csid) in order.iter().enumerate() {
+        exporter.add_changeset(csid, RevIdx::from(rev))?;
+        if (rev + 1) % COMMIT_INTERVAL == 0 {
+            exporter.commit()?;
+            println!("exported {} of {} changesets", rev + 1, order.len());
+        }
+    }
+    exporter.commit()?;
+    exporter.write_fncache()?;
+
+    let exported: HashSet<_> = order.into_iter().collect();
+    write_bookmarks(&repo, &exported, &dothg.join("bookmarks"))?;
+
+    // Written last, so that a partial export isn't mistaken for a usable repo
+    let mut requires = Vec::new();
+    for requirement in REQUIREMENTS {
+        write!(requires, "{}\n", requirement)?;
+    }
+    File::create(dothg.join("requires"))?.write_all(&requires)?;
+
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let matches = App::new("blobexport")
+        .version("0.0.0")
+        .about("write a blob repo out as a Mercurial revlog repo")
+        .args_from_usage(concat!(
+            "<REPO>      'path to the blob repo'\n",
+            "<OUTPUT>    'directory to create the revlog repo in'"
+        ))
+        .arg(
+            Arg::with_name("blobstore")
+                .long("blobstore")
+                .short("B")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb"])
+                .required(true)
+                .help("blobstore type"),
+        )
+        .get_matches();
+
+    let repopath = Path::new(matches.value_of("REPO").unwrap());
+    let output = Path::new(matches.value_of("OUTPUT").unwrap());
+
+    match matches.value_of("blobstore").unwrap() {
+        "files" => export(BlobRepo::new(FilesBlobState::new(repopath)?), output)?,
+        "rocksdb" => export(BlobRepo::new(RocksBlobState::new(repopath)?), output)?,
+        bad => bail!("unknown blobstore type {}", bad),
+    }
+
+    println!("Wrote {}", output.display());
+    Ok(())
+}
+
+fn main() {
+    if let Err(ref e) = run() {
+        println!("Failed: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        std::process::exit(1);
+    }
+}
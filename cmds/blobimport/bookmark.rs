@@ -0,0 +1,175 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashSet;
+
+use futures::Stream;
+use regex::bytes::Regex;
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use bookmarks::{Bookmarks, BookmarksMut};
+use filebookmarks::FileBookmarks;
+use filephases::FilePhases;
+use mercurial::RevlogRepo;
+use mercurial::revlog::{RevIdx, Revlog};
+use mercurial_types::NodeHash;
+use phases::{Phase, Phases};
+
+use errors::*;
+
+/// Which of the repo's bookmarks to import, and what to call them.
+pub(crate) struct BookmarkOptions {
+    pub filter: Option<Regex>,
+    pub prefix: Vec<u8>,
+}
+
+impl BookmarkOptions {
+    // The name to import the bookmark `name` as, if it's to be imported at all
+    fn rename(&self, name: &[u8]) -> Option<Vec<u8>> {
+        if let Some(ref filter) = self.filter {
+            if !filter.is_match(name) {
+                return None;
+            }
+        }
+        let mut renamed = self.prefix.clone();
+        renamed.extend_from_slice(name);
+        Some(renamed)
+    }
+}
+
+/// Copy the repo's bookmarks into `bookmarks`. Like a push would, this makes each bookmarked
+/// changeset and its ancestors public before the bookmark is set.
+///
+/// Bookmarks which already exist are moved to where they are in the repo. Bookmarks on changesets
+/// which weren't imported because of `commits_limit` are skipped.
+pub(crate) fn import_bookmarks(
+    core: &mut Core,
+    repo: &RevlogRepo,
+    bookmarks: &FileBookmarks<NodeHash>,
+    phases: &FilePhases<NodeHash>,
+    options: &BookmarkOptions,
+    commits_limit: Option<usize>,
+    logger: &Logger,
+) -> Result<()> {
+    let source = repo.bookmarks()?;
+    let names = core.run(source.keys().collect())
+        .chain_err(|| "Failed to list bookmarks")?;
+
+    for name in names {
+        let display = String::from_utf8_lossy(&name).into_owned();
+        let newname = match options.rename(&name) {
+            Some(newname) => newname,
+            None => {
+                debug!(logger, "skipping bookmark {}, which doesn't match the filter", display);
+                continue;
+            }
+        };
+
+        let node = match core.run(source.get(&name))
+            .chain_err(|| format!("Failed to read bookmark {}", display))?
+        {
+            Some((node, _)) => node,
+            None => continue,
+        };
+        let imported = match repo.get_changelog().get_idx_by_nodeid(&node) {
+            Ok(rev) => match commits_limit {
+                Some(limit) if u32::from(rev) as usize >= limit => None,
+                _ => Some(rev),
+            },
+            Err(_) => None,
+        };
+        let rev = match imported {
+            Some(rev) => rev,
+            None => {
+                warn!(logger, "skipping bookmark {}: {} wasn't imported", display, node);
+                continue;
+            }
+        };
+
+        make_public(core, repo.get_changelog(), phases, rev)
+            .chain_err(|| format!("Failed to make {} public", node))?;
+
+        let update = match core.run(bookmarks.get(&newname))? {
+            Some((ref current, _)) if *current == node => {
+                debug!(logger, "bookmark {} is already at {}", display, node);
+                continue;
+            }
+            Some((_, ref version)) => bookmarks.set(&newname, &node, version),
+            None => bookmarks.create(&newname, &node),
+        };
+        if core.run(update)?.is_none() {
+            bail!("bookmark {} changed while it was being imported", display);
+        }
+        info!(
+            logger,
+            "bookmark {} -> {}",
+            String::from_utf8_lossy(&newname),
+            node
+        );
+    }
+
+    Ok(())
+}
+
+/// Make every imported changeset public, as if it had been pushed to a publishing repo.
+pub(crate) fn make_all_public(
+    core: &mut Core,
+    repo: &RevlogRepo,
+    phases: &FilePhases<NodeHash>,
+    commits_limit: Option<usize>,
+) -> Result<()> {
+    let changelog = repo.get_changelog();
+    let revs: Vec<_> = changelog
+        .into_iter()
+        .map(|(rev, _)| rev)
+        .take_while(|rev| match commits_limit {
+            Some(limit) => (u32::from(*rev) as usize) < limit,
+            None => true,
+        })
+        .collect();
+
+    // Going from the newest changeset means most of them are made public along with a
+    // descendant, and are then skipped
+    for rev in revs.into_iter().rev() {
+        make_public(core, changelog, phases, rev)?;
+    }
+    Ok(())
+}
+
+// Make the changeset `rev` and its ancestors public. This stops at public changesets, since
+// their ancestors are public already.
+fn make_public(
+    core: &mut Core,
+    changelog: &Revlog,
+    phases: &FilePhases<NodeHash>,
+    rev: RevIdx,
+) -> Result<()> {
+    let mut draft = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![rev];
+    while let Some(rev) = pending.pop() {
+        if !seen.insert(rev) {
+            continue;
+        }
+        let entry = changelog.get_entry(rev)?;
+        if core.run(phases.get_phase(&entry.nodeid))? == Phase::Public {
+            continue;
+        }
+        draft.push((rev, entry.nodeid));
+        pending.extend(entry.p1);
+        pending.extend(entry.p2);
+    }
+
+    // Parents come before their children in the changelog, so going in revision order keeps
+    // the public changesets closed under ancestry even if this is interrupted.
+    draft.sort();
+    for (_, node) in draft {
+        core.run(phases.add_public(&node))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use futures::future::{self, Future};
+use futures::sync::oneshot;
+
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial::revlog::RevIdx;
+use mercurial_types::NodeHash;
+
+use errors::*;
+
+/// Records how far an import has got, so that it can resume from there. Everything in the
+/// changelog up to and including `rev` has been written to the blobstore.
+///
+/// The nodeid is kept as well, to check that the checkpoint still matches the repo being
+/// imported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Checkpoint {
+    pub rev: RevIdx,
+    pub nodeid: NodeHash,
+}
+
+impl Checkpoint {
+    /// Read the checkpoint at `path`, if there is one.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let mut data = String::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_string(&mut data)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let fields: Vec<_> = data.split_whitespace().collect();
+        if fields.len() == 2 {
+            let rev = RevIdx::from_str(fields[0]);
+            let nodeid = NodeHash::from_str(fields[1]);
+            if let (Ok(rev), Ok(nodeid)) = (rev, nodeid) {
+                return Ok(Some(Checkpoint { rev, nodeid }));
+            }
+        }
+        bail!("invalid checkpoint in {}: {:?}", path.display(), data)
+    }
+
+    /// Replace the checkpoint at `path` with this one. It's synced to disk before it replaces
+    /// the old one, so that a crash leaves one or the other.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let tmppath = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmppath)?;
+            write!(file, "{} {}\n", u32::from(self.rev), self.nodeid)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmppath, path)?;
+        Ok(())
+    }
+}
+
+/// Tracks the writes the io thread has started, so that each checkpoint is only recorded once
+/// everything sent before it has been written.
+pub(crate) struct Checkpointer {
+    path: PathBuf,
+    // Fire when each write since the last checkpoint, and that checkpoint itself, is done
+    pending: Vec<oneshot::Receiver<()>>,
+}
+
+impl Checkpointer {
+    pub fn new(path: PathBuf) -> Self {
+        Checkpointer {
+            path: path,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Track `write`, which has to finish before the next checkpoint is recorded.
+    pub fn track<F>(&mut self, write: F) -> BoxFuture<(), Error>
+    where
+        F: Future<Item = (), Error = Error> + Send + 'static,
+    {
+        let (done, wait) = oneshot::channel();
+        self.pending.push(wait);
+        write
+            .map(move |()| {
+                let _ = done.send(());
+            })
+            .boxify()
+    }
+
+    /// Record `checkpoint` once all the writes tracked so far have finished. If any of them
+    /// fail, it isn't recorded.
+    pub fn checkpoint(&mut self, checkpoint: Checkpoint) -> BoxFuture<(), Error> {
+        let path = self.path.clone();
+        let earlier = mem::replace(&mut self.pending, Vec::new());
+        let record = future::join_all(earlier)
+            .from_err()
+            .and_then(move |_| checkpoint.write(&path));
+
+        // The next checkpoint waits for this one too, so they're recorded in order
+        self.track(record)
+    }
+}
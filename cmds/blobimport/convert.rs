@@ -4,100 +4,269 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::HashSet;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::mpsc::SyncSender;
 
-use futures::{Future, IntoFuture, Stream};
+use futures::{stream, Future, IntoFuture, Stream};
 use futures_cpupool::CpuPool;
 use slog::Logger;
 use tokio_core::reactor::Core;
 
-use blobrepo::BlobChangeset;
-use futures_ext::{BoxStream, FutureExt, StreamExt};
+use blobrepo::{self, BlobChangeset};
+use blobstore::Blobstore;
+use futures_ext::FutureExt;
 use heads::Heads;
 use linknodes::Linknodes;
-use mercurial::{self, RevlogManifest, RevlogRepo};
+use mercurial::{RevlogManifest, RevlogRepo};
 use mercurial::revlog::RevIdx;
 use mercurial_types::{Changeset, Manifest, NodeHash, RepoPath};
 use stats::Timeseries;
 
 use BlobstoreEntry;
 use STATS;
+use checkpoint::Checkpoint;
 use errors::*;
 use manifest;
+use progress::Progress;
 
-pub(crate) struct ConvertContext<H> {
+// How many changesets are imported between checkpoints
+const CHECKPOINT_INTERVAL: usize = 1000;
+
+pub(crate) struct ConvertContext {
     pub repo: RevlogRepo,
     pub sender: SyncSender<BlobstoreEntry>,
-    pub headstore: H,
     pub core: Core,
     pub cpupool: Arc<CpuPool>,
     pub logger: Logger,
     pub commits_limit: Option<usize>,
+    // In incremental mode, the changelog revisions which an earlier import already copied
+    pub imported: HashSet<RevIdx>,
+    // The first changelog revision to import, which is after the checkpoint when resuming
+    pub start: RevIdx,
+    pub progress: Arc<Progress>,
 }
 
-impl<H> ConvertContext<H>
-where
-    H: Heads<Key = String>,
-    H::Error: Into<Error>,
-{
+impl ConvertContext {
+    /// Import the changesets in batches of `CHECKPOINT_INTERVAL`. Once a batch has been handed
+    /// to the io thread, it's followed by a checkpoint, which the io thread records after it has
+    /// written everything in the batch.
     pub fn convert<L: Linknodes>(self, linknodes_store: L) -> Result<()> {
         let mut core = self.core;
         let logger_owned = self.logger;
         let logger = &logger_owned;
         let cpupool = self.cpupool;
-        let headstore = self.headstore;
         let commits_limit = self.commits_limit;
-
-        let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(limit) = commits_limit {
-            self.repo.changesets().take(limit as u64).boxify()
-        } else {
-            self.repo.changesets().boxify()
-        };
+        let imported = self.imported;
+        let progress = self.progress;
+        let repo = self.repo;
+        let sender = self.sender;
         let linknodes_store = Arc::new(linknodes_store);
 
-        // Generate stream of changesets. For each changeset, save the cs blob, and the manifest
-        // blob, and the files.
-        let changesets = changesets
-            .map_err(Error::from)
-            .enumerate()
-            .map({
-                let repo = self.repo.clone();
-                let sender = self.sender.clone();
-                move |(seq, csid)| {
-                    debug!(logger, "{}: changeset {}", seq, csid);
-                    STATS::changesets.add_value(1);
-                    copy_changeset(repo.clone(), sender.clone(), linknodes_store.clone(), csid)
-                }
-            }) // Stream<Future<()>>
-            .map(|copy| cpupool.spawn(copy))
-            .buffer_unordered(100);
-
-        let heads = self.repo
-            .get_heads()
-            .map_err(Error::from)
-            .map_err(|err| Error::with_chain(err, "Failed get heads"))
-            .map(|h| {
-                debug!(logger, "head {}", h);
-                STATS::heads.add_value(1);
-                headstore
-                    .add(&format!("{}", h))
-                    .map_err(Into::into)
-                    .map_err({
-                        move |err| Error::with_chain(err, format!("Failed to create head {}", h))
-                    })
+        // Earlier imports went in revision order, so skip straight past what they copied
+        let mut start = self.start;
+        while imported.contains(&start) {
+            start = start.succ();
+        }
+
+        let mut revs = repo.get_changelog().into_iter();
+        revs.seek(start);
+        let mut revs = revs.map(|(rev, entry)| (rev, entry.nodeid))
+            .take_while(|&(rev, _)| match commits_limit {
+                Some(limit) => (u32::from(rev) as usize) < limit,
+                None => true,
             })
-            .buffer_unordered(100);
+            .filter(|&(rev, csid)| {
+                if imported.contains(&rev) {
+                    debug!(logger, "skipping changeset {}, already imported", csid);
+                    STATS::skipped.add_value(1);
+                    progress.changeset_skipped();
+                    false
+                } else {
+                    true
+                }
+            });
+
+        loop {
+            let batch: Vec<_> = revs.by_ref().take(CHECKPOINT_INTERVAL).collect();
+            let checkpoint = match batch.last() {
+                Some(&(rev, nodeid)) => Checkpoint { rev, nodeid },
+                None => break,
+            };
 
-        let convert = changesets.select(heads).for_each(|_| Ok(()));
+            // Generate stream of changesets. For each changeset, save the cs blob, and the
+            // manifest blob, and the files.
+            let changesets = stream::iter_ok(batch)
+                .map({
+                    let repo = repo.clone();
+                    let sender = sender.clone();
+                    let linknodes_store = linknodes_store.clone();
+                    let progress = progress.clone();
+                    move |(rev, csid)| {
+                        debug!(logger, "{}: changeset {}", u32::from(rev), csid);
+                        STATS::changesets.add_value(1);
+                        let progress = progress.clone();
+                        copy_changeset(repo.clone(), sender.clone(), linknodes_store.clone(), csid)
+                            .map(move |()| progress.changeset_imported())
+                    }
+                }) // Stream<Future<()>>
+                .map(|copy| cpupool.spawn(copy))
+                .buffer_unordered(100);
 
-        core.run(convert)?;
+            core.run(changesets.for_each(|_| Ok(())))?;
+
+            sender
+                .send(BlobstoreEntry::Checkpoint(checkpoint))
+                .map_err(|e| Error::from(e.to_string()))?;
+        }
 
         info!(logger, "parsed everything, waiting for io");
         Ok(())
     }
 }
 
+/// The changelog revisions which an earlier import has already copied, which are the ancestors
+/// of the heads it recorded in `headstore`. Heads which aren't in the repo or in `blobstore` are
+/// ignored.
+pub(crate) fn imported_revs<H, B>(
+    core: &mut Core,
+    repo: &RevlogRepo,
+    headstore: &H,
+    blobstore: B,
+    logger: &Logger,
+) -> Result<HashSet<RevIdx>>
+where
+    H: Heads<Key = String>,
+    H::Error: Into<Error>,
+    B: Blobstore<Key = String>,
+{
+    let heads = core.run(headstore.heads().map_err(Into::into).collect())
+        .chain_err(|| "Failed to read existing heads")?;
+
+    let changelog = repo.get_changelog();
+    let mut pending = Vec::new();
+    for head in heads {
+        let node = NodeHash::from_str(&head).chain_err(|| format!("Invalid head {}", head))?;
+        let rev = match changelog.get_idx_by_nodeid(&node) {
+            Ok(rev) => rev,
+            Err(_) => {
+                warn!(logger, "head {} is not in the repo, ignoring it", head);
+                continue;
+            }
+        };
+
+        // Heads are only recorded once their changesets are written, but don't skip a head's
+        // ancestors unless it really is there
+        let exists = core.run(BlobChangeset::load(&blobstore, &node))
+            .chain_err(|| format!("Failed to load head {}", head))?
+            .is_some();
+        if exists {
+            pending.push(rev);
+        } else {
+            warn!(logger, "head {} is not in the blobstore, ignoring it", head);
+        }
+    }
+
+    let mut imported = HashSet::new();
+    while let Some(rev) = pending.pop() {
+        if !imported.insert(rev) {
+            continue;
+        }
+        let entry = changelog.get_entry(rev)?;
+        pending.extend(entry.p1);
+        pending.extend(entry.p2);
+    }
+
+    Ok(imported)
+}
+
+/// Add the changesets to the index used to look them up by prefix. That includes any which an
+/// earlier import copied, so that importing again indexes repos imported before there was one.
+pub(crate) fn index_changesets<B>(
+    core: &mut Core,
+    repo: &RevlogRepo,
+    blobstore: B,
+    commits_limit: Option<usize>,
+) -> Result<()>
+where
+    B: Blobstore<Key = String> + Clone,
+    B::ValueIn: From<Vec<u8>>,
+{
+    let nodes = repo.get_changelog()
+        .into_iter()
+        .take_while(|&(rev, _)| match commits_limit {
+            Some(limit) => (u32::from(rev) as usize) < limit,
+            None => true,
+        })
+        .map(|(_, entry)| entry.nodeid)
+        .collect();
+
+    core.run(blobrepo::index_changesets(blobstore, nodes))
+        .chain_err(|| "Failed to index changesets")
+}
+
+/// Record the repo's heads in `headstore`. This should only be done once everything has been
+/// written to the blobstore, so that the heads never refer to changesets which aren't there yet.
+///
+/// In incremental mode, heads left by an earlier import which have had changesets added on top of
+/// them since are removed.
+pub(crate) fn update_heads<H>(
+    core: &mut Core,
+    repo: &RevlogRepo,
+    headstore: &H,
+    logger: &Logger,
+    incremental: bool,
+) -> Result<()>
+where
+    H: Heads<Key = String>,
+    H::Error: Into<Error>,
+{
+    let old_heads = if incremental {
+        core.run(headstore.heads().map_err(Into::into).collect())
+            .chain_err(|| "Failed to read existing heads")?
+    } else {
+        Vec::new()
+    };
+
+    let heads = core.run(repo.get_heads().collect())
+        .map_err(Error::from)
+        .chain_err(|| "Failed get heads")?;
+    let heads: HashSet<String> = heads.iter().map(|h| format!("{}", h)).collect();
+
+    let add = stream::iter_ok(heads.iter().cloned())
+        .map(|h| {
+            debug!(logger, "head {}", h);
+            STATS::heads.add_value(1);
+            headstore
+                .add(&h)
+                .map_err(Into::into)
+                .map_err({
+                    move |err| Error::with_chain(err, format!("Failed to create head {}", h))
+                })
+        })
+        .buffer_unordered(100)
+        .for_each(|_| Ok(()));
+    core.run(add)?;
+
+    for old in old_heads {
+        if heads.contains(&old) {
+            continue;
+        }
+        let node = NodeHash::from_str(&old).chain_err(|| format!("Invalid head {}", old))?;
+        // Leave alone heads the repo doesn't know about, as they didn't come from it
+        if !core.run(repo.changeset_exists(&node))? {
+            warn!(logger, "head {} is not in the repo, leaving it", old);
+            continue;
+        }
+        debug!(logger, "removing old head {}", old);
+        core.run(headstore.remove(&old).map_err(Into::into))
+            .chain_err(|| format!("Failed to remove head {}", old))?;
+    }
+
+    Ok(())
+}
+
 /// Copy a changeset and its manifest into the blobstore
 ///
 /// The changeset and the manifest are straightforward - we just make literal copies of the
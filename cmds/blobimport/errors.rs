@@ -10,7 +10,9 @@ error_chain! {
         Mercurial(::mercurial::Error, ::mercurial::ErrorKind);
         Rocksblob(::rocksblob::Error, ::rocksblob::ErrorKind);
         FileKV(::filekv::Error, ::filekv::ErrorKind);
+        FileBookmarks(::filebookmarks::Error, ::filebookmarks::ErrorKind);
         FileHeads(::fileheads::Error, ::fileheads::ErrorKind);
+        FilePhases(::filephases::Error, ::filephases::ErrorKind);
         Fileblob(::fileblob::Error, ::fileblob::ErrorKind);
         Linknodes(::linknodes::Error, ::linknodes::ErrorKind);
         Manifold(::manifoldblob::Error, ::manifoldblob::ErrorKind);
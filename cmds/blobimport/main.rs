@@ -16,6 +16,7 @@ extern crate futures;
 extern crate futures_cpupool;
 #[macro_use]
 extern crate lazy_static;
+extern crate regex;
 #[macro_use]
 extern crate slog;
 #[macro_use]
@@ -25,26 +26,36 @@ extern crate tokio_core;
 
 extern crate blobrepo;
 extern crate blobstore;
+extern crate bookmarks;
 extern crate fileblob;
+extern crate filebookmarks;
 extern crate fileheads;
 extern crate filekv;
 extern crate filelinknodes;
+extern crate filephases;
 extern crate futures_ext;
 extern crate heads;
 extern crate linknodes;
 extern crate manifoldblob;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate phases;
 extern crate rocksblob;
 extern crate rocksdb;
 extern crate services;
 #[macro_use]
 extern crate stats;
 
+mod bookmark;
+mod checkpoint;
 mod convert;
 mod errors;
 mod manifest;
+mod progress;
 
+use std::collections::HashSet;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::mpsc::sync_channel;
@@ -54,6 +65,7 @@ use bytes::Bytes;
 use clap::{App, Arg, ArgMatches};
 use futures::{stream, Future, IntoFuture, Stream};
 use futures_cpupool::CpuPool;
+use regex::bytes::Regex;
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::default_drain as glog_drain;
 use stats::Timeseries;
@@ -62,15 +74,22 @@ use tokio_core::reactor::{Core, Remote};
 use blobrepo::BlobChangeset;
 use blobstore::Blobstore;
 use fileblob::Fileblob;
+use filebookmarks::FileBookmarks;
 use fileheads::FileHeads;
 use filelinknodes::FileLinknodes;
+use filephases::FilePhases;
 use futures_ext::{BoxFuture, FutureExt};
 use linknodes::NoopLinknodes;
 use manifoldblob::ManifoldBlob;
 use mercurial::RevlogRepo;
+use mercurial::revlog::RevIdx;
+use mercurial_types::NodeHash;
 use rocksblob::Rocksblob;
 
+use bookmark::BookmarkOptions;
+use checkpoint::{Checkpoint, Checkpointer};
 use errors::*;
+use progress::{CountingBlobstore, Progress};
 
 const DEFAULT_MANIFOLD_BUCKET: &str = "mononoke_prod";
 
@@ -79,11 +98,12 @@ define_stats! {
     changesets: timeseries(RATE, SUM),
     heads: timeseries(RATE, SUM),
     duplicates: timeseries(RATE, SUM),
+    skipped: timeseries(RATE, SUM),
     failures: timeseries(RATE, SUM),
     successes: timeseries(RATE, SUM),
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum BlobstoreType {
     Files,
     Rocksdb,
@@ -110,6 +130,8 @@ fn _assert_blobstore<T: Blobstore>(_: &T) {}
 pub(crate) enum BlobstoreEntry {
     ManifestEntry((String, Bytes)),
     Changeset(BlobChangeset),
+    // Ends a batch of changesets, to be recorded once everything sent before it is written
+    Checkpoint(Checkpoint),
 }
 
 fn run_blobimport<In, Out>(
@@ -117,6 +139,9 @@ fn run_blobimport<In, Out>(
     output: Out,
     blobtype: BlobstoreType,
     write_linknodes: bool,
+    incremental: bool,
+    draft: bool,
+    bookmark_options: &BookmarkOptions,
     logger: &Logger,
     postpone_compaction: bool,
     channel_size: usize,
@@ -131,16 +156,69 @@ where
     let output = output.into();
     let core = Core::new()?;
     let cpupool = Arc::new(CpuPool::new_num_cpus());
+    let progress = Arc::new(Progress::new());
+
+    let repo = open_repo(&input)?;
+
+    let checkpoint_path = output.join("checkpoint");
+    let start = match Checkpoint::read(&checkpoint_path)? {
+        None => RevIdx::zero(),
+        Some(checkpoint) => {
+            let matches = repo.get_changelog()
+                .get_entry(checkpoint.rev)
+                .map(|entry| entry.nodeid == checkpoint.nodeid)
+                .unwrap_or(false);
+            if !matches {
+                bail!(
+                    "checkpoint {} doesn't match the repo: revision {} is not {} (remove it to \
+                     import from the start)",
+                    checkpoint_path.display(),
+                    u32::from(checkpoint.rev),
+                    checkpoint.nodeid
+                );
+            }
+            info!(
+                logger,
+                "Resuming after revision {} ({})",
+                u32::from(checkpoint.rev),
+                checkpoint.nodeid
+            );
+            checkpoint.rev.succ()
+        }
+    };
 
     info!(logger, "Opening headstore: {}", output.display());
     let headstore = open_headstore(&output, &cpupool)?;
 
+    info!(logger, "Creating phase store: {}", output.display());
+    let phasestore = create_phasestore(&output, &cpupool)?;
+
+    info!(logger, "Opening bookmark store: {}", output.display());
+    let bookmarkstore = open_bookmarkstore(&output, &cpupool)?;
+
     if let BlobstoreType::Manifold(ref bucket) = blobtype {
         info!(logger, "Using ManifoldBlob with bucket: {:?}", bucket);
     } else {
         info!(logger, "Opening blobstore: {}", output.display());
     }
 
+    let imported = if incremental {
+        info!(logger, "Incremental import, skipping ancestors of the existing heads");
+        let mut core = Core::new()?;
+        // The blobstore is closed again before the io thread opens it
+        let blobstore = open_blobstore(
+            output.clone(),
+            blobtype.clone(),
+            &core.remote(),
+            postpone_compaction,
+            max_blob_size,
+            progress.clone(),
+        )?;
+        convert::imported_revs(&mut core, &repo, &headstore, blobstore, logger)?
+    } else {
+        HashSet::new()
+    };
+
     let (sender, recv) = sync_channel::<BlobstoreEntry>(channel_size);
     // Separate thread that does all blobstore operations. Other worker threads send parsed revlog
     // data to this thread.
@@ -148,6 +226,9 @@ where
         .name("iothread".to_owned())
         .spawn({
             let output = output.clone();
+            let blobtype = blobtype.clone();
+            let checkpoint_path = checkpoint_path.clone();
+            let progress = progress.clone();
             move || {
                 let receiverstream = stream::iter_ok::<_, ()>(recv);
                 let mut core = Core::new().expect("cannot create core in iothread");
@@ -157,22 +238,27 @@ where
                     &core.remote(),
                     postpone_compaction,
                     max_blob_size,
+                    progress,
                 )?;
+                let mut checkpointer = Checkpointer::new(checkpoint_path);
                 // Filter only manifest entries, because changeset entries should be unique
                 let mut inserted_manifest_entries = std::collections::HashSet::new();
                 let stream = receiverstream
                     .map(move |sender_helper| match sender_helper {
                         BlobstoreEntry::Changeset(bcs) => {
-                            bcs.save(blobstore.clone()).from_err().boxify()
+                            checkpointer.track(bcs.save(blobstore.clone()).from_err())
                         }
                         BlobstoreEntry::ManifestEntry((key, value)) => {
                             if inserted_manifest_entries.insert(key.clone()) {
-                                blobstore.put(key.clone(), value).boxify()
+                                checkpointer.track(blobstore.put(key.clone(), value))
                             } else {
                                 STATS::duplicates.add_value(1);
                                 Ok(()).into_future().boxify()
                             }
                         }
+                        BlobstoreEntry::Checkpoint(checkpoint) => {
+                            checkpointer.checkpoint(checkpoint)
+                        }
                     })
                     .map_err(|_| Error::from("error happened"))
                     .buffer_unordered(channel_size)
@@ -189,18 +275,19 @@ where
         })
         .expect("cannot start iothread");
 
-    let repo = open_repo(&input)?;
-
     info!(logger, "Converting: {}", input.display());
     let convert_context = convert::ConvertContext {
-        repo,
+        repo: repo.clone(),
         sender,
-        headstore,
         core,
         cpupool: cpupool.clone(),
         logger: logger.clone(),
         commits_limit: commits_limit,
+        imported: imported,
+        start: start,
+        progress: progress.clone(),
     };
+    Progress::report_periodically(progress.clone(), logger.clone())?;
     let res = if write_linknodes {
         info!(logger, "Opening linknodes store: {:?}", output);
         let linknodes_store = open_linknodes_store(&output, &cpupool)?;
@@ -210,7 +297,52 @@ where
         convert_context.convert(NoopLinknodes::new())
     };
     iothread.join().expect("failed to join io thread")?;
-    res
+    res?;
+
+    info!(logger, "Indexing changesets");
+    let mut core = Core::new()?;
+    let blobstore = open_blobstore(
+        output.clone(),
+        blobtype,
+        &core.remote(),
+        postpone_compaction,
+        max_blob_size,
+        progress.clone(),
+    )?;
+    convert::index_changesets(&mut core, &repo, blobstore, commits_limit)?;
+
+    info!(logger, "Updating heads");
+    convert::update_heads(&mut core, &repo, &headstore, logger, incremental)?;
+
+    // Changesets which are left draft only become public as bookmarks pointing to them are
+    // imported
+    if !draft {
+        info!(logger, "Making changesets public");
+        bookmark::make_all_public(&mut core, &repo, &phasestore, commits_limit)?;
+    }
+
+    info!(logger, "Importing bookmarks");
+    bookmark::import_bookmarks(
+        &mut core,
+        &repo,
+        &bookmarkstore,
+        &phasestore,
+        bookmark_options,
+        commits_limit,
+        logger,
+    )?;
+
+    // The import is complete, so running it again should start from the beginning
+    if let Err(err) = fs::remove_file(&checkpoint_path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err).chain_err(|| {
+                format!("Failed to remove checkpoint {}", checkpoint_path.display())
+            });
+        }
+    }
+
+    progress.finish(logger);
+    Ok(())
 }
 
 fn open_repo<P: Into<PathBuf>>(input: P) -> Result<RevlogRepo> {
@@ -234,6 +366,28 @@ fn open_headstore<P: Into<PathBuf>>(heads: P, pool: &Arc<CpuPool>) -> Result<Fil
     Ok(headstore)
 }
 
+fn create_phasestore<P: Into<PathBuf>>(
+    path: P,
+    pool: &Arc<CpuPool>,
+) -> Result<FilePhases<NodeHash>> {
+    let mut path = path.into();
+    path.push("phases");
+    let phasestore = FilePhases::create_with_pool(path, pool.clone())?;
+
+    Ok(phasestore)
+}
+
+fn open_bookmarkstore<P: Into<PathBuf>>(
+    path: P,
+    pool: &Arc<CpuPool>,
+) -> Result<FileBookmarks<NodeHash>> {
+    let mut path = path.into();
+    path.push("books");
+    let bookmarkstore = FileBookmarks::create_with_pool(path, pool.clone())?;
+
+    Ok(bookmarkstore)
+}
+
 fn open_linknodes_store<P: Into<PathBuf>>(path: P, pool: &Arc<CpuPool>) -> Result<FileLinknodes> {
     let mut linknodes_path = path.into();
     linknodes_path.push("linknodes");
@@ -247,6 +401,7 @@ fn open_blobstore<P: Into<PathBuf>>(
     remote: &Remote,
     postpone_compaction: bool,
     max_blob_size: Option<usize>,
+    progress: Arc<Progress>,
 ) -> Result<BBlobstore> {
     let mut output = output.into();
     output.push("blobs");
@@ -271,6 +426,11 @@ fn open_blobstore<P: Into<PathBuf>>(
         }
     };
 
+    let blobstore: BBlobstore = Arc::new(CountingBlobstore {
+        blobstore,
+        progress,
+    });
+
     let blobstore = if let Some(max_blob_size) = max_blob_size {
         Arc::new(LimitedBlobstore {
             blobstore,
@@ -321,18 +481,22 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .about("make blobs")
         .args_from_usage(
             r#"
-            <INPUT>                  'input revlog repo'
-            <OUTPUT>                 'output blobstore RepoCtx'
-
-            -p, --port [PORT]        'if provided the thrift server will start on this port'
-
-            --postpone-compaction    '(rocksdb only) postpone auto compaction while importing'
-
-            -d, --debug              'print debug level output'
-            --linknodes              'also generate linknodes'
-            --channel-size [SIZE]    'channel size between worker and io threads. Default: 1000'
-            --commits-limit [LIMIT]  'import only LIMIT first commits from revlog repo'
-            --max-blob-size [LIMIT]  'max size of the blob to be inserted'
+            <INPUT>                    'input revlog repo'
+            <OUTPUT>                   'output blobstore RepoCtx'
+
+            -p, --port [PORT]          'if provided the thrift server will start on this port'
+
+            --postpone-compaction      '(rocksdb only) postpone auto compaction while importing'
+
+            -d, --debug                'print debug level output'
+            --linknodes                'also generate linknodes'
+            --incremental              'skip changesets which are already imported'
+            --draft                    'only make changesets public if an imported bookmark points to them'
+            --channel-size [SIZE]      'channel size between worker and io threads. Default: 1000'
+            --commits-limit [LIMIT]    'import only LIMIT first commits from revlog repo'
+            --max-blob-size [LIMIT]    'max size of the blob to be inserted'
+            --bookmark-filter [REGEX]  'only import bookmarks whose names match REGEX'
+            --bookmark-prefix [PREFIX] 'prepend PREFIX to the names of imported bookmarks'
         "#,
         )
         .arg(
@@ -428,12 +592,30 @@ fn main() {
             .unwrap_or(1000);
 
         let write_linknodes = matches.is_present("linknodes");
+        let incremental = matches.is_present("incremental");
+        let draft = matches.is_present("draft");
+
+        let bookmark_filter = match matches.value_of("bookmark-filter") {
+            Some(filter) => Some(Regex::new(filter).chain_err(|| "invalid --bookmark-filter")?),
+            None => None,
+        };
+        let bookmark_options = BookmarkOptions {
+            filter: bookmark_filter,
+            prefix: matches
+                .value_of("bookmark-prefix")
+                .unwrap_or("")
+                .as_bytes()
+                .to_vec(),
+        };
 
         run_blobimport(
             input,
             output,
             blobtype,
             write_linknodes,
+            incremental,
+            draft,
+            &bookmark_options,
             &root_log,
             postpone_compaction,
             channel_size,
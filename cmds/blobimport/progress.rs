@@ -0,0 +1,172 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::Future;
+use slog::Logger;
+
+use blobstore::Blobstore;
+use futures_ext::{BoxFuture, FutureExt};
+
+use BBlobstore;
+use errors::*;
+
+const REPORT_INTERVAL: u64 = 30; // seconds
+
+#[derive(Default)]
+struct BlobCounts {
+    blobs: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl BlobCounts {
+    fn add(&self, bytes: usize) {
+        self.blobs.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> (usize, usize) {
+        (
+            self.blobs.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counts of what an import has done so far, shared between the threads doing it.
+pub(crate) struct Progress {
+    start: Instant,
+    finished: AtomicBool,
+    changesets: AtomicUsize,
+    skipped: AtomicUsize,
+    // Blobs written, by the kind of key they're stored under
+    changeset_blobs: BlobCounts,
+    node_blobs: BlobCounts,
+    content_blobs: BlobCounts,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress {
+            start: Instant::now(),
+            finished: AtomicBool::new(false),
+            changesets: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            changeset_blobs: BlobCounts::default(),
+            node_blobs: BlobCounts::default(),
+            content_blobs: BlobCounts::default(),
+        }
+    }
+
+    pub fn changeset_imported(&self) {
+        self.changesets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn changeset_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn blob_written(&self, key: &str, bytes: usize) {
+        if key.starts_with("changeset-") {
+            self.changeset_blobs.add(bytes);
+        } else if key.starts_with("node-") {
+            self.node_blobs.add(bytes);
+        } else {
+            self.content_blobs.add(bytes);
+        }
+    }
+
+    /// Log the progress so far.
+    pub fn report(&self, logger: &Logger) {
+        let changesets = self.changesets.load(Ordering::Relaxed);
+        let (blobs, bytes) = [&self.changeset_blobs, &self.node_blobs, &self.content_blobs]
+            .iter()
+            .map(|counts| counts.get())
+            .fold((0, 0), |(blobs, bytes), (b, n)| (blobs + b, bytes + n));
+        info!(
+            logger,
+            "imported {} changesets ({:.1}/s), wrote {} blobs ({} bytes)",
+            changesets,
+            changesets as f64 / self.elapsed_secs(),
+            blobs,
+            bytes
+        );
+    }
+
+    /// Log a breakdown of everything imported, and stop any periodic reports.
+    pub fn finish(&self, logger: &Logger) {
+        self.finished.store(true, Ordering::Relaxed);
+
+        info!(
+            logger,
+            "import finished in {:.0}s: {} changesets imported, {} already imported",
+            self.elapsed_secs(),
+            self.changesets.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed)
+        );
+        let kinds = [
+            ("changeset", &self.changeset_blobs),
+            ("node", &self.node_blobs),
+            ("content", &self.content_blobs),
+        ];
+        for &(kind, counts) in kinds.iter() {
+            let (blobs, bytes) = counts.get();
+            info!(logger, "{} blobs: {} ({} bytes)", kind, blobs, bytes);
+        }
+    }
+
+    /// Report progress every so often until the import finishes.
+    pub fn report_periodically(progress: Arc<Self>, logger: Logger) -> Result<()> {
+        thread::Builder::new()
+            .name("progress".to_owned())
+            .spawn(move || loop {
+                thread::sleep(Duration::from_secs(REPORT_INTERVAL));
+                if progress.finished.load(Ordering::Relaxed) {
+                    break;
+                }
+                progress.report(&logger);
+            })?; // thread detached
+        Ok(())
+    }
+
+    fn elapsed_secs(&self) -> f64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+    }
+}
+
+/// Blobstore that counts the blobs written to it in a `Progress`
+pub(crate) struct CountingBlobstore {
+    pub blobstore: BBlobstore,
+    pub progress: Arc<Progress>,
+}
+
+impl Blobstore for CountingBlobstore {
+    type Key = String;
+    type ValueIn = Bytes;
+    type ValueOut = Vec<u8>;
+    type Error = Error;
+    type GetBlob = BoxFuture<Option<Vec<u8>>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: &Self::Key) -> Self::GetBlob {
+        self.blobstore.get(key)
+    }
+
+    fn put(&self, key: Self::Key, val: Self::ValueIn) -> Self::PutBlob {
+        let progress = self.progress.clone();
+        let bytes = val.len();
+        self.blobstore
+            .put(key.clone(), val)
+            .map(move |()| progress.blob_written(&key, bytes))
+            .boxify()
+    }
+}
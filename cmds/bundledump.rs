@@ -0,0 +1,301 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Print the contents of a bundle, as written by `hg bundle` or sent in reply to `getbundle`.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+
+use bytes::Bytes;
+use clap::App;
+use futures::Stream;
+use slog::{Discard, Logger};
+
+use mercurial_bundles::Bundle2Item;
+use mercurial_bundles::bundle1::Bundle1Stream;
+use mercurial_bundles::bundle2::Bundle2Stream;
+use mercurial_bundles::changegroup::{CgDeltaChunk, Part, Section};
+use mercurial_types::{delta, BlobNode, NodeHash, NULL_HASH};
+
+mod errors {
+    error_chain! {
+        links {
+            MercurialBundles(::mercurial_bundles::Error, ::mercurial_bundles::ErrorKind);
+        }
+        foreign_links {
+            Io(::std::io::Error);
+        }
+    }
+}
+
+use errors::*;
+
+/// Prints changegroups a chunk at a time, optionally rebuilding each revision to check it.
+struct ChangegroupDump {
+    verify: bool,
+    in_section: bool,
+    // The full text of each revision in the current section, as delta bases for later ones
+    texts: HashMap<NodeHash, Vec<u8>>,
+    verified: usize,
+    unverifiable: usize,
+    failed: usize,
+}
+
+/// What happened when rebuilding a revision from its delta.
+enum Rebuilt {
+    Text(Vec<u8>),
+    // The base isn't in the bundle, or couldn't be rebuilt itself
+    NoBase,
+    BadDelta,
+}
+
+impl ChangegroupDump {
+    fn new(verify: bool) -> Self {
+        ChangegroupDump {
+            verify: verify,
+            in_section: false,
+            texts: HashMap::new(),
+            verified: 0,
+            unverifiable: 0,
+            failed: 0,
+        }
+    }
+
+    fn part(&mut self, part: &Part, indent: &str) {
+        match part {
+            &Part::CgChunk(ref section, ref chunk) => {
+                if !self.in_section {
+                    println!("{}{}:", indent, section_name(section));
+                    self.in_section = true;
+                }
+                let status = if self.verify {
+                    self.check(chunk)
+                } else {
+                    String::new()
+                };
+                println!("{}  {}{}", indent, chunk_summary(chunk), status);
+            }
+            &Part::SectionEnd(_) => {
+                self.in_section = false;
+                self.texts.clear();
+            }
+            &Part::End => {}
+        }
+    }
+
+    // Rebuild the revision in `chunk`, and describe how that went
+    fn check(&mut self, chunk: &CgDeltaChunk) -> String {
+        let text = match rebuild(&self.texts, chunk) {
+            Rebuilt::Text(text) => text,
+            Rebuilt::NoBase => {
+                self.unverifiable += 1;
+                return " (base not available)".into();
+            }
+            Rebuilt::BadDelta => {
+                self.failed += 1;
+                return " BAD: delta does not apply to its base".into();
+            }
+        };
+
+        let p1 = if chunk.p1 == NULL_HASH { None } else { Some(&chunk.p1) };
+        let p2 = if chunk.p2 == NULL_HASH { None } else { Some(&chunk.p2) };
+        let computed = BlobNode::new(text.clone(), p1, p2)
+            .nodeid()
+            .expect("revision text is always present");
+
+        if computed == chunk.node {
+            self.texts.insert(chunk.node, text);
+            self.verified += 1;
+            " ok".into()
+        } else {
+            self.failed += 1;
+            format!(" BAD: hashes to {}", computed)
+        }
+    }
+}
+
+// A delta's base is either the null revision or an earlier revision in the same section
+fn rebuild(texts: &HashMap<NodeHash, Vec<u8>>, chunk: &CgDeltaChunk) -> Rebuilt {
+    let base: &[u8] = if chunk.base == NULL_HASH {
+        &[]
+    } else {
+        match texts.get(&chunk.base) {
+            Some(text) => &text[..],
+            None => return Rebuilt::NoBase,
+        }
+    };
+
+    // Applying a delta trusts its offsets, so check them against the base first
+    if chunk
+        .delta
+        .fragments()
+        .iter()
+        .any(|frag| frag.end > base.len())
+    {
+        return Rebuilt::BadDelta;
+    }
+    Rebuilt::Text(delta::apply(base, chunk.delta.clone()))
+}
+
+fn section_name(section: &Section) -> String {
+    match section {
+        &Section::Changeset => "changesets".into(),
+        &Section::Manifest => "manifests".into(),
+        &Section::Treemanifest(ref path) => format!("tree manifest {}", path),
+        &Section::Filelog(ref path) => format!("file {}", path),
+    }
+}
+
+fn chunk_summary(chunk: &CgDeltaChunk) -> String {
+    let mut out = format!(
+        "node {} p1 {} p2 {} linknode {} base {}",
+        chunk.node,
+        chunk.p1,
+        chunk.p2,
+        chunk.linknode,
+        chunk.base
+    );
+    if !chunk.flags.is_empty() {
+        out.push_str(&format!(" flags {:?}", chunk.flags));
+    }
+    out
+}
+
+fn param_value(value: &Bytes) -> String {
+    String::from_utf8_lossy(value.as_ref()).into_owned()
+}
+
+fn dump_bundle2(data: Vec<u8>, dump: &mut ChangegroupDump) -> Result<()> {
+    let logger = Logger::root(Discard, o!());
+    let mut stream = Bundle2Stream::new(Cursor::new(data), logger);
+
+    // The whole bundle is in memory, so decoding it never has to wait for I/O.
+    for item in stream.by_ref().wait() {
+        match item? {
+            Bundle2Item::Start(header) => {
+                let compression = header
+                    .m_stream_params
+                    .get("compression")
+                    .map(|c| c.as_str())
+                    .unwrap_or("UN");
+                println!("bundle2, compression {}", compression);
+                for (key, value) in &header.m_stream_params {
+                    println!("stream param (mandatory): {}={}", key, value);
+                }
+                for (key, value) in &header.a_stream_params {
+                    println!("stream param (advisory): {}={}", key, value);
+                }
+            }
+            Bundle2Item::Header(header) => {
+                let kind = if header.is_mandatory() {
+                    "mandatory"
+                } else {
+                    "advisory"
+                };
+                println!(
+                    "part {}: {} ({})",
+                    header.part_id(),
+                    header.part_type(),
+                    kind
+                );
+                for (key, value) in header.mparams() {
+                    println!("  param (mandatory): {}={}", key, param_value(value));
+                }
+                for (key, value) in header.aparams() {
+                    println!("  param (advisory): {}={}", key, param_value(value));
+                }
+            }
+            Bundle2Item::Inner(inner) => if inner.is_cg2() {
+                dump.part(&inner.cg2_part(), "  ");
+            } else {
+                println!("  {:?}", inner);
+            },
+        }
+    }
+
+    // Parts that couldn't be decoded are skipped, so point them out
+    for err in stream.app_errors() {
+        println!("skipped: {}", err);
+    }
+
+    Ok(())
+}
+
+fn dump_bundle1(data: Vec<u8>, dump: &mut ChangegroupDump) -> Result<()> {
+    println!("bundle1, compression {}", String::from_utf8_lossy(&data[4..6]));
+
+    let logger = Logger::root(Discard, o!());
+    let stream = Bundle1Stream::new(Cursor::new(data), logger);
+    for part in stream.wait() {
+        dump.part(&part?, "");
+    }
+
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let matches = App::new("bundledump")
+        .version("0.0.0")
+        .about("print the contents of a bundle1 or bundle2 file")
+        .args_from_usage(concat!(
+            "--verify    'check that every delta applies and the result matches its hash'\n",
+            "<BUNDLE>    'bundle file to read'"
+        ))
+        .get_matches();
+
+    let path = matches.value_of("BUNDLE").unwrap();
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .chain_err(|| format!("failed to read {}", path))?;
+
+    let mut dump = ChangegroupDump::new(matches.is_present("verify"));
+    if data.starts_with(b"HG10") && data.len() >= 6 {
+        dump_bundle1(data, &mut dump)?;
+    } else {
+        dump_bundle2(data, &mut dump)?;
+    }
+
+    if dump.verify {
+        println!(
+            "verified {} revisions, {} failed, {} with bases outside the bundle",
+            dump.verified,
+            dump.failed,
+            dump.unverifiable
+        );
+        if dump.failed > 0 {
+            bail!("{} revisions failed verification", dump.failed);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(ref e) = run() {
+        println!("Failed: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Generate a full bundle2 file from a blob repo, to be served to clients through clonebundles.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+
+extern crate async_compression;
+extern crate blobrepo;
+extern crate bundle_generator;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+extern crate repoinfo;
+
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::{App, Arg};
+use futures::{Future, Stream};
+
+use async_compression::CompressorType;
+use blobrepo::{BlobRepo, FilesBlobState, RocksBlobState};
+use bundle_generator::create_changegroup;
+use mercurial_bundles::Bundle2EncodeBuilder;
+use mercurial_bundles::changegroup::CgVersion;
+use mercurial_bundles::parts::changegroup_part;
+use mercurial_types::{NodeHash, Repo};
+use repoinfo::RepoGenCache;
+
+mod errors {
+    error_chain! {
+        links {
+            Blobrepo(::blobrepo::Error, ::blobrepo::ErrorKind);
+            BundleGenerator(::bundle_generator::Error, ::bundle_generator::ErrorKind);
+            MercurialBundles(::mercurial_bundles::Error, ::mercurial_bundles::ErrorKind);
+            MercurialTypes(::mercurial_types::Error, ::mercurial_types::ErrorKind);
+        }
+        foreign_links {
+            Io(::std::io::Error);
+        }
+    }
+}
+
+use errors::*;
+
+// The bundle is written uncompressed, which is what this spec describes
+const BUNDLESPEC: &str = "none-v2";
+
+// Memory budget for the generation number cache used to compute the changegroup
+const REPO_GEN_CACHE_SIZE: usize = 10 * 1024 * 1024;
+
+fn write_bundle<R>(repo: R, heads: Vec<NodeHash>, output: &Path) -> Result<()>
+where
+    R: Repo,
+{
+    let repo = Arc::new(repo);
+
+    let heads = if heads.is_empty() {
+        repo.get_heads()
+            .collect()
+            .wait()
+            .chain_err(|| "failed to get repo heads")?
+    } else {
+        heads
+    };
+
+    let changegroup = create_changegroup(
+        repo,
+        RepoGenCache::new(REPO_GEN_CACHE_SIZE),
+        heads,
+        vec![],
+        CgVersion::Cg2,
+    );
+
+    let mut bundle = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+    bundle.set_compressor_type(CompressorType::Uncompressed);
+    bundle.add_part(changegroup_part(changegroup, CgVersion::Cg2)?);
+    let data = bundle.build().wait()?.into_inner();
+
+    let mut file = File::create(output)?;
+    file.write_all(&data)?;
+
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let matches = App::new("clonebundle")
+        .version("0.0.0")
+        .about("generate a full bundle of a blob repo, to be advertised through clonebundles")
+        .args_from_usage(concat!(
+            "<REPO>      'path to the blob repo'\n",
+            "<OUTPUT>    'bundle file to write'\n",
+            "[HEAD]...   'heads to bundle (default: all of the repo's heads)'"
+        ))
+        .arg(
+            Arg::with_name("blobstore")
+                .long("blobstore")
+                .short("B")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb"])
+                .required(true)
+                .help("blobstore type"),
+        )
+        .get_matches();
+
+    let repopath = Path::new(matches.value_of("REPO").unwrap());
+    let output = Path::new(matches.value_of("OUTPUT").unwrap());
+    let heads = matches
+        .values_of("HEAD")
+        .into_iter()
+        .flat_map(|heads| heads)
+        .map(NodeHash::from_str)
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    match matches.value_of("blobstore").unwrap() {
+        "files" => write_bundle(
+            BlobRepo::new(FilesBlobState::new(repopath)?),
+            heads,
+            output,
+        )?,
+        "rocksdb" => write_bundle(
+            BlobRepo::new(RocksBlobState::new(repopath)?),
+            heads,
+            output,
+        )?,
+        bad => bail!("unknown blobstore type {}", bad),
+    }
+
+    println!("Wrote {}", output.display());
+    println!("Add it to the repo's config with:");
+    println!("    [[clonebundles]]");
+    println!("    url=\"<URL of {}>\"", output.display());
+    println!("    bundlespec=\"{}\"", BUNDLESPEC);
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(ref e) = run() {
+        println!("Failed: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        std::process::exit(1);
+    }
+}
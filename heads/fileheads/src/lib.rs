@@ -13,13 +13,17 @@ extern crate error_chain;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate futures_ext;
+extern crate nix;
+extern crate rand;
+extern crate storage_types;
 #[cfg(test)]
 extern crate tempdir;
 
 use std::error;
-use std::fs::{self, File};
-use std::io;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::ToString;
@@ -30,25 +34,36 @@ use futures::future::{poll_fn, Future, IntoFuture};
 use futures::stream::{self, Stream};
 use futures_cpupool::CpuPool;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use nix::fcntl::{self, FlockArg};
 
 use heads::Heads;
+use storage_types::Version;
 
 mod errors {
     error_chain!{
         foreign_links {
             Io(::std::io::Error);
+            Nix(::nix::Error);
         }
     }
 }
 pub use errors::*;
 
 static PREFIX: &'static str = "head-";
+static JOURNAL: &'static str = "update";
+static VERSION: &'static str = "version";
+static LOCK: &'static str = "lock";
 
 /// A basic file-based persistent head store.
 ///
 /// Stores heads as empty files in the specified directory. File operations are dispatched to
-/// a thread pool to avoid blocking the main thread with IO. For simplicity, file accesses
-/// are unsynchronized since each operation performs just a single File IO syscall.
+/// a thread pool to avoid blocking the main thread with IO.
+///
+/// Every change, along with the new version of the heads, is first recorded in a journal,
+/// which is renamed into place in one go and then applied. Until it has been applied and
+/// removed, anything reading the heads applies it first, so a change is never seen half done.
+/// Each operation holds an `flock(2)` on a lock file in the directory, so that this holds
+/// across processes, and a version can't change between being checked and being replaced.
 pub struct FileHeads<T> {
     base: PathBuf,
     pool: Arc<CpuPool>,
@@ -69,6 +84,11 @@ where
         if !path.is_dir() {
             bail!("'{}' is not a directory", path.to_string_lossy());
         }
+        // Finish any change which was interrupted
+        {
+            let _lock = lock(path)?;
+            replay_journal(path)?;
+        }
 
         Ok(FileHeads {
             base: path.to_path_buf(),
@@ -90,6 +110,31 @@ where
     fn get_path(&self, key: &T) -> Result<PathBuf> {
         Ok(self.base.join(format!("{}{}", PREFIX, key.to_string())))
     }
+
+    // Apply changes in journal format, if the heads are still at `expected` when there is one.
+    // Returns the new version, or None if they aren't.
+    fn change(
+        &self,
+        changes: String,
+        expected: Option<Version>,
+    ) -> BoxFuture<Option<Version>, Error> {
+        let base = self.base.clone();
+        let future = poll_fn(move || {
+            let _lock = lock(&base)?;
+            replay_journal(&base)?;
+            if let Some(expected) = expected {
+                if read_version(&base)? != expected {
+                    return Ok(Async::Ready(None));
+                }
+            }
+
+            let version = rand::random::<u64>();
+            write_journal(&base, &format!("{}v{}\n", changes, version))?;
+            replay_journal(&base)?;
+            Ok(Async::Ready(Some(Version::from(version))))
+        });
+        self.pool.spawn(future).boxify()
+    }
 }
 
 impl<T> Heads for FileHeads<T>
@@ -103,75 +148,193 @@ where
     type Effect = BoxFuture<(), Self::Error>;
     type Bool = BoxFuture<bool, Self::Error>;
     type Heads = BoxStream<Self::Key, Self::Error>;
+    type GetVersion = BoxFuture<Version, Self::Error>;
+    type UpdateIf = BoxFuture<Option<Version>, Self::Error>;
 
     fn add(&self, key: &Self::Key) -> Self::Effect {
-        let pool = self.pool.clone();
-        self.get_path(&key)
-            .into_future()
-            .and_then(move |path| {
-                let future = poll_fn(move || {
-                    File::create(&path)?;
-                    Ok(Async::Ready(()))
-                });
-                pool.spawn(future)
-            })
+        self.change(format!("+{}\n", key.to_string()), None)
+            .map(|_| ())
             .boxify()
     }
 
     fn remove(&self, key: &Self::Key) -> Self::Effect {
-        let pool = self.pool.clone();
-        self.get_path(&key)
-            .into_future()
-            .and_then(move |path| {
-                let future = poll_fn(move || {
-                    fs::remove_file(&path).or_else(|e| {
-                        // Don't report an error if the file doesn't exist.
-                        match e.kind() {
-                            io::ErrorKind::NotFound => Ok(()),
-                            _ => Err(e),
-                        }
-                    })?;
-                    Ok(Async::Ready(()))
-                });
-                pool.spawn(future)
-            })
+        self.change(format!("-{}\n", key.to_string()), None)
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn update(&self, add: &[Self::Key], remove: &[Self::Key]) -> Self::Effect {
+        self.change(changes(add, remove), None)
+            .map(|_| ())
             .boxify()
     }
 
+    fn update_if(
+        &self,
+        add: &[Self::Key],
+        remove: &[Self::Key],
+        version: &Version,
+    ) -> Self::UpdateIf {
+        self.change(changes(add, remove), Some(*version))
+    }
+
     fn is_head(&self, key: &Self::Key) -> Self::Bool {
         let pool = self.pool.clone();
+        let base = self.base.clone();
         self.get_path(&key)
             .into_future()
             .and_then(move |path| {
-                let future = poll_fn(move || Ok(Async::Ready(path.exists())));
+                let future = poll_fn(move || {
+                    let _lock = lock(&base)?;
+                    replay_journal(&base)?;
+                    Ok(Async::Ready(path.exists()))
+                });
                 pool.spawn(future)
             })
             .boxify()
     }
 
     fn heads(&self) -> Self::Heads {
-        let names = fs::read_dir(&self.base).map(|entries| {
-            entries
-                .map(|result| {
-                    result
-                        .map_err(From::from)
-                        .map(|entry| entry.file_name().to_string_lossy().into_owned())
-                })
-                .filter_map(|result| match result {
-                    Ok(ref name) if name.starts_with(PREFIX) => {
-                        let name = &name[PREFIX.len()..];
-                        let name = T::from_str(name).chain_err(|| "can't parse name");
-                        Some(name)
-                    }
-                    Ok(_) => None,
-                    Err(err) => Some(Err(err)),
-                })
+        let names = lock(&self.base).and_then(|_lock| {
+            // Another process may have recorded a change without applying it yet
+            replay_journal(&self.base)?;
+            list_dir(&self.base)
+        });
+        let names = match names {
+            Ok(names) => names,
+            Err(err) => return stream::once(Err(err)).boxify(),
+        };
+
+        let heads = names
+            .into_iter()
+            .filter(|name| name.starts_with(PREFIX))
+            .map(|name| T::from_str(&name[PREFIX.len()..]).chain_err(|| "can't parse name"));
+        stream::iter_ok(heads).and_then(|v| v).boxify()
+    }
+
+    fn version(&self) -> Self::GetVersion {
+        let base = self.base.clone();
+        let future = poll_fn(move || {
+            let _lock = lock(&base)?;
+            replay_journal(&base)?;
+            Ok(Async::Ready(read_version(&base)?))
         });
-        match names {
-            Ok(v) => stream::iter_ok(v).and_then(|v| v).boxify(),
-            Err(e) => stream::once(Err(e.into())).boxify(),
+        self.pool.spawn(future).boxify()
+    }
+}
+
+// Changes in journal format. Removals come first, so that keys which are also added end up as
+// heads.
+fn changes<T: ToString>(add: &[T], remove: &[T]) -> String {
+    let mut changes = String::new();
+    for key in remove {
+        changes.push_str(&format!("-{}\n", key.to_string()));
+    }
+    for key in add {
+        changes.push_str(&format!("+{}\n", key.to_string()));
+    }
+    changes
+}
+
+// Lock the heads against changes by other threads and processes, until the returned file is
+// closed
+fn lock(base: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(base.join(LOCK))?;
+    fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+    Ok(file)
+}
+
+// The version recorded by the last change, which is absent if there hasn't been one
+fn read_version(base: &Path) -> Result<Version> {
+    let mut version = String::new();
+    match File::open(base.join(VERSION)) {
+        Ok(mut file) => {
+            file.read_to_string(&mut version)?;
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Version::absent()),
+        Err(err) => return Err(err.into()),
+    }
+    let version = version
+        .trim()
+        .parse::<u64>()
+        .chain_err(|| format!("invalid heads version {:?}", version))?;
+    Ok(Version::from(version))
+}
+
+// Replace the version file, so that it's never seen partly written
+fn write_version(base: &Path, version: &str) -> Result<()> {
+    let tmppath = base.join(format!("{}.tmp", VERSION));
+    {
+        let mut file = File::create(&tmppath)?;
+        file.write_all(version.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmppath, base.join(VERSION))?;
+    Ok(())
+}
+
+// The names of the files in a directory
+fn list_dir(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    fs::remove_file(path).or_else(|e| {
+        // Don't report an error if the file doesn't exist.
+        match e.kind() {
+            io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        }
+    })
+}
+
+// Record an update as the journal. It's written in full before it's renamed into place, so it
+// only ever appears complete.
+fn write_journal(base: &Path, journal: &str) -> Result<()> {
+    let tmppath = base.join(format!("{}.tmp", JOURNAL));
+    {
+        let mut file = File::create(&tmppath)?;
+        file.write_all(journal.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmppath, base.join(JOURNAL))?;
+    Ok(())
+}
+
+// Apply the update recorded in the journal, if there is one, and then remove it. Applying an
+// update more than once has the same effect as applying it once, so this is safe to repeat
+// after an interruption, or while another process does the same.
+fn replay_journal(base: &Path) -> Result<()> {
+    let path = base.join(JOURNAL);
+    let mut journal = String::new();
+    match File::open(&path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut journal)?;
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    }
+
+    for line in journal.lines() {
+        if line.starts_with('+') {
+            File::create(base.join(format!("{}{}", PREFIX, &line[1..])))?;
+        } else if line.starts_with('-') {
+            remove_if_exists(&base.join(format!("{}{}", PREFIX, &line[1..])))?;
+        } else if line.starts_with('v') {
+            write_version(base, &line[1..])?;
+        } else {
+            bail!("invalid line in heads journal {}: {:?}", path.display(), line);
         }
     }
+    remove_if_exists(&path)?;
+    Ok(())
 }
 
 
@@ -186,4 +349,18 @@ mod test {
         let heads = FileHeads::<String>::open(tmp.path().join("does_not_exist"));
         assert!(heads.is_err());
     }
+
+    #[test]
+    fn interrupted_update() {
+        let tmp = TempDir::new("filebookmarks_heads_interrupted_update").unwrap();
+        File::create(tmp.path().join("head-foo")).unwrap();
+        File::create(tmp.path().join(JOURNAL))
+            .unwrap()
+            .write_all(b"-foo\n+bar\n")
+            .unwrap();
+
+        let heads = FileHeads::<String>::open(tmp.path()).unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec!["bar".to_string()]);
+        assert!(!tmp.path().join(JOURNAL).exists());
+    }
 }
@@ -11,6 +11,7 @@ extern crate futures;
 
 extern crate futures_ext;
 extern crate heads;
+extern crate storage_types;
 
 use std::hash::Hash;
 use std::sync::Mutex;
@@ -21,17 +22,39 @@ use futures_ext::{BoxStream, StreamExt};
 use std::collections::HashSet;
 
 use heads::Heads;
+use storage_types::Version;
 
 /// Generic, in-memory heads store backed by a HashSet, intended to be used in tests.
 pub struct MemHeads<T: Hash + Eq + Clone> {
-    heads: Mutex<HashSet<T>>,
+    heads: Mutex<State<T>>,
+}
+
+struct State<T> {
+    heads: HashSet<T>,
+    // Counts the changes made
+    version: u64,
+}
+
+impl<T: Hash + Eq + Clone> State<T> {
+    fn update(&mut self, add: &[T], remove: &[T]) {
+        for head in remove {
+            self.heads.remove(head);
+        }
+        for head in add {
+            self.heads.insert(head.clone());
+        }
+        self.version += 1;
+    }
 }
 
 impl<T: Hash + Eq + Clone + Send> MemHeads<T> {
     #[allow(dead_code)]
     pub fn new() -> Self {
         MemHeads {
-            heads: Mutex::new(HashSet::new()),
+            heads: Mutex::new(State {
+                heads: HashSet::new(),
+                version: 0,
+            }),
         }
     }
 }
@@ -43,24 +66,49 @@ impl<T: Hash + Eq + Clone + Send + 'static> Heads for MemHeads<T> {
     type Effect = FutureResult<(), Self::Error>;
     type Bool = FutureResult<bool, Self::Error>;
     type Heads = BoxStream<Self::Key, Self::Error>;
+    type GetVersion = FutureResult<Version, Self::Error>;
+    type UpdateIf = FutureResult<Option<Version>, Self::Error>;
 
     fn add(&self, head: &Self::Key) -> Self::Effect {
-        self.heads.lock().unwrap().insert(head.clone());
+        self.heads.lock().unwrap().update(&[head.clone()], &[]);
         ok(())
     }
 
     fn remove(&self, head: &Self::Key) -> Self::Effect {
-        self.heads.lock().unwrap().remove(head);
+        self.heads.lock().unwrap().update(&[], &[head.clone()]);
         ok(())
     }
 
+    fn update(&self, add: &[Self::Key], remove: &[Self::Key]) -> Self::Effect {
+        self.heads.lock().unwrap().update(add, remove);
+        ok(())
+    }
+
+    fn update_if(
+        &self,
+        add: &[Self::Key],
+        remove: &[Self::Key],
+        version: &Version,
+    ) -> Self::UpdateIf {
+        let mut state = self.heads.lock().unwrap();
+        if Version::from(state.version) != *version {
+            return ok(None);
+        }
+        state.update(add, remove);
+        ok(Some(Version::from(state.version)))
+    }
+
     fn is_head(&self, head: &Self::Key) -> Self::Bool {
-        ok(self.heads.lock().unwrap().contains(head))
+        ok(self.heads.lock().unwrap().heads.contains(head))
     }
 
     fn heads(&self) -> Self::Heads {
         let guard = self.heads.lock().unwrap();
-        let heads = (*guard).clone();
+        let heads = guard.heads.clone();
         iter_ok::<_, !>(heads).boxify()
     }
+
+    fn version(&self) -> Self::GetVersion {
+        ok(Version::from(self.heads.lock().unwrap().version))
+    }
 }
@@ -5,10 +5,13 @@
 // GNU General Public License version 2 or any later version.
 
 extern crate futures;
+extern crate storage_types;
 
 use futures::{Future, Stream};
 use std::error;
 
+use storage_types::Version;
+
 /// Trait representing the interface to a heads store, which more generally is just
 /// a set of commit identifiers.
 pub trait Heads: Send + 'static {
@@ -20,9 +23,26 @@ pub trait Heads: Send + 'static {
     type Heads: Stream<Item = Self::Key, Error = Self::Error> + Send + 'static;
     type Bool: Future<Item = bool, Error = Self::Error> + Send + 'static;
     type Effect: Future<Item = (), Error = Self::Error> + Send + 'static;
+    type GetVersion: Future<Item = Version, Error = Self::Error> + Send + 'static;
+    type UpdateIf: Future<Item = Option<Version>, Error = Self::Error> + Send + 'static;
 
     fn add(&self, &Self::Key) -> Self::Effect;
     fn remove(&self, &Self::Key) -> Self::Effect;
+    // Add and remove several heads at once, so that no reader sees some of the changes without
+    // the others. Keys which are in both lists are heads afterwards.
+    fn update(&self, add: &[Self::Key], remove: &[Self::Key]) -> Self::Effect;
+    // Like `update`, but only if the heads are still at `version`, so that a check of the heads
+    // and an update based on it can't be separated by someone else's update. Returns the new
+    // version, or None if the heads have moved on, in which case nothing is changed.
+    fn update_if(
+        &self,
+        add: &[Self::Key],
+        remove: &[Self::Key],
+        version: &Version,
+    ) -> Self::UpdateIf;
+    // The version of the heads, which changes with every add, remove or update. Heads read after
+    // getting the version are at least as new as it.
+    fn version(&self) -> Self::GetVersion;
     fn is_head(&self, &Self::Key) -> Self::Bool;
     fn heads(&self) -> Self::Heads;
 }
@@ -62,6 +62,60 @@ where
     assert_eq!(heads.heads().collect().wait().unwrap(), empty);
 }
 
+fn update<H>(heads: H)
+where
+    H: Heads<Key = String>,
+{
+    let foo = "foo".to_string();
+    let bar = "bar".to_string();
+    let baz = "baz".to_string();
+
+    heads.add(&foo).wait().unwrap();
+    heads.add(&bar).wait().unwrap();
+
+    // bar is both added and removed, so it stays a head.
+    heads
+        .update(&[bar.clone(), baz.clone()], &[foo.clone(), bar.clone()])
+        .wait()
+        .unwrap();
+
+    assert!(!heads.is_head(&foo).wait().unwrap());
+    let mut result = heads.heads().collect().wait().unwrap();
+    result.sort();
+    assert_eq!(result, vec![bar.clone(), baz.clone()]);
+}
+
+fn update_if<H>(heads: H)
+where
+    H: Heads<Key = String>,
+{
+    let foo = "foo".to_string();
+    let bar = "bar".to_string();
+
+    let version = heads.version().wait().unwrap();
+    let updated = heads
+        .update_if(&[foo.clone()], &[], &version)
+        .wait()
+        .unwrap()
+        .expect("heads should be at the version just read");
+    assert_eq!(heads.version().wait().unwrap(), updated);
+
+    // The heads have moved on since the first version, so nothing changes
+    assert_eq!(
+        heads.update_if(&[bar.clone()], &[], &version).wait().unwrap(),
+        None
+    );
+    assert!(!heads.is_head(&bar).wait().unwrap());
+
+    // Any change moves the version on
+    heads.add(&bar).wait().unwrap();
+    assert_eq!(
+        heads.update_if(&[], &[foo.clone()], &updated).wait().unwrap(),
+        None
+    );
+    assert!(heads.is_head(&foo).wait().unwrap());
+}
+
 fn persistence<F, H>(mut new_heads: F)
 where
     F: FnMut() -> H,
@@ -70,16 +124,18 @@ where
     let foo = "foo".to_string();
     let bar = "bar".to_string();
 
-    {
+    let version = {
         let heads = new_heads();
         heads.add(&foo).wait().unwrap();
         heads.add(&bar).wait().unwrap();
-    }
+        heads.version().wait().unwrap()
+    };
 
     let heads = new_heads();
     let mut result = heads.heads().collect().wait().unwrap();
     result.sort();
     assert_eq!(result, vec![bar.clone(), foo.clone()]);
+    assert_eq!(heads.version().wait().unwrap(), version);
 }
 
 fn save_node_hash<H>(heads: H)
@@ -109,6 +165,18 @@ macro_rules! heads_test_impl {
                 basic($new_cb(&state));
             }
 
+            #[test]
+            fn test_update() {
+                let state = $state;
+                update($new_cb(&state));
+            }
+
+            #[test]
+            fn test_update_if() {
+                let state = $state;
+                update_if($new_cb(&state));
+            }
+
             #[test]
             fn test_save_node_hash() {
                 let state = $state;
@@ -9,8 +9,6 @@
 //! Based on the Mercurial wire protocol documentation. See
 //! https://www.mercurial-scm.org/repo/hg/file/@/mercurial/help/internals/wireprotocol.txt.
 
-use bytes::Bytes;
-
 use errors::*;
 
 /// Unescape a batch-escaped argument key or value.
@@ -37,10 +35,11 @@ pub fn unescape(bs: &[u8]) -> Result<Vec<u8>> {
     Ok(out)
 }
 
-/// Escape a batch result.
-pub fn escape(res: &Bytes) -> Vec<u8> {
+/// Escape a batch result, or an argument key or value.
+pub fn escape<T: AsRef<[u8]>>(res: &T) -> Vec<u8> {
+    let res = res.as_ref();
     let mut out = Vec::with_capacity(res.len());
-    for b in res {
+    for &b in res {
         match b {
             b':' => out.extend_from_slice(b":c"),
             b',' => out.extend_from_slice(b":o"),
@@ -54,6 +53,7 @@ pub fn escape(res: &Bytes) -> Vec<u8> {
 
 #[cfg(test)]
 mod test {
+    use bytes::Bytes;
     use quickcheck::TestResult;
 
     use super::*;
@@ -14,9 +14,9 @@ use slog::Logger;
 
 use bytes::{Bytes, BytesMut};
 use futures::future::{self, Future};
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 
-use futures_ext::{futures_ordered, BoxFuture, BoxStream, FutureExt};
+use futures_ext::{futures_ordered, BoxFuture, BoxStream, FutureExt, StreamExt};
 use mercurial_types::NodeHash;
 
 use {BranchRes, GetbundleArgs, Request, Response};
@@ -33,6 +33,37 @@ impl<H: HgCommands> HgCommandHandler<H> {
         HgCommandHandler { commands, logger }
     }
 
+    /// Handle `req`, producing the response in pieces if it's one that can be streamed to the
+    /// client as it's generated. Protocols which have to know the whole response before sending
+    /// any of it should use `handle` instead.
+    pub fn handle_output(&self, req: Request) -> HgCommandOutput {
+        match &req {
+            &Request::Changegroup { .. }
+            | &Request::Changegroupsubset { .. }
+            | &Request::Getbundle(_)
+            | &Request::Streamout => debug!(self.logger, "Got request: {:?}", req),
+            _ => (),
+        }
+
+        let hgcmds = &self.commands;
+
+        match req {
+            Request::Changegroup { roots } => HgCommandOutput::Stream(hgcmds.changegroup(roots)),
+            Request::Changegroupsubset { bases, heads } => {
+                HgCommandOutput::Stream(hgcmds.changegroupsubset(bases, heads))
+            }
+            Request::Getbundle(args) => HgCommandOutput::Stream(hgcmds.getbundle(args)),
+            Request::Streamout => {
+                let stream = hgcmds
+                    .stream_out()
+                    .map(|stream| stream.map(Bytes::from))
+                    .flatten_stream();
+                HgCommandOutput::Stream(stream.boxify())
+            }
+            req => HgCommandOutput::Response(self.handle(req)),
+        }
+    }
+
     pub fn handle(&self, req: Request) -> BoxFuture<Response, Error>
     where
         H: HgCommands,
@@ -74,22 +105,21 @@ impl<H: HgCommands> HgCommandHandler<H> {
                 .map(Response::Capabilities)
                 .map_err(self::Error::into)
                 .boxify(),
-            Request::Changegroup { roots } => hgcmds
-                .changegroup(roots)
-                .map(|_| Response::Changegroup)
-                .map_err(self::Error::into)
-                .boxify(),
-            Request::Changegroupsubset { bases, heads } => hgcmds
-                .changegroupsubset(bases, heads)
-                .map(|_| Response::Changegroupsubset)
+            Request::Changegroup { roots } => collect_bytes(hgcmds.changegroup(roots))
+                .map(Response::Changegroup)
                 .map_err(self::Error::into)
                 .boxify(),
+            Request::Changegroupsubset { bases, heads } => {
+                collect_bytes(hgcmds.changegroupsubset(bases, heads))
+                    .map(Response::Changegroupsubset)
+                    .map_err(self::Error::into)
+                    .boxify()
+            }
             Request::Debugwireargs { one, two, all_args } => self.debugwireargs(one, two, all_args)
                 .map(Response::Debugwireargs)
                 .map_err(self::Error::into)
                 .boxify(),
-            Request::Getbundle(args) => hgcmds
-                .getbundle(args)
+            Request::Getbundle(args) => collect_bytes(hgcmds.getbundle(args))
                 .map(Response::Getbundle)
                 .map_err(self::Error::into)
                 .boxify(),
@@ -108,10 +138,20 @@ impl<H: HgCommands> HgCommandHandler<H> {
                 .map(Response::Listkeys)
                 .map_err(self::Error::into)
                 .boxify(),
+            // Mercurial reports a failed lookup as part of the response rather than as a
+            // command failure
             Request::Lookup { key } => hgcmds
                 .lookup(key)
-                .map(Response::Lookup)
-                .map_err(self::Error::into)
+                .then(|res| {
+                    // Repo errors are wrapped, so report the underlying cause
+                    let res = res.map_err(|err| {
+                        err.iter()
+                            .last()
+                            .map(|cause| cause.to_string())
+                            .unwrap_or_default()
+                    });
+                    Ok::<_, Error>(Response::Lookup(res))
+                })
                 .boxify(),
             Request::Known { nodes } => hgcmds
                 .known(nodes)
@@ -125,17 +165,18 @@ impl<H: HgCommands> HgCommandHandler<H> {
                 new,
             } => hgcmds
                 .pushkey(namespace, key, old, new)
-                .map(|_| Response::Pushkey)
+                .map(Response::Pushkey)
                 .map_err(self::Error::into)
                 .boxify(),
             Request::Streamout => hgcmds
                 .stream_out()
-                .map(|_| Response::Streamout)
+                .and_then(|stream| stream.concat2())
+                .map(|data| Response::Streamout(Bytes::from(data)))
                 .map_err(self::Error::into)
                 .boxify(),
             Request::Unbundle { heads, stream } => hgcmds
                 .unbundle(heads, stream)
-                .map(|_| Response::Unbundle)
+                .map(Response::Unbundle)
                 .map_err(self::Error::into)
                 .boxify(), //_ => unimplemented!()
         }
@@ -217,6 +258,17 @@ fn get_or_none<'a>(map: &'a HashMap<Vec<u8>, Vec<u8>>, key: &'a [u8]) -> &'a [u8
     }
 }
 
+// Gather a streamed response for protocols which send it in one piece
+fn collect_bytes(stream: HgCommandStream<Bytes>) -> HgCommandRes<Bytes> {
+    stream
+        .fold(BytesMut::new(), |mut bytes, chunk| {
+            bytes.extend_from_slice(&chunk);
+            Ok::<_, Error>(bytes)
+        })
+        .map(BytesMut::freeze)
+        .boxify()
+}
+
 #[inline]
 fn unimplemented<S, T>(op: S) -> HgCommandRes<T>
 where
@@ -226,9 +278,29 @@ where
     future::err(ErrorKind::Unimplemented(op.into()).into()).boxify()
 }
 
+#[inline]
+fn unimplemented_stream<S, T>(op: S) -> HgCommandStream<T>
+where
+    S: Into<String>,
+    T: Send + 'static,
+{
+    stream::once(Err(ErrorKind::Unimplemented(op.into()).into())).boxify()
+}
+
 // Async response from an Hg command
 pub type HgCommandRes<T> = BoxFuture<T, Error>;
 
+// Async response from an Hg command which is sent in pieces as it's generated
+pub type HgCommandStream<T> = BoxStream<T, Error>;
+
+/// The output of a command, as returned by `HgCommandHandler::handle_output`.
+pub enum HgCommandOutput {
+    /// A response which is encoded once it's complete
+    Response(BoxFuture<Response, Error>),
+    /// Raw bytes to send as they become available, without any framing
+    Stream(BoxStream<Bytes, Error>),
+}
+
 // Trait representing Mercurial protocol operations, generic across protocols
 // Derived from hg/mercurial/wireprotocol.py, functions with the `@wireprotocommand`
 // decorator.
@@ -263,20 +335,22 @@ pub trait HgCommands {
     }
 
     // @wireprotocommand('changegroup', 'roots')
-    fn changegroup(&self, _roots: Vec<NodeHash>) -> HgCommandRes<()> {
-        // TODO: streaming something
-        unimplemented("changegroup")
+    fn changegroup(&self, _roots: Vec<NodeHash>) -> HgCommandStream<Bytes> {
+        unimplemented_stream("changegroup")
     }
 
     // @wireprotocommand('changegroupsubset', 'bases heads')
-    fn changegroupsubset(&self, _bases: Vec<NodeHash>, _heads: Vec<NodeHash>) -> HgCommandRes<()> {
-        unimplemented("changegroupsubset")
+    fn changegroupsubset(
+        &self,
+        _bases: Vec<NodeHash>,
+        _heads: Vec<NodeHash>,
+    ) -> HgCommandStream<Bytes> {
+        unimplemented_stream("changegroupsubset")
     }
 
     // @wireprotocommand('getbundle', '*')
-    // TODO: make this streaming
-    fn getbundle(&self, _args: GetbundleArgs) -> HgCommandRes<Bytes> {
-        unimplemented("getbundle")
+    fn getbundle(&self, _args: GetbundleArgs) -> HgCommandStream<Bytes> {
+        unimplemented_stream("getbundle")
     }
 
     // @wireprotocommand('heads')
@@ -311,18 +385,17 @@ pub trait HgCommands {
         _key: String,
         _old: NodeHash,
         _new: NodeHash,
-    ) -> HgCommandRes<()> {
+    ) -> HgCommandRes<bool> {
         unimplemented("pushkey")
     }
 
     // @wireprotocommand('stream_out')
     fn stream_out(&self) -> HgCommandRes<BoxStream<Vec<u8>, Error>> {
-        // XXX raw streaming?
         unimplemented("stream_out")
     }
 
     // @wireprotocommand('unbundle', 'heads')
-    fn unbundle(&self, _heads: Vec<String>, _stream: Bytes) -> HgCommandRes<()> {
+    fn unbundle(&self, _heads: Vec<String>, _stream: Bytes) -> HgCommandRes<Bytes> {
         unimplemented("unbundle")
     }
 }
@@ -373,4 +446,23 @@ mod test {
             bad => panic!("Bad result {:?}", bad),
         }
     }
+
+    #[test]
+    fn lookup_failure() {
+        let logger = Logger::root(Discard, o!());
+        let handler = HgCommandHandler::new(Dummy, logger);
+
+        // Failed lookups are reported in the response, not as errors
+        let r = handler
+            .handle(Request::Lookup {
+                key: "foo".into(),
+            })
+            .wait();
+        println!("lookup r = {:?}", r);
+
+        match r {
+            Ok(Response::Lookup(Err(ref msg))) if msg == "unimplemented operation 'lookup'" => (),
+            bad => panic!("Bad result {:?}", bad),
+        }
+    }
 }
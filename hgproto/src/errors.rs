@@ -20,6 +20,9 @@ error_chain! {
             description("command parse failed")
             display("command parse failed for \"{}\"", String::from_utf8_lossy(&buf))
         }
+        MissingCommand {
+            description("request doesn't name a command")
+        }
         BatchInvalid(bs: Vec<u8>) {
             description("malformed batch command")
             display("malformed batch command '{}'", String::from_utf8_lossy(&bs))
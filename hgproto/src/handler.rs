@@ -7,7 +7,7 @@
 use std::io;
 use std::sync::Arc;
 
-use futures::{Poll, Stream};
+use futures::{Future, Poll, Stream};
 use futures_ext::{BoxStream, StreamExt, StreamLayeredExt};
 use tokio_io::codec::{Decoder, Encoder};
 
@@ -15,7 +15,7 @@ use bytes::Bytes;
 use slog::{self, Logger};
 
 use {HgCommands, Request, Response};
-use commands::HgCommandHandler;
+use commands::{HgCommandHandler, HgCommandOutput};
 
 use errors::*;
 
@@ -90,11 +90,16 @@ where
     instream
         .decode(handler.reqdec.clone())
         .from_err()
-        .and_then({
-            let handler = handler.clone();
-            move |req| handler.commands_handler.handle(req)
+        .map(move |req| match handler.commands_handler.handle_output(req) {
+            HgCommandOutput::Response(response) => response
+                .into_stream()
+                .encode(handler.respenc.clone())
+                .from_err()
+                .boxify(),
+            // Streamed responses go out as they're generated, so the next request isn't
+            // handled until all of this one has been sent.
+            HgCommandOutput::Stream(stream) => stream,
         })
-        .encode(handler.respenc.clone())
-        .from_err()
+        .flatten()
         .boxify()
 }
@@ -0,0 +1,82 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! HTTP protocol
+//!
+//! Reference is the "HTTP Version 1 Transport" section of
+//! https://www.mercurial-scm.org/repo/hg/file/@/mercurial/help/internals/wireprotocol.txt.
+//!
+//! A command is a GET or POST to the repo's URL, with its name in the `cmd` query parameter.
+//! Arguments are urlencoded, and are either in the rest of the query string or split across
+//! `X-HgArg-<N>` headers whose values are concatenated. The body of the request is only used
+//! by `unbundle`, for the bundle being pushed.
+//!
+//! Responses have no framing. Their content type is either:
+//! - `application/mercurial-0.1`, where `getbundle`'s payload is zlib-compressed and every other
+//!   command's payload is sent as is
+//! - `application/mercurial-0.2`, where the payload is preceded by a byte giving the length of
+//!   the name of the compression engine used, and then the name itself
+//!
+//! Clients list the media types and compression engines they accept in `X-HgProto-<N>` headers.
+//! Like arguments, the value can be split across several headers.
+
+pub mod request;
+pub mod response;
+
+/// The response formats a client accepts, as listed in its `X-HgProto-<N>` headers.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MediaTypes {
+    /// Whether the client understands `application/mercurial-0.2`
+    pub v02: bool,
+    /// Compression engines the client supports for 0.2 responses, in its order of preference
+    pub compression: Vec<Vec<u8>>,
+}
+
+impl MediaTypes {
+    /// Parse the concatenated values of the `X-HgProto-<N>` headers, which look like
+    /// `0.1 0.2 comp=zstd,zlib,none`.
+    pub fn parse(value: &[u8]) -> Self {
+        let mut res = MediaTypes::default();
+
+        for param in value.split(|b| *b == b' ') {
+            if param == b"0.2" {
+                res.v02 = true;
+            } else if param.starts_with(b"comp=") {
+                res.compression = param[5..]
+                    .split(|b| *b == b',')
+                    .filter(|engine| !engine.is_empty())
+                    .map(|engine| engine.to_vec())
+                    .collect();
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_media_types() {
+        assert_eq!(MediaTypes::parse(b""), MediaTypes::default());
+        assert_eq!(
+            MediaTypes::parse(b"0.1 0.2 comp=zstd,zlib,none"),
+            MediaTypes {
+                v02: true,
+                compression: vec![b"zstd".to_vec(), b"zlib".to_vec(), b"none".to_vec()],
+            }
+        );
+        assert_eq!(
+            MediaTypes::parse(b"0.1 partial-pull"),
+            MediaTypes {
+                v02: false,
+                compression: vec![],
+            }
+        );
+    }
+}
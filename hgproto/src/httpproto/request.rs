@@ -0,0 +1,216 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use sshproto;
+use Request;
+use errors::*;
+
+/// Parse a request from its query string, the concatenated values of its `X-HgArg-<N>`
+/// headers (empty if it has none), and its body.
+pub fn parse(query: &[u8], hgargs: &[u8], body: Bytes) -> Result<Request> {
+    let mut cmd = None;
+    let mut args = HashMap::new();
+    for (key, value) in urldecode(query).into_iter().chain(urldecode(hgargs)) {
+        if key == b"cmd" {
+            cmd = Some(value);
+        } else {
+            args.insert(key, value);
+        }
+    }
+
+    let cmd = match cmd {
+        Some(cmd) => cmd,
+        None => bail!(ErrorKind::MissingCommand),
+    };
+
+    if cmd == b"unbundle" {
+        // Over ssh the bundle follows the arguments, but here it's the whole request body
+        let heads = args.remove(&b"heads"[..])
+            .map(|heads| {
+                String::from_utf8_lossy(&heads)
+                    .split(' ')
+                    .filter(|head| !head.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        return Ok(Request::Unbundle {
+            heads: heads,
+            stream: body,
+        });
+    }
+
+    sshproto::request::parse_args(&String::from_utf8_lossy(&cmd), args)
+}
+
+// Split a urlencoded string into its key/value pairs
+fn urldecode(input: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    input
+        .split(|b| *b == b'&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut kv = param.splitn(2, |b| *b == b'=');
+            let key = kv.next().unwrap_or_default();
+            let value = kv.next().unwrap_or_default();
+            (unquote(key), unquote(value))
+        })
+        .collect()
+}
+
+// Undo percent-encoding, where '+' also stands for a space. Malformed escapes are kept as is.
+fn unquote(input: &[u8]) -> Vec<u8> {
+    fn hexval(b: u8) -> Option<u8> {
+        match b {
+            b'0'...b'9' => Some(b - b'0'),
+            b'a'...b'f' => Some(b - b'a' + 10),
+            b'A'...b'F' => Some(b - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut idx = 0;
+    while idx < input.len() {
+        let escaped = if input[idx] == b'%' && idx + 2 < input.len() {
+            match (hexval(input[idx + 1]), hexval(input[idx + 2])) {
+                (Some(hi), Some(lo)) => Some(hi << 4 | lo),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                idx += 3;
+            }
+            None => {
+                out.push(if input[idx] == b'+' { b' ' } else { input[idx] });
+                idx += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use GetbundleArgs;
+    use mercurial_types::nodehash;
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote(b"a+b%20c%3d%3D"), b"a b c==".to_vec());
+        assert_eq!(unquote(b"100%"), b"100%".to_vec());
+        assert_eq!(unquote(b"%zz%4"), b"%zz%4".to_vec());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let req = parse(b"cmd=lookup&key=tip", b"", Bytes::new()).expect("parse failed");
+        assert_eq!(
+            req,
+            Request::Lookup {
+                key: "tip".to_string(),
+            }
+        );
+
+        let req = parse(b"cmd=heads", b"", Bytes::new()).expect("parse failed");
+        assert_eq!(req, Request::Heads);
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        let hash = "0000000000000000000000000000000000000000";
+        let hgargs = format!("nodes={}+{}", hash, hash);
+        let req = parse(b"cmd=known", hgargs.as_bytes(), Bytes::new()).expect("parse failed");
+        assert_eq!(
+            req,
+            Request::Known {
+                nodes: vec![nodehash::NULL_HASH, nodehash::NULL_HASH],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_escaped() {
+        // Values containing the characters batch escaping protects must survive
+        let req = parse(b"cmd=batch", b"cmds=heads+%3Blookup+key%3Dtip", Bytes::new())
+            .expect("parse failed");
+        assert_eq!(
+            req,
+            Request::Batch {
+                cmds: vec![
+                    (b"heads".to_vec(), b"".to_vec()),
+                    (b"lookup".to_vec(), b"key=tip".to_vec()),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unbundle() {
+        let hash = "0000000000000000000000000000000000000000";
+        let query = format!("cmd=unbundle&heads={}", hash);
+        let body = Bytes::from(&b"HG20bundle"[..]);
+        let req = parse(query.as_bytes(), b"", body.clone()).expect("parse failed");
+        assert_eq!(
+            req,
+            Request::Unbundle {
+                heads: vec![hash.to_string()],
+                stream: body,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_getbundle() {
+        let head = "1111111111111111111111111111111111111111";
+        let common = "0000000000000000000000000000000000000000";
+        let hgargs = format!(
+            "heads={}&common={}&bundlecaps=HG20%2Cbundle2%3DHG20&cg=0&listkeys=phases%2Cbookmarks",
+            head,
+            common
+        );
+        let req = parse(b"cmd=getbundle", hgargs.as_bytes(), Bytes::new()).expect("parse failed");
+        assert_eq!(
+            req,
+            Request::Getbundle(GetbundleArgs {
+                heads: vec![head.parse().unwrap()],
+                common: vec![nodehash::NULL_HASH],
+                bundlecaps: vec![b"HG20".to_vec(), b"bundle2=HG20".to_vec()],
+                listkeys: vec![b"phases".to_vec(), b"bookmarks".to_vec()],
+                cg: false,
+                phases: false,
+            })
+        );
+
+        // A changegroup is sent unless asked not to
+        let req = parse(b"cmd=getbundle", b"", Bytes::new()).expect("parse failed");
+        assert_eq!(
+            req,
+            Request::Getbundle(GetbundleArgs {
+                heads: vec![],
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                cg: true,
+                phases: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_missing_command() {
+        assert!(parse(b"key=tip", b"", Bytes::new()).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::io::Write;
+
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use Response;
+use sshproto;
+
+use super::MediaTypes;
+
+pub const MEDIA_TYPE_V01: &str = "application/mercurial-0.1";
+pub const MEDIA_TYPE_V02: &str = "application/mercurial-0.2";
+
+// Compression engines which 0.2 responses can use, by the names clients know them by
+const ENGINE_NONE: &[u8] = b"none";
+const ENGINE_ZLIB: &[u8] = b"zlib";
+
+/// Encode a response for a client which accepts `accepts`, returning its content type and body.
+pub fn encode(response: &Response, accepts: &MediaTypes) -> (&'static str, Bytes) {
+    let payload = sshproto::response::encode_cmd(response);
+
+    match response {
+        // Bundles are the only payloads clients expect to be compressed
+        &Response::Changegroup(_) | &Response::Changegroupsubset(_) | &Response::Getbundle(_) => {
+            let engine = if accepts.v02 {
+                accepts
+                    .compression
+                    .iter()
+                    .map(|engine| engine.as_slice())
+                    .find(|engine| *engine == ENGINE_NONE || *engine == ENGINE_ZLIB)
+            } else {
+                None
+            };
+
+            match engine {
+                Some(engine) => {
+                    let payload = if engine == ENGINE_ZLIB {
+                        zlib(&payload)
+                    } else {
+                        payload.to_vec()
+                    };
+
+                    let mut out = Vec::with_capacity(1 + engine.len() + payload.len());
+                    out.push(engine.len() as u8);
+                    out.extend_from_slice(engine);
+                    out.extend_from_slice(&payload);
+                    (MEDIA_TYPE_V02, Bytes::from(out))
+                }
+                None => (MEDIA_TYPE_V01, Bytes::from(zlib(&payload))),
+            }
+        }
+        _ => (MEDIA_TYPE_V01, payload),
+    }
+}
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+    encoder.write_all(data).expect("write to vec failed");
+    encoder.finish().expect("write to vec failed")
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    fn unzlib(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        ZlibDecoder::new(data)
+            .read_to_end(&mut out)
+            .expect("decompression failed");
+        out
+    }
+
+    #[test]
+    fn test_encode_plain() {
+        let (ctype, body) = encode(&Response::Pushkey(true), &MediaTypes::parse(b"0.1 0.2"));
+        assert_eq!(ctype, MEDIA_TYPE_V01);
+        assert_eq!(body, Bytes::from(&b"1\n"[..]));
+    }
+
+    #[test]
+    fn test_encode_getbundle_v01() {
+        let bundle = Bytes::from(&b"HG20bundle"[..]);
+        let (ctype, body) = encode(&Response::Getbundle(bundle.clone()), &MediaTypes::default());
+        assert_eq!(ctype, MEDIA_TYPE_V01);
+        assert_eq!(unzlib(&body), bundle.to_vec());
+    }
+
+    #[test]
+    fn test_encode_getbundle_v02() {
+        let bundle = Bytes::from(&b"HG20bundle"[..]);
+        let response = Response::Getbundle(bundle.clone());
+
+        let (ctype, body) = encode(&response, &MediaTypes::parse(b"0.1 0.2 comp=zstd,none"));
+        assert_eq!(ctype, MEDIA_TYPE_V02);
+        assert_eq!(body, Bytes::from(&b"\x04noneHG20bundle"[..]));
+
+        let (ctype, body) = encode(&response, &MediaTypes::parse(b"0.1 0.2 comp=zlib,none"));
+        assert_eq!(ctype, MEDIA_TYPE_V02);
+        assert_eq!(&body[..5], b"\x04zlib");
+        assert_eq!(unzlib(&body[5..]), bundle.to_vec());
+
+        // No engine in common
+        let (ctype, _) = encode(&response, &MediaTypes::parse(b"0.1 0.2 comp=zstd"));
+        assert_eq!(ctype, MEDIA_TYPE_V01);
+    }
+}
@@ -31,6 +31,7 @@ extern crate maplit;
 #[macro_use]
 extern crate nom;
 
+extern crate flate2;
 extern crate futures_ext;
 extern crate mercurial;
 extern crate mercurial_bundles;
@@ -43,6 +44,7 @@ extern crate quickcheck;
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
+use std::result;
 
 use bytes::Bytes;
 
@@ -52,15 +54,19 @@ mod batch;
 mod errors;
 mod handler;
 mod commands;
+pub mod httpproto;
 pub mod sshproto;
 
 // result from `branches()`
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct BranchRes {
-    top: NodeHash,
-    node: NodeHash,
-    p0: Option<NodeHash>,
-    p1: Option<NodeHash>,
+    /// The node the linear branch was looked up from
+    pub top: NodeHash,
+    /// The bottom of the linear branch, which is either a root or a merge
+    pub node: NodeHash,
+    /// The parents of `node`
+    pub p0: Option<NodeHash>,
+    pub p1: Option<NodeHash>,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -105,6 +111,10 @@ pub struct GetbundleArgs {
     pub common: Vec<NodeHash>,
     pub bundlecaps: Vec<Vec<u8>>,
     pub listkeys: Vec<Vec<u8>>,
+    /// Whether to include a changegroup. Clients which only want bookmarks or phases turn it off.
+    pub cg: bool,
+    /// Whether to include the phases of the changesets sent
+    pub phases: bool,
 }
 
 impl Debug for GetbundleArgs {
@@ -122,6 +132,8 @@ impl Debug for GetbundleArgs {
             .field("common", &self.common)
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
+            .field("cg", &self.cg)
+            .field("phases", &self.phases)
             .finish()
     }
 }
@@ -134,18 +146,18 @@ pub enum Response {
     Branches(Vec<BranchRes>),
     Clonebundles(String),
     Capabilities(Vec<String>),
-    Changegroup,
-    Changegroupsubset,
+    Changegroup(Bytes),
+    Changegroupsubset(Bytes),
     Debugwireargs(Bytes),
     Getbundle(Bytes),
     Heads(HashSet<NodeHash>),
     Hello(HashMap<String, Vec<String>>),
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
-    Lookup(NodeHash),
+    Lookup(result::Result<NodeHash, String>),
     Known(Vec<bool>),
-    Pushkey,
-    Streamout, /* (BoxStream<Vec<u8>, Error>) */
-    Unbundle,
+    Pushkey(bool),
+    Streamout(Bytes),
+    Unbundle(Bytes),
 }
 
 impl Response {
@@ -154,13 +166,16 @@ impl Response {
         use Response::*;
 
         match self {
+            &Changegroup(_) => true,
+            &Changegroupsubset(_) => true,
             &Getbundle(_) => true,
-            &Unbundle => true,
+            &Streamout(_) => true,
+            &Unbundle(_) => true,
             _ => false,
         }
     }
 }
 
-pub use commands::{HgCommandRes, HgCommands};
+pub use commands::{HgCommandHandler, HgCommandOutput, HgCommandRes, HgCommandStream, HgCommands};
 pub use errors::{Error, ErrorKind, Result, ResultExt};
 pub use handler::HgProtoHandler;
@@ -16,7 +16,7 @@ use slog;
 use tokio_io::AsyncRead;
 
 use mercurial_bundles::bundle2::Bundle2Stream;
-use mercurial_types::NodeHash;
+use mercurial_types::{NodeHash, NULL_HASH};
 
 use {GetbundleArgs, Request};
 use batch;
@@ -167,6 +167,16 @@ named!(
     map_res!(take!(40), |v: &[u8]| str::parse(str::from_utf8(v)?))
 );
 
+/// A nodehash, or nothing at all. pushkey uses an empty value to say that a key has no old or
+/// no new value, which is represented by the null hash.
+fn nodehash_or_empty(inp: &[u8]) -> IResult<&[u8], NodeHash> {
+    if inp.is_empty() {
+        IResult::Done(inp, NULL_HASH)
+    } else {
+        nodehash(inp)
+    }
+}
+
 /// A pair of nodehashes, separated by '-'
 named!(
     pair<(NodeHash, NodeHash)>,
@@ -215,6 +225,12 @@ fn commavalues(input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
     }
 }
 
+/// A boolean, which Mercurial sends as "0" or "1".
+named!(
+    boolean<bool>,
+    alt!(value!(false, tag!("0")) | value!(true, tag!("1")))
+);
+
 fn notsemi(b: u8) -> bool {
     b != b';'
 }
@@ -360,6 +376,12 @@ where
     }
 }
 
+/// Take all of the input as a `String`, for values like bookmark names which aren't restricted
+/// to identifier characters.
+fn any_string(inp: &[u8]) -> IResult<&[u8], String> {
+    IResult::Done(&inp[inp.len()..], String::from_utf8_lossy(inp).into_owned())
+}
+
 /// Parse an ident, and map it to `String`.
 fn ident_string(inp: &[u8]) -> IResult<&[u8], String> {
     match ident_complete(inp) {
@@ -369,46 +391,84 @@ fn ident_string(inp: &[u8]) -> IResult<&[u8], String> {
     }
 }
 
-macro_rules! replace_expr {
-    ($_t:tt $sub:expr) => {$sub};
-}
-
-macro_rules! count_tts {
-    ($($tts:tt)*) => {0usize $(+ replace_expr!($tts 1usize))*};
-}
-
-/// Macro to take a spec of a mercurial wire protocol command and generate the
-/// code to invoke a parser for it. This works for "regular" commands with a
-/// fixed number of named parameters.
-macro_rules! command_common {
-    // No parameters
-    ( $i:expr, $name:expr, $req:ident, $star:expr, $parseparam:expr, { } ) => {
-        call!($i, parse_command, $name, $parseparam, $star, |_| Ok($req))
-    };
-
-    // One key/parser pair for each parameter
-    ( $i:expr, $name:expr, $req:ident, $star:expr, $parseparam:expr,
-            { $( ($key:ident, $parser:expr) )+ } ) => {
-        call!($i, parse_command, $name, $parseparam, $star+count_tts!( $($key)+ ),
-            |kv| Ok($req {
-                $( $key: parseval(&kv, stringify!($key), $parser)?, )*
-            })
-        )
-    };
-}
-
+/// Macro to invoke the parser for a mercurial wire protocol command with `$nargs`
+/// parameters (counting '*' as one), building its `Request` with `parse_args`.
 macro_rules! command {
-    ( $i:expr, $name:expr, $req:ident, $parseparam:expr,
-            { $( $key:ident => $parser:expr, )* } ) => {
-        command_common!($i, $name, $req, 0, $parseparam, { $(($key, $parser))* } )
+    ( $i:expr, $name:expr, $nargs:expr, $parseparam:expr ) => {
+        call!($i, parse_command, $name, $parseparam, $nargs, |kv| parse_args($name, kv))
     };
 }
 
-macro_rules! command_star {
-    ( $i:expr, $name:expr, $req:ident, $parseparam:expr,
-            { $( $key:ident => $parser:expr, )* } ) => {
-        command_common!($i, $name, $req, 1, $parseparam, { $(($key, $parser))* } )
+/// Build the `Request` for command `cmd` from its named parameters. This is shared with
+/// transports which deliver the parameters already decoded. unbundle isn't handled here,
+/// as its bundle doesn't come with the parameters.
+pub fn parse_args(cmd: &str, kv: HashMap<Vec<u8>, Vec<u8>>) -> Result<Request> {
+    use Request::*;
+
+    let req = match cmd {
+        "batch" => Batch {
+            cmds: parseval(&kv, "cmds", cmdlist)?,
+        },
+        "between" => Between {
+            pairs: parseval(&kv, "pairs", pairlist)?,
+        },
+        "branchmap" => Branchmap,
+        "branches" => Branches {
+            nodes: parseval(&kv, "nodes", hashlist)?,
+        },
+        "clonebundles" => Clonebundles,
+        "capabilities" => Capabilities,
+        "changegroup" => Changegroup {
+            roots: parseval(&kv, "roots", hashlist)?,
+        },
+        "changegroupsubset" => Changegroupsubset {
+            heads: parseval(&kv, "heads", hashlist)?,
+            bases: parseval(&kv, "bases", hashlist)?,
+        },
+        "debugwireargs" => {
+            let one = parseval(&kv, "one", ident_complete)?.to_vec();
+            let two = parseval(&kv, "two", ident_complete)?.to_vec();
+            Debugwireargs {
+                one: one,
+                two: two,
+                all_args: kv,
+            }
+        }
+        "getbundle" => Getbundle(GetbundleArgs {
+            // Some params are currently ignored, like:
+            // - obsmarkers
+            // - cbattempted
+            // If those params are needed, they should be parsed here.
+            heads: parseval_default(&kv, "heads", hashlist)?,
+            common: parseval_default(&kv, "common", hashlist)?,
+            bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
+            listkeys: parseval_default(&kv, "listkeys", commavalues)?,
+            // Mercurial sends a changegroup unless told not to
+            cg: !kv.contains_key(&b"cg"[..]) || parseval(&kv, "cg", boolean)?,
+            phases: parseval_default(&kv, "phases", boolean)?,
+        }),
+        "heads" => Heads,
+        "hello" => Hello,
+        "listkeys" => Listkeys {
+            namespace: parseval(&kv, "namespace", ident_string)?,
+        },
+        "lookup" => Lookup {
+            key: parseval(&kv, "key", ident_string)?,
+        },
+        "known" => Known {
+            nodes: parseval(&kv, "nodes", hashlist)?,
+        },
+        "pushkey" => Pushkey {
+            namespace: parseval(&kv, "namespace", ident_string)?,
+            key: parseval(&kv, "key", any_string)?,
+            old: parseval(&kv, "old", nodehash_or_empty)?,
+            new: parseval(&kv, "new", nodehash_or_empty)?,
+        },
+        // Mercurial clients send "stream_out"
+        "stream_out" | "streamout" => Streamout,
+        _ => bail!(errors::ErrorKind::CommandParse(cmd.as_bytes().to_vec())),
     };
+    Ok(req)
 }
 
 /// Parse a non-batched command
@@ -432,19 +492,12 @@ fn unbundle(
     parse_params: fn(&[u8], usize)
         -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>>,
 ) -> IResult<&[u8], Request> {
-    // Use this as a syntactic proxy for Request::Unbundle, which works because Request's
-    // values are struct-like enums, and this is a struct, so the command macro is happy
-    // to fill it out.
-    struct UnbundleCmd {
-        heads: Vec<String>,
-    }
     do_parse!(inp,
-        unbundle: command!("unbundle", UnbundleCmd, parse_params, {
-                heads => stringlist,
-            }) >>
+        heads: call!(parse_command, "unbundle", parse_params, 1,
+            |kv| parseval(&kv, "heads", stringlist)) >>
         stream: bundle2stream >>
             (Request::Unbundle {
-                heads: unbundle.heads,
+                heads: heads,
                 stream: stream
             })
     )
@@ -458,66 +511,28 @@ fn parse_common(
     parse_params: fn(&[u8], usize)
         -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>>,
 ) -> Result<Option<Request>> {
-    use Request::*;
-
     let res = {
         let origlen = buf.len();
+        // Each command with its number of parameters, counting '*' as one
         let parse_res = alt!(&buf[..],
-              command_star!("batch", Batch, parse_params, {
-                  cmds => cmdlist,
-              })
-            | command!("between", Between, parse_params, {
-                  pairs => pairlist,
-              })
-            | command!("branchmap", Branchmap, parse_params, {})
-            | command!("branches", Branches, parse_params, {
-                  nodes => hashlist,
-              })
-            | command!("clonebundles", Clonebundles, parse_params, {})
-            | command!("capabilities", Capabilities, parse_params, {})
-            | command!("changegroup", Changegroup, parse_params, {
-                  roots => hashlist,
-              })
-            | command!("changegroupsubset", Changegroupsubset, parse_params, {
-                  heads => hashlist,
-                  bases => hashlist,
-              })
-            | call!(parse_command, "debugwireargs", parse_params, 2+1,
-                |kv| Ok(Debugwireargs {
-                    one: parseval(&kv, "one", ident_complete)?.to_vec(),
-                    two: parseval(&kv, "two", ident_complete)?.to_vec(),
-                    all_args: kv,
-                }))
-            | call!(parse_command, "getbundle", parse_params, 0+1,
-                |kv| Ok(Getbundle(GetbundleArgs {
-                    // Some params are currently ignored, like:
-                    // - obsmarkers
-                    // - cg
-                    // - cbattempted
-                    // If those params are needed, they should be parsed here.
-                    heads: parseval_default(&kv, "heads", hashlist)?,
-                    common: parseval_default(&kv, "common", hashlist)?,
-                    bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
-                    listkeys: parseval_default(&kv, "listkeys", commavalues)?,
-                })))
-            | command!("heads", Heads, parse_params, {})
-            | command!("hello", Hello, parse_params, {})
-            | command!("listkeys", Listkeys, parse_params, {
-                  namespace => ident_string,
-              })
-            | command!("lookup", Lookup, parse_params, {
-                  key => ident_string,
-              })
-            | command_star!("known", Known, parse_params, {
-                  nodes => hashlist,
-              })
-            | command!("pushkey", Pushkey, parse_params, {
-                  namespace => ident_string,
-                  key => ident_string,
-                  old => nodehash,
-                  new => nodehash,
-              })
-            | command!("streamout", Streamout, parse_params, {})
+              command!("batch", 2, parse_params)
+            | command!("between", 1, parse_params)
+            | command!("branchmap", 0, parse_params)
+            | command!("branches", 1, parse_params)
+            | command!("clonebundles", 0, parse_params)
+            | command!("capabilities", 0, parse_params)
+            | command!("changegroup", 1, parse_params)
+            | command!("changegroupsubset", 2, parse_params)
+            | command!("debugwireargs", 3, parse_params)
+            | command!("getbundle", 1, parse_params)
+            | command!("heads", 0, parse_params)
+            | command!("hello", 0, parse_params)
+            | command!("listkeys", 1, parse_params)
+            | command!("lookup", 1, parse_params)
+            | command!("known", 2, parse_params)
+            | command!("pushkey", 4, parse_params)
+            | command!("stream_out", 0, parse_params)
+            | command!("streamout", 0, parse_params)
             | call!(unbundle, parse_params)
         );
 
@@ -1204,12 +1219,14 @@ mod test_parse {
                 common: vec![],
                 bundlecaps: vec![],
                 listkeys: vec![],
+                cg: true,
+                phases: false,
             }),
         );
 
         // with arguments
         let inp = "getbundle\n\
-                   * 5\n\
+                   * 7\n\
                    heads 40\n\
                    1111111111111111111111111111111111111111\
                    common 81\n\
@@ -1218,6 +1235,10 @@ mod test_parse {
                    cap1,CAP2,cap3\
                    listkeys 9\n\
                    key1,key2\
+                   cg 1\n\
+                   0\
+                   phases 1\n\
+                   1\
                    extra 5\n\
                    extra";
         test_parse(
@@ -1227,6 +1248,8 @@ mod test_parse {
                 common: vec![hash_twos(), hash_threes()],
                 bundlecaps: vec![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
+                cg: false,
+                phases: true,
             }),
         );
     }
@@ -1311,6 +1334,28 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_parse_pushkey_create() {
+        let inp = "pushkey\n\
+                   namespace 9\n\
+                   bookmarks\
+                   key 11\n\
+                   feature/foo\
+                   old 0\n\
+                   new 40\n\
+                   2222222222222222222222222222222222222222";
+
+        test_parse(
+            inp,
+            Request::Pushkey {
+                namespace: "bookmarks".to_string(),
+                key: "feature/foo".to_string(),
+                old: NULL_HASH,
+                new: hash_twos(),
+            },
+        );
+    }
+
     #[test]
     fn test_parse_streamout() {
         let inp = "streamout\n";
@@ -1318,6 +1363,13 @@ mod test_parse {
         test_parse(inp, Request::Streamout {});
     }
 
+    #[test]
+    fn test_parse_stream_out() {
+        let inp = "stream_out\n";
+
+        test_parse(inp, Request::Streamout {});
+    }
+
     fn test_parse_unbundle_with(bundle: &[u8]) {
         let mut inp = b"unbundle\n\
                        heads 10\n\
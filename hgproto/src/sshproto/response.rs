@@ -9,6 +9,9 @@ use std::fmt::Display;
 
 use bytes::{BufMut, Bytes, BytesMut};
 
+use mercurial_bundles::parts::encode_listkey;
+use mercurial_types::{percent_encode, NULL_HASH};
+
 use batch;
 use Response;
 
@@ -73,6 +76,46 @@ pub fn encode_cmd(response: &Response) -> Bytes {
             Bytes::from(out)
         }
 
+        &Branches(ref branches) => {
+            let mut out = Vec::new();
+
+            for branch in branches {
+                let nodes = [
+                    branch.top,
+                    branch.node,
+                    branch.p0.unwrap_or(NULL_HASH),
+                    branch.p1.unwrap_or(NULL_HASH),
+                ];
+                separated(&mut out, &nodes, " ").expect("write to vec failed");
+            }
+
+            Bytes::from(out)
+        }
+
+        &Branchmap(ref map) => {
+            let mut branches: Vec<_> = map.iter().collect();
+            branches.sort_by_key(|&(name, _)| name);
+
+            let mut out = Vec::new();
+            for (name, heads) in branches {
+                let mut heads: Vec<_> = heads.iter().collect();
+                heads.sort();
+
+                write!(out, "{} ", percent_encode(name)).expect("write to vec failed");
+                separated(&mut out, heads, " ").expect("write to vec failed");
+            }
+
+            Bytes::from(out)
+        }
+
+        &Capabilities(ref caps) => Bytes::from(caps.join(" ")),
+
+        &Changegroup(ref res) => res.clone(),
+
+        &Changegroupsubset(ref res) => res.clone(),
+
+        &Clonebundles(ref res) => Bytes::from(res.as_bytes()),
+
         &Debugwireargs(ref res) => res.clone(),
 
         &Heads(ref set) => {
@@ -83,6 +126,15 @@ pub fn encode_cmd(response: &Response) -> Bytes {
             Bytes::from(out)
         }
 
+        &Lookup(ref res) => {
+            let out = match res {
+                &Ok(ref node) => format!("1 {}\n", node),
+                &Err(ref err) => format!("0 {}\n", err),
+            };
+
+            Bytes::from(out)
+        }
+
         &Known(ref knowns) => {
             let out: Vec<_> = knowns
                 .iter()
@@ -94,6 +146,118 @@ pub fn encode_cmd(response: &Response) -> Bytes {
 
         &Getbundle(ref res) => res.clone(),
 
+        &Listkeys(ref res) => {
+            let mut keys: Vec<_> = res.iter().collect();
+            keys.sort();
+
+            let mut out = Vec::new();
+            for (key, value) in keys {
+                encode_listkey(&mut out, key, value);
+            }
+
+            Bytes::from(out)
+        }
+
+        &Pushkey(ref res) => {
+            let out = if *res { b"1\n" } else { b"0\n" };
+
+            Bytes::from(&out[..])
+        }
+
+        &Streamout(ref res) => res.clone(),
+
+        &Unbundle(ref res) => res.clone(),
+
         r => panic!("Response for {:?} unimplemented", r),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use BranchRes;
+    use mercurial_types::NodeHash;
+
+    const ONES: &str = "1111111111111111111111111111111111111111";
+    const TWOS: &str = "2222222222222222222222222222222222222222";
+    const THREES: &str = "3333333333333333333333333333333333333333";
+
+    fn hash(hex: &str) -> NodeHash {
+        hex.parse().expect("bad hash")
+    }
+
+    fn encoded(response: Response) -> BytesMut {
+        let mut out = BytesMut::new();
+        encode(&response, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_branches() {
+        let res = Response::Branches(vec![
+            BranchRes {
+                top: hash(ONES),
+                node: hash(TWOS),
+                p0: Some(hash(THREES)),
+                p1: None,
+            },
+        ]);
+        let expected = format!("164\n{} {} {} {}\n", ONES, TWOS, THREES, NULL_HASH);
+        assert_eq!(encoded(res), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_branchmap() {
+        let res = Response::Branchmap(hashmap! {
+            "feature/x".to_string() => hashset! { hash(THREES) },
+            "default".to_string() => hashset! { hash(TWOS), hash(ONES) },
+        });
+        let expected = format!("143\ndefault {} {}\nfeature%2Fx {}\n", ONES, TWOS, THREES);
+        assert_eq!(encoded(res), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let res = Response::Capabilities(vec![
+            "lookup".to_string(),
+            "known".to_string(),
+            "getbundle".to_string(),
+        ]);
+        assert_eq!(encoded(res), &b"22\nlookup known getbundle"[..]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let res = Response::Lookup(Ok(hash(ONES)));
+        assert_eq!(encoded(res), format!("43\n1 {}\n", ONES).as_bytes());
+
+        let res = Response::Lookup(Err("unknown revision 'foo'".to_string()));
+        assert_eq!(encoded(res), &b"25\n0 unknown revision 'foo'\n"[..]);
+    }
+
+    #[test]
+    fn test_listkeys() {
+        let res = Response::Listkeys(hashmap! {
+            b"b".to_vec() => TWOS.as_bytes().to_vec(),
+            b"a".to_vec() => ONES.as_bytes().to_vec(),
+        });
+        let expected = format!("86\na\t{}\nb\t{}\n", ONES, TWOS);
+        assert_eq!(encoded(res), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_pushkey() {
+        assert_eq!(encoded(Response::Pushkey(true)), &b"2\n1\n"[..]);
+        assert_eq!(encoded(Response::Pushkey(false)), &b"2\n0\n"[..]);
+    }
+
+    #[test]
+    fn test_streams() {
+        // Streaming responses go out as they are, without a length
+        let stream = Bytes::from(&b"0\n1 5\nstore"[..]);
+        assert_eq!(encoded(Response::Streamout(stream.clone())), &stream[..]);
+
+        let bundle = Bytes::from(&b"HG20\0\0\0\0\0\0\0\0"[..]);
+        assert_eq!(encoded(Response::Unbundle(bundle.clone())), &bundle[..]);
+    }
+}
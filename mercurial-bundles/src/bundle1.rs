@@ -0,0 +1,184 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Parsing bundle1 streams, as written by `hg bundle` before bundle2 existed. A bundle1 is the
+//! magic string `HG10`, two bytes naming the compression, and then a compressed version 01
+//! changegroup.
+
+use std::fmt::{self, Debug, Formatter};
+use std::mem;
+
+use async_compression::{Decompressor, DecompressorType};
+use bytes::BytesMut;
+use futures::{Async, Poll, Stream};
+use futures_ext::{AsyncReadExt, FramedStream, ReadLeadingBuffer};
+use slog;
+use tokio_io::AsyncRead;
+use tokio_io::codec::Decoder;
+
+use changegroup::{CgVersion, Part};
+use changegroup::unpacker::CgUnpacker;
+use errors::*;
+
+/// A stream of the changegroup parts in a bundle1.
+#[derive(Debug)]
+pub struct Bundle1Stream<'a, R>
+where
+    R: AsyncRead + 'a,
+{
+    logger: slog::Logger,
+    current_stream: CurrentStream<'a, R>,
+}
+
+enum CurrentStream<'a, R>
+where
+    R: AsyncRead + 'a,
+{
+    Start(FramedStream<R, StartDecoder>),
+    Changegroup(FramedStream<Decompressor<'a, ReadLeadingBuffer<R>>, CgUnpacker>),
+    Invalid,
+    End,
+}
+
+impl<'a, R> CurrentStream<'a, R>
+where
+    R: AsyncRead + 'a,
+{
+    pub fn take(&mut self) -> Self {
+        mem::replace(self, CurrentStream::Invalid)
+    }
+}
+
+impl<'a, R> Debug for CurrentStream<'a, R>
+where
+    R: AsyncRead + Debug + 'a,
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            &CurrentStream::Start(ref stream) => write!(f, "Start({:?})", stream),
+            &CurrentStream::Changegroup(ref stream) => write!(f, "Changegroup({:?})", stream),
+            &CurrentStream::Invalid => write!(f, "Invalid"),
+            &CurrentStream::End => write!(f, "End"),
+        }
+    }
+}
+
+impl<'a, R> Bundle1Stream<'a, R>
+where
+    R: AsyncRead + 'a,
+{
+    pub fn new(read: R, logger: slog::Logger) -> Bundle1Stream<'a, R> {
+        Bundle1Stream {
+            logger: logger,
+            current_stream: CurrentStream::Start(read.framed_stream(StartDecoder)),
+        }
+    }
+}
+
+impl<'a, R> Stream for Bundle1Stream<'a, R>
+where
+    R: AsyncRead + 'a,
+{
+    type Item = Part;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Part>, Error> {
+        loop {
+            match self.current_stream.take() {
+                CurrentStream::Start(mut stream) => match stream.poll() {
+                    Ok(Async::Ready(Some(decompressor_type))) => {
+                        let unpacker = CgUnpacker::new(
+                            self.logger.new(o!("stream" => "cg1")),
+                            CgVersion::Cg1,
+                        );
+                        let changegroup =
+                            Decompressor::new(stream.into_inner_leading(), decompressor_type)
+                                .framed_stream(unpacker);
+                        self.current_stream = CurrentStream::Changegroup(changegroup);
+                    }
+                    Ok(Async::Ready(None)) => {
+                        self.current_stream = CurrentStream::End;
+                        return Ok(Async::Ready(None));
+                    }
+                    Ok(Async::NotReady) => {
+                        self.current_stream = CurrentStream::Start(stream);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                },
+                CurrentStream::Changegroup(mut stream) => match stream.poll() {
+                    Ok(Async::Ready(Some(inner))) => {
+                        let part = inner.cg2_part();
+                        self.current_stream = if part == Part::End {
+                            CurrentStream::End
+                        } else {
+                            CurrentStream::Changegroup(stream)
+                        };
+                        return Ok(Async::Ready(Some(part)));
+                    }
+                    Ok(Async::Ready(None)) => {
+                        // The unpacker only complains about a truncated changegroup if
+                        // there's a partial chunk left over.
+                        let msg = "bundle1 ended before its changegroup did".into();
+                        return Err(ErrorKind::Bundle1Decode(msg).into());
+                    }
+                    Ok(Async::NotReady) => {
+                        self.current_stream = CurrentStream::Changegroup(stream);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                },
+                CurrentStream::Invalid => {
+                    let msg = "corrupt byte stream".into();
+                    return Err(ErrorKind::Bundle1Decode(msg).into());
+                }
+                CurrentStream::End => {
+                    self.current_stream = CurrentStream::End;
+                    return Ok(Async::Ready(None));
+                }
+            }
+        }
+    }
+}
+
+/// Decodes the header of a bundle1 into the type of compression used for the rest of it.
+#[derive(Debug)]
+struct StartDecoder;
+
+impl Decoder for StartDecoder {
+    type Item = DecompressorType;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<DecompressorType>> {
+        // bundle1 spec: "HG10" + 2 byte compression type + compressed changegroup
+        if buf.len() < 6 {
+            return Ok(None);
+        }
+
+        if &buf[..4] != b"HG10" {
+            bail!(ErrorKind::Bundle1Decode("invalid bundle magic string".into()));
+        }
+
+        let compression = [buf[4], buf[5]];
+        let decompressor_type = match &compression {
+            b"UN" => DecompressorType::Uncompressed,
+            b"BZ" => DecompressorType::Bzip2,
+            b"GZ" => DecompressorType::Gzip,
+            _ => bail!(ErrorKind::Bundle1Decode(format!(
+                "unknown compression '{}'",
+                String::from_utf8_lossy(&compression)
+            ))),
+        };
+        let header_len = match decompressor_type {
+            // The compression type is also the magic string which starts a bzip2 stream, so
+            // leave it for the decompressor.
+            DecompressorType::Bzip2 => 4,
+            _ => 6,
+        };
+        let _ = buf.split_to(header_len);
+        Ok(Some(decompressor_type))
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Generating bundle1 streams.
+
+use std::io;
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use tokio_io::AsyncWrite;
+use tokio_io::io::write_all;
+
+use async_compression::{Compressor, CompressorType};
+
+use changegroup::{encode_changegroup, CgVersion, Part};
+use errors::*;
+
+/// Write `parts` to `writer` as a bundle1, compressing the changegroup with `ct`. The parts
+/// must make up a version 01 changegroup -- see `CgPacker` for what that means for deltas.
+///
+/// bundle1 doesn't support zstd, so that's an error.
+pub fn encode_bundle1<W, S>(writer: W, ct: CompressorType, parts: S) -> BoxFuture<W, Error>
+where
+    W: AsyncWrite + Send + 'static,
+    S: Stream<Item = Part> + Send + 'static,
+    S::Error: ::std::error::Error + Send,
+{
+    let header: &'static [u8] = match ct {
+        CompressorType::Uncompressed => b"HG10UN",
+        // bzip2 streams start with "BZ", which doubles as the compression type.
+        CompressorType::Bzip2(_) => b"HG10",
+        CompressorType::Gzip => b"HG10GZ",
+        CompressorType::Zstd { .. } => {
+            let msg = "bundle1 doesn't support zstd compression".into();
+            return future::err(ErrorKind::Bundle1Encode(msg).into()).boxify();
+        }
+    };
+
+    write_all(writer, header)
+        .from_err()
+        .and_then(move |(writer, _)| {
+            let compressor = Compressor::new(writer, ct);
+            encode_changegroup(parts, CgVersion::Cg1).fold(compressor, |compressor, bytes| {
+                write_all(compressor, bytes).map(|(compressor, _)| compressor)
+            })
+        })
+        .and_then(|compressor| FinishCompressor(Some(compressor)))
+        .boxify()
+}
+
+/// A future which flushes out the rest of the compressed data, and returns the writer.
+struct FinishCompressor<W>(Option<Compressor<W>>)
+where
+    W: AsyncWrite + 'static;
+
+impl<W> Future for FinishCompressor<W>
+where
+    W: AsyncWrite + Send + 'static,
+{
+    type Item = W;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<W, Error> {
+        let compressor = self.0
+            .take()
+            .expect("polled FinishCompressor future after it is complete");
+        match compressor.try_finish() {
+            Ok(inner) => Ok(Async::Ready(inner)),
+            Err((compressor, err)) => if err.kind() == io::ErrorKind::WouldBlock {
+                self.0 = Some(compressor);
+                Ok(Async::NotReady)
+            } else {
+                Err(err).chain_err(|| {
+                    ErrorKind::Bundle1Encode("error while completing write".into())
+                })
+            },
+        }
+    }
+}
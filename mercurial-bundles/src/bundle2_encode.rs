@@ -5,12 +5,13 @@
 // GNU General Public License version 2 or any later version.
 
 use std::collections::HashMap;
-use std::io::{self, Cursor};
+use std::io::{self, Cursor, Write};
 use std::mem;
+use std::sync::{Arc, Mutex};
 use std::vec::IntoIter;
 
 use byteorder::ByteOrder;
-use bytes::{BigEndian, Buf, BufMut, Bytes, IntoBuf};
+use bytes::{BigEndian, Buf, BufMut, Bytes, BytesMut, IntoBuf};
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
 use futures::stream::Forward;
 use tokio_io::AsyncWrite;
@@ -25,6 +26,10 @@ use part_encode::{PartEncode, PartEncodeBuilder};
 use types::StreamHeader;
 use utils::{capitalize_first, get_compression_param, is_mandatory_param};
 
+// How much encoded output a Bundle2EncodeStream buffers before the encoder has to wait for it
+// to be consumed
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
 /// Builder to generate a bundle2.
 pub struct Bundle2EncodeBuilder<W> {
     writer: W,
@@ -128,6 +133,92 @@ where
     }
 }
 
+impl Bundle2EncodeBuilder<StreamWriter> {
+    /// Create a builder for a bundle2 which is produced as a stream of chunks, rather than
+    /// written out in one go. See `build_stream`.
+    pub fn new_stream() -> Self {
+        Self::new(StreamWriter(Arc::new(Mutex::new(BytesMut::new()))))
+    }
+
+    /// Generate the bundle2 a chunk at a time, as the returned stream is polled. Generation
+    /// waits while there's too much output that hasn't been consumed yet, so the whole bundle2
+    /// never has to be held in memory.
+    pub fn build_stream(self) -> Bundle2EncodeStream {
+        let buf = self.writer.0.clone();
+        Bundle2EncodeStream {
+            encode: Some(self.build()),
+            error: None,
+            buf: buf,
+        }
+    }
+}
+
+/// The writer used by bundle2s which are generated as streams. Writes collect the output for
+/// the `Bundle2EncodeStream` to return, and fail with `WouldBlock` once enough has been
+/// collected.
+#[derive(Debug)]
+pub struct StreamWriter(Arc<Mutex<BytesMut>>);
+
+impl Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = self.0.lock().expect("lock poisoned");
+        if out.len() >= STREAM_BUFFER_SIZE {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for StreamWriter {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A bundle2 being generated as a stream of chunks. Created by
+/// `Bundle2EncodeBuilder::build_stream`.
+pub struct Bundle2EncodeStream {
+    // None once generation has finished
+    encode: Option<Bundle2Encode<StreamWriter>>,
+    // Why generation failed, which is returned once the output before the failure is consumed
+    error: Option<Error>,
+    buf: Arc<Mutex<BytesMut>>,
+}
+
+impl Stream for Bundle2EncodeStream {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        if let Some(mut encode) = self.encode.take() {
+            match encode.poll() {
+                Ok(Async::Ready(_writer)) => {}
+                Ok(Async::NotReady) => self.encode = Some(encode),
+                Err(err) => self.error = Some(err),
+            }
+        }
+
+        // The encoder can stop either because it's waiting for parts to be generated, or
+        // because the writer is full. It's only safe to wait in the first case, since the task
+        // won't be woken up again in the second.
+        let chunk = self.buf.lock().expect("lock poisoned").take().freeze();
+        if !chunk.is_empty() {
+            Ok(Async::Ready(Some(chunk)))
+        } else if let Some(err) = self.error.take() {
+            Err(err)
+        } else if self.encode.is_none() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
 /// A sink that chunks generated by PartEncodes goes into.
 type PartSink<W> = FramedWrite<Compressor<W>, ChunkEncoder>;
 
@@ -143,6 +234,8 @@ where
     Part(PartFuture<W>, IntoIter<PartEncode>),
     EndOfStream(PartSink<W>, bool),
     Finish(Compressor<W>),
+    // A part was interrupted by an error, so the output is flushed before failing
+    Abort(Compressor<W>, Error),
     Done,
     Invalid,
 }
@@ -242,6 +335,7 @@ where
             EncodeState::Part(part_fut, iter) => Self::poll_part(part_fut, iter),
             EncodeState::EndOfStream(sink, eos_written) => Self::poll_eos(sink, eos_written),
             EncodeState::Finish(compressor) => Self::poll_finish(compressor),
+            EncodeState::Abort(compressor, err) => Self::poll_abort(compressor, err),
             EncodeState::Done => panic!("polled Bundle2Encode future after it is complete"),
             EncodeState::Invalid => {
                 panic!("polled Bundle2Encode future after it returned an error")
@@ -254,10 +348,12 @@ where
         iter: IntoIter<PartEncode>,
     ) -> (Poll<W, Error>, EncodeState<W>) {
         match part_fut.poll() {
-            Ok(Async::Ready((_part_encoder, sink))) => {
+            Ok(Async::Ready((mut part_encoder, sink))) => match part_encoder.take_error() {
+                // The part was interrupted, and the client will stop reading after that.
+                Some(err) => Self::poll_abort(sink.into_inner(), err),
                 // This part is done.
-                Self::poll_next_part(iter, sink)
-            }
+                None => Self::poll_next_part(iter, sink),
+            },
             Ok(Async::NotReady) => {
                 // This part is still writing.
                 (Ok(Async::NotReady), EncodeState::Part(part_fut, iter))
@@ -312,6 +408,18 @@ where
             },
         }
     }
+
+    fn poll_abort(compressor: Compressor<W>, err: Error) -> (Poll<W, Error>, EncodeState<W>) {
+        match compressor.try_finish() {
+            Err((compressor, e)) => if e.kind() == io::ErrorKind::WouldBlock {
+                (Ok(Async::NotReady), EncodeState::Abort(compressor, err))
+            } else {
+                // The interrupting error is what the caller needs to know about
+                (Err(err), EncodeState::Invalid)
+            },
+            Ok(_) => (Err(err), EncodeState::Invalid),
+        }
+    }
 }
 
 /// Ensure that Bundle2Encode is Send.
@@ -323,4 +431,7 @@ fn _assert_send() {
     let builder = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
     _assert(&builder);
     _assert(&builder.build());
+
+    let builder = Bundle2EncodeBuilder::new_stream();
+    _assert(&builder.build_stream());
 }
@@ -4,15 +4,62 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use bytes::Bytes;
+use futures::Stream;
+
+use futures_ext::{BoxStream, StreamExt};
 use mercurial_types::{Delta, MPath, NodeHash};
 
+use errors::*;
+
 pub mod packer;
 pub mod unpacker;
 
+/// Changegroup versions. They mostly differ in how each delta chunk's header is encoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CgVersion {
+    /// Deltas are against the previous chunk in the section, or the first chunk's p1
+    Cg1,
+    /// Each chunk names its own delta base
+    Cg2,
+    /// Each chunk also carries revision flags, and tree manifest directories get their own
+    /// sections between the root manifest and the filelogs
+    Cg3,
+}
+
+impl CgVersion {
+    /// Parse the `version` parameter of a changegroup part.
+    pub fn from_param(version: &[u8]) -> Option<Self> {
+        match version {
+            b"01" => Some(CgVersion::Cg1),
+            b"02" => Some(CgVersion::Cg2),
+            b"03" => Some(CgVersion::Cg3),
+            _ => None,
+        }
+    }
+
+    /// The name of this version in the `version` parameter of changegroup parts.
+    pub fn to_param(&self) -> &'static str {
+        match *self {
+            CgVersion::Cg1 => "01",
+            CgVersion::Cg2 => "02",
+            CgVersion::Cg3 => "03",
+        }
+    }
+}
+
+/// Changegroup versions which can be both generated and applied, as named by the `version`
+/// parameter of changegroup parts.
+pub const SUPPORTED_VERSIONS: &[&str] = &["02", "03"];
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Section {
     Changeset,
+    /// The flat manifest, or the root manifest of a treemanifest repository
     Manifest,
+    /// The manifest of a directory in a treemanifest repository. Only version 03 changegroups
+    /// can contain these.
+    Treemanifest(MPath),
     Filelog(MPath),
 }
 
@@ -39,9 +86,34 @@ pub struct CgDeltaChunk {
     pub p2: NodeHash,
     pub base: NodeHash,
     pub linknode: NodeHash,
+    /// Only version 03 changegroups can transmit flags. They're empty otherwise.
+    pub flags: RevFlags,
     pub delta: Delta,
 }
 
+bitflags! {
+    /// Per-revision flags, as stored in revlog indexes.
+    pub struct RevFlags: u16 {
+        const CENSORED      = 1 << 15;
+        const ELLIPSIS      = 1 << 14;
+        const EXTSTORED     = 1 << 13;
+    }
+}
+
+/// Encode `parts` as a bare changegroup, without any bundle wrapped around it. This is what the
+/// legacy `changegroup` and `changegroupsubset` commands send.
+pub fn encode_changegroup<S>(parts: S, version: CgVersion) -> BoxStream<Bytes, Error>
+where
+    S: Stream<Item = Part> + Send + 'static,
+    S::Error: ::std::error::Error + Send,
+{
+    let parts = parts.or_else(|err| Err(err).chain_err(|| ErrorKind::ChangegroupGeneration));
+    // Each chunk produced by the packer already starts with its length
+    packer::CgPacker::new(parts, version)
+        .and_then(|chunk| chunk.into_bytes())
+        .boxify()
+}
+
 #[cfg(test)]
 mod test {
     use std::io::{self, Cursor};
@@ -58,7 +130,7 @@ mod test {
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
     use chunk::{ChunkDecoder, ChunkEncoder};
-    use quickcheck_types::Cg2PartSequence;
+    use quickcheck_types::CgPartSequence;
 
     use super::*;
 
@@ -76,7 +148,7 @@ mod test {
         quickcheck.quickcheck(
             roundtrip as
                 fn(
-                    Cg2PartSequence,
+                    CgPartSequence,
                     PartialWithErrors<GenWouldBlock>,
                     PartialWithErrors<GenWouldBlock>,
                 ) -> TestResult,
@@ -91,7 +163,7 @@ mod test {
         quickcheck.quickcheck(
             roundtrip as
                 fn(
-                    Cg2PartSequence,
+                    CgPartSequence,
                     PartialWithErrors<GenWouldBlock>,
                     PartialWithErrors<GenWouldBlock>,
                 ) -> TestResult,
@@ -99,14 +171,14 @@ mod test {
     }
 
     fn roundtrip(
-        seq: Cg2PartSequence,
+        seq: CgPartSequence,
         write_ops: PartialWithErrors<GenWouldBlock>,
         read_ops: PartialWithErrors<GenWouldBlock>,
     ) -> TestResult {
         // Encode this sequence.
         let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
         let partial_write = PartialAsyncWrite::new(cursor, write_ops);
-        let packer = packer::Cg2Packer::new(seq.to_stream().and_then(|x| x));
+        let packer = packer::CgPacker::new(seq.to_stream().and_then(|x| x), seq.version());
         let sink = FramedWrite::new(partial_write, ChunkEncoder);
         let encode_fut = packer.forward(sink);
 
@@ -122,7 +194,7 @@ mod test {
             .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
 
         let logger = make_root_logger();
-        let unpacker = unpacker::Cg2Unpacker::new(logger);
+        let unpacker = unpacker::CgUnpacker::new(logger, seq.version());
         let part_stream = chunks.decode(unpacker);
 
         let parts = Vec::new();
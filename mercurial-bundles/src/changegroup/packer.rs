@@ -15,23 +15,51 @@ use chunk::Chunk;
 use delta;
 use errors::*;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
-pub struct Cg2Packer<S> {
+pub struct CgPacker<S> {
     delta_stream: S,
     last_seen: Section,
+    version: CgVersion,
+    // Version 03 has an empty chunk after the last tree manifest directory section
+    manifests_done: bool,
 }
 
-impl<S> Cg2Packer<S> {
-    pub fn new(delta_stream: S) -> Self {
-        Cg2Packer {
+impl<S> CgPacker<S> {
+    /// Encode the parts in `delta_stream` as a changegroup of version `version`.
+    ///
+    /// Version 01 changegroups have no way to name delta bases, so in that case every delta
+    /// must already be against the previous chunk in its section (or the first chunk's p1). The
+    /// `base` of each chunk is ignored.
+    ///
+    /// Only version 03 changegroups can contain tree manifest directory sections and revision
+    /// flags. Encoding either with an earlier version is an error.
+    pub fn new(delta_stream: S, version: CgVersion) -> Self {
+        CgPacker {
             delta_stream: delta_stream,
             last_seen: Section::Changeset,
+            version: version,
+            manifests_done: version != CgVersion::Cg3,
+        }
+    }
+
+    /// Whether the part about to be encoded is the first one after all the manifests, and so
+    /// needs to be preceded by the empty chunk which ends the tree manifest sections.
+    fn ends_manifests(&mut self, part: &Part) -> bool {
+        let ends = match part {
+            &Part::CgChunk(Section::Filelog(_), _) | &Part::End => true,
+            _ => false,
+        };
+        if ends && !self.manifests_done {
+            self.manifests_done = true;
+            true
+        } else {
+            false
         }
     }
 }
 
-impl<S> Stream for Cg2Packer<S>
+impl<S> Stream for CgPacker<S>
 where
     S: Stream<Item = Part>,
     Error: From<S::Error>,
@@ -42,31 +70,30 @@ where
     fn poll(&mut self) -> Poll<Option<Chunk>, Error> {
         use self::Part::*;
 
-        match try_ready!(self.delta_stream.poll()) {
-            None => Ok(Async::Ready(None)),
-            Some(CgChunk(section, delta_chunk)) => {
-                let mut builder = ChunkBuilder::new();
+        let part = match try_ready!(self.delta_stream.poll()) {
+            None => return Ok(Async::Ready(None)),
+            Some(part) => part,
+        };
+
+        let mut builder = ChunkBuilder::new();
+        if self.ends_manifests(&part) {
+            builder.encode_empty();
+        }
+
+        match part {
+            CgChunk(section, delta_chunk) => {
                 if self.last_seen != section {
-                    builder.encode_section(&section)?;
+                    builder.encode_section(&section, self.version)?;
                     self.last_seen = section;
                 }
-                builder.encode_delta_chunk(delta_chunk);
+                builder.encode_delta_chunk(delta_chunk, self.version)?;
                 Ok(Async::Ready(Some(builder.build()?)))
             }
-            Some(SectionEnd(_section)) => Ok(Async::Ready(Some(empty_cg_chunk()))),
-            Some(End) => Ok(Async::Ready(Some(empty_cg_chunk()))),
+            SectionEnd(_) | End => Ok(Async::Ready(Some(builder.build_empty()?))),
         }
     }
 }
 
-/// Produce an empty changegroup chunk.
-///
-/// Note that this is distinct from Chunk::empty() -- this is an actual chunk
-/// with a 4-byte payload.
-fn empty_cg_chunk() -> Chunk {
-    Chunk::new(vec![0, 0, 0, 0]).expect("Chunk::new should not fail for a 4-byte chunk")
-}
-
 #[derive(Debug)]
 struct ChunkBuilder {
     inner: Vec<u8>,
@@ -82,44 +109,95 @@ impl ChunkBuilder {
         }
     }
 
+    /// Encode an empty changegroup chunk, which ends a section or the whole changegroup. This
+    /// should happen before anything else is encoded.
+    ///
+    /// Note that this is distinct from Chunk::empty() -- this is an actual chunk
+    /// with a 4-byte payload.
+    pub fn encode_empty(&mut self) -> &mut Self {
+        assert_eq!(
+            self.inner.len(),
+            self.len_offset + 4,
+            "encode_empty must only be called at the start"
+        );
+        // The length of an empty chunk is 0 rather than 4, so leave it as it is and reserve
+        // four more bytes for whatever comes next.
+        self.len_offset = self.inner.len();
+        self.inner.put_slice(&[0, 0, 0, 0]);
+        self
+    }
+
     /// Encode the beginning of a section. This should always happen before any
     /// delta chunks are encoded.
-    pub fn encode_section(&mut self, section: &Section) -> Result<&mut Self> {
+    pub fn encode_section(&mut self, section: &Section, version: CgVersion) -> Result<&mut Self> {
         assert_eq!(
             self.inner.len(),
-            4,
+            self.len_offset + 4,
             "encode_section must only be called once at the start"
         );
         // Changeset and manifest sections are implicitly encoded, so we don't
         // need to do anything there.
-        // TODO: will need to encode tree manifests here as well
-        if let &Section::Filelog(ref f) = section {
-            let f_vec = f.to_vec();
-            if f_vec.len() == 0 {
-                bail!(ErrorKind::Cg2Encode(
-                    "attempted to encode a zero-length path".into()
-                ));
+        let path = match section {
+            &Section::Changeset | &Section::Manifest => return Ok(self),
+            &Section::Treemanifest(ref dir) => {
+                if version != CgVersion::Cg3 {
+                    bail!(ErrorKind::Cg2Encode(format!(
+                        "tree manifests can't be encoded in changegroup version {}",
+                        version.to_param()
+                    )));
+                }
+                // Directory names are told apart from file names by their trailing slash.
+                let mut path = dir.to_vec();
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+                path
             }
-            // Note that the filename length must include the four bytes for itself.
-            BigEndian::write_i32(&mut self.inner[0..], (f_vec.len() + 4) as i32);
-            self.inner.put_slice(f_vec.as_slice());
-            // Add four more bytes for the start of the section.
-            self.len_offset = self.inner.len();
-            self.inner.put_slice(&[0, 0, 0, 0]);
+            &Section::Filelog(ref f) => f.to_vec(),
+        };
+        if path.len() == 0 {
+            bail!(ErrorKind::Cg2Encode(
+                "attempted to encode a zero-length path".into()
+            ));
         }
+        // Note that the filename length must include the four bytes for itself.
+        BigEndian::write_i32(&mut self.inner[self.len_offset..], (path.len() + 4) as i32);
+        self.inner.put_slice(path.as_slice());
+        // Add four more bytes for the start of the section.
+        self.len_offset = self.inner.len();
+        self.inner.put_slice(&[0, 0, 0, 0]);
         Ok(self)
     }
 
-    pub fn encode_delta_chunk(&mut self, chunk: CgDeltaChunk) -> &mut Self {
+    pub fn encode_delta_chunk(
+        &mut self,
+        chunk: CgDeltaChunk,
+        version: CgVersion,
+    ) -> Result<&mut Self> {
+        if version != CgVersion::Cg3 && !chunk.flags.is_empty() {
+            bail!(ErrorKind::Cg2Encode(format!(
+                "revision {} has flags {:?}, which can't be encoded in changegroup version {}",
+                chunk.node,
+                chunk.flags,
+                version.to_param()
+            )));
+        }
+
         self.inner.put_slice(chunk.node.as_ref());
         self.inner.put_slice(chunk.p1.as_ref());
         self.inner.put_slice(chunk.p2.as_ref());
-        self.inner.put_slice(chunk.base.as_ref());
+        // Version 01 delta bases are implicit
+        if version != CgVersion::Cg1 {
+            self.inner.put_slice(chunk.base.as_ref());
+        }
         self.inner.put_slice(chunk.linknode.as_ref());
+        if version == CgVersion::Cg3 {
+            self.inner.put_u16::<BigEndian>(chunk.flags.bits());
+        }
 
         delta::encode_delta(&chunk.delta, &mut self.inner);
 
-        self
+        Ok(self)
     }
 
     pub fn build(self) -> Result<Chunk> {
@@ -128,20 +206,96 @@ impl ChunkBuilder {
         BigEndian::write_i32(&mut inner[self.len_offset..], len as i32);
         Chunk::new(inner)
     }
+
+    /// Build a chunk which ends with an empty changegroup chunk instead of a delta chunk.
+    pub fn build_empty(self) -> Result<Chunk> {
+        assert_eq!(
+            self.inner.len(),
+            self.len_offset + 4,
+            "build_empty must not be called after encoding a section or delta chunk"
+        );
+        // The four bytes reserved for the length are already 0.
+        Chunk::new(self.inner)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use mercurial_types::MPath;
+    use changegroup::RevFlags;
+    use mercurial_types::{Delta, MPath, NULL_HASH};
 
     #[test]
     fn test_empty_filelog_path() {
         let mut builder = ChunkBuilder::new();
         let section = Section::Filelog(MPath::new("").unwrap());
         assert_matches!(
-            builder.encode_section(&section),
+            builder.encode_section(&section, CgVersion::Cg2),
+            Err(Error(ErrorKind::Cg2Encode(_), _))
+        );
+    }
+
+    fn null_chunk(flags: RevFlags) -> CgDeltaChunk {
+        CgDeltaChunk {
+            node: NULL_HASH,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: NULL_HASH,
+            flags: flags,
+            delta: Delta::new_fulltext(b"abc".to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_cg1_implicit_base() {
+        let chunk = null_chunk(RevFlags::empty());
+
+        let mut cg1 = ChunkBuilder::new();
+        cg1.encode_delta_chunk(chunk.clone(), CgVersion::Cg1).unwrap();
+        let mut cg2 = ChunkBuilder::new();
+        cg2.encode_delta_chunk(chunk, CgVersion::Cg2).unwrap();
+
+        // Version 01 only leaves out the 20 byte delta base
+        let cg1 = cg1.build().unwrap().into_bytes().unwrap();
+        let cg2 = cg2.build().unwrap().into_bytes().unwrap();
+        assert_eq!(cg1.len() + 20, cg2.len());
+        assert_eq!(&cg1[..4], &[0, 0, 0, (4 + 80 + 12 + 3) as u8][..]);
+    }
+
+    #[test]
+    fn test_cg3_flags() {
+        let chunk = null_chunk(RevFlags::CENSORED);
+
+        let mut cg3 = ChunkBuilder::new();
+        cg3.encode_delta_chunk(chunk.clone(), CgVersion::Cg3).unwrap();
+        let cg3 = cg3.build().unwrap().into_bytes().unwrap();
+        // The flags follow the linknode
+        assert_eq!(&cg3[..4], &[0, 0, 0, (4 + 100 + 2 + 12 + 3) as u8][..]);
+        assert_eq!(&cg3[104..106], &[0x80, 0][..]);
+
+        // Earlier versions can't transmit flags at all
+        let mut cg2 = ChunkBuilder::new();
+        assert_matches!(
+            cg2.encode_delta_chunk(chunk, CgVersion::Cg2),
             Err(Error(ErrorKind::Cg2Encode(_), _))
         );
     }
+
+    #[test]
+    fn test_treemanifest_section() {
+        let section = Section::Treemanifest(MPath::new("dir/subdir").unwrap());
+
+        let mut cg2 = ChunkBuilder::new();
+        assert_matches!(
+            cg2.encode_section(&section, CgVersion::Cg2),
+            Err(Error(ErrorKind::Cg2Encode(_), _))
+        );
+
+        let mut cg3 = ChunkBuilder::new();
+        cg3.encode_section(&section, CgVersion::Cg3).unwrap();
+        let header = cg3.build().unwrap().into_bytes().unwrap();
+        // Directory names have a trailing slash
+        assert_eq!(&header[..15], &b"\0\0\0\x0fdir/subdir/"[..]);
+    }
 }
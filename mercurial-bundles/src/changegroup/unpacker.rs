@@ -14,19 +14,23 @@ use bytes::BytesMut;
 use slog;
 use tokio_io::codec::Decoder;
 
-use mercurial_types::MPath;
+use mercurial_types::{MPath, NodeHash, NULL_HASH};
 
 use InnerPart;
 use delta;
 use errors::*;
 use utils::BytesExt;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, RevFlags, Section};
 
 #[derive(Debug)]
-pub struct Cg2Unpacker {
+pub struct CgUnpacker {
     logger: slog::Logger,
+    version: CgVersion,
     state: State,
+    // The last node in the current section, which is the delta base of the next one in version
+    // 01 changegroups
+    prev_node: Option<NodeHash>,
 }
 
 impl Part {
@@ -59,13 +63,17 @@ impl Part {
 // See the chunk header definition below for the first 100 bytes. The last 4 is
 // for the length field itself.
 const CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
+// Version 01 chunk headers don't have the base node.
+const CG1_CHUNK_HEADER_LEN: usize = CHUNK_HEADER_LEN - 20;
+// Version 03 chunk headers also have 2 bytes of flags.
+const CG3_CHUNK_HEADER_LEN: usize = CHUNK_HEADER_LEN + 2;
 
-impl Decoder for Cg2Unpacker {
+impl Decoder for CgUnpacker {
     type Item = InnerPart;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
-        match Self::decode_next(buf, self.state.take()) {
+        match Self::decode_next(buf, self.state.take(), self.version) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -74,7 +82,7 @@ impl Decoder for Cg2Unpacker {
                 self.state = state;
                 match ret {
                     None => Ok(None),
-                    Some(v) => Ok(Some(InnerPart::Cg2(v))),
+                    Some(v) => Ok(Some(InnerPart::Cg2(self.set_delta_base(v)))),
                 }
             }
         }
@@ -109,17 +117,44 @@ impl Decoder for Cg2Unpacker {
     }
 }
 
-impl Cg2Unpacker {
-    pub fn new(logger: slog::Logger) -> Self {
-        Cg2Unpacker {
+impl CgUnpacker {
+    /// Create an unpacker for changegroups of version `version`.
+    pub fn new(logger: slog::Logger, version: CgVersion) -> Self {
+        CgUnpacker {
             logger: logger,
+            version: version,
             state: State::Changeset,
+            prev_node: None,
         }
     }
 
-    fn decode_next(buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
+    /// Version 01 changegroups don't name delta bases: each delta is against the previous
+    /// chunk in its section, or the first chunk's p1. Fill those in so that `base` is always
+    /// meaningful.
+    fn set_delta_base(&mut self, part: Part) -> Part {
+        if self.version != CgVersion::Cg1 {
+            return part;
+        }
+        match part {
+            Part::CgChunk(section, mut chunk) => {
+                chunk.base = self.prev_node.unwrap_or(chunk.p1);
+                self.prev_node = Some(chunk.node);
+                Part::CgChunk(section, chunk)
+            }
+            part => {
+                self.prev_node = None;
+                part
+            }
+        }
+    }
+
+    fn decode_next(
+        buf: &mut BytesMut,
+        state: State,
+        version: CgVersion,
+    ) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf)? {
+            State::Changeset => match Self::decode_chunk(buf, version)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => Ok((
                     Some(Part::SectionEnd(Section::Changeset)),
@@ -130,32 +165,70 @@ impl Cg2Unpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf)? {
+            State::Manifest => match Self::decode_chunk(buf, version)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
-                    Ok((Some(Part::SectionEnd(Section::Manifest)), State::Filename))
+                    let next = if version == CgVersion::Cg3 {
+                        State::Dirname
+                    } else {
+                        State::Filename
+                    };
+                    Ok((Some(Part::SectionEnd(Section::Manifest)), next))
                 }
                 Some(CgChunk::Delta(chunk)) => Ok((
                     Some(Part::CgChunk(Section::Manifest, chunk)),
                     State::Manifest,
                 )),
             },
+            State::Dirname => {
+                let dirname = Self::decode_filename(buf)?;
+                match dirname {
+                    DecodeRes::None => Ok((None, State::Dirname)),
+                    DecodeRes::Some(d) => Self::decode_treemanifest_chunk(buf, d, version),
+                    // The empty chunk ending the tree manifests doesn't have a part of its own,
+                    // so carry on with the filelogs.
+                    DecodeRes::End => Self::decode_next(buf, State::Filename, version),
+                }
+            }
+            State::Treemanifest(dirname) => Self::decode_treemanifest_chunk(buf, dirname, version),
             State::Filename => {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f),
+                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f, version),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename),
+            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename, version),
             State::End => Ok((None, State::End)),
             State::Invalid => Err(ErrorKind::Cg2Decode("byte stream corrupt".into()).into()),
         }
     }
 
-    fn decode_filelog_chunk(buf: &mut BytesMut, f: MPath) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf)? {
+    fn decode_treemanifest_chunk(
+        buf: &mut BytesMut,
+        d: MPath,
+        version: CgVersion,
+    ) -> Result<(Option<Part>, State)> {
+        match Self::decode_chunk(buf, version)? {
+            None => Ok((None, State::Treemanifest(d))),
+            Some(CgChunk::Empty) => Ok((
+                Some(Part::SectionEnd(Section::Treemanifest(d))),
+                State::Dirname,
+            )),
+            Some(CgChunk::Delta(chunk)) => Ok((
+                Some(Part::CgChunk(Section::Treemanifest(d.clone()), chunk)),
+                State::Treemanifest(d),
+            )),
+        }
+    }
+
+    fn decode_filelog_chunk(
+        buf: &mut BytesMut,
+        f: MPath,
+        version: CgVersion,
+    ) -> Result<(Option<Part>, State)> {
+        match Self::decode_chunk(buf, version)? {
             None => Ok((None, State::Filelog(f))),
             Some(CgChunk::Empty) => Ok((
                 Some(Part::SectionEnd(Section::Filelog(f))),
@@ -168,7 +241,7 @@ impl Cg2Unpacker {
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+    fn decode_chunk(buf: &mut BytesMut, version: CgVersion) -> Result<Option<CgChunk>> {
         if buf.len() < 4 {
             return Ok(None);
         }
@@ -181,10 +254,15 @@ impl Cg2Unpacker {
             let _ = buf.drain_i32();
             return Ok(Some(CgChunk::Empty));
         }
-        if chunk_len < CHUNK_HEADER_LEN {
+        let header_len = match version {
+            CgVersion::Cg1 => CG1_CHUNK_HEADER_LEN,
+            CgVersion::Cg2 => CHUNK_HEADER_LEN,
+            CgVersion::Cg3 => CG3_CHUNK_HEADER_LEN,
+        };
+        if chunk_len < header_len {
             let msg = format!(
                 "invalid chunk: length >= {} required, found {}",
-                CHUNK_HEADER_LEN,
+                header_len,
                 chunk_len
             );
             bail!(ErrorKind::Cg2Decode(msg));
@@ -202,21 +280,40 @@ impl Cg2Unpacker {
         // p2: NodeHash (20 bytes) -- NULL_HASH if only 1 parent
         // base node: NodeHash (20 bytes) (new in changegroup2)
         // link node: NodeHash (20 bytes)
+        // flags: u16 (2 bytes) (new in changegroup3)
         // ---
 
         let node = buf.drain_node();
         let p1 = buf.drain_node();
         let p2 = buf.drain_node();
-        let base = buf.drain_node();
+        // Version 01 delta bases are filled in later, since they depend on the previous chunk.
+        let base = if version == CgVersion::Cg1 {
+            NULL_HASH
+        } else {
+            buf.drain_node()
+        };
         let linknode = buf.drain_node();
+        let flags = if version == CgVersion::Cg3 {
+            let bits = buf.drain_u16();
+            match RevFlags::from_bits(bits) {
+                Some(flags) => flags,
+                None => {
+                    let msg = format!("unknown flags {:#06x} for revision {}", bits, node);
+                    bail!(ErrorKind::Cg2Decode(msg));
+                }
+            }
+        } else {
+            RevFlags::empty()
+        };
 
-        let delta = delta::decode_delta(buf.split_to(chunk_len - CHUNK_HEADER_LEN))?;
+        let delta = delta::decode_delta(buf.split_to(chunk_len - header_len))?;
         return Ok(Some(CgChunk::Delta(CgDeltaChunk {
             node: node,
             p1: p1,
             p2: p2,
             base: base,
             linknode: linknode,
+            flags: flags,
             delta: delta,
         })));
     }
@@ -260,6 +357,8 @@ enum CgChunk {
 enum State {
     Changeset,
     Manifest,
+    Dirname,
+    Treemanifest(MPath),
     Filename,
     Filelog(MPath),
     End,
@@ -13,6 +13,14 @@ use part_header::PartHeader;
 #[recursion_limit = "1024"]
 error_chain! {
     errors {
+        Bundle1Decode(msg: String) {
+            description("bundle1 decode error")
+            display("{}", msg)
+        }
+        Bundle1Encode(msg: String) {
+            description("bundle1 encode error")
+            display("{}", msg)
+        }
         Bundle2Decode(msg: String) {
             description("bundle2 decode error")
                 display("{}", msg)
@@ -49,6 +57,14 @@ error_chain! {
             description("error while generating listkey part")
             display("error while generating listkey part")
         }
+        ChangegroupGeneration {
+            description("error while generating changegroup part")
+            display("error while generating changegroup part")
+        }
+        PhaseHeadsGeneration {
+            description("error while generating phase-heads part")
+            display("error while generating phase-heads part")
+        }
     }
 
     foreign_links {
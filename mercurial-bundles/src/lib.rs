@@ -10,6 +10,8 @@ extern crate ascii;
 #[macro_use]
 #[cfg(test)]
 extern crate assert_matches;
+#[macro_use]
+extern crate bitflags;
 extern crate byteorder;
 extern crate bytes;
 #[macro_use]
@@ -38,7 +40,10 @@ extern crate futures_ext;
 extern crate mercurial_types;
 #[cfg(test)]
 extern crate partial_io;
+extern crate phases;
 
+pub mod bundle1;
+pub mod bundle1_encode;
 pub mod bundle2;
 pub mod bundle2_encode;
 pub mod changegroup;
@@ -49,6 +54,7 @@ pub mod part_encode;
 mod part_header;
 mod part_inner;
 mod part_outer;
+pub mod part_types;
 #[cfg(test)]
 mod quickcheck_types;
 mod stream_start;
@@ -61,12 +67,13 @@ mod errors;
 pub use errors::*;
 mod utils;
 
-pub use bundle2_encode::Bundle2EncodeBuilder;
+pub use bundle2_encode::{Bundle2EncodeBuilder, Bundle2EncodeStream, StreamWriter};
 pub use part_header::PartHeader;
 pub use part_inner::InnerPart;
 pub use types::StreamHeader;
+pub use utils::COMPRESSION_ENGINES;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Bundle2Item {
     Start(StreamHeader),
     Header(PartHeader),
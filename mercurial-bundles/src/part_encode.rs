@@ -8,6 +8,7 @@
 
 use std::fmt::{self, Debug, Formatter};
 use std::mem;
+use std::vec;
 
 use bytes::Bytes;
 use futures::{Async, Future, Poll, Stream};
@@ -15,6 +16,7 @@ use futures::{Async, Future, Poll, Stream};
 use chunk::Chunk;
 use errors::*;
 use part_header::{PartHeader, PartHeaderBuilder};
+use parts;
 
 /// Represents a stream of chunks produced by the individual part handler.
 pub struct ChunkStream(Box<Stream<Item = Chunk, Error = Error> + Send>);
@@ -47,6 +49,10 @@ enum GenerationState {
     NotStarted(PartHeader, PartEncodeData),
     Fixed(Chunk),
     Generating(ChunkStream),
+    // Generating the payload failed, and it's being interrupted by an error part
+    Interrupting(vec::IntoIter<Chunk>, Error),
+    // The payload was interrupted, and this error is waiting to be reported
+    Failed(Error),
     EmptyChunk,
     Done,
     Invalid,
@@ -141,6 +147,31 @@ impl Stream for PartEncode {
 }
 
 impl PartEncode {
+    /// If generating the payload failed, get the error. The part will have been ended with an
+    /// interrupting `error:abort` part, so that the client reports the error rather than seeing
+    /// an unexpected end of the bundle2, but nothing can usefully be sent after it.
+    pub fn take_error(&mut self) -> Option<Error> {
+        match self.state.take() {
+            GenerationState::Failed(err) => {
+                self.state = GenerationState::Done;
+                Some(err)
+            }
+            state => {
+                self.state = state;
+                None
+            }
+        }
+    }
+
+    // The chunks which follow the interrupt flag when generating the payload fails: an
+    // `error:abort` part with no payload, then an empty chunk to end the interrupted payload.
+    // This is what Mercurial sends when it fails part way through a part.
+    fn interrupt_chunks(err: &Error) -> Result<Vec<Chunk>> {
+        let message: Vec<_> = err.iter().map(|err| err.to_string()).collect();
+        let part = parts::error_abort_part(&message.join(": "), None)?;
+        Ok(vec![part.headerb.build(0).encode(), Chunk::empty(), Chunk::empty()])
+    }
+
     fn poll_next(state: GenerationState) -> (Poll<Option<Chunk>, Error>, GenerationState) {
         // An individual part has three sections:
         // (1) a header (1 chunk)
@@ -151,6 +182,8 @@ impl PartEncode {
         // NotStarted = header not output yet
         // Generating = payload currently being generated by inner stream
         // Fixed = fixed-length payload (no generation, just one chunk)
+        // Interrupting = error part being sent after the payload failed
+        // Failed = payload failed, error part sent
         // EmptyChunk = end of payload (or no payload)
         // Done = chunk completed
         // Invalid = some sort of error occured
@@ -160,7 +193,13 @@ impl PartEncode {
             NotStarted(header, data) => {
                 let header_chunk = header.encode();
                 let next_state = match data {
-                    PartEncodeData::Fixed(b) => Fixed(b),
+                    // An empty chunk would end the payload early, so an empty fixed payload
+                    // is the same as no payload.
+                    PartEncodeData::Fixed(b) => if b.is_empty() {
+                        EmptyChunk
+                    } else {
+                        Fixed(b)
+                    },
                     PartEncodeData::None => EmptyChunk,
                     PartEncodeData::Generated(ChunkStream(stream)) => {
                         Generating(ChunkStream(stream))
@@ -170,16 +209,30 @@ impl PartEncode {
             }
             Generating(ChunkStream(mut stream)) => {
                 match stream.poll() {
+                    Ok(Async::Ready(Some(ref v))) if v.is_empty() => {
+                        // Skip empty chunks for the same reason as above.
+                        Self::poll_next(Generating(ChunkStream(stream)))
+                    }
                     Ok(Async::Ready(Some(v))) => {
                         // TODO: don't send too large or too small chunks to clients
                         (Ok(Async::Ready(Some(v))), Generating(ChunkStream(stream)))
                     }
                     Ok(Async::Ready(None)) => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
                     Ok(Async::NotReady) => (Ok(Async::NotReady), Generating(ChunkStream(stream))),
-                    // TODO: produce an error part for (some kinds of?) errors
-                    Err(e) => (Err(e), Generating(ChunkStream(stream))),
+                    Err(e) => match Self::interrupt_chunks(&e) {
+                        Ok(chunks) => (
+                            Ok(Async::Ready(Some(Chunk::error()))),
+                            Interrupting(chunks.into_iter(), e),
+                        ),
+                        Err(_) => (Err(e), Done),
+                    },
                 }
             }
+            Interrupting(mut chunks, e) => match chunks.next() {
+                Some(chunk) => (Ok(Async::Ready(Some(chunk))), Interrupting(chunks, e)),
+                None => (Ok(Async::Ready(None)), Failed(e)),
+            },
+            Failed(e) => (Ok(Async::Ready(None)), Failed(e)),
             Fixed(chunk) => (Ok(Async::Ready(Some(chunk))), EmptyChunk),
             EmptyChunk => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
             Done => (Ok(Async::Ready(None)), Done),
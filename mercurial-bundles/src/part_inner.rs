@@ -7,29 +7,32 @@
 //! Type definitions for inner streams.
 #![deny(warnings)]
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str;
 
-use futures::{future, Stream};
+use futures::{future, Async, Poll, Stream};
 use slog;
 
 use ascii::AsciiStr;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures::stream::Map;
 use tokio_io::AsyncRead;
 
+use mercurial_types::NodeHash;
+use phases::Phase;
+
 use changegroup;
 use errors::*;
 use futures_ext::{BoxStreamWrapper, StreamExt, StreamLayeredExt, TakeWhile};
 use part_header::PartHeader;
 use part_outer::{OuterFrame, OuterStream};
+use part_types::{self, Listkeys, Obsmarker, Pushkey, ReplyPushkey};
 
 // --- Part parameters
 
 macro_rules! add_part {
     ( $m:expr, $part_type:expr, [$( $params:expr ),*] ) => {{
-        let mut h = HashSet::new();
-        $(h.insert($params);)*
+        let h: HashSet<&'static str> = [$( $params ),*].iter().cloned().collect();
         $m.insert(AsciiStr::from_ascii($part_type).unwrap(), h);
     }}
 }
@@ -38,6 +41,16 @@ lazy_static! {
     static ref KNOWN_PARAMS: HashMap<&'static AsciiStr, HashSet<&'static str>> = {
         let mut m: HashMap<&'static AsciiStr, HashSet<&'static str>> = HashMap::new();
         add_part!(m, "changegroup", ["version", "nbchanges", "treemanifest"]);
+        add_part!(m, "listkeys", ["namespace"]);
+        add_part!(m, "pushkey", ["namespace", "key", "old", "new"]);
+        add_part!(m, "bookmarks", []);
+        add_part!(m, "phase-heads", []);
+        add_part!(m, "check:heads", []);
+        add_part!(m, "check:updated-heads", []);
+        add_part!(m, "replycaps", []);
+        add_part!(m, "reply:pushkey", ["in-reply-to", "return"]);
+        add_part!(m, "obsmarkers", []);
+        add_part!(m, "output", []);
         m
     };
 }
@@ -65,21 +78,45 @@ where
 
 pub type BoxInnerStream<'a, T> = Box<InnerStream<'a, T, Item = InnerPart, Error = Error> + 'a>;
 
-#[derive(Debug, Eq, PartialEq)]
+/// The decoded contents of a part. Changegroups are decoded a piece at a time, and every other
+/// part type as a whole.
+#[derive(Clone, Debug, PartialEq)]
 pub enum InnerPart {
     Cg2(changegroup::Part),
+    Listkeys(Listkeys),
+    Pushkey(Pushkey),
+    /// Bookmark names, and the nodes they now point at. `None` means the bookmark is deleted.
+    Bookmarks(Vec<(Bytes, Option<NodeHash>)>),
+    PhaseHeads(Vec<(Phase, NodeHash)>),
+    /// The heads the pusher expects the repo to have, to detect races with other pushes
+    CheckHeads(Vec<NodeHash>),
+    /// Like `CheckHeads`, but only for the heads the push would replace
+    CheckUpdatedHeads(Vec<NodeHash>),
+    /// The bundle2 capabilities of the client, for any reply bundle
+    Replycaps(BTreeMap<String, Vec<String>>),
+    ReplyPushkey(ReplyPushkey),
+    Obsmarkers(Vec<Obsmarker>),
+    /// Output for the user from the other end
+    Output(Bytes),
 }
 
 impl InnerPart {
     pub fn is_cg2(&self) -> bool {
         match self {
             &InnerPart::Cg2(_) => true,
+            _ => false,
         }
     }
 
+    /// Gets the changegroup piece inside this part.
+    ///
+    /// # Panics
+    ///
+    /// When self isn't from a changegroup part.
     pub fn cg2_part(self) -> changegroup::Part {
         match self {
             InnerPart::Cg2(part) => part,
+            _ => panic!("cg2_part called on an InnerPart that isn't Cg2!"),
         }
     }
 }
@@ -100,6 +137,17 @@ pub fn validate_header(header: PartHeader) -> Result<Option<PartHeader>> {
                     unknown_params
                 ));
             }
+            if header.part_type_lower().as_str() == "changegroup" {
+                if let Some(version) = header.mparams().get("version") {
+                    let supported = str::from_utf8(version)
+                        .map(|version| changegroup::SUPPORTED_VERSIONS.contains(&version))
+                        .unwrap_or(false);
+                    if !supported {
+                        let msg = format!("unsupported changegroup version {:?}", version);
+                        bail!(ErrorKind::Cg2Decode(msg));
+                    }
+                }
+            }
             Ok(Some(header))
         }
         None => {
@@ -124,12 +172,82 @@ pub fn inner_stream<'a, R: AsyncRead + 'a>(
         .map(OuterFrame::get_payload as fn(OuterFrame) -> Bytes);
     match header.part_type_lower().as_str() {
         "changegroup" => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
+            // validate_header has already rejected versions that aren't supported.
+            let version = header
+                .mparams()
+                .get("version")
+                .and_then(|version| changegroup::CgVersion::from_param(version))
+                .unwrap_or(changegroup::CgVersion::Cg2);
+            let cg_stream = wrapped_stream.decode(changegroup::unpacker::CgUnpacker::new(
+                logger.new(o!("stream" => "changegroup")),
+                version,
             ));
-            Box::new(cg2_stream)
+            Box::new(cg_stream)
+        }
+        // Every other known part type is decoded as a whole
+        _ => Box::new(WholePart::new(header.clone(), wrapped_stream)),
+    }
+}
+
+/// A stream which collects the whole payload of a part, and then decodes it into a single
+/// `InnerPart`.
+struct WholePart<S> {
+    header: PartHeader,
+    input: S,
+    buf: BytesMut,
+    done: bool,
+}
+
+impl<S> WholePart<S> {
+    fn new(header: PartHeader, input: S) -> Self {
+        WholePart {
+            header: header,
+            input: input,
+            buf: BytesMut::new(),
+            done: false,
         }
-        _ => panic!("TODO: make this an error"),
+    }
+}
+
+impl<S> Stream for WholePart<S>
+where
+    S: Stream<Item = Bytes, Error = Error>,
+{
+    type Item = InnerPart;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<InnerPart>, Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        loop {
+            match try_ready!(self.input.poll()) {
+                Some(bytes) => self.buf.extend_from_slice(&bytes),
+                None => {
+                    self.done = true;
+                    let payload = self.buf.take().freeze();
+                    let part = part_types::decode_part(&self.header, payload)?;
+                    return Ok(Async::Ready(Some(part)));
+                }
+            }
+        }
+    }
+}
+
+impl<S> BoxStreamWrapper<S> for WholePart<S>
+where
+    S: Stream<Item = Bytes, Error = Error>,
+{
+    fn get_ref(&self) -> &S {
+        &self.input
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        &mut self.input
+    }
+
+    fn into_inner(self: Box<Self>) -> S {
+        self.input
     }
 }
 
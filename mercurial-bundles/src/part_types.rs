@@ -0,0 +1,526 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Typed contents of the bundle2 parts other than changegroups.
+//!
+//! These parts are all small, so each one is decoded in one go once its whole payload has
+//! arrived. The matching encoders are in `parts`.
+
+use std::collections::BTreeMap;
+use std::str;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use bytes::Bytes;
+use url::percent_encoding;
+
+use mercurial_types::{percent_encode, NodeHash, NULL_HASH};
+use phases::Phase;
+
+use errors::*;
+use part_header::PartHeader;
+use part_inner::InnerPart;
+
+/// The keys in a pushkey namespace, as sent in a `listkeys` part.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Listkeys {
+    pub namespace: String,
+    pub keys: BTreeMap<Bytes, Bytes>,
+}
+
+/// An update to a key in a pushkey namespace, such as a bookmark. Part parameters can't be
+/// empty, so an empty old or new value (which is how Mercurial says there isn't one) is `None`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pushkey {
+    pub namespace: String,
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// The result of the `pushkey` part `in_reply_to`. A nonzero `ret` means the update succeeded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplyPushkey {
+    pub in_reply_to: u32,
+    pub ret: i64,
+}
+
+/// An obsolescence marker, recording that `predecessor` was rewritten into `successors`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Obsmarker {
+    pub predecessor: NodeHash,
+    /// No successors means the predecessor was pruned.
+    pub successors: Vec<NodeHash>,
+    /// The parents of the predecessor, if they were recorded.
+    pub parents: Option<Vec<NodeHash>>,
+    /// When the marker was created, in seconds since the epoch.
+    pub time: f64,
+    /// The timezone the marker was created in, in seconds west of UTC. Markers can only store
+    /// whole minutes.
+    pub tz: i32,
+    pub flags: u16,
+    pub metadata: Vec<(String, String)>,
+}
+
+// The only obsolescence marker format that's supported
+const OBSMARKERS_VERSION: u8 = 1;
+// Fixed-size start of a version 1 marker: total size, time, timezone, flags, the number of
+// successors, parents and metadata entries, and the predecessor
+const OBSMARKER_FIXED_LEN: usize = 4 + 8 + 2 + 2 + 1 + 1 + 1 + 20;
+// The number of parents recorded for markers which don't record any
+const OBSMARKER_NO_PARENTS: u8 = 3;
+// Markers with this flag use 32 byte hashes, which aren't supported
+const OBSMARKER_SHA256: u16 = 1 << 1;
+
+/// Decode the part with header `header` from its whole payload.
+pub fn decode_part(header: &PartHeader, payload: Bytes) -> Result<InnerPart> {
+    let part_type = header.part_type_lower().as_str();
+    decode_part_inner(part_type, header, payload)
+        .chain_err(|| ErrorKind::Bundle2Decode(format!("invalid {} part", part_type)))
+}
+
+fn decode_part_inner(part_type: &str, header: &PartHeader, payload: Bytes) -> Result<InnerPart> {
+    let part = match part_type {
+        "listkeys" => InnerPart::Listkeys(Listkeys {
+            namespace: required_param(header, "namespace")?,
+            keys: decode_listkeys(&payload)?,
+        }),
+        "pushkey" => InnerPart::Pushkey(Pushkey {
+            namespace: required_param(header, "namespace")?,
+            key: required_param(header, "key")?,
+            old: param(header, "old")?,
+            new: param(header, "new")?,
+        }),
+        "reply:pushkey" => InnerPart::ReplyPushkey(ReplyPushkey {
+            in_reply_to: parse_param(header, "in-reply-to")?,
+            ret: parse_param(header, "return")?,
+        }),
+        "bookmarks" => InnerPart::Bookmarks(decode_bookmarks(payload)?),
+        "phase-heads" => InnerPart::PhaseHeads(decode_phase_heads(payload)?),
+        "check:heads" => InnerPart::CheckHeads(decode_nodes(payload)?),
+        "check:updated-heads" => InnerPart::CheckUpdatedHeads(decode_nodes(payload)?),
+        "replycaps" => InnerPart::Replycaps(decode_caps(&payload)?),
+        "obsmarkers" => InnerPart::Obsmarkers(decode_obsmarkers(payload)?),
+        "output" => InnerPart::Output(payload),
+        _ => bail!("unknown part type"),
+    };
+    Ok(part)
+}
+
+fn param(header: &PartHeader, key: &str) -> Result<Option<String>> {
+    let value = header.mparams().get(key).or(header.aparams().get(key));
+    match value {
+        Some(value) => {
+            let value = str::from_utf8(value)
+                .chain_err(|| format!("param '{}' is invalid UTF-8", key))?;
+            Ok(Some(value.to_string()))
+        }
+        None => Ok(None),
+    }
+}
+
+fn required_param(header: &PartHeader, key: &str) -> Result<String> {
+    match param(header, key)? {
+        Some(value) => Ok(value),
+        None => bail!("missing param '{}'", key),
+    }
+}
+
+fn parse_param<T: str::FromStr>(header: &PartHeader, key: &str) -> Result<T> {
+    let value = required_param(header, key)?;
+    match value.parse() {
+        Ok(value) => Ok(value),
+        Err(_) => bail!("invalid value '{}' for param '{}'", value, key),
+    }
+}
+
+fn decode_node(bytes: &[u8]) -> Result<NodeHash> {
+    NodeHash::from_bytes(bytes).chain_err(|| "invalid node")
+}
+
+fn decode_listkeys(payload: &[u8]) -> Result<BTreeMap<Bytes, Bytes>> {
+    let mut keys = BTreeMap::new();
+    for line in payload.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+        let mut key_value = line.splitn(2, |c| *c == b'\t');
+        let key = key_value.next().expect("splitn always returns something");
+        let value = match key_value.next() {
+            Some(value) => value,
+            None => bail!("listkeys entry without a value"),
+        };
+        keys.insert(Bytes::from(key), Bytes::from(value));
+    }
+    Ok(keys)
+}
+
+/// Encode the payload of a `listkeys` part.
+pub fn encode_listkeys(keys: &BTreeMap<Bytes, Bytes>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in keys {
+        ::parts::encode_listkey(&mut out, key, value);
+    }
+    out
+}
+
+// Bookmarks are a node followed by the length of the name and the name itself. A null node
+// means that the bookmark is deleted.
+fn decode_bookmarks(mut payload: Bytes) -> Result<Vec<(Bytes, Option<NodeHash>)>> {
+    let mut bookmarks = Vec::new();
+    while !payload.is_empty() {
+        if payload.len() < 22 {
+            bail!("truncated bookmark entry");
+        }
+        let node = decode_node(&payload.split_to(20))?;
+        let len = BigEndian::read_u16(&payload.split_to(2)) as usize;
+        if payload.len() < len {
+            bail!("truncated bookmark name");
+        }
+        let name = payload.split_to(len);
+        let node = if node == NULL_HASH { None } else { Some(node) };
+        bookmarks.push((name, node));
+    }
+    Ok(bookmarks)
+}
+
+/// Encode the payload of a `bookmarks` part.
+pub fn encode_bookmarks(bookmarks: &[(Bytes, Option<NodeHash>)]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for &(ref name, ref node) in bookmarks {
+        if name.len() > u16::max_value() as usize {
+            bail!("bookmark name of length {} is too long", name.len());
+        }
+        out.extend_from_slice(node.as_ref().unwrap_or(&NULL_HASH).as_ref());
+        out.write_u16::<BigEndian>(name.len() as u16).expect("write to vec failed");
+        out.extend_from_slice(name);
+    }
+    Ok(out)
+}
+
+fn decode_phase_heads(mut payload: Bytes) -> Result<Vec<(Phase, NodeHash)>> {
+    if payload.len() % 24 != 0 {
+        bail!("payload length {} isn't a multiple of 24", payload.len());
+    }
+    let mut heads = Vec::with_capacity(payload.len() / 24);
+    while !payload.is_empty() {
+        let phase = BigEndian::read_u32(&payload.split_to(4));
+        let phase = match Phase::from_hg(phase) {
+            Some(phase) => phase,
+            None => bail!("unknown phase {}", phase),
+        };
+        heads.push((phase, decode_node(&payload.split_to(20))?));
+    }
+    Ok(heads)
+}
+
+/// Encode the payload of a `phase-heads` part. Mercurial groups the heads by phase, so
+/// they should already be sorted.
+pub fn encode_phase_heads(heads: &[(Phase, NodeHash)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(heads.len() * 24);
+    for &(phase, ref node) in heads {
+        out.write_u32::<BigEndian>(phase.to_hg()).expect("write to vec failed");
+        out.extend_from_slice(node.as_ref());
+    }
+    out
+}
+
+fn decode_nodes(mut payload: Bytes) -> Result<Vec<NodeHash>> {
+    if payload.len() % 20 != 0 {
+        bail!("payload length {} isn't a multiple of 20", payload.len());
+    }
+    let mut nodes = Vec::with_capacity(payload.len() / 20);
+    while !payload.is_empty() {
+        nodes.push(decode_node(&payload.split_to(20))?);
+    }
+    Ok(nodes)
+}
+
+/// Encode the payload of a `check:heads` or `check:updated-heads` part.
+pub fn encode_nodes(nodes: &[NodeHash]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * 20);
+    for node in nodes {
+        out.extend_from_slice(node.as_ref());
+    }
+    out
+}
+
+// Capabilities are one per line, with their values after a '=' and separated by ','. Names and
+// values are percent-encoded.
+fn decode_caps(payload: &[u8]) -> Result<BTreeMap<String, Vec<String>>> {
+    let decode = |bytes: &[u8]| -> Result<String> {
+        let decoded = percent_encoding::percent_decode(bytes)
+            .decode_utf8()
+            .chain_err(|| "capability is invalid UTF-8")?;
+        Ok(decoded.into_owned())
+    };
+
+    let mut caps = BTreeMap::new();
+    for line in payload.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+        let mut key_values = line.splitn(2, |c| *c == b'=');
+        let key = decode(key_values.next().expect("splitn always returns something"))?;
+        let values = match key_values.next() {
+            Some(values) => values
+                .split(|c| *c == b',')
+                .map(|value| decode(value))
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        caps.insert(key, values);
+    }
+    Ok(caps)
+}
+
+/// Decode the bundle2 capabilities in a `getbundle` command's `bundlecaps`, where clients which
+/// accept bundle2 list them as a `bundle2=` entry holding a percent-encoded `replycaps` payload.
+pub fn decode_bundlecaps(bundlecaps: &[Vec<u8>]) -> Result<Option<BTreeMap<String, Vec<String>>>> {
+    const PREFIX: &[u8] = b"bundle2=";
+
+    match bundlecaps.iter().find(|cap| cap.starts_with(PREFIX)) {
+        Some(cap) => {
+            let payload: Vec<u8> = percent_encoding::percent_decode(&cap[PREFIX.len()..]).collect();
+            decode_caps(&payload).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Encode the payload of a `replycaps` part.
+pub fn encode_caps(caps: &BTreeMap<String, Vec<String>>) -> Vec<u8> {
+    let lines: Vec<_> = caps.iter()
+        .map(|(key, values)| {
+            let key = percent_encode(key);
+            if values.is_empty() {
+                key
+            } else {
+                let values: Vec<_> = values.iter().map(|value| percent_encode(value)).collect();
+                format!("{}={}", key, values.join(","))
+            }
+        })
+        .collect();
+    lines.join("\n").into_bytes()
+}
+
+fn decode_obsmarkers(mut payload: Bytes) -> Result<Vec<Obsmarker>> {
+    if payload.is_empty() {
+        bail!("missing obsolescence marker format version");
+    }
+    let version = payload.split_to(1)[0];
+    if version != OBSMARKERS_VERSION {
+        bail!("unsupported obsolescence marker format version {}", version);
+    }
+
+    let mut markers = Vec::new();
+    while !payload.is_empty() {
+        if payload.len() < OBSMARKER_FIXED_LEN {
+            bail!("truncated obsolescence marker");
+        }
+        let len = BigEndian::read_u32(&payload) as usize;
+        if len < OBSMARKER_FIXED_LEN || payload.len() < len {
+            bail!("invalid obsolescence marker length {}", len);
+        }
+        markers.push(decode_obsmarker(payload.split_to(len))?);
+    }
+    Ok(markers)
+}
+
+fn decode_obsmarker(mut marker: Bytes) -> Result<Obsmarker> {
+    let fixed = marker.split_to(OBSMARKER_FIXED_LEN);
+    let time = BigEndian::read_f64(&fixed[4..12]);
+    let tz = BigEndian::read_i16(&fixed[12..14]) as i32 * 60;
+    let flags = BigEndian::read_u16(&fixed[14..16]);
+    let nsuccessors = fixed[16] as usize;
+    let nparents = fixed[17];
+    let nmetadata = fixed[18] as usize;
+    let predecessor = decode_node(&fixed[19..])?;
+
+    if flags & OBSMARKER_SHA256 != 0 {
+        bail!("obsolescence markers with SHA-256 hashes aren't supported");
+    }
+
+    let take = |marker: &mut Bytes, len: usize| -> Result<Bytes> {
+        if marker.len() < len {
+            bail!("truncated obsolescence marker");
+        }
+        Ok(marker.split_to(len))
+    };
+
+    let successors = decode_nodes(take(&mut marker, nsuccessors * 20)?)?;
+    let parents = if nparents == OBSMARKER_NO_PARENTS {
+        None
+    } else if nparents > 2 {
+        bail!("obsolescence marker has {} parents", nparents);
+    } else {
+        Some(decode_nodes(take(&mut marker, nparents as usize * 20)?)?)
+    };
+
+    let sizes = take(&mut marker, nmetadata * 2)?;
+    let mut metadata = Vec::with_capacity(nmetadata);
+    for size in sizes.chunks(2) {
+        let key = take(&mut marker, size[0] as usize)?;
+        let value = take(&mut marker, size[1] as usize)?;
+        let key = str::from_utf8(&key).chain_err(|| "metadata key is invalid UTF-8")?;
+        let value = str::from_utf8(&value).chain_err(|| "metadata value is invalid UTF-8")?;
+        metadata.push((key.to_string(), value.to_string()));
+    }
+
+    if !marker.is_empty() {
+        bail!("{} trailing bytes in obsolescence marker", marker.len());
+    }
+
+    Ok(Obsmarker {
+        predecessor,
+        successors,
+        parents,
+        time,
+        tz,
+        flags,
+        metadata,
+    })
+}
+
+/// Encode the payload of an `obsmarkers` part, using version 1 of the marker format.
+pub fn encode_obsmarkers(markers: &[Obsmarker]) -> Result<Vec<u8>> {
+    let mut out = vec![OBSMARKERS_VERSION];
+    for marker in markers {
+        encode_obsmarker(&mut out, marker)?;
+    }
+    Ok(out)
+}
+
+fn encode_obsmarker(out: &mut Vec<u8>, marker: &Obsmarker) -> Result<()> {
+    let max = u8::max_value() as usize;
+
+    if marker.flags & OBSMARKER_SHA256 != 0 {
+        bail!("obsolescence markers with SHA-256 hashes aren't supported");
+    }
+    if marker.tz % 60 != 0 || marker.tz / 60 > i16::max_value() as i32
+        || marker.tz / 60 < i16::min_value() as i32
+    {
+        bail!("timezone offset {} can't be stored", marker.tz);
+    }
+    if marker.successors.len() > max || marker.metadata.len() > max {
+        bail!("too many successors or metadata entries");
+    }
+    let nparents = match marker.parents {
+        Some(ref parents) if parents.len() > 2 => {
+            bail!("obsolescence marker has {} parents", parents.len())
+        }
+        Some(ref parents) => parents.len() as u8,
+        None => OBSMARKER_NO_PARENTS,
+    };
+    let parents: &[NodeHash] = match marker.parents {
+        Some(ref parents) => parents.as_slice(),
+        None => &[],
+    };
+
+    let mut len = OBSMARKER_FIXED_LEN + (marker.successors.len() + parents.len()) * 20;
+    for &(ref key, ref value) in &marker.metadata {
+        if key.len() > max || value.len() > max {
+            bail!("metadata entry '{}' is too long", key);
+        }
+        len += 2 + key.len() + value.len();
+    }
+
+    out.write_u32::<BigEndian>(len as u32).expect("write to vec failed");
+    out.write_f64::<BigEndian>(marker.time).expect("write to vec failed");
+    out.write_i16::<BigEndian>((marker.tz / 60) as i16).expect("write to vec failed");
+    out.write_u16::<BigEndian>(marker.flags).expect("write to vec failed");
+    out.push(marker.successors.len() as u8);
+    out.push(nparents);
+    out.push(marker.metadata.len() as u8);
+    out.extend_from_slice(marker.predecessor.as_ref());
+    for node in marker.successors.iter().chain(parents.iter()) {
+        out.extend_from_slice(node.as_ref());
+    }
+    for &(ref key, ref value) in &marker.metadata {
+        out.push(key.len() as u8);
+        out.push(value.len() as u8);
+    }
+    for &(ref key, ref value) in &marker.metadata {
+        out.extend_from_slice(key.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(byte: u8) -> NodeHash {
+        NodeHash::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    #[test]
+    fn test_decode_bookmarks() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(node(1).as_ref());
+        payload.extend_from_slice(b"\x00\x06master");
+        payload.extend_from_slice(NULL_HASH.as_ref());
+        payload.extend_from_slice(b"\x00\x03old");
+
+        let bookmarks = decode_bookmarks(Bytes::from(payload)).unwrap();
+        assert_eq!(
+            bookmarks,
+            vec![
+                (Bytes::from(&b"master"[..]), Some(node(1))),
+                (Bytes::from(&b"old"[..]), None),
+            ]
+        );
+
+        assert!(decode_bookmarks(Bytes::from(&b"\x00\x06master"[..])).is_err());
+    }
+
+    #[test]
+    fn test_decode_caps() {
+        let caps = decode_caps(b"HG20\nchangegroup=01,02\nerror=abort,push%2Craced").unwrap();
+        let mut expected = BTreeMap::new();
+        expected.insert("HG20".to_string(), vec![]);
+        expected.insert(
+            "changegroup".to_string(),
+            vec!["01".to_string(), "02".to_string()],
+        );
+        expected.insert(
+            "error".to_string(),
+            vec!["abort".to_string(), "push,raced".to_string()],
+        );
+        assert_eq!(caps, expected);
+    }
+
+    #[test]
+    fn test_decode_bundlecaps() {
+        let bundlecaps = vec![
+            b"HG20".to_vec(),
+            b"bundle2=HG20%0Achangegroup%3D01%2C02".to_vec(),
+        ];
+        let caps = decode_bundlecaps(&bundlecaps).unwrap().unwrap();
+        assert_eq!(
+            caps.get("changegroup"),
+            Some(&vec!["01".to_string(), "02".to_string()])
+        );
+
+        assert_eq!(decode_bundlecaps(&[b"HG20".to_vec()]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_obsmarker_len() {
+        let marker = Obsmarker {
+            predecessor: node(1),
+            successors: vec![node(2)],
+            parents: None,
+            time: 0.0,
+            tz: -3600,
+            flags: 0,
+            metadata: vec![("user".to_string(), "test".to_string())],
+        };
+        let payload = encode_obsmarkers(&[marker.clone()]).unwrap();
+
+        // The version, then the fixed part, one successor and one metadata entry
+        assert_eq!(payload.len(), 1 + 39 + 20 + 2 + 8);
+        assert_eq!(&payload[1..5], &[0, 0, 0, 39 + 20 + 2 + 8][..]);
+        assert_eq!(
+            decode_obsmarkers(Bytes::from(payload)).unwrap(),
+            vec![marker]
+        );
+    }
+}
@@ -4,11 +4,19 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::BTreeMap;
+
 use bytes::Bytes;
 use futures::{Future, Stream};
 
+use mercurial_types::NodeHash;
+use phases::Phase;
+
+use changegroup::{self, CgVersion};
+use changegroup::packer::CgPacker;
 use errors::*;
 use part_encode::PartEncodeBuilder;
+use part_types::{self, Obsmarker, Pushkey};
 
 pub fn listkey_part<N, S, K, V>(namespace: N, items: S) -> Result<PartEncodeBuilder>
 where
@@ -24,10 +32,7 @@ where
     let payload = Vec::with_capacity(256);
     let fut = items
         .fold(payload, |mut payload, (key, value)| {
-            payload.extend_from_slice(key.as_ref());
-            payload.push(b'\t');
-            payload.extend_from_slice(value.as_ref());
-            payload.push(b'\n');
+            encode_listkey(&mut payload, key, value);
             Ok(payload)
         })
         .or_else(|err| Err(err).chain_err(|| ErrorKind::ListkeyGeneration));
@@ -36,3 +41,302 @@ where
 
     Ok(builder)
 }
+
+/// Append a single listkeys entry to `out`. The same encoding is used by the `listkeys` wire
+/// command and bundle2 part.
+pub fn encode_listkey<K, V>(out: &mut Vec<u8>, key: K, value: V)
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    out.extend_from_slice(key.as_ref());
+    out.push(b'\t');
+    out.extend_from_slice(value.as_ref());
+    out.push(b'\n');
+}
+
+/// Tell the client the phases of the changesets it's getting, as a list of heads for each phase.
+/// Every ancestor of a public head is public too.
+pub fn phase_heads_part<S>(heads: S) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = (Phase, NodeHash)> + Send + 'static,
+    S::Error: ::std::error::Error + Send,
+{
+    let mut builder = PartEncodeBuilder::mandatory("phase-heads")?;
+    let fut = heads
+        .collect()
+        .map(|mut heads| {
+            // Mercurial groups heads by phase
+            heads.sort_by_key(|&(phase, node)| (phase.to_hg(), node));
+            part_types::encode_phase_heads(&heads)
+        })
+        .or_else(|err| Err(err).chain_err(|| ErrorKind::PhaseHeadsGeneration));
+
+    builder.set_data_future(fut);
+
+    Ok(builder)
+}
+
+pub fn changegroup_part<S>(changelogentries: S, version: CgVersion) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = changegroup::Part> + Send + 'static,
+    S::Error: ::std::error::Error + Send,
+{
+    let mut builder = PartEncodeBuilder::mandatory("changegroup")?;
+    builder.add_mparam("version", version.to_param())?;
+
+    let changelogentries =
+        changelogentries.or_else(|err| Err(err).chain_err(|| ErrorKind::ChangegroupGeneration));
+    builder.set_data_generated(CgPacker::new(changelogentries, version));
+
+    Ok(builder)
+}
+
+/// Acknowledge that the changegroup in part `in_reply_to` has been applied. `result` is the
+/// value Mercurial's `addchangegroup` would have returned for it.
+pub fn replychangegroup_part(in_reply_to: u32, result: i64) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory("reply:changegroup")?;
+    builder.add_aparam("in-reply-to", format!("{}", in_reply_to))?;
+    builder.add_aparam("return", format!("{}", result))?;
+
+    Ok(builder)
+}
+
+/// Ask for the key `pushkey.key` in `pushkey.namespace` to be moved from `pushkey.old` to
+/// `pushkey.new`.
+pub fn pushkey_part(pushkey: &Pushkey) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("pushkey")?;
+    builder.add_mparam("namespace", pushkey.namespace.clone())?;
+    builder.add_mparam("key", pushkey.key.clone())?;
+    if let Some(ref old) = pushkey.old {
+        builder.add_mparam("old", old.clone())?;
+    }
+    if let Some(ref new) = pushkey.new {
+        builder.add_mparam("new", new.clone())?;
+    }
+
+    Ok(builder)
+}
+
+/// Report whether the pushkey in part `in_reply_to` succeeded. `result` is nonzero if it did.
+pub fn replypushkey_part(in_reply_to: u32, result: i64) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory("reply:pushkey")?;
+    builder.add_aparam("in-reply-to", format!("{}", in_reply_to))?;
+    builder.add_aparam("return", format!("{}", result))?;
+
+    Ok(builder)
+}
+
+/// Move each bookmark in `bookmarks` to its node, or delete it if there isn't one.
+pub fn bookmarks_part(bookmarks: &[(Bytes, Option<NodeHash>)]) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("bookmarks")?;
+    builder.set_data_bytes(part_types::encode_bookmarks(bookmarks)?)?;
+
+    Ok(builder)
+}
+
+/// Make sure that the repo's heads are still `heads`, so that a push doesn't race with another
+/// one.
+pub fn check_heads_part(heads: &[NodeHash]) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("check:heads")?;
+    builder.set_data_bytes(part_types::encode_nodes(heads))?;
+
+    Ok(builder)
+}
+
+/// Like `check_heads_part`, but only for the heads that the push replaces, so that pushes to
+/// unrelated heads don't race.
+pub fn check_updated_heads_part(heads: &[NodeHash]) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("check:updated-heads")?;
+    builder.set_data_bytes(part_types::encode_nodes(heads))?;
+
+    Ok(builder)
+}
+
+/// Tell the other end which bundle2 capabilities any reply to this bundle can use.
+pub fn replycaps_part(caps: &BTreeMap<String, Vec<String>>) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("replycaps")?;
+    builder.set_data_bytes(part_types::encode_caps(caps))?;
+
+    Ok(builder)
+}
+
+pub fn obsmarkers_part(markers: &[Obsmarker]) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("obsmarkers")?;
+    builder.set_data_bytes(part_types::encode_obsmarkers(markers)?)?;
+
+    Ok(builder)
+}
+
+/// Output which Mercurial shows to the user as `remote: ` lines.
+pub fn output_part<B: Into<Bytes>>(output: B) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory("output")?;
+    builder.set_data_bytes(output)?;
+
+    Ok(builder)
+}
+
+/// Report that a request failed, which Mercurial shows to the user as an abort with `message`,
+/// followed by `hint` if there is one.
+pub fn error_abort_part(message: &str, hint: Option<&str>) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("error:abort")?;
+    builder.add_mparam("message", param_value(message))?;
+    if let Some(hint) = hint {
+        builder.add_aparam("hint", param_value(hint))?;
+    }
+
+    Ok(builder)
+}
+
+/// Report that the client sent a part of type `part_type`, or with `params`, that isn't
+/// supported.
+pub fn error_unsupportedcontent_part<S>(
+    part_type: Option<&str>,
+    params: &[S],
+) -> Result<PartEncodeBuilder>
+where
+    S: AsRef<str>,
+{
+    let mut builder = PartEncodeBuilder::mandatory("error:unsupportedcontent")?;
+    if let Some(part_type) = part_type {
+        builder.add_mparam("parttype", part_type.to_string())?;
+    }
+    if !params.is_empty() {
+        let params: Vec<_> = params.iter().map(|param| param.as_ref()).collect();
+        builder.add_mparam("params", param_value(&params.join("\0")))?;
+    }
+
+    Ok(builder)
+}
+
+/// Report that a push was rejected because the repo changed while it was in progress. Mercurial
+/// shows `message`, and suggests pulling and trying again.
+pub fn error_pushraced_part(message: &str) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("error:pushraced")?;
+    builder.add_mparam("message", param_value(message))?;
+
+    Ok(builder)
+}
+
+/// The details of a failed pushkey, any of which may be unknown.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PushkeyFailure {
+    pub namespace: Option<String>,
+    pub key: Option<String>,
+    pub new: Option<String>,
+    pub old: Option<String>,
+    /// What the pushkey returned
+    pub ret: Option<String>,
+}
+
+/// Report that the pushkey part `in_reply_to` failed.
+pub fn error_pushkey_part(
+    in_reply_to: u32,
+    failure: &PushkeyFailure,
+) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("error:pushkey")?;
+    builder.add_mparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    let params = [
+        ("namespace", &failure.namespace),
+        ("key", &failure.key),
+        ("new", &failure.new),
+        ("old", &failure.old),
+        ("ret", &failure.ret),
+    ];
+    for &(key, value) in params.iter() {
+        match value {
+            // Empty values can't be encoded, and an empty old or new value is how Mercurial
+            // says there isn't one anyway
+            &Some(ref value) if !value.is_empty() => {
+                builder.add_aparam(key, param_value(value))?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(builder)
+}
+
+// Part parameter values are limited to 255 bytes, so cut longer ones short, on a character
+// boundary
+fn param_value(value: &str) -> String {
+    let max = u8::max_value() as usize;
+    if value.len() <= max {
+        return value.to_string();
+    }
+
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value[..end].to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use part_header::{self, PartHeader};
+
+    #[test]
+    fn test_param_value() {
+        assert_eq!(param_value("short"), "short");
+
+        let long = "a".repeat(300);
+        assert_eq!(param_value(&long).len(), 255);
+
+        // Don't split the last character
+        let long = format!("{}\u{e9}", "a".repeat(254));
+        assert_eq!(param_value(&long), "a".repeat(254));
+    }
+
+    // Encode a part, and decode its header back
+    fn encoded_header(builder: PartEncodeBuilder) -> PartHeader {
+        let chunks = builder.build(0).collect().wait().unwrap();
+        let header = chunks.into_iter().next().unwrap().into_bytes().unwrap();
+        part_header::decode(header).unwrap()
+    }
+
+    #[test]
+    fn test_error_parts() {
+        let long = "x".repeat(1000);
+        let header = encoded_header(error_abort_part(&long, Some(&long)).unwrap());
+        assert_eq!(header.part_type_lower().as_str(), "error:abort");
+        assert!(header.is_mandatory());
+        assert_eq!(header.mparams()["message"].len(), 255);
+        assert_eq!(header.aparams()["hint"].len(), 255);
+
+        let header = encoded_header(error_abort_part("failed", None).unwrap());
+        assert_eq!(&header.mparams()["message"][..], b"failed");
+        assert!(header.aparams().is_empty());
+
+        let header =
+            encoded_header(error_unsupportedcontent_part(Some("foo"), &["bar", "baz"]).unwrap());
+        assert_eq!(header.part_type_lower().as_str(), "error:unsupportedcontent");
+        assert_eq!(&header.mparams()["parttype"][..], b"foo");
+        assert_eq!(&header.mparams()["params"][..], b"bar\0baz");
+
+        let header = encoded_header(error_pushraced_part("repository changed").unwrap());
+        assert_eq!(header.part_type_lower().as_str(), "error:pushraced");
+        assert_eq!(&header.mparams()["message"][..], b"repository changed");
+
+        let failure = PushkeyFailure {
+            namespace: Some("bookmarks".into()),
+            key: Some("master".into()),
+            old: Some("".into()),
+            ret: Some("0".into()),
+            ..Default::default()
+        };
+        let header = encoded_header(error_pushkey_part(1, &failure).unwrap());
+        assert_eq!(header.part_type_lower().as_str(), "error:pushkey");
+        assert!(header.is_mandatory());
+        assert_eq!(&header.mparams()["in-reply-to"][..], b"1");
+        assert_eq!(&header.aparams()["namespace"][..], b"bookmarks");
+        assert_eq!(&header.aparams()["key"][..], b"master");
+        assert_eq!(&header.aparams()["ret"][..], b"0");
+        // Missing and empty values are left out
+        assert!(!header.aparams().contains_key("old"));
+        assert!(!header.aparams().contains_key("new"));
+    }
+}
@@ -7,6 +7,7 @@
 //! Quickcheck support for a few types that don't have support upstream,
 //! and for a few other test types.
 
+use std::collections::BTreeMap;
 use std::convert::From;
 use std::iter;
 use std::result;
@@ -16,10 +17,13 @@ use bytes::Bytes;
 use futures::stream;
 use quickcheck::{empty_shrinker, Arbitrary, Gen};
 
-use mercurial_types::{Delta, MPath, NodeHash};
+use mercurial_types::{Delta, MPath, NodeHash, NULL_HASH};
+use phases::Phase;
 
 use changegroup;
 use errors::*;
+use part_inner::InnerPart;
+use part_types::{Listkeys, Obsmarker, Pushkey, ReplyPushkey};
 
 #[derive(Clone, Debug)]
 pub struct QCBytes(Bytes);
@@ -43,17 +47,25 @@ impl Arbitrary for QCBytes {
 }
 
 #[derive(Clone, Debug)]
-pub struct Cg2PartSequence {
+pub struct CgPartSequence {
+    version: changegroup::CgVersion,
     // Storing the ends in here bypasses a number of lifetime issues.
     changesets: Vec<changegroup::Part>,
     changesets_end: changegroup::Part,
     manifests: Vec<changegroup::Part>,
     manifests_end: changegroup::Part,
+    // Only version 03 changegroups have tree manifests.
+    treemanifests: Vec<(Vec<changegroup::Part>, changegroup::Part)>,
     filelogs: Vec<(Vec<changegroup::Part>, changegroup::Part)>,
     end: changegroup::Part,
 }
 
-impl Cg2PartSequence {
+impl CgPartSequence {
+    /// The changegroup version which can encode this sequence.
+    pub fn version(&self) -> changegroup::CgVersion {
+        self.version
+    }
+
     /// Combine all the changesets, manifests and filelogs into a single iterator.
     pub fn as_iter<'a>(&'a self) -> Box<Iterator<Item = &'a changegroup::Part> + 'a> {
         // Trying to describe the type here is madness. Just box it.
@@ -63,17 +75,8 @@ impl Cg2PartSequence {
                 .chain(iter::once(&self.changesets_end))
                 .chain(self.manifests.iter())
                 .chain(iter::once(&self.manifests_end))
-                .chain(
-                    self.filelogs
-                        .iter()
-                        .filter(|&&(ref parts, _)| {
-                            // If there are no filelog parts, it isn't valid to return a
-                            // SectionEnd since that won't be referring to anything. So
-                            // just skip the whole filelog.
-                            !parts.is_empty()
-                        })
-                        .flat_map(|&(ref parts, ref end)| parts.iter().chain(iter::once(end))),
-                )
+                .chain(path_sections(&self.treemanifests))
+                .chain(path_sections(&self.filelogs))
                 .chain(iter::once(&self.end)),
         )
     }
@@ -90,42 +93,58 @@ impl Cg2PartSequence {
     }
 }
 
-impl PartialEq<[changegroup::Part]> for Cg2PartSequence {
+/// Combine sections which are named by a path (tree manifests and filelogs) into a single
+/// iterator.
+fn path_sections<'a>(
+    sections: &'a [(Vec<changegroup::Part>, changegroup::Part)],
+) -> Box<Iterator<Item = &'a changegroup::Part> + 'a> {
+    Box::new(
+        sections
+            .iter()
+            .filter(|&&(ref parts, _)| {
+                // If there are no parts in a section, it isn't valid to return a
+                // SectionEnd since that won't be referring to anything. So just
+                // skip the whole section.
+                !parts.is_empty()
+            })
+            .flat_map(|&(ref parts, ref end)| parts.iter().chain(iter::once(end))),
+    )
+}
+
+impl PartialEq<[changegroup::Part]> for CgPartSequence {
     fn eq(&self, other: &[changegroup::Part]) -> bool {
         self.as_iter().eq(other.iter())
     }
 }
 
-impl Arbitrary for Cg2PartSequence {
+impl Arbitrary for CgPartSequence {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
         use changegroup::*;
 
-        // Generate a valid part sequence (changegroup, then manifest, then filelogs).
-        let size = g.size();
-
-        let changesets = gen_parts(Section::Changeset, g);
-        let manifests = gen_parts(Section::Manifest, g);
+        // Generate a valid part sequence (changegroup, then manifest, then tree manifests, then
+        // filelogs).
+        let version = match g.gen_range(0, 3) {
+            0 => CgVersion::Cg1,
+            1 => CgVersion::Cg2,
+            _ => CgVersion::Cg3,
+        };
 
-        let nfilelogs = g.gen_range(0, size);
-        let mut filelogs = Vec::with_capacity(nfilelogs);
-
-        for _ in 0..nfilelogs {
-            // Changegroups can't support empty paths, so skip over those.
-            let path = loop {
-                let path = MPath::arbitrary(g);
-                if !path.is_empty() {
-                    break path;
-                }
-            };
-            let section_end = Part::SectionEnd(Section::Filelog(path.clone()));
-            filelogs.push((gen_parts(Section::Filelog(path), g), section_end));
-        }
+        let changesets = gen_parts(Section::Changeset, version, g);
+        let manifests = gen_parts(Section::Manifest, version, g);
+        let treemanifests = if version == CgVersion::Cg3 {
+            gen_path_sections(Section::Treemanifest, version, g)
+        } else {
+            Vec::new()
+        };
+        let filelogs = gen_path_sections(Section::Filelog, version, g);
 
-        Cg2PartSequence {
+        CgPartSequence {
+            version: version,
             changesets: changesets,
             changesets_end: Part::SectionEnd(Section::Changeset),
             manifests: manifests,
             manifests_end: Part::SectionEnd(Section::Manifest),
+            treemanifests: treemanifests,
             filelogs: filelogs,
             end: Part::End,
         }
@@ -137,18 +156,31 @@ impl Arbitrary for Cg2PartSequence {
         // All the parts can be shrinked independently as long as the section
         // remains the same (ensured in the impl of Arbitrary for
         // changegroup::Part).
+        let version = self.version;
         Box::new(
             (
                 self.changesets.clone(),
                 self.manifests.clone(),
+                self.treemanifests.clone(),
                 self.filelogs.clone(),
             ).shrink()
-                .map(|(c, m, f)| {
-                    Cg2PartSequence {
+                .map(move |(mut c, mut m, t, mut f)| {
+                    if version == CgVersion::Cg1 {
+                        // Shrinking can remove chunks, which changes what the deltas of the
+                        // remaining ones are against.
+                        set_cg1_delta_bases(&mut c);
+                        set_cg1_delta_bases(&mut m);
+                        for &mut (ref mut parts, _) in f.iter_mut() {
+                            set_cg1_delta_bases(parts);
+                        }
+                    }
+                    CgPartSequence {
+                        version: version,
                         changesets: c,
                         changesets_end: Part::SectionEnd(Section::Changeset),
                         manifests: m,
                         manifests_end: Part::SectionEnd(Section::Manifest),
+                        treemanifests: t,
                         filelogs: f,
                         end: Part::End,
                     }
@@ -157,11 +189,61 @@ impl Arbitrary for Cg2PartSequence {
     }
 }
 
-fn gen_parts<G: Gen>(section: changegroup::Section, g: &mut G) -> Vec<changegroup::Part> {
+fn gen_parts<G: Gen>(
+    section: changegroup::Section,
+    version: changegroup::CgVersion,
+    g: &mut G,
+) -> Vec<changegroup::Part> {
+    let size = g.size();
+    let mut parts: Vec<_> = (0..g.gen_range(0, size))
+        .map(|_| {
+            let mut chunk = changegroup::CgDeltaChunk::arbitrary(g);
+            // Only version 03 changegroups can transmit flags.
+            if version != changegroup::CgVersion::Cg3 {
+                chunk.flags = changegroup::RevFlags::empty();
+            }
+            changegroup::Part::CgChunk(section.clone(), chunk)
+        })
+        .collect();
+    if version == changegroup::CgVersion::Cg1 {
+        set_cg1_delta_bases(&mut parts);
+    }
+    parts
+}
+
+/// Version 01 changegroups can't name delta bases, so make the chunks of a section use the ones
+/// that are implied: the previous chunk's node, or p1 for the first chunk.
+fn set_cg1_delta_bases(parts: &mut [changegroup::Part]) {
+    let mut prev = None;
+    for part in parts {
+        if let &mut changegroup::Part::CgChunk(_, ref mut chunk) = part {
+            chunk.base = prev.unwrap_or(chunk.p1);
+            prev = Some(chunk.node);
+        }
+    }
+}
+
+fn gen_path_sections<F, G>(
+    section: F,
+    version: changegroup::CgVersion,
+    g: &mut G,
+) -> Vec<(Vec<changegroup::Part>, changegroup::Part)>
+where
+    F: Fn(MPath) -> changegroup::Section,
+    G: Gen,
+{
     let size = g.size();
     (0..g.gen_range(0, size))
         .map(|_| {
-            changegroup::Part::CgChunk(section.clone(), changegroup::CgDeltaChunk::arbitrary(g))
+            // Changegroups can't support empty paths, so skip over those.
+            let path = loop {
+                let path = MPath::arbitrary(g);
+                if !path.is_empty() {
+                    break path;
+                }
+            };
+            let section_end = changegroup::Part::SectionEnd(section(path.clone()));
+            (gen_parts(section(path), version, g), section_end)
         })
         .collect()
 }
@@ -198,6 +280,7 @@ impl Arbitrary for changegroup::CgDeltaChunk {
             p2: NodeHash::arbitrary(g),
             base: NodeHash::arbitrary(g),
             linknode: NodeHash::arbitrary(g),
+            flags: changegroup::RevFlags::from_bits_truncate(g.gen()),
             delta: Delta::arbitrary(g),
         }
     }
@@ -212,8 +295,144 @@ impl Arbitrary for changegroup::CgDeltaChunk {
                 p2: clone.p2.clone(),
                 base: clone.base.clone(),
                 linknode: clone.linknode.clone(),
+                flags: clone.flags,
                 delta: delta,
             }
         }))
     }
 }
+
+impl Arbitrary for InnerPart {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        // Changegroups are covered by CgPartSequence, so only generate the other part types.
+        match g.gen_range(0, 10) {
+            0 => {
+                let keys = gen_vec(g, |g| {
+                    let key = gen_bytes(g, |c| c != b'\t' && c != b'\n');
+                    let value = gen_bytes(g, |c| c != b'\n');
+                    (key, value)
+                });
+                InnerPart::Listkeys(Listkeys {
+                    namespace: gen_param(g),
+                    keys: keys.into_iter().collect(),
+                })
+            }
+            1 => InnerPart::Pushkey(Pushkey {
+                namespace: gen_param(g),
+                key: gen_param(g),
+                old: gen_option(g, gen_param),
+                new: gen_option(g, gen_param),
+            }),
+            2 => InnerPart::Bookmarks(gen_vec(g, |g| {
+                let name = Bytes::from(Vec::<u8>::arbitrary(g));
+                // A null node is how a deleted bookmark is encoded.
+                let node = gen_option(g, NodeHash::arbitrary).and_then(|node| {
+                    if node == NULL_HASH {
+                        None
+                    } else {
+                        Some(node)
+                    }
+                });
+                (name, node)
+            })),
+            3 => {
+                let mut heads = gen_vec(g, |g| {
+                    let phase = if g.gen() { Phase::Public } else { Phase::Draft };
+                    (phase, NodeHash::arbitrary(g))
+                });
+                // Phase heads are always sent grouped by phase.
+                heads.sort_by_key(|&(phase, node)| (phase.to_hg(), node));
+                InnerPart::PhaseHeads(heads)
+            }
+            4 => InnerPart::CheckHeads(Vec::arbitrary(g)),
+            5 => InnerPart::CheckUpdatedHeads(Vec::arbitrary(g)),
+            6 => {
+                let caps = gen_vec(g, |g| (gen_param(g), Vec::arbitrary(g)));
+                InnerPart::Replycaps(caps.into_iter().collect())
+            }
+            7 => InnerPart::ReplyPushkey(ReplyPushkey {
+                in_reply_to: g.gen(),
+                ret: g.gen(),
+            }),
+            8 => InnerPart::Obsmarkers(gen_vec(g, gen_obsmarker)),
+            _ => InnerPart::Output(QCBytes::arbitrary(g).into()),
+        }
+    }
+
+    fn shrink(&self) -> Box<Iterator<Item = Self>> {
+        empty_shrinker()
+    }
+}
+
+fn gen_obsmarker<G: Gen>(g: &mut G) -> Obsmarker {
+    // Only one or two parents can be recorded.
+    let parents = gen_option(g, |g| {
+        let count = g.gen_range(0, 3);
+        (0..count).map(|_| NodeHash::arbitrary(g)).collect()
+    });
+    // Timezones are stored as a signed 16-bit number of minutes.
+    let tz = g.gen_range(i16::min_value() as i32, i16::max_value() as i32 + 1) * 60;
+    Obsmarker {
+        predecessor: NodeHash::arbitrary(g),
+        successors: gen_vec(g, NodeHash::arbitrary),
+        parents,
+        time: f64::arbitrary(g),
+        tz,
+        // Bit 1 marks markers using SHA-256 hashes, which aren't supported.
+        flags: g.gen::<u16>() & !(1 << 1),
+        metadata: gen_vec(g, |g| (gen_string(g, 255), gen_string(g, 255))),
+    }
+}
+
+fn gen_vec<G, T, F>(g: &mut G, mut f: F) -> Vec<T>
+where
+    G: Gen,
+    F: FnMut(&mut G) -> T,
+{
+    // Many of the encodings store lengths as a single byte.
+    let size = ::std::cmp::min(g.size(), 255);
+    let len = g.gen_range(0, size + 1);
+    (0..len).map(|_| f(g)).collect()
+}
+
+fn gen_option<G, T, F>(g: &mut G, f: F) -> Option<T>
+where
+    G: Gen,
+    F: FnOnce(&mut G) -> T,
+{
+    if g.gen() {
+        Some(f(g))
+    } else {
+        None
+    }
+}
+
+fn gen_bytes<G, F>(g: &mut G, f: F) -> Bytes
+where
+    G: Gen,
+    F: Fn(u8) -> bool,
+{
+    let v: Vec<u8> = Vec::arbitrary(g);
+    v.into_iter().filter(|c| f(*c)).collect::<Vec<_>>().into()
+}
+
+/// Generate a string which is at most `max_len` bytes long.
+fn gen_string<G: Gen>(g: &mut G, max_len: usize) -> String {
+    let mut len = 0;
+    String::arbitrary(g)
+        .chars()
+        .take_while(|c| {
+            len += c.len_utf8();
+            len <= max_len
+        })
+        .collect()
+}
+
+/// Generate a string which is valid as a part parameter: non-empty and at most 255 bytes long.
+fn gen_param<G: Gen>(g: &mut G) -> String {
+    let mut param = gen_string(g, 255);
+    if param.is_empty() {
+        param.push('a');
+    }
+    param
+}
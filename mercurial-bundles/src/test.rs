@@ -10,7 +10,9 @@ use std::fmt::Debug;
 use std::io::{self, Cursor};
 use std::str::FromStr;
 
-use futures::stream::Stream;
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use futures::stream::{self, Stream};
 use slog::{Drain, Logger};
 use slog_term;
 use tokio_core::reactor::Core;
@@ -20,16 +22,22 @@ use async_compression::{CompressorType, ZSTD_DEFAULT_LEVEL};
 use async_compression::membuf::MemBuf;
 use mercurial_types::{MPath, NodeHash, NULL_HASH};
 use partial_io::{GenWouldBlock, PartialAsyncRead, PartialWithErrors};
-use quickcheck::{QuickCheck, StdGen};
+use quickcheck::{QuickCheck, StdGen, TestResult};
 use rand;
 
 use Bundle2Item;
+use bundle1::Bundle1Stream;
+use bundle1_encode::encode_bundle1;
 use bundle2::Bundle2Stream;
 use bundle2_encode::Bundle2EncodeBuilder;
 use changegroup;
+use chunk::Chunk;
 use errors::*;
 use part_encode::PartEncodeBuilder;
-use part_header::PartHeaderBuilder;
+use part_header::{self, PartHeaderBuilder};
+use part_inner::InnerPart;
+use parts;
+use quickcheck_types::CgPartSequence;
 use types::StreamHeader;
 use utils::get_compression_param;
 
@@ -179,6 +187,199 @@ fn unknown_part(ct: CompressorType) {
                     if header.part_type() == "UNKNOWN:UNKNOWN");
 }
 
+#[test]
+fn test_typed_parts_roundtrip() {
+    let rng = StdGen::new(rand::thread_rng(), 20);
+    let mut quickcheck = QuickCheck::new().gen(rng).tests(50);
+    quickcheck.quickcheck(typed_parts_roundtrip as fn(Vec<InnerPart>) -> bool);
+}
+
+fn typed_parts_roundtrip(parts: Vec<InnerPart>) -> bool {
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let mut builder = Bundle2EncodeBuilder::new(cursor);
+    builder.set_compressor_type(CompressorType::Uncompressed);
+    for part in &parts {
+        builder.add_part(encode_typed_part(part));
+    }
+    let encode_fut = builder.build();
+
+    let mut core = Core::new().unwrap();
+    let mut buf = core.run(encode_fut).unwrap();
+    buf.set_position(0);
+
+    let logger = make_root_logger();
+    let stream = Bundle2Stream::new(buf, logger);
+    let decode_fut = stream
+        .filter(|item| item.is_inner())
+        .map(|item| item.inner_part())
+        .collect();
+    let decoded = core.run(decode_fut).unwrap();
+
+    decoded == parts
+}
+
+fn encode_typed_part(part: &InnerPart) -> PartEncodeBuilder {
+    let builder = match part {
+        &InnerPart::Listkeys(ref listkeys) => {
+            let keys: Vec<_> = listkeys.keys.clone().into_iter().collect();
+            parts::listkey_part(
+                listkeys.namespace.clone(),
+                stream::iter_ok::<_, Error>(keys),
+            )
+        }
+        &InnerPart::Pushkey(ref pushkey) => parts::pushkey_part(pushkey),
+        &InnerPart::Bookmarks(ref bookmarks) => parts::bookmarks_part(bookmarks),
+        &InnerPart::PhaseHeads(ref heads) => {
+            parts::phase_heads_part(stream::iter_ok::<_, Error>(heads.clone()))
+        }
+        &InnerPart::CheckHeads(ref heads) => parts::check_heads_part(heads),
+        &InnerPart::CheckUpdatedHeads(ref heads) => parts::check_updated_heads_part(heads),
+        &InnerPart::Replycaps(ref caps) => parts::replycaps_part(caps),
+        &InnerPart::ReplyPushkey(ref reply) => {
+            parts::replypushkey_part(reply.in_reply_to, reply.ret)
+        }
+        &InnerPart::Obsmarkers(ref markers) => parts::obsmarkers_part(markers),
+        &InnerPart::Output(ref output) => parts::output_part(output.clone()),
+        &InnerPart::Cg2(_) => panic!("changegroups are tested separately"),
+    };
+    builder.unwrap()
+}
+
+#[test]
+fn test_stream_encode_uncompressed() {
+    stream_encode(CompressorType::Uncompressed);
+}
+
+#[test]
+fn test_stream_encode_zstd() {
+    stream_encode(CompressorType::Zstd {
+        level: ZSTD_DEFAULT_LEVEL,
+    });
+}
+
+fn stream_encode(ct: CompressorType) {
+    // Large enough that the stream has to wait for its output to be consumed.
+    let outputs: Vec<Vec<u8>> = (0..4u8).map(|n| vec![n; 100 * 1024]).collect();
+
+    let mut core = Core::new().unwrap();
+
+    let mut builder = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+    builder.set_compressor_type(ct);
+    for output in &outputs {
+        builder.add_part(parts::output_part(output.clone()).unwrap());
+    }
+    let expected = core.run(builder.build()).unwrap().into_inner();
+
+    let mut builder = Bundle2EncodeBuilder::new_stream();
+    builder.set_compressor_type(ct);
+    for output in &outputs {
+        builder.add_part(parts::output_part(output.clone()).unwrap());
+    }
+    let chunks = core.run(builder.build_stream().collect()).unwrap();
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+
+    let actual: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_stream_encode_interrupted() {
+    let mut core = Core::new().unwrap();
+
+    let data = stream::once(Chunk::new(&b"abc"[..])).chain(stream::once(Err("failed".into())));
+    let mut part = PartEncodeBuilder::advisory("output").unwrap();
+    part.set_data_generated(data);
+
+    let mut builder = Bundle2EncodeBuilder::new_stream();
+    builder.set_compressor_type(CompressorType::Uncompressed);
+    builder.add_part(part);
+    let results = core.run(
+        builder
+            .build_stream()
+            .then(|res| Ok::<_, ()>(res))
+            .collect(),
+    ).unwrap();
+
+    // Everything generated before the failure is output, and then the error is returned
+    let (last, output) = results.split_last().unwrap();
+    assert_eq!(last.as_ref().unwrap_err().to_string(), "failed");
+    let output: Vec<u8> = output
+        .iter()
+        .flat_map(|res| res.as_ref().unwrap().iter().cloned())
+        .collect();
+
+    // The payload is interrupted by an error:abort part, and then ended
+    let payload_end = output
+        .windows(7)
+        .position(|window| window == b"\0\0\0\x03abc")
+        .unwrap() + 7;
+    let rest = &output[payload_end..];
+    assert_eq!(BigEndian::read_i32(&rest[..4]), -1);
+
+    let header_len = BigEndian::read_i32(&rest[4..8]) as usize;
+    let header = part_header::decode(Bytes::from(&rest[8..8 + header_len])).unwrap();
+    assert_eq!(header.part_type_lower().as_str(), "error:abort");
+    assert_eq!(&header.mparams()["message"][..], b"failed");
+
+    assert_eq!(&rest[8 + header_len..], &[0u8; 8][..]);
+}
+
+#[test]
+fn test_bundle1_roundtrip_uncompressed() {
+    quickcheck_bundle1(bundle1_roundtrip_uncompressed);
+}
+
+fn bundle1_roundtrip_uncompressed(seq: CgPartSequence) -> TestResult {
+    bundle1_roundtrip(CompressorType::Uncompressed, seq)
+}
+
+#[test]
+fn test_bundle1_roundtrip_gzip() {
+    quickcheck_bundle1(bundle1_roundtrip_gzip);
+}
+
+fn bundle1_roundtrip_gzip(seq: CgPartSequence) -> TestResult {
+    bundle1_roundtrip(CompressorType::Gzip, seq)
+}
+
+fn quickcheck_bundle1(f: fn(CgPartSequence) -> TestResult) {
+    let rng = StdGen::new(rand::thread_rng(), 20);
+    let mut quickcheck = QuickCheck::new().gen(rng).tests(20);
+    quickcheck.quickcheck(f);
+}
+
+fn bundle1_roundtrip(ct: CompressorType, seq: CgPartSequence) -> TestResult {
+    // bundle1 can only contain version 01 changegroups.
+    if seq.version() != changegroup::CgVersion::Cg1 {
+        return TestResult::discard();
+    }
+
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let encode_fut = encode_bundle1(cursor, ct, seq.to_stream().and_then(|x| x));
+
+    let mut core = Core::new().unwrap();
+    let mut buf = core.run(encode_fut).unwrap();
+    buf.set_position(0);
+
+    let stream = Bundle1Stream::new(buf, make_root_logger());
+    let parts = core.run(stream.collect()).unwrap();
+
+    if seq != parts[..] {
+        return TestResult::failed();
+    }
+    TestResult::passed()
+}
+
+#[test]
+fn test_bundle1_unknown_compression() {
+    let mut core = Core::new().unwrap();
+    let stream = Bundle1Stream::new(Cursor::new(b"HG10IL".to_vec()), make_root_logger());
+    let err = core.run(stream.collect()).unwrap_err();
+    assert_matches!(err.kind(),
+                    &ErrorKind::Bundle1Decode(ref msg) if msg == "unknown compression 'IL'");
+}
+
 fn parse_bundle(
     input: &[u8],
     compression: Option<&str>,
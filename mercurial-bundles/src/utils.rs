@@ -37,6 +37,7 @@ impl SplitTo for BytesMut {
 
 pub trait BytesExt {
     fn drain_u8(&mut self) -> u8;
+    fn drain_u16(&mut self) -> u16;
     fn drain_u32(&mut self) -> u32;
     fn drain_i32(&mut self) -> i32;
     fn drain_str(&mut self, len: usize) -> Result<String>;
@@ -55,6 +56,11 @@ where
         self.split_to(1)[0]
     }
 
+    #[inline]
+    fn drain_u16(&mut self) -> u16 {
+        BigEndian::read_u16(self.split_to(2).as_ref())
+    }
+
     #[inline]
     fn drain_u32(&mut self) -> u32 {
         BigEndian::read_u32(self.split_to(4).as_ref())
@@ -109,6 +115,9 @@ pub fn is_mandatory_param(s: &str) -> Result<bool> {
     }
 }
 
+/// Values of the bundle2 `Compression` parameter which can be decoded, in order of preference.
+pub const COMPRESSION_ENGINES: &[&str] = &["ZS", "GZ", "BZ", "UN"];
+
 pub fn get_decompressor_type(compression: Option<&str>) -> Result<DecompressorType> {
     match compression {
         Some("BZ") => Ok(DecompressorType::Bzip2),
@@ -17,6 +17,9 @@ use nodehash::NodeHash;
 use path::MPath;
 use repo::Repo;
 
+/// The branch of changesets which don't name one.
+pub const DEFAULT_BRANCH: &[u8] = b"default";
+
 pub trait Changeset: Send + 'static {
     fn manifestid(&self) -> &NodeHash;
     fn user(&self) -> &[u8];
@@ -26,6 +29,19 @@ pub trait Changeset: Send + 'static {
     fn time(&self) -> &Time;
     fn parents(&self) -> &Parents;
 
+    /// The named branch this changeset is on, as recorded in the `branch` extra.
+    fn branch(&self) -> &[u8] {
+        self.extra()
+            .get(&b"branch"[..])
+            .map(|branch| branch.as_slice())
+            .unwrap_or(DEFAULT_BRANCH)
+    }
+
+    /// Whether this changeset closes its branch, as recorded in the `close` extra.
+    fn closes_branch(&self) -> bool {
+        self.extra().contains_key(&b"close"[..])
+    }
+
     fn boxed(self) -> Box<Changeset>
     where
         Self: Sized,
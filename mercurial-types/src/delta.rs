@@ -23,6 +23,20 @@ impl Delta {
         Ok(Delta { frags: frags })
     }
 
+    /// Construct a Delta which replaces an empty text with `text`. This is what Mercurial
+    /// sends over the wire for a revision whose delta base is the null revision.
+    pub fn new_fulltext<T: Into<Vec<u8>>>(text: T) -> Self {
+        Delta {
+            frags: vec![
+                Fragment {
+                    start: 0,
+                    end: 0,
+                    content: text.into(),
+                },
+            ],
+        }
+    }
+
     pub fn fragments(&self) -> &[Fragment] {
         self.frags.as_slice()
     }
@@ -297,6 +311,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_apply_fulltext() {
+        let delta = Delta::new_fulltext(&b"aaaa\nbbbb\n"[..]);
+
+        let res = apply(b"", delta);
+        assert_eq!(&res[..], b"aaaa\nbbbb\n");
+    }
+
     #[test]
     fn test_apply_1() {
         let text = b"aaaa\nbbbb\ncccc\n";
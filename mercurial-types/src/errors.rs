@@ -15,6 +15,10 @@ error_chain! {
             description("invalid sha-1 input")
             display("invalid sha-1 input: {}", msg)
         }
+        InvalidHashPrefix(prefix: String) {
+            description("invalid hash prefix")
+            display("invalid hash prefix '{}': need 1 to 40 hex digits", prefix)
+        }
         InvalidPath(path: Vec<u8>, msg: String) {
             description("invalid path")
             display("invalid path '{}': {}", String::from_utf8_lossy(&path[..]), msg)
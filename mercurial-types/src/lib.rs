@@ -58,11 +58,11 @@ mod node;
 
 pub use blob::{Blob, BlobHash};
 pub use blobnode::{BlobNode, Parents};
-pub use changeset::{Changeset, Time};
+pub use changeset::{Changeset, Time, DEFAULT_BRANCH};
 pub use delta::Delta;
 pub use manifest::{Entry, Manifest, Type};
 pub use node::Node;
-pub use nodehash::{NodeHash, NULL_HASH};
+pub use nodehash::{NodeHash, NodeHashPrefix, NULL_HASH};
 pub use path::{fsencode, MPath, MPathElement, RepoPath};
 pub use repo::{BoxRepo, Repo};
 pub use utils::percent_encode;
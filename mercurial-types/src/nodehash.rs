@@ -126,6 +126,54 @@ impl Display for NodeHash {
     }
 }
 
+/// The leading hex digits of a `NodeHash`, as used to abbreviate hashes.
+///
+/// Hex digits sort in the same order as the bytes they encode, so the hashes matching a prefix
+/// form a contiguous range, from the prefix padded with `0`s to the prefix padded with `f`s.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub struct NodeHashPrefix {
+    min: NodeHash,
+    max: NodeHash,
+}
+
+impl NodeHashPrefix {
+    /// The smallest hash with this prefix.
+    pub fn min(&self) -> &NodeHash {
+        &self.min
+    }
+
+    /// The largest hash with this prefix.
+    pub fn max(&self) -> &NodeHash {
+        &self.max
+    }
+
+    pub fn matches(&self, node: &NodeHash) -> bool {
+        &self.min <= node && node <= &self.max
+    }
+}
+
+impl FromStr for NodeHashPrefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<NodeHashPrefix> {
+        if s.is_empty() || s.len() > 40 {
+            bail!(ErrorKind::InvalidHashPrefix(s.into()));
+        }
+        if !s.chars().all(|c| c.is_digit(16)) {
+            bail!(ErrorKind::InvalidHashPrefix(s.into()));
+        }
+
+        let pad = 40 - s.len();
+        let min = format!("{}{}", s, "0".repeat(pad));
+        let max = format!("{}{}", s, "f".repeat(pad));
+
+        Ok(NodeHashPrefix {
+            min: min.parse()?,
+            max: max.parse()?,
+        })
+    }
+}
+
 impl Arbitrary for NodeHash {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
         NodeHash(Sha1::arbitrary(g))
@@ -135,3 +183,37 @@ impl Arbitrary for NodeHash {
         single_shrinker(NULL_HASH)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefix_matches() {
+        let prefix: NodeHashPrefix = "1a2".parse().unwrap();
+
+        let matching: NodeHash = "1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d".parse().unwrap();
+        let other: NodeHash = "1a3b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d".parse().unwrap();
+        assert!(prefix.matches(&matching));
+        assert!(!prefix.matches(&other));
+
+        assert_eq!(prefix.min().to_hex().as_str(), "1a20000000000000000000000000000000000000");
+        assert_eq!(prefix.max().to_hex().as_str(), "1a2fffffffffffffffffffffffffffffffffffff");
+    }
+
+    #[test]
+    fn test_prefix_full_hash() {
+        let node: NodeHash = "1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d".parse().unwrap();
+        let prefix: NodeHashPrefix = "1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d".parse().unwrap();
+        assert_eq!(prefix.min(), &node);
+        assert_eq!(prefix.max(), &node);
+    }
+
+    #[test]
+    fn test_prefix_invalid() {
+        assert!("".parse::<NodeHashPrefix>().is_err());
+        assert!("xyz".parse::<NodeHashPrefix>().is_err());
+        assert!("+f".parse::<NodeHashPrefix>().is_err());
+        assert!("1".repeat(41).parse::<NodeHashPrefix>().is_err());
+    }
+}
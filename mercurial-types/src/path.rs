@@ -223,6 +223,18 @@ impl MPath {
         ret.join(&b'/')
     }
 
+    /// Apply Mercurial's `encodedir` to this path, which only escapes directory names that
+    /// could be confused with revlog files. Stream clones send store paths in this form.
+    pub fn encodedir(&self) -> Vec<u8> {
+        let mut elements: Vec<_> = self.elements.iter().map(|e| e.0.clone()).collect();
+        if let Some((_file, dirs)) = elements.split_last_mut() {
+            for dir in dirs {
+                *dir = direncode(dir);
+            }
+        }
+        elements.join(&b'/')
+    }
+
     /// The length of this path, including any slashes in it.
     pub fn len(&self) -> usize {
         if self.is_empty() {
@@ -17,7 +17,7 @@ use storage_types::Version;
 
 use changeset::Changeset;
 use manifest::{BoxManifest, Manifest};
-use nodehash::NodeHash;
+use nodehash::{NodeHash, NodeHashPrefix};
 
 pub type BoxedBookmarks<E> = Box<
     Bookmarks<
@@ -41,6 +41,12 @@ pub trait Repo: Send + Sync + 'static {
     /// will be O(changesets) in size. Probably OK up to 10-100M changesets.
     fn get_changesets(&self) -> BoxStream<NodeHash, Self::Error>;
 
+    /// Return a stream of the changeset ids which start with `prefix`, in no particular order.
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error>;
+
     fn get_heads(&self) -> BoxStream<NodeHash, Self::Error>;
     fn get_bookmarks(&self) -> Result<BoxedBookmarks<Self::Error>, Self::Error>;
     fn changeset_exists(&self, nodeid: &NodeHash) -> BoxFuture<bool, Self::Error>;
@@ -111,6 +117,16 @@ where
         self.repo.get_changesets().map_err(self.cvterr).boxify()
     }
 
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error> {
+        self.repo
+            .get_changesets_by_prefix(prefix)
+            .map_err(self.cvterr)
+            .boxify()
+    }
+
     fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
         self.repo.get_heads().map_err(self.cvterr).boxify()
     }
@@ -161,6 +177,13 @@ where
         (**self).get_changesets()
     }
 
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
+
     fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
         (**self).get_heads()
     }
@@ -195,6 +218,13 @@ where
         (**self).get_changesets()
     }
 
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
+
     fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
         (**self).get_heads()
     }
@@ -229,6 +259,13 @@ where
         (**self).get_changesets()
     }
 
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
+
     fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
         (**self).get_heads()
     }
@@ -263,6 +300,13 @@ where
         (**self).get_changesets()
     }
 
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
+
     fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
         (**self).get_heads()
     }
@@ -301,6 +345,13 @@ mod test {
             unimplemented!("dummy impl")
         }
 
+        fn get_changesets_by_prefix(
+            &self,
+            _prefix: &NodeHashPrefix,
+        ) -> BoxStream<NodeHash, Self::Error> {
+            unimplemented!("dummy impl")
+        }
+
         fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
             unimplemented!("dummy impl")
         }
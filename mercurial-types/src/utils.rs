@@ -7,8 +7,9 @@
 use url::percent_encoding::{self, USERINFO_ENCODE_SET};
 
 define_encode_set! {
-    // Python urllib also encodes ','
-    pub HG_ENCODE_SET = [USERINFO_ENCODE_SET] | {','}
+    // Python urllib also encodes ',', and '%' has to be encoded for decoding to get the
+    // original string back
+    pub HG_ENCODE_SET = [USERINFO_ENCODE_SET] | {',', '%'}
 }
 
 pub fn percent_encode(input: &str) -> String {
@@ -119,21 +119,25 @@ impl Extra {
     }
 
     pub fn generate<W: Write>(&self, out: &mut W) -> io::Result<()> {
-        // assume BTreeMap is sorted enough
-        let kv: Vec<_> = self.0
-            .iter()
-            .map(|(k, v)| {
-                let mut vec = Vec::new();
-                vec.extend_from_slice(k);
-                vec.push(b':');
-                vec.extend_from_slice(v);
-                escape(&vec)
-            })
-            .collect();
-        out.write_all(kv.join(&b'\0').as_slice())
+        generate_extra(&self.0, out)
     }
 }
 
+fn generate_extra<W: Write>(extra: &BTreeMap<Vec<u8>, Vec<u8>>, out: &mut W) -> io::Result<()> {
+    // assume BTreeMap is sorted enough
+    let kv: Vec<_> = extra
+        .iter()
+        .map(|(k, v)| {
+            let mut vec = Vec::new();
+            vec.extend_from_slice(k);
+            vec.push(b':');
+            vec.extend_from_slice(v);
+            escape(&vec)
+        })
+        .collect();
+    out.write_all(kv.join(&b'\0').as_slice())
+}
+
 fn try_get<T>(v: &[T], idx: usize) -> Option<&T> {
     let v = v.as_ref();
     if idx < v.len() {
@@ -249,19 +253,7 @@ impl RevlogChangeset {
     /// Generate a serialized changeset. This is the counterpart to parse, and generates
     /// in the same format as Mercurial. It should be bit-for-bit identical in fact.
     pub fn generate<W: Write>(&self, out: &mut W) -> Result<()> {
-        write!(out, "{}\n", self.manifestid)?;
-        out.write_all(&self.user)?;
-        out.write_all(b"\n")?;
-        write!(out, "{} {} ", self.time.time, self.time.tz)?;
-        self.extra.generate(out)?;
-        write!(out, "\n")?;
-        for f in &self.files {
-            write!(out, "{}\n", f)?;
-        }
-        write!(out, "\n")?;
-        out.write_all(&self.comments)?;
-
-        Ok(())
+        serialize_cs(self, out)
     }
 
     pub fn get_node(&self) -> Result<BlobNode<Vec<u8>>> {
@@ -273,6 +265,28 @@ impl RevlogChangeset {
     }
 }
 
+/// Serialize any `Changeset` into the changelog format used by Mercurial. This is what the
+/// changeset's node hash is computed over, so it must match Mercurial bit-for-bit.
+pub fn serialize_cs<W: Write>(cs: &Changeset, out: &mut W) -> Result<()> {
+    write!(out, "{}\n", cs.manifestid())?;
+    out.write_all(cs.user())?;
+    out.write_all(b"\n")?;
+    write!(out, "{} {}", cs.time().time, cs.time().tz)?;
+    // Mercurial leaves out the separator entirely when there are no extras
+    if !cs.extra().is_empty() {
+        write!(out, " ")?;
+        generate_extra(cs.extra(), out)?;
+    }
+    write!(out, "\n")?;
+    for f in cs.files() {
+        write!(out, "{}\n", f)?;
+    }
+    write!(out, "\n")?;
+    out.write_all(cs.comments())?;
+
+    Ok(())
+}
+
 impl Changeset for RevlogChangeset {
     fn manifestid(&self) -> &NodeHash {
         &self.manifestid
@@ -9,9 +9,9 @@ use std::str;
 
 use quickcheck::{QuickCheck, TestResult};
 
-use mercurial_types::{Blob, BlobNode, MPath, NodeHash};
+use mercurial_types::{Blob, BlobNode, MPath, NodeHash, Parents};
 
-use changeset::{escape, unescape, Extra, RevlogChangeset, Time};
+use changeset::{escape, serialize_cs, unescape, Extra, RevlogChangeset, Time};
 
 const CHANGESET: &[u8] = include_bytes!("cset.bin");
 const CHANGESETBLOB: Blob<&[u8]> = Blob::Dirty(CHANGESET);
@@ -66,6 +66,27 @@ fn test_generate() {
     assert_eq!(new, CHANGESET);
 }
 
+#[test]
+fn test_serialize_no_extra() {
+    let cset = RevlogChangeset {
+        parents: Parents::None,
+        manifestid: "497522ef3706a1665bf4140497c65b467454e962".parse().unwrap(),
+        user: "test".into(),
+        time: Time { time: 0, tz: 0 },
+        extra: Extra::default(),
+        files: vec![MPath::new(b"a").unwrap()],
+        comments: "added a".into(),
+    };
+
+    let mut out = Vec::new();
+    serialize_cs(&cset, &mut out).expect("serialize failed");
+
+    assert_eq!(
+        out,
+        &b"497522ef3706a1665bf4140497c65b467454e962\ntest\n0 0\na\n\nadded a"[..]
+    );
+}
+
 quickcheck! {
     fn escape_roundtrip(s: Vec<u8>) -> bool {
         let esc = escape(&s);
@@ -0,0 +1,155 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Construct revlogs in memory
+
+use std::collections::HashMap;
+
+use mercurial_types::{NodeHash, NULL_HASH};
+
+use errors::*;
+
+use super::parser::{self, Features};
+use super::revidx::RevIdx;
+
+// Parent and base revisions which don't exist are encoded as -1
+pub(super) const NULL_REV: u32 = !0;
+
+/// Build an inline version 1 ("NG") revlog in memory.
+///
+/// Every revision is stored as an uncompressed full text. That makes the revlog cheap to build
+/// and readable by any Mercurial client, regardless of its support for general delta or
+/// particular compression engines.
+#[derive(Debug)]
+pub struct RevlogBuilder {
+    out: Vec<u8>,
+    // Bytes already handed out by `take_encoded`
+    taken: usize,
+    dataoff: u64,
+    nodeidx: HashMap<NodeHash, RevIdx>,
+    next: RevIdx,
+}
+
+impl RevlogBuilder {
+    pub fn new() -> Self {
+        RevlogBuilder {
+            out: Vec::new(),
+            taken: 0,
+            dataoff: 0,
+            nodeidx: HashMap::new(),
+            next: RevIdx::zero(),
+        }
+    }
+
+    /// Return the index of the revision with the given nodeid, if it has been added.
+    pub fn get_idx_by_nodeid(&self, nodeid: &NodeHash) -> Option<RevIdx> {
+        self.nodeidx.get(nodeid).cloned()
+    }
+
+    /// Append a revision with full text `text`.
+    ///
+    /// Parents must have already been added, and `linkrev` is the index of the changeset which
+    /// introduced this revision.
+    pub fn add_rev(
+        &mut self,
+        nodeid: NodeHash,
+        p1: &NodeHash,
+        p2: &NodeHash,
+        linkrev: RevIdx,
+        text: &[u8],
+    ) -> Result<RevIdx> {
+        if self.nodeidx.contains_key(&nodeid) {
+            bail!(ErrorKind::Revlog(format!("nodeid {} added twice", nodeid)));
+        }
+
+        let p1 = self.parent_rev(p1)?;
+        let p2 = self.parent_rev(p2)?;
+
+        // Mercurial marks uncompressed data with 'u', unless it's empty or starts with '\0'
+        let mut chunk = Vec::with_capacity(text.len() + 1);
+        if !text.is_empty() && text[0] != b'\0' {
+            chunk.push(b'u');
+        }
+        chunk.extend_from_slice(text);
+
+        let idx = self.next;
+        let rev = u32::from(idx);
+
+        let start = self.out.len();
+        // The offset shares its 8 bytes with the (empty) per-revision flags
+        put_u64(&mut self.out, self.dataoff << 16);
+        put_u32(&mut self.out, chunk.len() as u32);
+        put_u32(&mut self.out, text.len() as u32);
+        // Full texts are their own base
+        put_u32(&mut self.out, rev);
+        put_u32(&mut self.out, linkrev.into());
+        put_u32(&mut self.out, p1);
+        put_u32(&mut self.out, p2);
+        self.out.extend_from_slice(nodeid.sha1().as_ref());
+        self.out.extend_from_slice(&[0; 12]);
+        debug_assert_eq!(self.out.len() - start, parser::indexng_size());
+
+        if rev == 0 {
+            // The first entry's offset is always 0, so the header takes its place
+            let features = Features::INLINE.bits();
+            let version = 1u16;
+            self.out[0..2].copy_from_slice(&[(features >> 8) as u8, features as u8]);
+            self.out[2..4].copy_from_slice(&[(version >> 8) as u8, version as u8]);
+        }
+
+        // The data for each revision immediately follows its index entry
+        self.out.extend_from_slice(&chunk);
+        self.dataoff += chunk.len() as u64;
+
+        self.nodeidx.insert(nodeid, idx);
+        self.next = idx.succ();
+
+        Ok(idx)
+    }
+
+    /// The size of the encoded revlog so far.
+    pub fn len(&self) -> usize {
+        self.taken + self.out.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove and return what has been encoded since the last call. This lets a revlog be written
+    /// out as it's built, keeping only enough in memory to add more revisions.
+    pub fn take_encoded(&mut self) -> Vec<u8> {
+        self.taken += self.out.len();
+        ::std::mem::replace(&mut self.out, Vec::new())
+    }
+
+    /// Return the encoded revlog, less anything already returned by `take_encoded`.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.out
+    }
+
+    fn parent_rev(&self, parent: &NodeHash) -> Result<u32> {
+        if parent == &NULL_HASH {
+            return Ok(NULL_REV);
+        }
+
+        match self.nodeidx.get(parent) {
+            Some(idx) => Ok((*idx).into()),
+            None => bail!(ErrorKind::Revlog(
+                format!("parent {} must be added before its children", parent)
+            )),
+        }
+    }
+}
+
+pub(super) fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+pub(super) fn put_u64(out: &mut Vec<u8>, v: u64) {
+    put_u32(out, (v >> 32) as u32);
+    put_u32(out, v as u32);
+}
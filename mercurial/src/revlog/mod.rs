@@ -5,6 +5,7 @@
 // GNU General Public License version 2 or any later version.
 
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::Bound::Included;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io;
@@ -16,21 +17,25 @@ use errors::*;
 use memmap::Mmap;
 use nom::IResult;
 
-use mercurial_types::{Blob, BlobNode, NodeHash};
+use mercurial_types::{Blob, BlobNode, NodeHash, NodeHashPrefix};
 pub use mercurial_types::bdiff::{self, Delta};
 pub use mercurial_types::delta;
 
 // Submodules
+mod builder;
 mod parser;
 mod revidx;
 mod lz4;
+mod writer;
 
 #[cfg(test)]
 mod test;
 
 use self::parser::{Header, Version};
-pub use self::parser::Entry;
+pub use self::builder::RevlogBuilder;
+pub use self::parser::{Entry, Features};
 pub use self::revidx::RevIdx;
+pub use self::writer::RevlogWriter;
 
 #[derive(Debug)]
 enum Datafile {
@@ -87,7 +92,8 @@ struct RevlogInner {
     idx: Datafile,
     data: Option<Datafile>,
     idxoff: BTreeMap<RevIdx, usize>,    // cache of index -> offset
-    nodeidx: HashMap<NodeHash, RevIdx>, // cache of nodeid -> index
+    // cache of nodeid -> index, ordered so that it can also look up hash prefixes
+    nodeidx: BTreeMap<NodeHash, RevIdx>,
 }
 
 impl PartialEq<Self> for Revlog {
@@ -110,14 +116,14 @@ impl Revlog {
         }
 
         let mut idxoff = BTreeMap::new();
-        let mut nodeidx = HashMap::new();
+        let mut nodeidx = BTreeMap::new();
 
         let mut inner = RevlogInner {
             header: hdr,
             idx: idx,
             data: data,
             idxoff: BTreeMap::new(),
-            nodeidx: HashMap::new(),
+            nodeidx: BTreeMap::new(),
         };
 
         let mut off = 0;
@@ -210,6 +216,11 @@ impl Revlog {
         self.inner.get_idx_by_nodeid(nodeid)
     }
 
+    /// Return the nodeids of all the entries which start with `prefix`.
+    pub fn get_nodeids_by_prefix(&self, prefix: &NodeHashPrefix) -> Vec<NodeHash> {
+        self.inner.get_nodeids_by_prefix(prefix)
+    }
+
     /// Return the ordinal index of an entry with the given nodeid.
     pub fn get_entry_by_nodeid(&self, nodeid: &NodeHash) -> Result<Entry> {
         self.inner.get_entry_by_nodeid(nodeid)
@@ -323,6 +334,13 @@ impl RevlogInner {
         }
     }
 
+    fn get_nodeids_by_prefix(&self, prefix: &NodeHashPrefix) -> Vec<NodeHash> {
+        self.nodeidx
+            .range((Included(prefix.min()), Included(prefix.max())))
+            .map(|(nodeid, _)| *nodeid)
+            .collect()
+    }
+
     fn get_entry_by_nodeid(&self, nodeid: &NodeHash) -> Result<Entry> {
         self.get_idx_by_nodeid(nodeid)
             .and_then(|idx| self.get_entry(idx))
@@ -6,6 +6,9 @@
 
 use super::*;
 
+use mercurial_types::NULL_HASH;
+use tempdir::TempDir;
+
 static EMPTY: &[u8] = include_bytes!("empty.i.bin");
 
 #[test]
@@ -17,3 +20,163 @@ fn emptyrev() {
 
     assert_eq!(node.size(), Some(0));
 }
+
+#[test]
+fn builder_roundtrip() {
+    let text1 = b"hello\n".to_vec();
+    let node1 = BlobNode::new(text1.clone(), None, None).nodeid().unwrap();
+    // Texts starting with a NUL are stored without a marker
+    let text2 = b"\0hello world\n".to_vec();
+    let node2 = BlobNode::new(text2.clone(), Some(&node1), None)
+        .nodeid()
+        .unwrap();
+
+    let mut builder = RevlogBuilder::new();
+    builder
+        .add_rev(node1, &NULL_HASH, &NULL_HASH, RevIdx::zero(), &text1)
+        .expect("add rev 0 failed");
+    builder
+        .add_rev(node2, &node1, &NULL_HASH, RevIdx::zero().succ(), &text2)
+        .expect("add rev 1 failed");
+
+    let revlog = Revlog::new(builder.into_bytes(), None).expect("construction failed");
+    assert!(revlog.get_header().features.contains(parser::Features::INLINE));
+
+    let entry = revlog.get_entry_by_nodeid(&node2).expect("missing node2");
+    assert_eq!(entry.p1, Some(RevIdx::zero()));
+    assert_eq!(entry.linkrev, RevIdx::zero().succ());
+
+    let rev1 = revlog.get_rev_by_nodeid(&node1).expect("failed to get node1");
+    assert_eq!(rev1.as_blob().as_slice(), Some(&text1[..]));
+    let rev2 = revlog.get_rev_by_nodeid(&node2).expect("failed to get node2");
+    assert_eq!(rev2.as_blob().as_slice(), Some(&text2[..]));
+}
+
+#[test]
+fn builder_take_encoded() {
+    let text1 = b"hello\n".to_vec();
+    let node1 = BlobNode::new(text1.clone(), None, None).nodeid().unwrap();
+    let text2 = b"hello world\n".to_vec();
+    let node2 = BlobNode::new(text2.clone(), Some(&node1), None)
+        .nodeid()
+        .unwrap();
+
+    let mut whole = RevlogBuilder::new();
+    let mut pieces = RevlogBuilder::new();
+    let mut taken = Vec::new();
+    for builder in vec![&mut whole, &mut pieces] {
+        builder
+            .add_rev(node1, &NULL_HASH, &NULL_HASH, RevIdx::zero(), &text1)
+            .expect("add rev 0 failed");
+    }
+    taken.extend(pieces.take_encoded());
+    for builder in vec![&mut whole, &mut pieces] {
+        builder
+            .add_rev(node2, &node1, &NULL_HASH, RevIdx::zero().succ(), &text2)
+            .expect("add rev 1 failed");
+    }
+    taken.extend(pieces.take_encoded());
+
+    assert_eq!(pieces.len(), whole.len());
+    assert!(pieces.take_encoded().is_empty());
+    assert_eq!(taken, whole.into_bytes());
+}
+
+#[test]
+fn builder_missing_parent() {
+    let node = BlobNode::new(b"text".to_vec(), None, None).nodeid().unwrap();
+
+    let mut builder = RevlogBuilder::new();
+    assert!(
+        builder
+            .add_rev(NULL_HASH, &node, &NULL_HASH, RevIdx::zero(), b"child")
+            .is_err()
+    );
+}
+
+fn writer_roundtrip(name: &str, features: Features) {
+    let dir = TempDir::new(&format!("mercurial-revlog-{}", name)).expect("tempdir failed");
+    let idxpath = dir.path().join("file.i");
+
+    // Each text grows from its parent, so there's something to delta against
+    let mut revs = Vec::new();
+    let mut parent = NULL_HASH;
+    let mut text = Vec::new();
+    for i in 0..10 {
+        text.extend_from_slice(format!("line {} of a file which keeps growing\n", i).as_bytes());
+        let p1 = if parent == NULL_HASH {
+            None
+        } else {
+            Some(&parent)
+        };
+        let node = BlobNode::new(text.clone(), p1, None).nodeid().unwrap();
+        revs.push((node, parent, text.clone()));
+        parent = node;
+    }
+
+    // Write the revisions in batches, committing each one and reopening the revlog halfway
+    for half in revs.chunks(6) {
+        let mut writer = RevlogWriter::open(&idxpath, features).expect("open failed");
+        for batch in half.chunks(2) {
+            for &(node, ref p1, ref text) in batch {
+                let linkrev = writer.get_idx_by_nodeid(p1).map_or(RevIdx::zero(), RevIdx::succ);
+                writer
+                    .add_rev(node, p1, &NULL_HASH, linkrev, text)
+                    .expect("add_rev failed");
+            }
+            writer.commit().expect("commit failed");
+        }
+    }
+
+    let revlog = Revlog::from_idx_data(&idxpath, None::<&::std::path::Path>)
+        .expect("construction failed");
+    assert_eq!(revlog.get_header().features, features);
+
+    let mut deltas = 0;
+    for (i, &(node, parent, ref text)) in revs.iter().enumerate() {
+        let entry = revlog.get_entry_by_nodeid(&node).expect("missing node");
+        assert_eq!(entry.linkrev, RevIdx::from(i));
+        if i > 0 {
+            assert_eq!(entry.p1, revlog.get_idx_by_nodeid(&parent).ok());
+        }
+        if entry.baserev.is_some() {
+            deltas += 1;
+        }
+
+        let rev = revlog.get_rev_by_nodeid(&node).expect("failed to get rev");
+        assert_eq!(rev.as_blob().as_slice(), Some(&text[..]));
+    }
+    assert!(deltas > 0);
+}
+
+#[test]
+fn writer_roundtrip_inline() {
+    writer_roundtrip("inline", Features::INLINE);
+}
+
+#[test]
+fn writer_roundtrip_separate_data() {
+    writer_roundtrip("separate_data", Features::empty());
+}
+
+#[test]
+fn writer_roundtrip_general_delta() {
+    writer_roundtrip("general_delta", Features::INLINE | Features::GENERAL_DELTA);
+}
+
+#[test]
+fn writer_duplicate_node() {
+    let idxpath = test_dir("duplicate_node").join("file.i");
+    let node = BlobNode::new(b"text".to_vec(), None, None).nodeid().unwrap();
+
+    let mut writer = RevlogWriter::open(&idxpath, Features::INLINE).expect("open failed");
+    writer
+        .add_rev(node, &NULL_HASH, &NULL_HASH, RevIdx::zero(), b"text")
+        .expect("add_rev failed");
+    writer.commit().expect("commit failed");
+    assert!(
+        writer
+            .add_rev(node, &NULL_HASH, &NULL_HASH, RevIdx::zero(), b"text")
+            .is_err()
+    );
+}
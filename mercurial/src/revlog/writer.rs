@@ -0,0 +1,426 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Append revisions to revlogs on disk
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use mercurial_types::{NodeHash, NULL_HASH};
+
+use errors::*;
+
+use super::Revlog;
+use super::builder::{put_u32, put_u64, NULL_REV};
+use super::parser::{self, Entry, Features, Header, IdxFlags, Version};
+use super::revidx::RevIdx;
+
+// Mercurial doesn't bother compressing anything shorter than this
+const MIN_COMPRESS_LEN: usize = 44;
+
+/// Append revisions to a version 1 ("NG") revlog on disk, which may be inline or not.
+///
+/// Each revision is stored as a delta against the previous revision, or against its first
+/// parent if the revlog uses general delta. If rebuilding it would mean reading more than twice
+/// its size in deltas, the full text is stored instead. Chunks are compressed with zlib whenever
+/// that makes them smaller.
+///
+/// Added revisions are held in memory until `commit`, which appends their data and then their
+/// index entries, so readers never see an index entry referring to missing data. Only the text
+/// of the last committed revision is kept, so a revision whose delta parent was committed
+/// earlier by this writer is stored as a full text.
+#[derive(Debug)]
+pub struct RevlogWriter {
+    idxpath: PathBuf,
+    datapath: PathBuf,
+    header: Header,
+    // The revisions which were on disk when the revlog was opened
+    opened: Option<Revlog>,
+    opened_revs: usize,
+    // The revisions committed since then
+    written: Vec<Entry>,
+    // The text of the last committed revision, which the next one is most likely to be a delta
+    // against
+    last_text: Option<(RevIdx, Vec<u8>)>,
+    pending: Vec<PendingRev>,
+    // Only covers the revisions added since the revlog was opened
+    nodeidx: HashMap<NodeHash, RevIdx>,
+    // For each revision, the total size of the chunks needed to rebuild it
+    chain_sizes: Vec<u64>,
+    // How much of the index and data files has been committed
+    committed_idxlen: u64,
+    committed_dataoff: u64,
+    // Where the next revision's data goes, not counting inline index entries
+    dataoff: u64,
+}
+
+#[derive(Debug)]
+struct PendingRev {
+    entry: Entry,
+    chunk: Vec<u8>,
+    text: Vec<u8>,
+}
+
+impl RevlogWriter {
+    /// Open the revlog whose index is at `idxpath` for appending. If it doesn't exist yet, it
+    /// is created with `features`; otherwise the features it already has are kept. The data
+    /// file of a non-inline revlog is the index path with a `.d` extension.
+    pub fn open<P: AsRef<Path>>(idxpath: P, features: Features) -> Result<Self> {
+        let idxpath = idxpath.as_ref().to_path_buf();
+        let mut writer = RevlogWriter {
+            datapath: idxpath.with_extension("d"),
+            idxpath: idxpath,
+            header: Header {
+                version: Version::RevlogNG,
+                features: features,
+            },
+            opened: None,
+            opened_revs: 0,
+            written: Vec::new(),
+            last_text: None,
+            pending: Vec::new(),
+            nodeidx: HashMap::new(),
+            chain_sizes: Vec::new(),
+            committed_idxlen: 0,
+            committed_dataoff: 0,
+            dataoff: 0,
+        };
+        writer.load()?;
+        Ok(writer)
+    }
+
+    /// Return the index of the revision with the given nodeid, whether it's been committed yet
+    /// or not.
+    pub fn get_idx_by_nodeid(&self, nodeid: &NodeHash) -> Option<RevIdx> {
+        self.nodeidx.get(nodeid).cloned().or_else(|| {
+            self.opened
+                .as_ref()
+                .and_then(|revlog| revlog.get_idx_by_nodeid(nodeid).ok())
+        })
+    }
+
+    /// Append a revision with full text `text`.
+    ///
+    /// Parents must have already been added, and `linkrev` is the index of the changeset which
+    /// introduced this revision.
+    pub fn add_rev(
+        &mut self,
+        nodeid: NodeHash,
+        p1: &NodeHash,
+        p2: &NodeHash,
+        linkrev: RevIdx,
+        text: &[u8],
+    ) -> Result<RevIdx> {
+        if self.get_idx_by_nodeid(&nodeid).is_some() {
+            bail!(ErrorKind::Revlog(format!("nodeid {} added twice", nodeid)));
+        }
+
+        let p1 = self.parent_rev(p1)?;
+        let p2 = self.parent_rev(p2)?;
+        let idx = RevIdx::from(self.chain_sizes.len());
+
+        let (baserev, chunk, chain_size) = self.encode_rev(idx, p1, text)?;
+
+        let entry = Entry {
+            offset: self.dataoff,
+            flags: IdxFlags::empty(),
+            compressed_len: chunk.len() as u32,
+            len: Some(text.len() as u32),
+            baserev: baserev,
+            linkrev: linkrev,
+            p1: p1,
+            p2: p2,
+            nodeid: nodeid,
+        };
+        self.dataoff += chunk.len() as u64;
+        self.chain_sizes.push(chain_size);
+        self.nodeidx.insert(nodeid, idx);
+        self.pending.push(PendingRev {
+            entry: entry,
+            chunk: chunk,
+            text: text.to_vec(),
+        });
+
+        Ok(idx)
+    }
+
+    /// Write the revisions added since the last commit to disk.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(dir) = self.idxpath.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let inline = self.header.features.contains(Features::INLINE);
+        if !inline {
+            let mut data = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(&self.datapath)?;
+            // Drop anything left behind by an earlier commit which didn't finish
+            data.set_len(self.committed_dataoff)?;
+            data.seek(SeekFrom::End(0))?;
+            for rev in &self.pending {
+                data.write_all(&rev.chunk)?;
+            }
+            data.sync_all()?;
+        }
+
+        let first = self.opened_revs + self.written.len();
+        let mut entries = Vec::new();
+        for (i, rev) in self.pending.iter().enumerate() {
+            encode_entry(&mut entries, RevIdx::from(first + i), &rev.entry, self.header);
+            if inline {
+                entries.extend_from_slice(&rev.chunk);
+            }
+        }
+
+        let mut index = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&self.idxpath)?;
+        index.set_len(self.committed_idxlen)?;
+        index.seek(SeekFrom::End(0))?;
+        index.write_all(&entries)?;
+        index.sync_all()?;
+
+        self.committed_idxlen += entries.len() as u64;
+        self.committed_dataoff = self.dataoff;
+        let last = RevIdx::from(first + self.pending.len() - 1);
+        for rev in self.pending.drain(..) {
+            self.written.push(rev.entry);
+            self.last_text = Some((last, rev.text));
+        }
+        Ok(())
+    }
+
+    // Read the revisions on disk
+    fn load(&mut self) -> Result<()> {
+        // Mercurial never writes an empty index, but treat one as a new revlog
+        let idxlen = match fs::metadata(&self.idxpath) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        if idxlen == 0 {
+            return Ok(());
+        }
+
+        let revlog = open_revlog(&self.idxpath, &self.datapath)
+            .chain_err(|| format!("can't open revlog {:?}", self.idxpath))?;
+        let header = revlog.get_header();
+        if header.version != Version::RevlogNG {
+            bail!(ErrorKind::Revlog(
+                format!("{:?}: only version 1 revlogs can be written", self.idxpath)
+            ));
+        }
+        self.header = header;
+
+        for (idx, entry) in &revlog {
+            let chain_size = match entry.baserev {
+                None => 0,
+                Some(base) if self.is_general_delta() => self.chain_sizes[u32::from(base) as usize],
+                Some(_) => self.chain_sizes[u32::from(idx.pred()) as usize],
+            };
+            self.chain_sizes.push(chain_size + entry.compressed_len as u64);
+            self.dataoff = entry.offset + entry.compressed_len as u64;
+        }
+
+        self.opened_revs = self.chain_sizes.len();
+        self.committed_idxlen = idxlen;
+        self.committed_dataoff = self.dataoff;
+        self.opened = Some(revlog);
+        Ok(())
+    }
+
+    fn is_general_delta(&self) -> bool {
+        self.header.features.contains(Features::GENERAL_DELTA)
+    }
+
+    // Choose how to store a new revision: as a delta against an earlier revision if that keeps
+    // its delta chain short enough, and as its full text otherwise. Returns the base revision
+    // for the index (None for full texts), the chunk, and the size of its delta chain.
+    fn encode_rev(
+        &self,
+        idx: RevIdx,
+        p1: Option<RevIdx>,
+        text: &[u8],
+    ) -> Result<(Option<RevIdx>, Vec<u8>, u64)> {
+        // Without general delta, each delta is against the previous revision and the index
+        // records where its chain starts
+        let delta_parent = if self.is_general_delta() {
+            p1
+        } else if idx == RevIdx::zero() {
+            None
+        } else {
+            Some(idx.pred())
+        };
+
+        let base = match delta_parent {
+            Some(parent) => self.get_text(parent)?.map(|base| (parent, base)),
+            None => None,
+        };
+        if let Some((parent, base)) = base {
+            let chunk = compress(&encode_delta(&base, text))?;
+            let chain_size = self.chain_sizes[u32::from(parent) as usize] + chunk.len() as u64;
+            if chain_size <= 2 * text.len() as u64 {
+                let baserev = if self.is_general_delta() {
+                    parent
+                } else {
+                    self.get_entry(parent)?.baserev.unwrap_or(parent)
+                };
+                return Ok((Some(baserev), chunk, chain_size));
+            }
+        }
+
+        let chunk = compress(text)?;
+        let chain_size = chunk.len() as u64;
+        Ok((None, chunk, chain_size))
+    }
+
+    fn get_entry(&self, idx: RevIdx) -> Result<Entry> {
+        let i = u32::from(idx) as usize;
+        if i < self.opened_revs {
+            if let Some(ref revlog) = self.opened {
+                return revlog.get_entry(idx);
+            }
+        } else if let Some(entry) = self.written.get(i - self.opened_revs) {
+            return Ok(*entry);
+        } else if let Some(rev) = self.pending.get(i - self.opened_revs - self.written.len()) {
+            return Ok(rev.entry);
+        }
+        bail!(ErrorKind::Revlog(format!("rev {:?} not found", idx)))
+    }
+
+    // The text of a revision, unless it was committed by this writer and isn't the last one
+    fn get_text(&self, idx: RevIdx) -> Result<Option<Vec<u8>>> {
+        let i = u32::from(idx) as usize;
+        if i < self.opened_revs {
+            if let Some(ref revlog) = self.opened {
+                let node = revlog.get_rev(idx)?;
+                return Ok(Some(node.as_blob().as_slice().unwrap_or(&[]).to_vec()));
+            }
+        } else if i < self.opened_revs + self.written.len() {
+            return match self.last_text {
+                Some((last, ref text)) if last == idx => Ok(Some(text.clone())),
+                _ => Ok(None),
+            };
+        } else if let Some(rev) = self.pending.get(i - self.opened_revs - self.written.len()) {
+            return Ok(Some(rev.text.clone()));
+        }
+        bail!(ErrorKind::Revlog(format!("rev {:?} not found", idx)))
+    }
+
+    fn parent_rev(&self, parent: &NodeHash) -> Result<Option<RevIdx>> {
+        if parent == &NULL_HASH {
+            return Ok(None);
+        }
+
+        match self.get_idx_by_nodeid(parent) {
+            Some(idx) => Ok(Some(idx)),
+            None => bail!(ErrorKind::Revlog(
+                format!("parent {} must be added before its children", parent)
+            )),
+        }
+    }
+}
+
+// Open the revlog on disk. An empty data file can't be mapped, so in that case it's read instead.
+fn open_revlog(idxpath: &Path, datapath: &Path) -> Result<Revlog> {
+    let empty_data = match fs::metadata(datapath) {
+        Ok(metadata) => metadata.len() == 0,
+        Err(_) => false,
+    };
+
+    if empty_data {
+        let mut idx = Vec::new();
+        File::open(idxpath)?.read_to_end(&mut idx)?;
+        Revlog::new(idx, Some(Vec::new()))
+    } else {
+        Revlog::from_idx_data(idxpath, None::<&Path>)
+    }
+}
+
+fn encode_entry(out: &mut Vec<u8>, idx: RevIdx, entry: &Entry, header: Header) {
+    let rev_or_null = |rev: Option<RevIdx>| rev.map(u32::from).unwrap_or(NULL_REV);
+
+    let start = out.len();
+    // The offset shares its 8 bytes with the per-revision flags
+    put_u64(out, (entry.offset << 16) | entry.flags.bits() as u64);
+    put_u32(out, entry.compressed_len);
+    put_u32(out, entry.len.unwrap_or(0));
+    // Full texts are their own base
+    put_u32(out, u32::from(entry.baserev.unwrap_or(idx)));
+    put_u32(out, entry.linkrev.into());
+    put_u32(out, rev_or_null(entry.p1));
+    put_u32(out, rev_or_null(entry.p2));
+    out.extend_from_slice(entry.nodeid.sha1().as_ref());
+    out.extend_from_slice(&[0; 12]);
+    debug_assert_eq!(out.len() - start, parser::indexng_size());
+
+    if idx == RevIdx::zero() {
+        // The first entry's offset is always 0, so the header takes its place
+        let features = header.features.bits();
+        let version = header.version as u16;
+        out[start..start + 2].copy_from_slice(&[(features >> 8) as u8, features as u8]);
+        out[start + 2..start + 4].copy_from_slice(&[(version >> 8) as u8, version as u8]);
+    }
+}
+
+// A delta turning `base` into `text`, made of one hunk replacing everything between their
+// common prefix and suffix. That's bigger than the line-based deltas Mercurial computes when
+// changes are spread out, but it's always valid.
+fn encode_delta(base: &[u8], text: &[u8]) -> Vec<u8> {
+    let prefix = base.iter()
+        .zip(text.iter())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(text[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let content = &text[prefix..text.len() - suffix];
+
+    let mut out = Vec::with_capacity(12 + content.len());
+    put_u32(&mut out, prefix as u32);
+    put_u32(&mut out, (base.len() - suffix) as u32);
+    put_u32(&mut out, content.len() as u32);
+    out.extend_from_slice(content);
+    out
+}
+
+// Compress a chunk with zlib if that makes it smaller. Otherwise it's stored as it is, marked
+// with 'u' unless it's empty or starts with '\0'.
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if data.len() >= MIN_COMPRESS_LEN {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < data.len() {
+            return Ok(compressed);
+        }
+    }
+
+    let mut chunk = Vec::with_capacity(data.len() + 1);
+    if data[0] != b'\0' {
+        chunk.push(b'u');
+    }
+    chunk.extend_from_slice(data);
+    Ok(chunk)
+}
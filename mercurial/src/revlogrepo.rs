@@ -21,7 +21,7 @@ use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use asyncmemo::{Asyncmemo, Filler};
 use bookmarks::{Bookmarks, BoxedBookmarks};
 use mercurial_types::{fsencode, BlobNode, Changeset, MPath, MPathElement, Manifest, NodeHash,
-                      Repo, RepoPath, NULL_HASH};
+                      NodeHashPrefix, Repo, RepoPath, NULL_HASH};
 use stockbookmarks::StockBookmarks;
 use storage_types::Version;
 
@@ -435,6 +435,13 @@ impl Repo for RevlogRepo {
         self.changesets().boxify()
     }
 
+    fn get_changesets_by_prefix(
+        &self,
+        prefix: &NodeHashPrefix,
+    ) -> BoxStream<NodeHash, Self::Error> {
+        stream::iter_ok(self.changelog.get_nodeids_by_prefix(prefix)).boxify()
+    }
+
     fn changeset_exists(&self, nodeid: &NodeHash) -> BoxFuture<bool, Self::Error> {
         RevlogRepo::changeset_exists(self, nodeid).boxify()
     }
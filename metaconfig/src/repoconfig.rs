@@ -7,7 +7,7 @@
 //! Contains structures describing configuration of the entire repo. Those structures are
 //! deserialized from TOML files from metaconfig repo
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::path::PathBuf;
 use std::str::from_utf8;
@@ -29,6 +29,23 @@ use errors::*;
 pub struct RepoConfig {
     /// Defines the type of repository
     pub repotype: RepoType,
+    /// Pre-generated bundles which clients can clone from instead of pulling everything
+    pub clonebundles: Vec<CloneBundle>,
+    /// Whether pushes are refused, even if the repo's storage would accept them
+    pub readonly: bool,
+}
+
+/// Descriptor of a pre-generated bundle, as advertised to clients by the `clonebundles` command
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct CloneBundle {
+    /// Where clients can fetch the bundle from
+    pub url: String,
+    /// Mercurial's BUNDLESPEC for the bundle (eg. "none-v2"), so clients can skip bundles they
+    /// can't read
+    pub bundlespec: Option<String>,
+    /// Any other attributes clients can use to choose between bundles
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
 }
 
 /// Types of repositories supported
@@ -176,6 +193,10 @@ impl RepoConfigs {
 struct RawRepoConfig {
     path: PathBuf,
     repotype: RawRepoType,
+    #[serde(default)]
+    clonebundles: Vec<CloneBundle>,
+    #[serde(default)]
+    readonly: bool,
 }
 
 /// Types of repositories supported
@@ -198,7 +219,11 @@ impl TryFrom<RawRepoConfig> for RepoConfig {
             BlobRocks => RepoType::BlobRocks(this.path),
         };
 
-        Ok(RepoConfig { repotype })
+        Ok(RepoConfig {
+            repotype,
+            clonebundles: this.clonebundles,
+            readonly: this.readonly,
+        })
     }
 }
 
@@ -219,6 +244,13 @@ mod test {
         let www_content = r#"
             path="/tmp/www"
             repotype="revlog"
+            readonly=true
+
+            [[clonebundles]]
+            url="https://example.com/www/full.hg"
+            bundlespec="none-v2"
+            [clonebundles.attributes]
+            REQUIRESNI="true"
         "#;
 
         let repoconfig = RepoConfigs::read_manifest(&MockManifest::<Error>::with_content(vec![
@@ -233,12 +265,24 @@ mod test {
             "fbsource".to_string(),
             RepoConfig {
                 repotype: RepoType::BlobFiles("/tmp/fbsource".into()),
+                clonebundles: vec![],
+                readonly: false,
             },
         );
         repos.insert(
             "www".to_string(),
             RepoConfig {
                 repotype: RepoType::Revlog("/tmp/www".into()),
+                clonebundles: vec![
+                    CloneBundle {
+                        url: "https://example.com/www/full.hg".into(),
+                        bundlespec: Some("none-v2".into()),
+                        attributes: vec![("REQUIRESNI".to_string(), "true".to_string())]
+                            .into_iter()
+                            .collect(),
+                    },
+                ],
+                readonly: true,
             },
         );
         assert_eq!(
@@ -0,0 +1,174 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate phases;
+
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate futures_ext;
+#[cfg(test)]
+extern crate tempdir;
+
+use std::error;
+use std::fs::{self, File};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::string::ToString;
+use std::sync::Arc;
+
+use futures::Async;
+use futures::future::{poll_fn, Future, IntoFuture};
+use futures::stream::{self, Stream};
+use futures_cpupool::CpuPool;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use phases::{Phase, Phases};
+
+mod errors {
+    error_chain!{
+        foreign_links {
+            Io(::std::io::Error);
+        }
+    }
+}
+pub use errors::*;
+
+static PREFIX: &'static str = "public-";
+
+/// A basic file-based persistent phase store.
+///
+/// Stores each public changeset as an empty file in the specified directory, so that a
+/// changeset is draft exactly when its file is missing. File operations are dispatched to a
+/// thread pool to avoid blocking the main thread with IO.
+pub struct FilePhases<T> {
+    base: PathBuf,
+    pool: Arc<CpuPool>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> FilePhases<T>
+where
+    T: ToString + Send,
+{
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.as_ref();
+
+        if !path.is_dir() {
+            bail!("'{}' is not a directory", path.to_string_lossy());
+        }
+
+        Ok(FilePhases {
+            base: path.to_path_buf(),
+            pool: pool,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn create_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        Self::open_with_pool(path, pool)
+    }
+
+    fn get_path(&self, key: &T) -> Result<PathBuf> {
+        Ok(self.base.join(format!("{}{}", PREFIX, key.to_string())))
+    }
+}
+
+impl<T> Phases for FilePhases<T>
+where
+    T: FromStr + ToString + Send + 'static,
+    <T as FromStr>::Err: error::Error + Send,
+{
+    type Key = T;
+    type Error = Error;
+
+    type Public = BoxStream<Self::Key, Self::Error>;
+    type Phase = BoxFuture<Phase, Self::Error>;
+    type Effect = BoxFuture<(), Self::Error>;
+
+    fn add_public(&self, key: &Self::Key) -> Self::Effect {
+        let pool = self.pool.clone();
+        self.get_path(&key)
+            .into_future()
+            .and_then(move |path| {
+                let future = poll_fn(move || {
+                    File::create(&path)?;
+                    Ok(Async::Ready(()))
+                });
+                pool.spawn(future)
+            })
+            .boxify()
+    }
+
+    fn get_phase(&self, key: &Self::Key) -> Self::Phase {
+        let pool = self.pool.clone();
+        self.get_path(&key)
+            .into_future()
+            .and_then(move |path| {
+                let future = poll_fn(move || {
+                    let phase = if path.exists() {
+                        Phase::Public
+                    } else {
+                        Phase::Draft
+                    };
+                    Ok(Async::Ready(phase))
+                });
+                pool.spawn(future)
+            })
+            .boxify()
+    }
+
+    fn public(&self) -> Self::Public {
+        let names = fs::read_dir(&self.base).map(|entries| {
+            entries
+                .map(|result| {
+                    result
+                        .map_err(From::from)
+                        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                })
+                .filter_map(|result| match result {
+                    Ok(ref name) if name.starts_with(PREFIX) => {
+                        let name = &name[PREFIX.len()..];
+                        let name = T::from_str(name).chain_err(|| "can't parse name");
+                        Some(name)
+                    }
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err)),
+                })
+        });
+        match names {
+            Ok(v) => stream::iter_ok(v).and_then(|v| v).boxify(),
+            Err(e) => stream::once(Err(e.into())).boxify(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn invalid_dir() {
+        let tmp = TempDir::new("filephases_invalid_dir").unwrap();
+        let phases = FilePhases::<String>::open(tmp.path().join("does_not_exist"));
+        assert!(phases.is_err());
+    }
+}
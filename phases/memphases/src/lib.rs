@@ -0,0 +1,65 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+#![feature(never_type)]
+
+extern crate futures;
+
+extern crate futures_ext;
+extern crate phases;
+
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use futures::future::{ok, FutureResult};
+use futures::stream::iter_ok;
+use futures_ext::{BoxStream, StreamExt};
+
+use phases::{Phase, Phases};
+
+/// Generic, in-memory phase store backed by a HashSet of public keys, intended to be used in
+/// tests.
+pub struct MemPhases<T: Hash + Eq + Clone> {
+    public: Mutex<HashSet<T>>,
+}
+
+impl<T: Hash + Eq + Clone + Send> MemPhases<T> {
+    pub fn new() -> Self {
+        MemPhases {
+            public: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone + Send + 'static> Phases for MemPhases<T> {
+    type Key = T;
+    type Error = !;
+
+    type Public = BoxStream<Self::Key, Self::Error>;
+    type Phase = FutureResult<Phase, Self::Error>;
+    type Effect = FutureResult<(), Self::Error>;
+
+    fn add_public(&self, key: &Self::Key) -> Self::Effect {
+        self.public.lock().unwrap().insert(key.clone());
+        ok(())
+    }
+
+    fn get_phase(&self, key: &Self::Key) -> Self::Phase {
+        if self.public.lock().unwrap().contains(key) {
+            ok(Phase::Public)
+        } else {
+            ok(Phase::Draft)
+        }
+    }
+
+    fn public(&self) -> Self::Public {
+        let guard = self.public.lock().unwrap();
+        let public = (*guard).clone();
+        iter_ok::<_, !>(public).boxify()
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate futures;
+
+use std::error;
+use std::fmt;
+
+use futures::{Future, Stream};
+
+/// The phase of a changeset. A changeset becomes public once a bookmark has been pushed to it
+/// or to one of its descendants, and is draft until then.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Phase {
+    Public,
+    Draft,
+}
+
+impl Phase {
+    /// The number Mercurial uses for this phase on the wire.
+    pub fn to_hg(&self) -> u32 {
+        match *self {
+            Phase::Public => 0,
+            Phase::Draft => 1,
+        }
+    }
+
+    /// The phase Mercurial numbers `phase` on the wire, if it's one that's tracked here.
+    pub fn from_hg(phase: u32) -> Option<Phase> {
+        match phase {
+            0 => Some(Phase::Public),
+            1 => Some(Phase::Draft),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Public => write!(fmt, "public"),
+            Phase::Draft => write!(fmt, "draft"),
+        }
+    }
+}
+
+/// A store of which changesets are public. Anything not recorded as public is draft.
+///
+/// Changesets only ever move from draft to public, so there's no way to remove one. Callers are
+/// expected to keep the public changesets closed under ancestry, by making a changeset's
+/// ancestors public before the changeset itself.
+pub trait Phases: Send + 'static {
+    type Key: Send + 'static;
+    type Error: error::Error + Send + 'static;
+
+    // Public changesets are not guaranteed to be returned in any particular order.
+    type Public: Stream<Item = Self::Key, Error = Self::Error> + Send + 'static;
+    type Phase: Future<Item = Phase, Error = Self::Error> + Send + 'static;
+    type Effect: Future<Item = (), Error = Self::Error> + Send + 'static;
+
+    fn add_public(&self, &Self::Key) -> Self::Effect;
+    fn get_phase(&self, &Self::Key) -> Self::Phase;
+    fn public(&self) -> Self::Public;
+}
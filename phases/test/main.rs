@@ -0,0 +1,129 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests run against all phases implementations.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate tempdir;
+
+extern crate filephases;
+extern crate memphases;
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate phases;
+
+use futures::{Future, Stream};
+use tempdir::TempDir;
+
+use filephases::FilePhases;
+use memphases::MemPhases;
+use mercurial_types::NodeHash;
+use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+use phases::{Phase, Phases};
+
+fn basic<P>(phases: P)
+where
+    P: Phases<Key = String>,
+{
+    let empty: Vec<String> = Vec::new();
+    assert_eq!(phases.public().collect().wait().unwrap(), empty);
+
+    let foo = "foo".to_string();
+    let bar = "bar".to_string();
+    let baz = "baz".to_string();
+
+    assert_eq!(phases.get_phase(&foo).wait().unwrap(), Phase::Draft);
+
+    phases.add_public(&foo).wait().unwrap();
+    phases.add_public(&bar).wait().unwrap();
+    phases.add_public(&bar).wait().unwrap(); // Making a public key public again is a no-op.
+
+    assert_eq!(phases.get_phase(&foo).wait().unwrap(), Phase::Public);
+    assert_eq!(phases.get_phase(&bar).wait().unwrap(), Phase::Public);
+    assert_eq!(phases.get_phase(&baz).wait().unwrap(), Phase::Draft);
+
+    let mut result = phases.public().collect().wait().unwrap();
+    result.sort();
+    assert_eq!(result, vec![bar, foo]);
+}
+
+fn persistence<F, P>(mut new_phases: F)
+where
+    F: FnMut() -> P,
+    P: Phases<Key = String>,
+{
+    let foo = "foo".to_string();
+
+    {
+        let phases = new_phases();
+        phases.add_public(&foo).wait().unwrap();
+    }
+
+    let phases = new_phases();
+    assert_eq!(phases.get_phase(&foo).wait().unwrap(), Phase::Public);
+    assert_eq!(phases.public().collect().wait().unwrap(), vec![foo]);
+}
+
+fn save_node_hash<P>(phases: P)
+where
+    P: Phases<Key = NodeHash>,
+{
+    phases.add_public(&ONES_HASH).wait().unwrap();
+    assert_eq!(phases.get_phase(&ONES_HASH).wait().unwrap(), Phase::Public);
+    assert_eq!(phases.get_phase(&TWOS_HASH).wait().unwrap(), Phase::Draft);
+    assert_eq!(phases.public().collect().wait().unwrap(), vec![ONES_HASH]);
+}
+
+macro_rules! phases_test_impl {
+    ($mod_name: ident => {
+        state: $state: expr,
+        new: $new_cb: expr,
+        persistent: $persistent: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_basic() {
+                let state = $state;
+                basic($new_cb(&state));
+            }
+
+            #[test]
+            fn test_save_node_hash() {
+                let state = $state;
+                save_node_hash($new_cb(&state));
+            }
+
+            #[test]
+            fn test_persistence() {
+                // Not all phases implementations support persistence.
+                if $persistent {
+                    let state = $state;
+                    persistence(|| $new_cb(&state));
+                }
+            }
+        }
+    }
+}
+
+phases_test_impl! {
+    memphases_test => {
+        state: (),
+        new: |_| MemPhases::new(),
+        persistent: false,
+    }
+}
+
+phases_test_impl! {
+    filephases_test => {
+        state: TempDir::new("filephases_test").unwrap(),
+        new: |dir| FilePhases::open(&dir).unwrap(),
+        persistent: true,
+    }
+}
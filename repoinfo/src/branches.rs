@@ -0,0 +1,391 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Compute the heads of named branches
+//!
+//! A changeset is a head of its named branch if none of its children are on the same branch.
+//! Changesets which are on a branch but aren't heads of it don't appear in the result.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, Future, Loop};
+use futures::stream::{self, Stream};
+
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{NodeHash, Repo};
+
+// Number of changesets fetched concurrently
+const FETCH_CONCURRENCY: usize = 100;
+
+/// The heads of a single named branch
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BranchHeads {
+    /// Heads which are still open
+    pub open: HashSet<NodeHash>,
+    /// Heads which were committed with the `close` extra set
+    pub closed: HashSet<NodeHash>,
+}
+
+impl BranchHeads {
+    /// All the heads of the branch, both open and closed.
+    pub fn heads(&self) -> HashSet<NodeHash> {
+        self.open.union(&self.closed).cloned().collect()
+    }
+
+    /// A branch is closed once all of its heads are closed.
+    pub fn is_closed(&self) -> bool {
+        self.open.is_empty()
+    }
+}
+
+// The parts of a changeset which determine branch heads
+struct BranchInfo {
+    branch: Vec<u8>,
+    closed: bool,
+    parents: Vec<NodeHash>,
+}
+
+/// Compute the heads of every named branch in `repo`, keyed by branch name.
+///
+/// This needs every changeset in the repo, so it's as expensive as walking the whole history.
+/// Use `BranchHeadsCache` to avoid doing that more than once.
+pub fn branch_heads<R>(repo: &Arc<R>) -> BoxFuture<HashMap<Vec<u8>, BranchHeads>, R::Error>
+where
+    R: Repo,
+{
+    all_changesets(repo)
+        .map(|changesets| {
+            let mut state = BranchState::default();
+            state.add(changesets);
+            state.heads
+        })
+        .boxify()
+}
+
+/// Branch heads which are kept up to date as changesets are added to a repo.
+///
+/// The first lookup walks the whole history, like `branch_heads`. Later ones only fetch the
+/// changesets which have been added since, by walking back from the repo's heads until they
+/// reach changesets which have been seen before. A cache must only be used with one repo.
+#[derive(Default)]
+pub struct BranchHeadsCache {
+    state: Arc<Mutex<BranchState>>,
+}
+
+impl BranchHeadsCache {
+    /// Construct a new, empty cache.
+    pub fn new() -> Self {
+        BranchHeadsCache::default()
+    }
+
+    /// Compute the heads of every named branch in `repo`, keyed by branch name.
+    pub fn branch_heads<R>(
+        &self,
+        repo: &Arc<R>,
+    ) -> BoxFuture<HashMap<Vec<u8>, BranchHeads>, R::Error>
+    where
+        R: Repo,
+    {
+        let state = self.state.clone();
+        let is_empty = state.lock().expect("lock poisoned").changesets.is_empty();
+
+        let changesets = if is_empty {
+            all_changesets(repo)
+        } else {
+            let repo = repo.clone();
+            let state = state.clone();
+            repo.get_heads()
+                .collect()
+                .and_then(move |heads| new_changesets(repo, state, heads))
+                .boxify()
+        };
+
+        changesets
+            .map(move |changesets| {
+                let mut state = state.lock().expect("lock poisoned");
+                state.add(changesets);
+                state.heads.clone()
+            })
+            .boxify()
+    }
+}
+
+// The branch heads of the changesets seen so far
+#[derive(Default)]
+struct BranchState {
+    changesets: HashMap<NodeHash, BranchInfo>,
+    heads: HashMap<Vec<u8>, BranchHeads>,
+}
+
+impl BranchState {
+    // Add changesets whose parents have either been added already or are among them. Those
+    // which have been added already are skipped.
+    fn add(&mut self, changesets: Vec<(NodeHash, BranchInfo)>) {
+        let new: HashMap<_, _> = changesets
+            .into_iter()
+            .filter(|&(ref nodeid, _)| !self.changesets.contains_key(nodeid))
+            .collect();
+
+        // Changesets with a child on the same branch can't be heads of it
+        let mut superseded = HashSet::new();
+        for info in new.values() {
+            for parent in &info.parents {
+                let same_branch = new.get(parent)
+                    .or_else(|| self.changesets.get(parent))
+                    .map(|pinfo| pinfo.branch == info.branch)
+                    .unwrap_or(false);
+                if same_branch {
+                    superseded.insert(*parent);
+                }
+            }
+        }
+
+        // Only the new changesets' parents can stop being heads, since every child of an
+        // earlier changeset is either earlier too or new
+        for parent in &superseded {
+            if let Some(pinfo) = self.changesets.get(parent) {
+                if let Some(heads) = self.heads.get_mut(&pinfo.branch) {
+                    heads.open.remove(parent);
+                    heads.closed.remove(parent);
+                }
+            }
+        }
+
+        for (nodeid, info) in new {
+            if !superseded.contains(&nodeid) {
+                let heads = self.heads
+                    .entry(info.branch.clone())
+                    .or_insert_with(BranchHeads::default);
+                if info.closed {
+                    heads.closed.insert(nodeid);
+                } else {
+                    heads.open.insert(nodeid);
+                }
+            }
+            self.changesets.insert(nodeid, info);
+        }
+    }
+}
+
+fn all_changesets<R>(repo: &Arc<R>) -> BoxFuture<Vec<(NodeHash, BranchInfo)>, R::Error>
+where
+    R: Repo,
+{
+    repo.get_changesets()
+        .map({
+            let repo = repo.clone();
+            move |nodeid| branch_info(&repo, nodeid)
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .collect()
+        .boxify()
+}
+
+// Fetch the ancestors of `heads` which haven't been added to `state`
+fn new_changesets<R>(
+    repo: Arc<R>,
+    state: Arc<Mutex<BranchState>>,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<Vec<(NodeHash, BranchInfo)>, R::Error>
+where
+    R: Repo,
+{
+    let mut seen = HashSet::new();
+    let pending = unseen(&state, &mut seen, heads);
+
+    future::loop_fn(
+        (pending, seen, Vec::new()),
+        move |(pending, mut seen, mut found)| {
+            if pending.is_empty() {
+                return future::ok(Loop::Break(found)).boxify();
+            }
+
+            let state = state.clone();
+            stream::iter_ok::<_, R::Error>(pending)
+                .map({
+                    let repo = repo.clone();
+                    move |nodeid| branch_info(&repo, nodeid)
+                })
+                .buffer_unordered(FETCH_CONCURRENCY)
+                .collect()
+                .map(move |infos| {
+                    let parents = infos
+                        .iter()
+                        .flat_map(|&(_, ref info)| info.parents.iter().cloned())
+                        .collect();
+                    let pending = unseen(&state, &mut seen, parents);
+                    found.extend(infos);
+                    Loop::Continue((pending, seen, found))
+                })
+                .boxify()
+        },
+    ).boxify()
+}
+
+// The nodes which haven't been seen on this walk or added to `state` before
+fn unseen(
+    state: &Mutex<BranchState>,
+    seen: &mut HashSet<NodeHash>,
+    nodes: Vec<NodeHash>,
+) -> Vec<NodeHash> {
+    let state = state.lock().expect("lock poisoned");
+    nodes
+        .into_iter()
+        .filter(|node| !state.changesets.contains_key(node) && seen.insert(*node))
+        .collect()
+}
+
+fn branch_info<R>(repo: &Arc<R>, nodeid: NodeHash) -> BoxFuture<(NodeHash, BranchInfo), R::Error>
+where
+    R: Repo,
+{
+    repo.get_changeset_by_nodeid(&nodeid)
+        .map(move |cs| {
+            let info = BranchInfo {
+                branch: cs.branch().to_vec(),
+                closed: cs.closes_branch(),
+                parents: cs.parents().into_iter().collect(),
+            };
+            (nodeid, info)
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use branch_even;
+    use linear;
+
+    use super::*;
+
+    fn node(hex: &str) -> NodeHash {
+        NodeHash::from_str(hex).unwrap()
+    }
+
+    fn info(branch: &str, closed: bool, parents: &[NodeHash]) -> BranchInfo {
+        BranchInfo {
+            branch: branch.as_bytes().to_vec(),
+            closed,
+            parents: parents.to_vec(),
+        }
+    }
+
+    fn branch(open: &[NodeHash], closed: &[NodeHash]) -> BranchHeads {
+        BranchHeads {
+            open: open.iter().cloned().collect(),
+            closed: closed.iter().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn named_branches() {
+        let a = node("1111111111111111111111111111111111111111");
+        let b = node("2222222222222222222222222222222222222222");
+        let c = node("3333333333333333333333333333333333333333");
+        let d = node("4444444444444444444444444444444444444444");
+        let e = node("5555555555555555555555555555555555555555");
+
+        // a - b (default)
+        //   \ c (stable) - d (stable, closed)
+        //   \ e (default)
+        let mut state = BranchState::default();
+        state.add(vec![
+            (a, info("default", false, &[])),
+            (b, info("default", false, &[a])),
+            (c, info("stable", false, &[a])),
+            (d, info("stable", true, &[c])),
+            (e, info("default", false, &[a])),
+        ]);
+
+        assert_eq!(state.heads.len(), 2);
+        assert_eq!(state.heads[&b"default"[..]], branch(&[b, e], &[]));
+        assert_eq!(state.heads[&b"stable"[..]], branch(&[], &[d]));
+        assert!(state.heads[&b"stable"[..]].is_closed());
+    }
+
+    #[test]
+    fn incremental() {
+        let a = node("1111111111111111111111111111111111111111");
+        let b = node("2222222222222222222222222222222222222222");
+        let c = node("3333333333333333333333333333333333333333");
+        let d = node("4444444444444444444444444444444444444444");
+
+        let mut state = BranchState::default();
+        state.add(vec![
+            (a, info("default", false, &[])),
+            (b, info("stable", false, &[a])),
+        ]);
+        assert_eq!(state.heads[&b"default"[..]], branch(&[a], &[]));
+
+        // A merge of both branches onto default, and a changeset which was added already
+        state.add(vec![
+            (c, info("default", false, &[a, b])),
+            (b, info("stable", false, &[a])),
+            (d, info("stable", true, &[b])),
+        ]);
+        assert_eq!(state.heads.len(), 2);
+        assert_eq!(state.heads[&b"default"[..]], branch(&[c], &[]));
+        assert_eq!(state.heads[&b"stable"[..]], branch(&[], &[d]));
+    }
+
+    #[test]
+    fn fixture_heads() {
+        let repo = Arc::new(branch_even::getrepo());
+        let heads = branch_heads(&repo).wait().unwrap();
+
+        assert_eq!(heads.len(), 1);
+        assert_eq!(
+            heads[&b"default"[..]],
+            branch(
+                &[
+                    node("16839021e338500b3cf7c9b871c8a07351697d68"),
+                    node("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                ],
+                &[],
+            )
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let repo = Arc::new(linear::getrepo());
+        let cache = BranchHeadsCache::new();
+        let expected = branch_heads(&repo).wait().unwrap();
+
+        assert_eq!(cache.branch_heads(&repo).wait().unwrap(), expected);
+        // Nothing has changed, so this only looks at the repo's heads
+        assert_eq!(cache.branch_heads(&repo).wait().unwrap(), expected);
+    }
+
+    #[test]
+    fn cache_walks_new_changesets() {
+        let repo = Arc::new(linear::getrepo());
+        let cache = BranchHeadsCache::new();
+        let head = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+
+        // Seed the cache with just the root, as if the rest was added since
+        let root = repo.get_changesets()
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|nodeid| branch_info(&repo, nodeid).wait().unwrap())
+            .find(|&(_, ref info)| info.parents.is_empty())
+            .unwrap();
+        cache.state.lock().unwrap().add(vec![root]);
+
+        let heads = cache.branch_heads(&repo).wait().unwrap();
+        assert_eq!(heads.len(), 1);
+        assert_eq!(heads[&b"default"[..]], branch(&[head], &[]));
+        assert_eq!(
+            cache.state.lock().unwrap().changesets.len(),
+            repo.get_changesets().collect().wait().unwrap().len()
+        );
+    }
+}
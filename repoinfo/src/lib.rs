@@ -6,8 +6,9 @@
 
 //! Crate to obtain derived information about repos and changesets within a repo
 //!
-//! Currently this just provides `RepoGenCache` which lazily computes generation numbers
-//! for changesets within a repo.
+//! This provides `RepoGenCache` which lazily computes generation numbers for changesets within
+//! a repo, and `branch_heads` and `BranchHeadsCache` which compute the heads of each named
+//! branch.
 #![deny(warnings)]
 #![deny(missing_docs)]
 #![feature(conservative_impl_trait)]
@@ -21,10 +22,17 @@ extern crate heapsize_derive;
 extern crate futures_ext;
 extern crate mercurial_types;
 
+#[cfg(test)]
+extern crate branch_even;
+#[cfg(test)]
+extern crate linear;
+
+mod branches;
 mod gen;
 mod nodehashkey;
 mod ptrwrap;
 
 pub use ptrwrap::PtrWrap;
 
+pub use branches::{branch_heads, BranchHeads, BranchHeadsCache};
 pub use gen::{Generation, RepoGenCache};
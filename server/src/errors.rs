@@ -6,6 +6,10 @@
 
 use bytes::Bytes;
 
+use mercurial_bundles::changegroup::RevFlags;
+use mercurial_bundles::parts::PushkeyFailure;
+use mercurial_types::NodeHash;
+
 #[recursion_limit = "1024"]
 error_chain! {
     errors {
@@ -13,12 +17,56 @@ error_chain! {
             description("failed to initialize server")
             display("{}", msg)
         }
+        ReadOnlyRepo {
+            description("repo does not accept pushes")
+        }
+        UnknownRevision(key: String) {
+            description("unknown revision")
+            display("unknown revision '{}'", key)
+        }
+        AmbiguousRevision(key: String) {
+            description("ambiguous identifier")
+            display("ambiguous identifier '{}'", key)
+        }
+        InvalidBundle(msg: String) {
+            description("invalid bundle")
+            display("invalid bundle: {}", msg)
+        }
+        UnsupportedContent(part_type: String, params: Vec<String>) {
+            description("unsupported bundle content")
+            display("unsupported bundle part '{}' (params: {})", part_type, params.join(", "))
+        }
+        InvalidDelta(node: NodeHash, base: NodeHash) {
+            description("delta does not apply to its base")
+            display("delta for {} does not apply to its base {}", node, base)
+        }
+        NodeHashMismatch(expected: NodeHash, computed: NodeHash) {
+            description("revision does not match its hash")
+            display("revision {} hashes to {}", expected, computed)
+        }
+        NoCommonChangegroupVersion(versions: Vec<String>) {
+            description("no common changegroup version")
+            display("no common changegroup version (client supports: {})", versions.join(", "))
+        }
+        UnsupportedRevFlags(node: NodeHash, flags: RevFlags) {
+            description("revision has unsupported flags")
+            display("revision {} has unsupported flags {:?}", node, flags)
+        }
+        PushRaced(msg: String) {
+            description("repo changed during push")
+            display("{}", msg)
+        }
+        PushkeyFailed(part_id: u32, failure: PushkeyFailure) {
+            description("pushkey failed")
+            display("pushkey in part {} failed: {:?}", part_id, failure)
+        }
     }
 
     links {
         Blobrepo(::blobrepo::Error, ::blobrepo::ErrorKind);
         HgProto(::hgproto::Error, ::hgproto::ErrorKind);
         Mercurial(::mercurial::Error, ::mercurial::ErrorKind);
+        MercurialBundles(::mercurial_bundles::Error, ::mercurial_bundles::ErrorKind);
         MercurialTypes(::mercurial_types::Error, ::mercurial_types::ErrorKind);
         Metaconfig(::metaconfig::Error, ::metaconfig::ErrorKind);
     }
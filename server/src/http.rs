@@ -0,0 +1,155 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Serve repos over Mercurial's HTTP wire protocol
+//!
+//! Each repo is at `/<repo name>`, so that a client can clone `http://<host>/<repo name>`.
+//! Every request is independent, which lets the server sit behind a plain HTTP load balancer.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use hyper::{self, StatusCode};
+use hyper::header::Headers;
+use hyper::server::{Http, Request, Response, Service};
+use slog::Logger;
+
+use hgproto::{self, HgCommandHandler};
+use hgproto::httpproto::{self, MediaTypes};
+
+use errors::*;
+use repo::{HgRepo, RepoClient, Transport};
+
+struct HgHttpService {
+    repos: Arc<HashMap<String, Arc<HgRepo>>>,
+    logger: Logger,
+}
+
+impl Service for HgHttpService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let reponame = req.path().trim_matches('/').to_owned();
+        let repo = match self.repos.get(&reponame) {
+            Some(repo) => repo.clone(),
+            None => {
+                let msg = format!("unknown repo '{}'", reponame);
+                return future::ok(error_response(StatusCode::NotFound, msg)).boxify();
+            }
+        };
+
+        let query = req.query().unwrap_or("").as_bytes().to_vec();
+        let hgargs = numbered_headers(req.headers(), "X-HgArg");
+        let accepts = MediaTypes::parse(&numbered_headers(req.headers(), "X-HgProto"));
+        let logger = self.logger.new(o!("repo" => reponame));
+
+        req.body()
+            .concat2()
+            .and_then(move |body| {
+                match httpproto::request::parse(&query, &hgargs, Bytes::from(&*body)) {
+                    Ok(hgreq) => run_command(repo, hgreq, accepts, logger),
+                    Err(err) => {
+                        let msg = format!("{}", err);
+                        future::ok(error_response(StatusCode::BadRequest, msg)).boxify()
+                    }
+                }
+            })
+            .boxify()
+    }
+}
+
+fn run_command(
+    repo: Arc<HgRepo>,
+    hgreq: hgproto::Request,
+    accepts: MediaTypes,
+    logger: Logger,
+) -> BoxFuture<Response, hyper::Error> {
+    let client = RepoClient::new(repo, Transport::Http, &logger);
+    let handler = HgCommandHandler::new(client, logger.clone());
+
+    handler
+        .handle(hgreq)
+        .then(move |res| {
+            let resp = match res {
+                Ok(res) => {
+                    let (content_type, body) = httpproto::response::encode(&res, &accepts);
+                    let mut resp = Response::new();
+                    resp.headers_mut().set_raw("Content-Type", content_type);
+                    resp.set_body(body.to_vec());
+                    resp
+                }
+                Err(err) => {
+                    let err = Error::from(err);
+                    let msg = format!("{}", err);
+                    error!(logger, "Command failed"; err);
+                    error_response(StatusCode::InternalServerError, msg)
+                }
+            };
+            Ok(resp)
+        })
+        .boxify()
+}
+
+// Mercurial splits values which are too long for a single header across `<prefix>-1`,
+// `<prefix>-2`, etc, so put them back together
+fn numbered_headers(headers: &Headers, prefix: &str) -> Vec<u8> {
+    let mut res = Vec::new();
+    for idx in 1.. {
+        match headers.get_raw(&format!("{}-{}", prefix, idx)) {
+            Some(raw) => for line in raw.iter() {
+                res.extend_from_slice(line);
+            },
+            None => break,
+        }
+    }
+    res
+}
+
+fn error_response(status: StatusCode, msg: String) -> Response {
+    let mut resp = Response::new();
+    resp.set_status(status);
+    resp.set_body(msg);
+    resp
+}
+
+/// Start a thread serving `repos`, keyed by name, over HTTP on `addr`.
+pub fn start_http_listener(
+    addr: SocketAddr,
+    repos: HashMap<String, Arc<HgRepo>>,
+    root_log: &Logger,
+) -> Result<JoinHandle<!>> {
+    let logger = root_log.new(o!("listener" => "http"));
+    info!(logger, "Serving repos over HTTP on {}", addr);
+
+    Ok(thread::Builder::new()
+        .name("http_listener".to_owned())
+        .spawn(move || {
+            let repos = Arc::new(repos);
+            let new_service = move || {
+                Ok(HgHttpService {
+                    repos: repos.clone(),
+                    logger: logger.clone(),
+                })
+            };
+
+            Http::new()
+                .bind(&addr, new_service)
+                .expect("failed to bind http listener")
+                .run()
+                .expect("failure while running http listener");
+
+            // The server only returns if it fails
+            unreachable!()
+        })?)
+}
@@ -15,6 +15,7 @@ extern crate tokio_io;
 extern crate tokio_uds;
 
 extern crate clap;
+extern crate hyper;
 
 #[macro_use]
 extern crate error_chain;
@@ -33,23 +34,37 @@ extern crate maplit;
 
 extern crate async_compression;
 extern crate blobrepo;
+extern crate blobstore;
+extern crate bundle_generator;
 extern crate bytes;
 extern crate hgproto;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
 extern crate metaconfig;
+extern crate phases;
+extern crate repoinfo;
 extern crate services;
 extern crate sshrelay;
 extern crate stats;
+extern crate storage_types;
+
+#[cfg(test)]
+extern crate linear;
+#[cfg(test)]
+extern crate merge_uneven;
 
 mod errors;
+mod http;
 mod repo;
 mod listener;
+mod push;
 
+use std::collections::HashMap;
 use std::io;
+use std::net::SocketAddr;
 use std::panic;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
@@ -68,7 +83,7 @@ use slog_logview::LogViewDrain;
 use bytes::Bytes;
 use hgproto::{sshproto, HgProtoHandler};
 use metaconfig::RepoConfigs;
-use metaconfig::repoconfig::RepoType;
+use metaconfig::repoconfig::{RepoConfig, RepoType};
 
 use errors::*;
 
@@ -116,6 +131,7 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
             [crhash]      -C, --configrepo_hash [HASH]           'config repo commit hash'
 
             -p, --thrift_port [PORT] 'if provided the thrift server will start on this port'
+            --http_listen [ADDR]     'if provided repos are also served over HTTP on this address'
 
             -d, --debug                                          'print debug level output'
         "#,
@@ -211,17 +227,33 @@ fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfi
         .wait()
 }
 
-fn start_repo_listeners<I>(repos: I, root_log: &Logger) -> Result<Vec<JoinHandle<!>>>
+fn start_http_listener<'a>(
+    repos: &[(String, PathBuf, Arc<repo::HgRepo>)],
+    root_log: &Logger,
+    matches: &ArgMatches<'a>,
+) -> Option<Result<JoinHandle<!>>> {
+    matches.value_of("http_listen").map(|addr| {
+        let addr: SocketAddr = addr.parse()
+            .chain_err(|| format!("Failed to parse http_listen address {}", addr))?;
+        let repos: HashMap<_, _> = repos
+            .iter()
+            .map(|&(ref name, _, ref repo)| (name.clone(), repo.clone()))
+            .collect();
+
+        http::start_http_listener(addr, repos, root_log)
+    })
+}
+
+fn init_repos<I>(repos: I, root_log: &Logger) -> Result<Vec<(String, PathBuf, Arc<repo::HgRepo>)>>
 where
-    I: IntoIterator<Item = RepoType>,
+    I: IntoIterator<Item = (String, RepoConfig)>,
 {
-    // Given the list of paths to repos:
-    // - initialize the repo
-    // - create a thread for it
-    // - wait for connections in that thread
     let repos: Vec<_> = repos
         .into_iter()
-        .map(|repotype| repo::init_repo(root_log, &repotype))
+        .map(|(name, config)| {
+            repo::init_repo(root_log, &config)
+                .map(|(sockname, repo)| (name, sockname, Arc::new(repo)))
+        })
         .collect();
 
     if repos.iter().any(Result::is_err) {
@@ -233,10 +265,21 @@ where
         ));
     }
 
+    Ok(repos.into_iter().filter_map(Result::ok).collect())
+}
+
+fn start_repo_listeners(
+    repos: &[(String, PathBuf, Arc<repo::HgRepo>)],
+    root_log: &Logger,
+) -> Result<Vec<JoinHandle<!>>> {
+    // Given the initialized repos:
+    // - create a thread for each
+    // - wait for connections in that thread
     let handles: Vec<_> = repos
-        .into_iter()
-        .filter_map(Result::ok)
-        .map(move |(sockname, repo)| {
+        .iter()
+        .map(move |&(_, ref sockname, ref repo)| {
+            let sockname = sockname.clone();
+            let repo = repo.clone();
             let listen_log = root_log.new(o!("repo" => repo.path().clone()));
             // start a thread for each repo to own the reactor and start listening for
             // connections and detach it
@@ -260,13 +303,12 @@ where
 }
 
 // Listener thread for a specific repo
-fn repo_listen<P>(sockname: P, repo: repo::HgRepo, listen_log: Logger) -> !
+fn repo_listen<P>(sockname: P, repo: Arc<repo::HgRepo>, listen_log: Logger) -> !
 where
     P: AsRef<Path>,
 {
     let mut core = tokio_core::reactor::Core::new().expect("failed to create tokio core");
     let handle = core.handle();
-    let repo = Arc::new(repo);
 
     let server = listener::listener(sockname, &handle)
         .expect("failed to create listener")
@@ -302,7 +344,7 @@ where
             // Construct a hg protocol handler
             let proto_handler = HgProtoHandler::new(
                 stdin,
-                repo::RepoClient::new(repo.clone(), &conn_log),
+                repo::RepoClient::new(repo.clone(), repo::Transport::Ssh, &conn_log),
                 sshproto::HgSshCommandDecode,
                 sshproto::HgSshCommandEncode,
                 &conn_log,
@@ -353,13 +395,18 @@ fn main() {
         };
 
         let config = get_config(root_log, &matches)?;
-        let repo_listeners =
-            start_repo_listeners(config.repos.into_iter().map(|(_, c)| c.repotype), root_log)?;
+        let repos = init_repos(config.repos, root_log)?;
+        let repo_listeners = start_repo_listeners(&repos, root_log)?;
+        let maybe_http = match start_http_listener(&repos, root_log, &matches) {
+            None => None,
+            Some(handle) => Some(handle?),
+        };
 
         for handle in vec![stats_aggregation]
             .into_iter()
             .chain(maybe_thrift.into_iter())
             .chain(repo_listeners.into_iter())
+            .chain(maybe_http.into_iter())
         {
             let thread_name = handle.thread().name().unwrap_or("unknown").to_owned();
             match handle.join() {
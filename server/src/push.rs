@@ -0,0 +1,974 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Applying the changegroups that clients push to a repo
+
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, stream, Async, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use blobrepo::{BlobRepo, BlobState};
+use blobstore::Blobstore;
+use mercurial::changeset::serialize_cs;
+use mercurial_bundles::{Bundle2Item, Error as BundleError, ErrorKind as BundleErrorKind,
+                        InnerPart};
+use mercurial_bundles::bundle2::Bundle2Stream;
+use mercurial_bundles::changegroup::{CgDeltaChunk, Part, Section};
+use mercurial_bundles::part_types::Pushkey;
+use mercurial_bundles::parts::PushkeyFailure;
+use mercurial_types::{delta, BlobNode, MPath, NodeHash, Parents, Repo, RepoPath, NULL_HASH};
+use mercurial_types::hash::{Context, Sha1};
+use phases::Phase;
+use storage_types::Version;
+
+use errors::*;
+use repo::PhasesRepo;
+
+/// Write access to a repo, which is needed to accept pushes. Only blob repos support this.
+pub trait PushRepo: Send + Sync + 'static {
+    /// Get the text of an existing changeset, to use as a delta base.
+    fn get_changeset_text(&self, nodeid: &NodeHash) -> BoxFuture<Vec<u8>, Error>;
+    /// Get the text of an existing manifest or file node, to use as a delta base.
+    fn get_node_text(&self, nodeid: &NodeHash) -> BoxFuture<Vec<u8>, Error>;
+
+    /// Whether the repo already has a changeset.
+    fn changeset_exists(&self, nodeid: &NodeHash) -> BoxFuture<bool, Error>;
+    fn put_changeset(&self, nodeid: &NodeHash, node: BlobNode) -> BoxFuture<(), Error>;
+    fn put_node(&self, nodeid: &NodeHash, parents: Parents, data: Vec<u8>)
+        -> BoxFuture<(), Error>;
+    fn add_linknode(
+        &self,
+        path: RepoPath,
+        nodeid: &NodeHash,
+        linknode: &NodeHash,
+    ) -> BoxFuture<(), Error>;
+    /// The version of the heads, which changes whenever they do.
+    fn heads_version(&self) -> BoxFuture<Version, Error>;
+    /// Add and remove heads in one step, if they're still at `version`. Returns the new
+    /// version, or `None` if the heads have changed since, in which case they're left alone.
+    fn update_heads(
+        &self,
+        add: &[NodeHash],
+        remove: &[NodeHash],
+        version: &Version,
+    ) -> BoxFuture<Option<Version>, Error>;
+    /// Make a changeset and all its ancestors public.
+    fn make_public(&self, nodeid: &NodeHash) -> BoxFuture<(), Error>;
+
+    /// Get the current value of a bookmark, or `None` if it doesn't exist.
+    fn get_bookmark(&self, key: &[u8]) -> BoxFuture<Option<NodeHash>, Error>;
+    /// Atomically move a bookmark from `old` to `new`, returning false if it no longer has the
+    /// value `old`. `None` means the bookmark doesn't exist.
+    fn update_bookmark(
+        &self,
+        key: Vec<u8>,
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<bool, Error>;
+}
+
+impl<State> PushRepo for BlobRepo<State>
+where
+    State: BlobState,
+    <State::Blobstore as Blobstore>::ValueIn: From<Vec<u8>>,
+{
+    fn get_changeset_text(&self, nodeid: &NodeHash) -> BoxFuture<Vec<u8>, Error> {
+        self.get_changeset_by_nodeid(nodeid)
+            .from_err()
+            .and_then(|cs| {
+                let mut data = Vec::new();
+                serialize_cs(&*cs, &mut data)?;
+                Ok(data)
+            })
+            .boxify()
+    }
+
+    fn get_node_text(&self, nodeid: &NodeHash) -> BoxFuture<Vec<u8>, Error> {
+        BlobRepo::get_node_text(self, nodeid).from_err().boxify()
+    }
+
+    fn changeset_exists(&self, nodeid: &NodeHash) -> BoxFuture<bool, Error> {
+        Repo::changeset_exists(self, nodeid).from_err().boxify()
+    }
+
+    fn put_changeset(&self, nodeid: &NodeHash, node: BlobNode) -> BoxFuture<(), Error> {
+        BlobRepo::put_changeset(self, nodeid, node)
+            .from_err()
+            .boxify()
+    }
+
+    fn put_node(
+        &self,
+        nodeid: &NodeHash,
+        parents: Parents,
+        data: Vec<u8>,
+    ) -> BoxFuture<(), Error> {
+        BlobRepo::put_node(self, nodeid, parents, data)
+            .from_err()
+            .boxify()
+    }
+
+    fn add_linknode(
+        &self,
+        path: RepoPath,
+        nodeid: &NodeHash,
+        linknode: &NodeHash,
+    ) -> BoxFuture<(), Error> {
+        BlobRepo::add_linknode(self, path, nodeid, linknode)
+            .from_err()
+            .boxify()
+    }
+
+    fn heads_version(&self) -> BoxFuture<Version, Error> {
+        BlobRepo::heads_version(self).from_err().boxify()
+    }
+
+    fn update_heads(
+        &self,
+        add: &[NodeHash],
+        remove: &[NodeHash],
+        version: &Version,
+    ) -> BoxFuture<Option<Version>, Error> {
+        BlobRepo::update_heads_if(self, add, remove, version)
+            .from_err()
+            .boxify()
+    }
+
+    fn make_public(&self, nodeid: &NodeHash) -> BoxFuture<(), Error> {
+        BlobRepo::make_public(self, nodeid).from_err().boxify()
+    }
+
+    fn get_bookmark(&self, key: &[u8]) -> BoxFuture<Option<NodeHash>, Error> {
+        let bookmarks = match self.get_bookmarks() {
+            Ok(bookmarks) => bookmarks,
+            Err(err) => return future::err(err.into()).boxify(),
+        };
+        bookmarks
+            .get(&key)
+            .map(|bookmark| bookmark.map(|(node, _)| node))
+            .from_err()
+            .boxify()
+    }
+
+    fn update_bookmark(
+        &self,
+        key: Vec<u8>,
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<bool, Error> {
+        BlobRepo::update_bookmark(self, key, old, new)
+            .from_err()
+            .boxify()
+    }
+}
+
+/// The changegroup part of a bundle2 pushed by a client.
+pub struct PushedChangegroup {
+    /// The id of the bundle2 part the changegroup came in, which the reply refers to.
+    pub part_id: u32,
+    pub parts: Vec<Part>,
+}
+
+/// The parts of a bundle2 pushed by a client which are acted on.
+pub struct PushedBundle {
+    pub changegroup: Option<PushedChangegroup>,
+    /// What the client expects the repo's heads to be, from `check:heads` and
+    /// `check:updated-heads` parts
+    pub heads_checks: Vec<HeadsCheck>,
+    /// The changesets to make public along with their ancestors, from `phase-heads` parts
+    pub public_heads: Vec<NodeHash>,
+    /// The keys to update once the changegroup has been applied, in the order they were sent
+    pub pushkeys: Vec<PushedPushkey>,
+}
+
+/// A `pushkey` part of a bundle2 pushed by a client.
+pub struct PushedPushkey {
+    /// The id of the part, which the reply refers to.
+    pub part_id: u32,
+    /// Whether the push fails if the update does
+    pub mandatory: bool,
+    pub pushkey: Pushkey,
+}
+
+/// A check that the repo's heads haven't moved since the client looked at them, so that a
+/// push isn't applied on top of another one the client doesn't know about.
+#[derive(Clone, Debug)]
+pub enum HeadsCheck {
+    /// The heads must be exactly these.
+    Exact(Vec<NodeHash>),
+    /// The heads must hash to this: the SHA-1 of their sorted binary hashes, concatenated.
+    Hashed(Sha1),
+    /// These must all still be heads. Other heads may have come and gone.
+    Contains(Vec<NodeHash>),
+}
+
+impl HeadsCheck {
+    /// Whether `heads` are still what the client expected them to be.
+    pub fn check(&self, heads: &HashSet<NodeHash>) -> bool {
+        // Mercurial considers the null revision the only head of an empty repo
+        let mut heads: Vec<_> = heads.iter().cloned().collect();
+        if heads.is_empty() {
+            heads.push(NULL_HASH);
+        }
+        heads.sort();
+
+        match self {
+            &HeadsCheck::Exact(ref expected) => {
+                let mut expected = expected.clone();
+                expected.sort();
+                expected.dedup();
+                expected == heads
+            }
+            &HeadsCheck::Hashed(ref expected) => {
+                let mut context = Context::new();
+                for head in &heads {
+                    context.update(head.sha1());
+                }
+                &context.finish() == expected
+            }
+            &HeadsCheck::Contains(ref expected) => expected
+                .iter()
+                .all(|head| heads.binary_search(head).is_ok()),
+        }
+    }
+}
+
+/// Decode a bundle2 sent with `unbundle`, and extract the changegroup and heads checks from it.
+///
+/// The whole bundle is already in memory, so it's decoded in one go once the future is polled.
+pub fn decode_bundle(bundle: Bytes, logger: Logger) -> BoxFuture<PushedBundle, Error> {
+    future::lazy(move || -> Result<_> {
+        let mut stream = Bundle2Stream::new(Cursor::new(bundle), logger);
+        let mut items = Vec::new();
+        loop {
+            match stream.poll()? {
+                Async::Ready(Some(item)) => items.push(item),
+                Async::Ready(None) => break,
+                // Reading from memory never has to wait for more data to arrive
+                Async::NotReady => bail!(ErrorKind::InvalidBundle("bundle is truncated".into())),
+            }
+        }
+        pushed_bundle(items, stream.app_errors())
+    }).boxify()
+}
+
+// Pick out the parts of a decoded bundle which are acted on
+fn pushed_bundle(items: Vec<Bundle2Item>, app_errors: &[BundleError]) -> Result<PushedBundle> {
+    let mut part_id = None;
+    let mut parts = Vec::new();
+    let mut heads_checks = Vec::new();
+    let mut public_heads = Vec::new();
+    let mut pushkeys = Vec::new();
+    // The header of the pushkey part whose contents come next
+    let mut pushkey_header = None;
+
+    // Parts that aren't understood are skipped while decoding, but the client needs to know
+    // if any of them were mandatory
+    for err in app_errors {
+        match err.kind() {
+            &BundleErrorKind::BundleUnknownPart(ref header) => bail!(
+                ErrorKind::UnsupportedContent(header.part_type_lower().to_string(), vec![])
+            ),
+            &BundleErrorKind::BundleUnknownPartParams(ref part_type, ref params) => bail!(
+                ErrorKind::UnsupportedContent(part_type.to_string(), params.clone())
+            ),
+            _ => {}
+        }
+    }
+
+    for item in items {
+        match item {
+            Bundle2Item::Start(_) => {}
+            Bundle2Item::Header(header) => match header.part_type_lower().as_str() {
+                "changegroup" => {
+                    if part_id.is_some() {
+                        bail!(ErrorKind::InvalidBundle(
+                            "more than one changegroup part".into()
+                        ));
+                    }
+                    part_id = Some(header.part_id());
+                }
+                "check:heads" | "check:updated-heads" | "phase-heads" => {}
+                "pushkey" => pushkey_header = Some((header.part_id(), header.is_mandatory())),
+                // Replies are only ever sent for the parts that are applied, so the
+                // capabilities the client can handle replies with don't matter.
+                "replycaps" => {}
+                // Other parts can be decoded but aren't applied yet, so the client can't rely
+                // on a mandatory one.
+                part_type if header.is_mandatory() => bail!(ErrorKind::UnsupportedContent(
+                    part_type.to_string(),
+                    vec![]
+                )),
+                _ => {}
+            },
+            Bundle2Item::Inner(inner) => match inner {
+                InnerPart::CheckHeads(heads) => heads_checks.push(HeadsCheck::Exact(heads)),
+                InnerPart::CheckUpdatedHeads(heads) => {
+                    heads_checks.push(HeadsCheck::Contains(heads))
+                }
+                // Phases only ever move towards public, so draft heads don't change anything
+                InnerPart::PhaseHeads(heads) => public_heads.extend(
+                    heads
+                        .into_iter()
+                        .filter(|&(phase, _)| phase == Phase::Public)
+                        .map(|(_, head)| head),
+                ),
+                InnerPart::Pushkey(pushkey) => match pushkey_header.take() {
+                    Some((part_id, mandatory)) => pushkeys.push(PushedPushkey {
+                        part_id,
+                        mandatory,
+                        pushkey,
+                    }),
+                    None => bail!(ErrorKind::InvalidBundle("pushkey without a header".into())),
+                },
+                inner => if inner.is_cg2() {
+                    parts.push(inner.cg2_part())
+                },
+            },
+        }
+    }
+
+    let changegroup = part_id.map(|part_id| {
+        PushedChangegroup {
+            part_id: part_id,
+            parts: parts,
+        }
+    });
+
+    Ok(PushedBundle {
+        changegroup: changegroup,
+        heads_checks: heads_checks,
+        public_heads: public_heads,
+        pushkeys: pushkeys,
+    })
+}
+
+/// Make the pushed public heads public, along with their ancestors.
+pub fn apply_public_heads(repo: Arc<PushRepo>, heads: Vec<NodeHash>) -> BoxFuture<(), Error> {
+    stream::iter_ok(heads)
+        .for_each(move |head| repo.make_public(&head))
+        .boxify()
+}
+
+/// Check the mandatory bookmark updates in a pushed bundle against the repo's bookmarks, so that
+/// a push whose bookmarks have moved since the client looked at them fails before anything is
+/// written.
+pub fn check_pushkeys(repo: Arc<PushRepo>, pushkeys: &[PushedPushkey]) -> BoxFuture<(), Error> {
+    let checks: Vec<_> = pushkeys
+        .iter()
+        .filter(|pushed| pushed.mandatory && pushed.pushkey.namespace == "bookmarks")
+        .map(|pushed| {
+            let old = match pushkey_node(&pushed.pushkey.old) {
+                Ok(old) => old,
+                Err(err) => return future::err(err).boxify(),
+            };
+            let part_id = pushed.part_id;
+            let failure = pushkey_failure(&pushed.pushkey);
+            repo.get_bookmark(pushed.pushkey.key.as_bytes())
+                .and_then(move |current| {
+                    if current != old {
+                        bail!(ErrorKind::PushkeyFailed(part_id, failure));
+                    }
+                    Ok(())
+                })
+                .boxify()
+        })
+        .collect();
+
+    future::join_all(checks).map(|_| ()).boxify()
+}
+
+/// Apply the pushkeys from a pushed bundle one at a time, in order, returning the part id of
+/// each along with whether it succeeded. If a mandatory one fails, so does the push.
+pub fn apply_pushkeys(
+    repo: Arc<PushRepo>,
+    phases: Option<Arc<PhasesRepo>>,
+    pushkeys: Vec<PushedPushkey>,
+) -> BoxFuture<Vec<(u32, bool)>, Error> {
+    stream::iter_ok(pushkeys)
+        .and_then(move |pushed| {
+            let applied = apply_pushkey(repo.clone(), phases.clone(), &pushed.pushkey);
+            applied.and_then(move |succeeded| {
+                if !succeeded && pushed.mandatory {
+                    let failure = pushkey_failure(&pushed.pushkey);
+                    bail!(ErrorKind::PushkeyFailed(pushed.part_id, failure));
+                }
+                Ok((pushed.part_id, succeeded))
+            })
+        })
+        .collect()
+        .boxify()
+}
+
+// How a failed pushkey is reported to the client
+fn pushkey_failure(pushkey: &Pushkey) -> PushkeyFailure {
+    PushkeyFailure {
+        namespace: Some(pushkey.namespace.clone()),
+        key: Some(pushkey.key.clone()),
+        new: pushkey.new.clone(),
+        old: pushkey.old.clone(),
+        ret: Some("0".into()),
+    }
+}
+
+/// Update a key the way Mercurial's pushkey does, returning whether the update happened. Like
+/// Mercurial, updates to namespaces which can't be pushed to fail rather than being errors.
+pub fn apply_pushkey(
+    repo: Arc<PushRepo>,
+    phases: Option<Arc<PhasesRepo>>,
+    pushkey: &Pushkey,
+) -> BoxFuture<bool, Error> {
+    match pushkey.namespace.as_str() {
+        "bookmarks" => {
+            let old = pushkey_node(&pushkey.old);
+            let new = pushkey_node(&pushkey.new);
+            match (old, new) {
+                (Ok(old), Ok(new)) => {
+                    repo.update_bookmark(pushkey.key.clone().into_bytes(), old, new)
+                }
+                (Err(err), _) | (_, Err(err)) => future::err(err).boxify(),
+            }
+        }
+        "phases" => push_phase(repo, phases, pushkey),
+        _ => future::ok(false).boxify(),
+    }
+}
+
+// A node sent as a pushkey value, where no value means there isn't one
+fn pushkey_node(value: &Option<String>) -> Result<Option<NodeHash>> {
+    match value {
+        &Some(ref value) => {
+            let node = value
+                .parse()
+                .chain_err(|| ErrorKind::InvalidBundle(format!("invalid node '{}'", value)))?;
+            Ok(Some(node))
+        }
+        &None => Ok(None),
+    }
+}
+
+// Move a changeset from the phase `pushkey.old` to `pushkey.new`. As in Mercurial, this only
+// happens if the changeset is still in the old phase and the new one is more public, and it
+// succeeds without doing anything if the changeset is already in the new phase.
+fn push_phase(
+    repo: Arc<PushRepo>,
+    phases: Option<Arc<PhasesRepo>>,
+    pushkey: &Pushkey,
+) -> BoxFuture<bool, Error> {
+    fn phase(value: &Option<String>) -> Option<Phase> {
+        value
+            .as_ref()
+            .and_then(|value| value.parse().ok())
+            .and_then(Phase::from_hg)
+    }
+
+    let node: NodeHash = match pushkey.key.parse() {
+        Ok(node) => node,
+        Err(_) => return future::ok(false).boxify(),
+    };
+    let (old, new) = match (phase(&pushkey.old), phase(&pushkey.new)) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return future::ok(false).boxify(),
+    };
+
+    let current = match phases {
+        Some(phases) => phases.get_phase(&node),
+        // Without phase tracking every changeset is public
+        None => future::ok(Phase::Public).boxify(),
+    };
+    current
+        .and_then(move |current| {
+            if current == old && new.to_hg() < old.to_hg() {
+                repo.make_public(&node).map(|()| true).boxify()
+            } else {
+                future::ok(current == new).boxify()
+            }
+        })
+        .boxify()
+}
+
+// A revision from a changegroup, with its full text rebuilt from the delta
+struct Revision {
+    nodeid: NodeHash,
+    linknode: NodeHash,
+    node: BlobNode,
+}
+
+impl Revision {
+    fn text(&self) -> &[u8] {
+        self.node
+            .as_blob()
+            .as_slice()
+            .expect("revision text is always present")
+    }
+}
+
+/// What applying a changegroup changed.
+pub struct AppliedChangegroup {
+    /// The changesets which were added
+    pub added: Vec<NodeHash>,
+    heads_added: Vec<NodeHash>,
+    heads_removed: Vec<NodeHash>,
+    // The version of the heads after the update
+    version: Version,
+}
+
+impl AppliedChangegroup {
+    /// Undo the changegroup's update of the heads, for a push which failed after it was applied.
+    /// The changesets it added are left in the store, but are no longer reachable from a head.
+    /// If the heads have been changed again since, they're left as they are.
+    pub fn roll_back(&self, repo: &Arc<PushRepo>) -> BoxFuture<(), Error> {
+        repo.update_heads(&self.heads_removed, &self.heads_added, &self.version)
+            .map(|_| ())
+            .boxify()
+    }
+}
+
+/// The error for a push which found the heads changed by someone else.
+pub fn push_raced() -> Error {
+    let msg = "repository changed while pushing - please try again";
+    ErrorKind::PushRaced(msg.into()).into()
+}
+
+/// Apply a changegroup to `repo`.
+///
+/// Every revision's hash is checked against its rebuilt text before anything is written. File
+/// and manifest revisions are stored before the changesets which refer to them, and the heads
+/// are only updated once everything else is in place, and only if they're still at `version`.
+/// If they aren't, the push fails with `PushRaced`.
+pub fn apply_changegroup(
+    repo: Arc<PushRepo>,
+    parts: Vec<Part>,
+    version: Version,
+) -> BoxFuture<AppliedChangegroup, Error> {
+    let (changesets, manifests, filelogs) = match split_sections(parts) {
+        Ok(sections) => sections,
+        Err(err) => return future::err(err).boxify(),
+    };
+
+    let changesets = resolve_revisions(changesets, {
+        let repo = repo.clone();
+        move |nodeid| repo.get_changeset_text(&nodeid)
+    });
+    let manifests = resolve_revisions(manifests, {
+        let repo = repo.clone();
+        move |nodeid| repo.get_node_text(&nodeid)
+    });
+    let filelogs = future::join_all(
+        filelogs
+            .into_iter()
+            .map(|(path, chunks)| {
+                let repo = repo.clone();
+                resolve_revisions(chunks, move |nodeid| repo.get_node_text(&nodeid))
+                    .map(move |revisions| (path, revisions))
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    changesets
+        .join3(manifests, filelogs)
+        .and_then(move |(changesets, manifests, filelogs)| {
+            let manifests = manifests
+                .into_iter()
+                .map(|revision| (RepoPath::root(), revision));
+            let mut nodes = Vec::new();
+            for (path, revisions) in filelogs {
+                let path = RepoPath::file(path)?;
+                nodes.extend(
+                    revisions
+                        .into_iter()
+                        .map(|revision| (path.clone(), revision)),
+                );
+            }
+            nodes.extend(manifests);
+
+            let put_nodes = future::join_all(
+                nodes
+                    .into_iter()
+                    .map(|(path, revision)| put_node(&repo, path, revision))
+                    .collect::<Vec<_>>(),
+            );
+
+            Ok(put_nodes.and_then(move |_| put_changesets(repo, changesets, version)))
+        })
+        .flatten()
+        .boxify()
+}
+
+// Split a changegroup into its changeset, manifest and filelog sections. Tree manifests and
+// revision flags can't be stored, so they're rejected.
+fn split_sections(
+    parts: Vec<Part>,
+) -> Result<(Vec<CgDeltaChunk>, Vec<CgDeltaChunk>, Vec<(MPath, Vec<CgDeltaChunk>)>)> {
+    let mut changesets = Vec::new();
+    let mut manifests = Vec::new();
+    let mut filelogs: Vec<(MPath, Vec<CgDeltaChunk>)> = Vec::new();
+
+    for part in parts {
+        if let Part::CgChunk(_, ref chunk) = part {
+            if !chunk.flags.is_empty() {
+                bail!(ErrorKind::UnsupportedRevFlags(chunk.node, chunk.flags));
+            }
+        }
+        match part {
+            Part::CgChunk(Section::Changeset, chunk) => changesets.push(chunk),
+            Part::CgChunk(Section::Manifest, chunk) => manifests.push(chunk),
+            Part::CgChunk(Section::Filelog(path), chunk) => {
+                let new_file = match filelogs.last() {
+                    Some(&(ref last, _)) => last != &path,
+                    None => true,
+                };
+                if new_file {
+                    filelogs.push((path, Vec::new()));
+                }
+                filelogs.last_mut().unwrap().1.push(chunk);
+            }
+            Part::CgChunk(Section::Treemanifest(_), _) => bail!(ErrorKind::UnsupportedContent(
+                "changegroup".into(),
+                vec!["treemanifest".into()],
+            )),
+            Part::SectionEnd(_) | Part::End => {}
+        }
+    }
+
+    Ok((changesets, manifests, filelogs))
+}
+
+// Rebuild the full text of each revision in a section. A delta's base is either the null
+// revision, an earlier revision in the same section, or a revision that's already in the repo.
+fn resolve_revisions<F>(
+    chunks: Vec<CgDeltaChunk>,
+    get_text: F,
+) -> BoxFuture<Vec<Revision>, Error>
+where
+    F: Fn(NodeHash) -> BoxFuture<Vec<u8>, Error> + Send + 'static,
+{
+    stream::iter_ok(chunks)
+        .fold(
+            (Vec::new(), HashMap::new()),
+            move |(mut revisions, mut index): (Vec<Revision>, HashMap<NodeHash, usize>), chunk| {
+                let resolved = if chunk.base == NULL_HASH {
+                    future::result(rebuild_revision(chunk, &[])).boxify()
+                } else if let Some(&idx) = index.get(&chunk.base) {
+                    future::result(rebuild_revision(chunk, revisions[idx].text())).boxify()
+                } else {
+                    get_text(chunk.base)
+                        .and_then(move |base| rebuild_revision(chunk, &base))
+                        .boxify()
+                };
+
+                resolved.map(move |revision| {
+                    index.insert(revision.nodeid, revisions.len());
+                    revisions.push(revision);
+                    (revisions, index)
+                })
+            },
+        )
+        .map(|(revisions, _)| revisions)
+        .boxify()
+}
+
+fn rebuild_revision(chunk: CgDeltaChunk, base: &[u8]) -> Result<Revision> {
+    let CgDeltaChunk {
+        node: nodeid,
+        p1,
+        p2,
+        base: basenode,
+        linknode,
+        delta,
+        ..
+    } = chunk;
+
+    // Applying a delta trusts its offsets, so check them against the base first
+    if delta.fragments().iter().any(|frag| frag.end > base.len()) {
+        bail!(ErrorKind::InvalidDelta(nodeid, basenode));
+    }
+    let text = delta::apply(base, delta);
+
+    let node = BlobNode::new(text, non_null(&p1), non_null(&p2));
+    let computed = node.nodeid().expect("revision text is always present");
+    if computed != nodeid {
+        bail!(ErrorKind::NodeHashMismatch(nodeid, computed));
+    }
+
+    Ok(Revision {
+        nodeid,
+        linknode,
+        node,
+    })
+}
+
+fn non_null(node: &NodeHash) -> Option<&NodeHash> {
+    if node == &NULL_HASH {
+        None
+    } else {
+        Some(node)
+    }
+}
+
+fn put_node(repo: &Arc<PushRepo>, path: RepoPath, revision: Revision) -> BoxFuture<(), Error> {
+    let data = Vec::from(revision.text());
+    let linknode = repo.add_linknode(path, &revision.nodeid, &revision.linknode);
+
+    repo.put_node(&revision.nodeid, *revision.node.parents(), data)
+        .join(linknode)
+        .map(|_| ())
+        .boxify()
+}
+
+// Store the changesets which the repo doesn't already have, and update the heads for them.
+// Changesets which already exist don't count as added, and the heads already account for them.
+fn put_changesets(
+    repo: Arc<PushRepo>,
+    changesets: Vec<Revision>,
+    version: Version,
+) -> BoxFuture<AppliedChangegroup, Error> {
+    let exists = future::join_all(
+        changesets
+            .iter()
+            .map(|cs| repo.changeset_exists(&cs.nodeid))
+            .collect::<Vec<_>>(),
+    );
+
+    exists
+        .and_then(move |exists| {
+            let changesets = changesets
+                .into_iter()
+                .zip(exists)
+                .filter(|&(_, exists)| !exists)
+                .map(|(cs, _)| cs)
+                .collect();
+            put_new_changesets(repo, changesets, version)
+        })
+        .boxify()
+}
+
+fn put_new_changesets(
+    repo: Arc<PushRepo>,
+    changesets: Vec<Revision>,
+    version: Version,
+) -> BoxFuture<AppliedChangegroup, Error> {
+    let added: Vec<_> = changesets.iter().map(|cs| cs.nodeid).collect();
+    let added_set: HashSet<_> = added.iter().cloned().collect();
+
+    // Changesets in the push which are parents of other changesets in the push aren't heads,
+    // while any parent outside the push stops being one.
+    let mut parents = HashSet::new();
+    for cs in &changesets {
+        parents.extend(cs.node.parents().into_iter());
+    }
+    let new_heads: Vec<_> = added
+        .iter()
+        .filter(|nodeid| !parents.contains(nodeid))
+        .cloned()
+        .collect();
+    let old_heads: Vec<_> = parents
+        .into_iter()
+        .filter(|nodeid| !added_set.contains(nodeid))
+        .collect();
+
+    // Storing a changeset updates an index shared with other changesets, so they're stored one
+    // at a time.
+    let put_changesets = stream::iter_ok(changesets).for_each({
+        let repo = repo.clone();
+        move |cs| repo.put_changeset(&cs.nodeid, cs.node)
+    });
+
+    // The heads change together, so nobody sees both the old and new heads, or neither. And
+    // they only change if nobody else has changed them since the push checked them.
+    put_changesets
+        .and_then(move |_| {
+            let update = repo.update_heads(&new_heads, &old_heads, &version);
+            update.and_then(move |version| match version {
+                Some(version) => Ok(AppliedChangegroup {
+                    added,
+                    heads_added: new_heads,
+                    heads_removed: old_heads,
+                    version,
+                }),
+                None => Err(push_raced()),
+            })
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use slog::Discard;
+
+    use linear;
+    use mercurial_bundles::{parts, Bundle2EncodeBuilder};
+    use mercurial_bundles::changegroup::RevFlags;
+    use mercurial_types::{Changeset, Delta};
+
+    use super::*;
+
+    fn node(hex: &str) -> NodeHash {
+        NodeHash::from_str(hex).unwrap()
+    }
+
+    fn heads(nodes: &[NodeHash]) -> HashSet<NodeHash> {
+        nodes.iter().cloned().collect()
+    }
+
+    #[test]
+    fn heads_check_exact() {
+        let a = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let b = node("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+
+        // Order and duplicates don't matter
+        let check = HeadsCheck::Exact(vec![b, a, b]);
+        assert!(check.check(&heads(&[a, b])));
+        assert!(!check.check(&heads(&[a])));
+        assert!(!check.check(&heads(&[a, b, NULL_HASH])));
+
+        // An empty repo's only head is the null revision
+        assert!(HeadsCheck::Exact(vec![NULL_HASH]).check(&heads(&[])));
+        assert!(!HeadsCheck::Exact(vec![]).check(&heads(&[])));
+    }
+
+    #[test]
+    fn heads_check_hashed() {
+        let a = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let b = node("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+
+        // The hash covers the heads in sorted order
+        let mut context = Context::new();
+        context.update(b.sha1());
+        context.update(a.sha1());
+        let check = HeadsCheck::Hashed(context.finish());
+
+        assert!(check.check(&heads(&[a, b])));
+        assert!(!check.check(&heads(&[a])));
+    }
+
+    #[test]
+    fn heads_check_contains() {
+        let a = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let b = node("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+
+        let check = HeadsCheck::Contains(vec![a]);
+        assert!(check.check(&heads(&[a])));
+        assert!(check.check(&heads(&[a, b])));
+        assert!(!check.check(&heads(&[b])));
+    }
+
+    // The replycaps, check:heads and pushkey parts that `hg push` sends with a bookmark
+    #[test]
+    fn decode_bookmark_push() {
+        let head = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let pushkey = Pushkey {
+            namespace: "bookmarks".into(),
+            key: "master".into(),
+            old: None,
+            new: Some(head.to_string()),
+        };
+
+        let mut builder = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+        builder
+            .add_part(parts::replycaps_part(&BTreeMap::new()).unwrap())
+            .add_part(parts::check_heads_part(&[head]).unwrap())
+            .add_part(parts::pushkey_part(&pushkey).unwrap());
+        let bundle = builder.build().wait().unwrap().into_inner();
+
+        let logger = Logger::root(Discard, o!());
+        let pushed = decode_bundle(Bytes::from(bundle), logger).wait().unwrap();
+
+        assert!(pushed.changegroup.is_none());
+        assert_eq!(pushed.heads_checks.len(), 1);
+        match pushed.heads_checks[0] {
+            HeadsCheck::Exact(ref heads) => assert_eq!(heads, &vec![head]),
+            ref check => panic!("unexpected heads check: {:?}", check),
+        }
+        assert!(pushed.public_heads.is_empty());
+        assert_eq!(pushed.pushkeys.len(), 1);
+        assert_eq!(pushed.pushkeys[0].part_id, 2);
+        assert!(pushed.pushkeys[0].mandatory);
+        assert_eq!(pushed.pushkeys[0].pushkey, pushkey);
+    }
+
+    #[test]
+    fn put_existing_changeset() {
+        let blobrepo = linear::getrepo();
+        let head = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let cs = blobrepo.get_changeset_by_nodeid(&head).wait().unwrap();
+        let (p1, p2) = cs.parents().get_nodes();
+
+        let repo: Arc<PushRepo> = Arc::new(blobrepo.clone());
+        let text = repo.get_changeset_text(&head).wait().unwrap();
+        let chunk = CgDeltaChunk {
+            node: head,
+            p1: *p1.unwrap_or(&NULL_HASH),
+            p2: *p2.unwrap_or(&NULL_HASH),
+            base: NULL_HASH,
+            linknode: head,
+            flags: RevFlags::empty(),
+            delta: Delta::new_fulltext(text),
+        };
+        let revision = rebuild_revision(chunk.clone(), &[]).unwrap();
+
+        // Pushing a changeset again doesn't add it, or make its parent a head again
+        let version = repo.heads_version().wait().unwrap();
+        let applied = put_changesets(repo.clone(), vec![revision], version)
+            .wait()
+            .unwrap();
+        assert!(applied.added.is_empty());
+        assert!(applied.heads_added.is_empty());
+        assert!(applied.heads_removed.is_empty());
+
+        // The heads have been updated since `version`, so a push based on it has raced
+        let revision = rebuild_revision(chunk, &[]).unwrap();
+        match put_changesets(repo, vec![revision], version).wait() {
+            Err(Error(ErrorKind::PushRaced(_), _)) => {}
+            res => panic!("unexpected result: {:?}", res.map(|applied| applied.added)),
+        }
+    }
+
+    fn bookmark_pushkey(old: Option<NodeHash>, mandatory: bool) -> PushedPushkey {
+        PushedPushkey {
+            part_id: 2,
+            mandatory,
+            pushkey: Pushkey {
+                namespace: "bookmarks".into(),
+                key: "master".into(),
+                old: old.map(|old| old.to_string()),
+                new: Some("a5ffa77602a066db7d5cfb9fb5823a0895717c5a".into()),
+            },
+        }
+    }
+
+    #[test]
+    fn check_pushkeys_old_bookmark() {
+        let repo: Arc<PushRepo> = Arc::new(linear::getrepo());
+        let head = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+
+        // The bookmark doesn't exist, so only an update which creates it can apply
+        assert!(
+            check_pushkeys(repo.clone(), &[bookmark_pushkey(None, true)])
+                .wait()
+                .is_ok()
+        );
+        match check_pushkeys(repo.clone(), &[bookmark_pushkey(Some(head), true)]).wait() {
+            Err(Error(ErrorKind::PushkeyFailed(2, _), _)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Advisory updates are allowed to fail, so they don't stop the push
+        assert!(
+            check_pushkeys(repo, &[bookmark_pushkey(Some(head), false)])
+                .wait()
+                .is_ok()
+        );
+    }
+}
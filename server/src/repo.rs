@@ -12,32 +12,44 @@ use std::fmt::{self, Debug};
 use std::io::Cursor;
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream};
-use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use futures::future::{Loop, Shared};
+use futures::sync::oneshot;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use slog::Logger;
 
 use async_compression::CompressorType;
+use bundle_generator::{create_cg1_changegroup, create_changegroup, create_stream_clone};
 use mercurial;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder};
-use mercurial_types::{percent_encode, BoxRepo, Changeset, NodeHash, Parents, Repo, NULL_HASH};
-use metaconfig::repoconfig::RepoType;
+use mercurial_bundles::{changegroup, part_types, parts, Bundle2EncodeBuilder,
+                        COMPRESSION_ENGINES};
+use mercurial_bundles::changegroup::CgVersion;
+use mercurial_types::{percent_encode, BoxRepo, Changeset, NodeHash, NodeHashPrefix, Parents, Repo,
+                      NULL_HASH};
+use mercurial_types::hash::Sha1;
+use metaconfig::repoconfig::{CloneBundle, RepoConfig, RepoType};
+use repoinfo::{BranchHeadsCache, RepoGenCache};
 
-use hgproto::{self, GetbundleArgs, HgCommandRes, HgCommands};
+use hgproto::{self, BranchRes, GetbundleArgs, HgCommandRes, HgCommandStream, HgCommands};
 
-use blobrepo::{BlobRepo, FilesBlobState, RocksBlobState};
+use blobrepo::{BlobRepo, BlobState, FilesBlobState, RocksBlobState};
+use blobstore::Blobstore;
+use phases::Phase;
 
 use errors::*;
+use push::{apply_changegroup, apply_public_heads, apply_pushkeys, check_pushkeys, decode_bundle,
+           push_raced, HeadsCheck, PushRepo, PushedBundle, PushedChangegroup};
 
-pub fn init_repo(parent_logger: &Logger, repotype: &RepoType) -> Result<(PathBuf, HgRepo)> {
-    let repopath = repotype.path();
+pub fn init_repo(parent_logger: &Logger, config: &RepoConfig) -> Result<(PathBuf, HgRepo)> {
+    let repopath = config.repotype.path();
 
     let mut sock = repopath.join(".hg");
 
-    let repo = HgRepo::new(parent_logger, repotype)
+    let repo = HgRepo::new(parent_logger, config)
         .chain_err(|| format!("Failed to initialize repo {:?}", repopath))?;
 
     sock.push("mononoke.sock");
@@ -46,13 +58,39 @@ pub fn init_repo(parent_logger: &Logger, repotype: &RepoType) -> Result<(PathBuf
 }
 
 
+/// A repo, along with the extra kinds of access to it which only some repo types support.
+pub struct OpenedRepo {
+    pub repo: Box<Repo<Error = hgproto::Error> + Sync + Send>,
+    /// Write access, for repos which accept pushes
+    pub pushrepo: Option<Arc<PushRepo>>,
+    /// Phase tracking. Repos without it treat every changeset as public.
+    pub phases: Option<Arc<PhasesRepo>>,
+}
+
 pub trait OpenableRepoType {
-    fn open(&self) -> Result<Box<Repo<Error = hgproto::Error> + Sync + Send>>;
+    fn open(&self) -> Result<Box<Repo<Error = hgproto::Error> + Sync + Send>> {
+        self.open_full().map(|opened| opened.repo)
+    }
+    fn open_full(&self) -> Result<OpenedRepo>;
     fn path(&self) -> &Path;
 }
 
+/// Access to the phases of a repo's changesets. Only blob repos track them.
+pub trait PhasesRepo: Send + Sync + 'static {
+    fn get_phase(&self, nodeid: &NodeHash) -> BoxFuture<Phase, Error>;
+}
+
+impl<State> PhasesRepo for BlobRepo<State>
+where
+    State: BlobState,
+{
+    fn get_phase(&self, nodeid: &NodeHash) -> BoxFuture<Phase, Error> {
+        BlobRepo::get_phase(self, nodeid).from_err().boxify()
+    }
+}
+
 impl OpenableRepoType for RepoType {
-    fn open(&self) -> Result<Box<Repo<Error = hgproto::Error> + Sync + Send>> {
+    fn open_full(&self) -> Result<OpenedRepo> {
         use hgproto::{Error, ErrorKind};
         use metaconfig::repoconfig::RepoType::*;
 
@@ -60,18 +98,29 @@ impl OpenableRepoType for RepoType {
             Error::with_chain(err, ErrorKind::Repo)
         }
 
-        let ret = match *self {
-            Revlog(ref path) => {
-                BoxRepo::new_with_cvterr(mercurial::RevlogRepo::open(path.join(".hg"))?, repo_chain)
-            }
-
-            BlobFiles(ref path) => {
-                BoxRepo::new_with_cvterr(BlobRepo::new(FilesBlobState::new(&path)?), repo_chain)
+        fn blob_repo<State>(repo: BlobRepo<State>) -> OpenedRepo
+        where
+            State: BlobState,
+            <State::Blobstore as Blobstore>::ValueIn: From<Vec<u8>>,
+        {
+            OpenedRepo {
+                repo: BoxRepo::new_with_cvterr(repo.clone(), repo_chain),
+                pushrepo: Some(Arc::new(repo.clone())),
+                phases: Some(Arc::new(repo)),
             }
+        }
 
-            BlobRocks(ref path) => {
-                BoxRepo::new_with_cvterr(BlobRepo::new(RocksBlobState::new(&path)?), repo_chain)
-            }
+        let ret = match *self {
+            Revlog(ref path) => OpenedRepo {
+                repo: BoxRepo::new_with_cvterr(
+                    mercurial::RevlogRepo::open(path.join(".hg"))?,
+                    repo_chain,
+                ),
+                pushrepo: None,
+                phases: None,
+            },
+            BlobFiles(ref path) => blob_repo(BlobRepo::new(FilesBlobState::new(&path)?)),
+            BlobRocks(ref path) => blob_repo(BlobRepo::new(RocksBlobState::new(&path)?)),
         };
 
         Ok(ret)
@@ -86,26 +135,86 @@ impl OpenableRepoType for RepoType {
     }
 }
 
+type BoxedRepo = Box<Repo<Error = hgproto::Error> + Send + Sync>;
+
+// Memory budget for the generation number cache used by revsets
+const REPO_GEN_CACHE_SIZE: usize = 10 * 1024 * 1024;
+// How many sets of heads to remember the draft boundaries of
+const DRAFT_BOUNDARY_CACHE_SIZE: usize = 64;
+
 pub struct HgRepo {
     path: String,
-    hgrepo: Arc<Box<Repo<Error = hgproto::Error> + Send + Sync>>,
+    hgrepo: Arc<BoxedRepo>,
+    // Only set for repos which accept pushes
+    pushrepo: Option<Arc<PushRepo>>,
+    // Only set for repos which track phases
+    phases: Option<Arc<PhasesRepo>>,
+    clonebundles: Vec<CloneBundle>,
+    repo_generation: RepoGenCache<BoxedRepo>,
+    // Named branch heads, updated with the changesets added since each branchmap
+    branch_heads: BranchHeadsCache,
+    // Pushes are applied one at a time, so that each can check the heads it's based on haven't
+    // moved before changing them. This completes when the most recent push has finished.
+    last_push: Mutex<Shared<oneshot::Receiver<()>>>,
+    // Finding the draft boundary means walking all the draft changesets, so remember it for the
+    // sets of heads clients ask about
+    draft_boundaries: Arc<Mutex<HashMap<Vec<NodeHash>, Arc<DraftBoundary>>>>,
     _logger: Logger,
 }
 
-fn wireprotocaps() -> Vec<String> {
-    vec![
-        "lookup".to_string(),
-        "known".to_string(),
-        "getbundle".to_string(),
-    ]
+// The changegroup versions offered in the bundle2 capabilities
+fn changegroup_versions(accepts_pushes: bool) -> Vec<&'static str> {
+    // Clients pick the changegroup version for pushes from the same list as for pulls. Pushes
+    // can't store the revision flags or tree manifests that version 03 adds, so it's only
+    // offered by repos which don't accept them.
+    changegroup::SUPPORTED_VERSIONS
+        .iter()
+        .cloned()
+        .filter(|version| !accepts_pushes || *version != "03")
+        .collect()
+}
+
+// The changegroup version to reply to getbundle with. Like Mercurial, this is the highest version
+// both sides support, or version 01 for clients which don't list any.
+fn getbundle_cg_version(
+    bundlecaps: &[Vec<u8>],
+    accepts_pushes: bool,
+) -> hgproto::Result<CgVersion> {
+    let client_versions = match part_types::decode_bundlecaps(bundlecaps)? {
+        Some(mut caps) => caps.remove("changegroup"),
+        None => None,
+    };
+    let client_versions = match client_versions {
+        Some(versions) => versions,
+        None => return Ok(CgVersion::Cg1),
+    };
+
+    let versions = changegroup_versions(accepts_pushes);
+    client_versions
+        .iter()
+        .filter(|version| versions.contains(&version.as_str()))
+        .filter_map(|version| CgVersion::from_param(version.as_bytes()))
+        .max_by_key(|version| version.to_param())
+        .ok_or_else(|| {
+            let err = ErrorKind::NoCommonChangegroupVersion(client_versions.clone());
+            repo_err(err.into())
+        })
 }
 
-fn bundle2caps() -> String {
-    let caps = hashmap! {
+fn bundle2caps(accepts_pushes: bool) -> String {
+    let mut caps = hashmap! {
         "HG20" => vec![],
         "listkeys" => vec![],
-        "changegroup" => vec!["02"],
+        "changegroup" => changegroup_versions(accepts_pushes),
+        "compression" => COMPRESSION_ENGINES.to_vec(),
+        "phases" => vec!["heads"],
+        "checkheads" => vec!["related"],
+        "error" => vec!["abort", "unsupportedcontent", "pushraced", "pushkey"],
     };
+    // Pushkey parts are only applied by repos which accept pushes
+    if accepts_pushes {
+        caps.insert("pushkey", vec![]);
+    }
 
     let mut encodedcaps = vec![];
 
@@ -123,12 +232,27 @@ fn bundle2caps() -> String {
 }
 
 impl HgRepo {
-    pub fn new(parent_logger: &Logger, repo: &RepoType) -> Result<Self> {
-        let path = repo.path().to_owned();
+    pub fn new(parent_logger: &Logger, config: &RepoConfig) -> Result<Self> {
+        let path = config.repotype.path().to_owned();
+        let OpenedRepo {
+            repo: hgrepo,
+            pushrepo,
+            phases,
+        } = config.repotype.open_full()?;
+        let pushrepo = if config.readonly { None } else { pushrepo };
+        // With no pushes yet, there's nothing to wait for
+        let (_, no_push) = oneshot::channel();
 
         Ok(HgRepo {
             path: format!("{}", path.display()),
-            hgrepo: Arc::new(repo.open()?),
+            hgrepo: Arc::new(hgrepo),
+            pushrepo: pushrepo,
+            phases: phases,
+            clonebundles: config.clonebundles.clone(),
+            repo_generation: RepoGenCache::new(REPO_GEN_CACHE_SIZE),
+            branch_heads: BranchHeadsCache::new(),
+            last_push: Mutex::new(no_push.shared()),
+            draft_boundaries: Arc::new(Mutex::new(HashMap::new())),
             _logger: parent_logger.new(o!("repo" => format!("{}", path.display()))),
         })
     }
@@ -136,6 +260,111 @@ impl HgRepo {
     pub fn path(&self) -> &String {
         &self.path
     }
+
+    /// Wait for the pushes already under way to finish. The next push can start once the
+    /// returned sender is dropped.
+    ///
+    /// This only orders the pushes made through this server. Pushes through other servers
+    /// sharing the repo's storage are caught by updating the heads only at the version the push
+    /// checked them at.
+    fn push_lock(&self) -> BoxFuture<oneshot::Sender<()>, Error> {
+        let (sender, this_push) = oneshot::channel();
+        let previous = mem::replace(
+            &mut *self.last_push.lock().expect("lock poisoned"),
+            this_push.shared(),
+        );
+        // The previous push is finished whether its sender was dropped or used
+        previous.then(move |_| Ok(sender)).boxify()
+    }
+
+    /// Find where the draft ancestors of `heads` meet the public changesets.
+    ///
+    /// This is remembered for each set of heads. Changesets only ever become public, along
+    /// with their ancestors, so a remembered boundary is still right as long as its draft roots
+    /// are still draft. That's checked each time it's used, which catches phase changes made by
+    /// any process sharing the repo's storage.
+    fn draft_boundary(
+        &self,
+        phases: Arc<PhasesRepo>,
+        mut heads: Vec<NodeHash>,
+    ) -> BoxFuture<Arc<DraftBoundary>, Error> {
+        heads.sort();
+        heads.dedup();
+
+        let cached = self.draft_boundaries
+            .lock()
+            .expect("lock poisoned")
+            .get(&heads)
+            .cloned();
+        let still_draft = match cached {
+            Some(ref boundary) => {
+                let root_phases: Vec<_> = boundary
+                    .draft_roots
+                    .iter()
+                    .map(|root| phases.get_phase(root))
+                    .collect();
+                future::join_all(root_phases)
+                    .map(|root_phases| root_phases.iter().all(|phase| *phase == Phase::Draft))
+                    .boxify()
+            }
+            None => future::ok(false).boxify(),
+        };
+
+        let hgrepo = self.hgrepo.clone();
+        let cache = self.draft_boundaries.clone();
+        still_draft
+            .and_then(move |still_draft| match cached {
+                Some(boundary) if still_draft => future::ok(boundary).boxify(),
+                _ => draft_boundary(hgrepo, phases, heads.clone())
+                    .map(move |boundary| {
+                        let boundary = Arc::new(boundary);
+                        let mut cache = cache.lock().expect("lock poisoned");
+                        if cache.len() >= DRAFT_BOUNDARY_CACHE_SIZE {
+                            cache.clear();
+                        }
+                        cache.insert(heads, boundary.clone());
+                        boundary
+                    })
+                    .boxify(),
+            })
+            .boxify()
+    }
+
+    /// The capabilities advertised to clients, which depend on what this repo is set up to do
+    /// and how the client is connected.
+    pub fn capabilities(&self, transport: Transport) -> Vec<String> {
+        let mut caps = vec![
+            "lookup".to_string(),
+            "branchmap".to_string(),
+            "known".to_string(),
+            "getbundle".to_string(),
+            "changegroupsubset".to_string(),
+            // Stream clones are made of uncompressed revlogs without general delta
+            "streamreqs=revlogv1".to_string(),
+            format!("bundle2={}", bundle2caps(self.pushrepo.is_some())),
+        ];
+
+        if transport == Transport::Http {
+            // Clients may send arguments in headers and accept version 0.2 responses, compressed
+            // with any of the listed engines
+            caps.push("httpheader=1024".to_string());
+            caps.push("httpmediatype=0.1rx,0.1tx,0.2tx".to_string());
+            caps.push("compression=zlib,none".to_string());
+        }
+
+        if self.pushrepo.is_some() {
+            caps.push("unbundle".to_string());
+            // Clients without bundle2 also check this before calling listkeys, so they only
+            // pull bookmarks and phases from repos which accept pushes. Bundle2 clients get
+            // them through getbundle's listkeys parts instead.
+            caps.push("pushkey".to_string());
+        }
+        if !self.clonebundles.is_empty() {
+            caps.push("clonebundles".to_string());
+        }
+
+        caps
+    }
 }
 
 impl Debug for HgRepo {
@@ -144,15 +373,24 @@ impl Debug for HgRepo {
     }
 }
 
+/// How a client is connected, which affects the capabilities it's offered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    Ssh,
+    Http,
+}
+
 pub struct RepoClient {
     repo: Arc<HgRepo>,
+    transport: Transport,
     logger: Logger,
 }
 
 impl RepoClient {
-    pub fn new(repo: Arc<HgRepo>, parent_logger: &Logger) -> Self {
+    pub fn new(repo: Arc<HgRepo>, transport: Transport, parent_logger: &Logger) -> Self {
         RepoClient {
             repo: repo,
+            transport: transport,
             logger: parent_logger.new(o!()), // connection details?
         }
     }
@@ -162,50 +400,478 @@ impl RepoClient {
         &self.logger
     }
 
-    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandRes<Bytes>> {
-        let writer = Cursor::new(Vec::new());
-        let mut bundle = Bundle2EncodeBuilder::new(writer);
+    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandStream<Bytes>> {
+        let mut bundle = Bundle2EncodeBuilder::new_stream();
         // Mercurial currently hangs while trying to read compressed bundles over the wire:
         // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
         // TODO: possibly enable compression support once this is fixed.
         bundle.set_compressor_type(CompressorType::Uncompressed);
 
+        if args.cg && !args.heads.is_empty() {
+            let version = getbundle_cg_version(&args.bundlecaps, self.repo.pushrepo.is_some())?;
+            let changegroup = create_changegroup(
+                self.repo.hgrepo.clone(),
+                self.repo.repo_generation.clone(),
+                args.heads.clone(),
+                args.common.clone(),
+                version,
+            );
+            bundle.add_part(parts::changegroup_part(changegroup, version)?);
+        }
+
         // TODO: generalize this to other listkey types
         // (note: just calling &b"bookmarks"[..] doesn't work because https://fburl.com/0p0sq6kp)
         if args.listkeys.contains(&b"bookmarks".to_vec()) {
-            let bookmarks = self.repo.hgrepo.get_bookmarks()?;
-            let bookmark_names = bookmarks.keys();
-            let items = bookmark_names.and_then(move |name| {
+            bundle.add_part(parts::listkey_part("bookmarks", self.bookmark_keys()?)?);
+        }
+
+        if args.phases {
+            bundle.add_part(parts::phase_heads_part(self.phase_heads(args.heads))?);
+        }
+
+        Ok(bundle.build_stream().from_err().boxify())
+    }
+
+    // The bookmarks in the repo, as listkeys entries
+    fn bookmark_keys(
+        &self,
+    ) -> hgproto::Result<BoxStream<(Vec<u8>, Vec<u8>), hgproto::Error>> {
+        let bookmarks = self.repo.hgrepo.get_bookmarks()?;
+        let bookmark_names = bookmarks.keys();
+        let items = bookmark_names
+            .and_then(move |name| {
                 // For each bookmark name, grab the corresponding value.
-                bookmarks.get(&name).and_then(|result| {
+                bookmarks.get(&name).map(|result| {
                     // If the name somehow wasn't found, it's possible a race happened. where the
                     // bookmark was deleted from underneath. Skip it.
-                    // Boxing is necessary here to make the match arms return the same types.
-                    match result {
-                        Some((hash, _version)) => {
-                            // AsciiString doesn't currently implement AsRef<[u8]>, so switch to
-                            // Vec which does
-                            let hash: Vec<u8> = hash.to_hex().into();
-                            Ok((name, hash)).into_future().boxify()
-                        }
-                        None => future::empty().boxify(),
-                    }
+                    result.map(|(hash, _version)| {
+                        // AsciiString doesn't currently implement AsRef<[u8]>, so switch to
+                        // Vec which does
+                        let hash: Vec<u8> = hash.to_hex().into();
+                        (name, hash)
+                    })
                 })
-            });
-            bundle.add_part(parts::listkey_part("bookmarks", items)?);
-        }
+            })
+            .filter_map(|item| item);
 
-        let encode_fut = bundle.build();
+        Ok(items.boxify())
+    }
+
+    // The phases of `heads`, along with the public changesets just below any draft ones, so
+    // that the client can tell which of the changesets it gets are public
+    fn phase_heads(&self, heads: Vec<NodeHash>) -> BoxStream<(Phase, NodeHash), Error> {
+        let phases = match self.repo.phases {
+            Some(ref phases) => phases.clone(),
+            // Without phase tracking every changeset is public
+            None => {
+                return stream::iter_ok(heads.into_iter().map(|head| (Phase::Public, head)))
+                    .boxify()
+            }
+        };
+
+        self.repo
+            .draft_boundary(phases, heads.clone())
+            .map(move |boundary| {
+                let draft: Vec<_> = heads
+                    .into_iter()
+                    .filter(|head| !boundary.public.contains(head))
+                    .map(|head| (Phase::Draft, head))
+                    .collect();
+                let public = boundary
+                    .public
+                    .iter()
+                    .map(|node| (Phase::Public, *node));
+
+                stream::iter_ok(public.chain(draft).collect::<Vec<_>>())
+            })
+            .flatten_stream()
+            .boxify()
+    }
+
+    // Apply a bundle pushed by the client, and generate the bundle to send back in reply
+    fn apply_bundle(&self, heads: Vec<String>, bundle: Bytes) -> Result<BoxFuture<Bytes, Error>> {
+        let pushrepo = match self.repo.pushrepo {
+            Some(ref pushrepo) => pushrepo.clone(),
+            None => bail!(ErrorKind::ReadOnlyRepo),
+        };
+
+        let heads_check = unbundle_heads_check(&heads)?;
+
+        let repo = self.repo.clone();
+        let hgrepo = self.repo.hgrepo.clone();
+        let phases = self.repo.phases.clone();
+        let logger = self.logger.clone();
+
+        // Holding the push lock from checking the heads until everything has been applied
+        // means no other push through this server can change the repo in between. Pushes
+        // through other servers sharing the repo's storage don't take this lock, so the heads
+        // are only updated if their version is still the one read before checking them. If
+        // another server has changed them in between, the push fails as a race.
+        let reply = decode_bundle(bundle, self.logger.clone())
+            .and_then(move |pushed| repo.push_lock().map(move |lock| (pushed, lock)))
+            .and_then(move |(pushed, lock)| {
+                let PushedBundle {
+                    changegroup,
+                    mut heads_checks,
+                    public_heads,
+                    pushkeys,
+                } = pushed;
+                heads_checks.extend(heads_check);
+
+                // The heads read after the version are at least as new as it, so if they've
+                // changed since, updating them at that version fails
+                let version = pushrepo.heads_version();
+                version
+                    .and_then({
+                        let hgrepo = hgrepo.clone();
+                        move |version| get_heads(&hgrepo).map(move |before| (version, before))
+                    })
+                    .and_then(move |(version, before)| {
+                        if !heads_checks.iter().all(|check| check.check(&before)) {
+                            return Err(push_raced());
+                        }
+
+                        // Nothing is written until the bookmark updates are known to apply, and
+                        // if the push fails after its changegroup is applied, the heads are
+                        // rolled back. Changesets already made public stay public.
+                        let checked = check_pushkeys(pushrepo.clone(), &pushkeys);
+                        let changegroup = {
+                            let pushrepo = pushrepo.clone();
+                            checked.and_then(move |()| match changegroup {
+                                None => future::ok(None).boxify(),
+                                Some(PushedChangegroup { part_id, parts }) => {
+                                    apply_changegroup(pushrepo, parts, version)
+                                        .and_then(move |applied| {
+                                            get_heads(&hgrepo).map(move |after| {
+                                                let added = applied.added.len();
+                                                info!(
+                                                    logger,
+                                                    "unbundle added {} changesets",
+                                                    added
+                                                );
+                                                let result = changegroup_result(
+                                                    added,
+                                                    before.len(),
+                                                    after.len(),
+                                                );
+                                                Some((part_id, result, applied))
+                                            })
+                                        })
+                                        .boxify()
+                                }
+                            })
+                        };
+                        Ok(changegroup.and_then(move |changegroup| {
+                            let rest = apply_public_heads(pushrepo.clone(), public_heads)
+                                .and_then({
+                                    let pushrepo = pushrepo.clone();
+                                    move |()| apply_pushkeys(pushrepo, phases, pushkeys)
+                                });
+                            rest.then(move |res| match (res, changegroup) {
+                                (Ok(pushkeys), changegroup) => {
+                                    let changegroup = changegroup
+                                        .map(|(part_id, result, _)| (part_id, result));
+                                    future::ok((changegroup, pushkeys)).boxify()
+                                }
+                                (Err(err), Some((_, _, applied))) => applied
+                                    .roll_back(&pushrepo)
+                                    .then(move |_| Err(err))
+                                    .boxify(),
+                                (Err(err), None) => future::err(err).boxify(),
+                            })
+                        }))
+                    })
+                    .flatten()
+                    .then(move |res| {
+                        drop(lock);
+                        res
+                    })
+            })
+            .boxify();
+
+        let encode_fut = reply
+            .and_then(|(changegroup, pushkeys)| {
+                let writer = Cursor::new(Vec::new());
+                let mut bundle = Bundle2EncodeBuilder::new(writer);
+                bundle.set_compressor_type(CompressorType::Uncompressed);
+
+                if let Some((part_id, result)) = changegroup {
+                    bundle.add_part(parts::replychangegroup_part(part_id, result)?);
+                }
+                for (part_id, succeeded) in pushkeys {
+                    let ret = if succeeded { 1 } else { 0 };
+                    bundle.add_part(parts::replypushkey_part(part_id, ret)?);
+                }
+
+                Ok(bundle.build().from_err())
+            })
+            .flatten();
 
         Ok(
             encode_fut
                 .map(|cursor| Bytes::from(cursor.into_inner()))
-                .from_err()
                 .boxify(),
         )
     }
 }
 
+fn get_heads(repo: &Arc<BoxedRepo>) -> BoxFuture<HashSet<NodeHash>, Error> {
+    repo.get_heads()
+        .collect()
+        .map(|heads| heads.into_iter().collect())
+        .from_err()
+        .boxify()
+}
+
+// Parse `unbundle`'s heads argument, which is "force", "hashed" followed by a hash of the heads,
+// or the heads themselves. Each entry is hex-encoded.
+fn unbundle_heads_check(heads: &[String]) -> Result<Option<HeadsCheck>> {
+    const FORCE: &str = "666f726365";
+    const HASHED: &str = "686173686564";
+
+    if heads.len() == 1 && heads[0] == FORCE {
+        return Ok(None);
+    }
+    if heads.len() == 2 && heads[0] == HASHED {
+        return Ok(Some(HeadsCheck::Hashed(heads[1].parse::<Sha1>()?)));
+    }
+
+    let heads = heads
+        .iter()
+        .map(|head| head.parse::<NodeHash>())
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    Ok(Some(HeadsCheck::Exact(heads)))
+}
+
+// What Mercurial's addchangegroup returns: 0 if nothing changed, otherwise 1 plus the number of
+// heads added, or -1 minus the number of heads removed.
+fn changegroup_result(added: usize, heads_before: usize, heads_after: usize) -> i64 {
+    if added == 0 {
+        return 0;
+    }
+
+    let delta = heads_after as i64 - heads_before as i64;
+    if delta < 0 {
+        delta - 1
+    } else {
+        delta + 1
+    }
+}
+
+fn repo_err(err: Error) -> hgproto::Error {
+    hgproto::Error::with_chain(err, hgproto::ErrorKind::Repo)
+}
+
+// Resolve a full hash, or a prefix of one which matches exactly one changeset
+fn lookup_hash(hgrepo: Arc<BoxedRepo>, key: String) -> BoxFuture<NodeHash, hgproto::Error> {
+    let unknown = {
+        let key = key.clone();
+        move || repo_err(ErrorKind::UnknownRevision(key).into())
+    };
+
+    if key.len() == 40 {
+        if let Ok(node) = key.parse::<NodeHash>() {
+            return hgrepo
+                .changeset_exists(&node)
+                .and_then(move |exists| if exists { Ok(node) } else { Err(unknown()) })
+                .boxify();
+        }
+    }
+
+    let prefix = match key.parse::<NodeHashPrefix>() {
+        Ok(prefix) => prefix,
+        Err(_) => return future::err(unknown()).boxify(),
+    };
+
+    // Two matches are enough to know the prefix is ambiguous
+    hgrepo
+        .get_changesets_by_prefix(&prefix)
+        .take(2)
+        .collect()
+        .and_then(move |nodes| match nodes.len() {
+            0 => Err(unknown()),
+            1 => Ok(nodes[0]),
+            _ => Err(repo_err(ErrorKind::AmbiguousRevision(key).into())),
+        })
+        .boxify()
+}
+
+// Format bundle descriptors as a clonebundles manifest: one line per bundle, made of the URL
+// followed by its percent-encoded attributes
+fn clonebundles_manifest(bundles: &[CloneBundle]) -> String {
+    let mut out = String::new();
+
+    for bundle in bundles {
+        out.push_str(&bundle.url);
+        if let Some(ref bundlespec) = bundle.bundlespec {
+            out.push_str(&format!(" BUNDLESPEC={}", percent_encode(bundlespec)));
+        }
+        for (key, value) in &bundle.attributes {
+            out.push_str(&format!(" {}={}", percent_encode(key), percent_encode(value)));
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+// Follow first parents down from `top` to the bottom of its linear branch: the first changeset
+// which is either a root or a merge
+fn linear_branch(hgrepo: Arc<BoxedRepo>, top: NodeHash) -> HgCommandRes<BranchRes> {
+    future::loop_fn(top, move |node| {
+        hgrepo.get_changeset_by_nodeid(&node).map(move |cs| {
+            let (p0, p1) = match cs.parents() {
+                &Parents::One(ref p) => return Loop::Continue(*p),
+                &Parents::None => (None, None),
+                &Parents::Two(ref p0, ref p1) => (Some(*p0), Some(*p1)),
+            };
+
+            Loop::Break(BranchRes {
+                top,
+                node,
+                p0,
+                p1,
+            })
+        })
+    }).boxify()
+}
+
+// Report a failed getbundle or unbundle as a bundle2 holding an error part, which Mercurial
+// shows to the user, rather than as an opaque protocol error
+fn error_bundle(err: Error, logger: &Logger) -> HgCommandRes<Bytes> {
+    let part = match err.kind() {
+        &ErrorKind::UnsupportedContent(ref part_type, ref params) => {
+            parts::error_unsupportedcontent_part(Some(part_type.as_str()), params.as_slice())
+        }
+        &ErrorKind::PushRaced(ref message) => parts::error_pushraced_part(message),
+        &ErrorKind::PushkeyFailed(part_id, ref failure) => {
+            parts::error_pushkey_part(part_id, failure)
+        }
+        _ => {
+            // The outermost errors are often generic, so include everything that led to them
+            let message: Vec<_> = err.iter().map(|err| err.to_string()).collect();
+            parts::error_abort_part(&message.join(": "), None)
+        }
+    };
+    error!(logger, "Command failed"; err);
+
+    let writer = Cursor::new(Vec::new());
+    let mut bundle = Bundle2EncodeBuilder::new(writer);
+    bundle.set_compressor_type(CompressorType::Uncompressed);
+    match part {
+        Ok(part) => {
+            bundle.add_part(part);
+        }
+        Err(err) => return future::err(err.into()).boxify(),
+    }
+
+    bundle
+        .build()
+        .map(|cursor| Bytes::from(cursor.into_inner()))
+        .from_err()
+        .boxify()
+}
+
+// Where the draft ancestors of some heads meet the public changesets
+#[derive(Default)]
+struct DraftBoundary {
+    // Draft changesets none of whose parents are draft
+    draft_roots: Vec<NodeHash>,
+    // Public changesets which are either heads or parents of draft changesets
+    public: HashSet<NodeHash>,
+}
+
+// Find the boundary of the draft ancestors of `heads`. Public changesets are closed under
+// ancestry, so this only needs to visit draft ones.
+fn draft_boundary(
+    hgrepo: Arc<BoxedRepo>,
+    phases: Arc<PhasesRepo>,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<DraftBoundary, Error> {
+    let head_phases: Vec<_> = heads
+        .into_iter()
+        .map(|head| phases.get_phase(&head).map(move |phase| (head, phase)))
+        .collect();
+
+    future::join_all(head_phases)
+        .and_then(move |heads| {
+            let mut boundary = DraftBoundary::default();
+            let mut draft = Vec::new();
+            for (head, phase) in heads {
+                match phase {
+                    Phase::Public => {
+                        boundary.public.insert(head);
+                    }
+                    Phase::Draft => draft.push(head),
+                }
+            }
+
+            future::loop_fn(
+                (draft, HashSet::new(), boundary),
+                move |(mut pending, mut seen, mut boundary)| {
+                    let node = match pending.pop() {
+                        Some(node) => node,
+                        None => return future::ok(Loop::Break(boundary)).boxify(),
+                    };
+                    if !seen.insert(node) {
+                        return future::ok(Loop::Continue((pending, seen, boundary))).boxify();
+                    }
+
+                    let phases = phases.clone();
+                    hgrepo
+                        .get_changeset_by_nodeid(&node)
+                        .from_err()
+                        .and_then(move |cs| {
+                            let parent_phases: Vec<_> = cs.parents()
+                                .into_iter()
+                                .map(|p| phases.get_phase(&p).map(move |phase| (p, phase)))
+                                .collect();
+                            future::join_all(parent_phases)
+                        })
+                        .map(move |parents| {
+                            let mut is_root = true;
+                            for (parent, phase) in parents {
+                                match phase {
+                                    Phase::Public => {
+                                        boundary.public.insert(parent);
+                                    }
+                                    Phase::Draft => {
+                                        is_root = false;
+                                        pending.push(parent);
+                                    }
+                                }
+                            }
+                            if is_root {
+                                boundary.draft_roots.push(node);
+                            }
+                            Loop::Continue((pending, seen, boundary))
+                        })
+                        .boxify()
+                },
+            )
+        })
+        .boxify()
+}
+
+// The bare version 01 changegroup of the changesets between `roots` and `heads`, as sent by the
+// commands which predate getbundle
+fn legacy_changegroup(
+    repo: &HgRepo,
+    roots: Vec<NodeHash>,
+    heads: Vec<NodeHash>,
+) -> HgCommandStream<Bytes> {
+    let parts = create_cg1_changegroup(
+        repo.hgrepo.clone(),
+        repo.repo_generation.clone(),
+        roots,
+        heads,
+    );
+
+    changegroup::encode_changegroup(parts, CgVersion::Cg1)
+        .from_err()
+        .boxify()
+}
+
 impl HgCommands for RepoClient {
     // @wireprotocommand('between', 'pairs')
     fn between(&self, pairs: Vec<(NodeHash, NodeHash)>) -> HgCommandRes<Vec<Vec<NodeHash>>> {
@@ -277,12 +943,78 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    // @wireprotocommand('branches', 'nodes')
+    fn branches(&self, nodes: Vec<NodeHash>) -> HgCommandRes<Vec<BranchRes>> {
+        info!(self.logger, "branches nodes {:?}", nodes);
+
+        let hgrepo = self.repo.hgrepo.clone();
+        stream::iter_ok(nodes)
+            .and_then(move |top| linear_branch(hgrepo.clone(), top))
+            .collect()
+            .boxify()
+    }
+
+    // @wireprotocommand('branchmap')
+    fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<NodeHash>>> {
+        info!(self.logger, "branchmap");
+
+        // Closed heads are still reported; clients work out which branches are closed themselves
+        self.repo
+            .branch_heads
+            .branch_heads(&self.repo.hgrepo)
+            .map(|branches| {
+                branches
+                    .into_iter()
+                    .map(|(name, heads)| {
+                        (String::from_utf8_lossy(&name).into_owned(), heads.heads())
+                    })
+                    .collect()
+            })
+            .boxify()
+    }
+
+    // @wireprotocommand('capabilities')
+    fn capabilities(&self) -> HgCommandRes<Vec<String>> {
+        info!(self.logger, "capabilities");
+
+        future::ok(self.repo.capabilities(self.transport)).boxify()
+    }
+
+    // @wireprotocommand('clonebundles', '')
+    fn clonebundles(&self) -> HgCommandRes<String> {
+        info!(self.logger, "clonebundles");
+
+        future::ok(clonebundles_manifest(&self.repo.clonebundles)).boxify()
+    }
+
     // @wireprotocommand('changegroup', 'roots')
-    fn changegroup(&self, roots: Vec<NodeHash>) -> HgCommandRes<()> {
-        // TODO: streaming something
+    fn changegroup(&self, roots: Vec<NodeHash>) -> HgCommandStream<Bytes> {
         info!(self.logger, "changegroup roots {:?}", roots);
 
-        future::ok(()).boxify()
+        let repo = self.repo.clone();
+        self.repo
+            .hgrepo
+            .get_heads()
+            .collect()
+            .map(move |heads| legacy_changegroup(&repo, roots, heads))
+            .flatten_stream()
+            .boxify()
+    }
+
+    // @wireprotocommand('changegroupsubset', 'bases heads')
+    fn changegroupsubset(
+        &self,
+        bases: Vec<NodeHash>,
+        heads: Vec<NodeHash>,
+    ) -> HgCommandStream<Bytes> {
+        info!(
+            self.logger,
+            "changegroupsubset bases {:?} heads {:?}",
+            bases,
+            heads
+        );
+
+        legacy_changegroup(&self.repo, bases, heads)
     }
 
     // @wireprotocommand('heads')
@@ -298,6 +1030,26 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    // @wireprotocommand('lookup', 'key')
+    fn lookup(&self, key: String) -> HgCommandRes<NodeHash> {
+        info!(self.logger, "lookup: {}", key);
+
+        let hgrepo = self.repo.hgrepo.clone();
+        let bookmarks = match hgrepo.get_bookmarks() {
+            Ok(bookmarks) => bookmarks,
+            Err(err) => return future::err(err).boxify(),
+        };
+
+        // Like Mercurial, bookmark names take precedence over hashes
+        bookmarks
+            .get(&key)
+            .and_then(move |bookmark| match bookmark {
+                Some((node, _version)) => future::ok(node).boxify(),
+                None => lookup_hash(hgrepo, key),
+            })
+            .boxify()
+    }
+
     // @wireprotocommand('known', 'nodes *'), but the '*' is ignored
     fn known(&self, nodes: Vec<NodeHash>) -> HgCommandRes<Vec<bool>> {
         info!(self.logger, "known: {:?}", nodes);
@@ -311,12 +1063,17 @@ impl HgCommands for RepoClient {
     }
 
     // @wireprotocommand('getbundle', '*')
-    fn getbundle(&self, args: GetbundleArgs) -> HgCommandRes<Bytes> {
+    fn getbundle(&self, args: GetbundleArgs) -> HgCommandStream<Bytes> {
         info!(self.logger, "Getbundle: {:?}", args);
 
+        // Once the bundle has started going out it's too late to replace it with an error
+        // bundle. Errors while generating a part interrupt it with an error:abort part
+        // instead, which the client reports before the response ends.
         match self.create_bundle(args) {
-            Ok(res) => res,
-            Err(err) => Err(err).into_future().boxify(),
+            Ok(bundle) => bundle,
+            Err(err) => error_bundle(Error::from(err), &self.logger)
+                .into_stream()
+                .boxify(),
         }
     }
 
@@ -325,16 +1082,286 @@ impl HgCommands for RepoClient {
         info!(self.logger, "Hello -> capabilities");
 
         let mut res = HashMap::new();
-        let mut caps = wireprotocaps();
-        caps.push(format!("bundle2={}", bundle2caps()));
-        res.insert("capabilities".to_string(), caps);
+        res.insert("capabilities".to_string(), self.repo.capabilities(self.transport));
 
         future::ok(res).boxify()
     }
 
+    // @wireprotocommand('listkeys', 'namespace')
+    fn listkeys(&self, namespace: String) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        info!(self.logger, "listkeys {}", namespace);
+
+        match namespace.as_str() {
+            "bookmarks" => match self.bookmark_keys() {
+                Ok(keys) => keys.collect()
+                    .map(|keys| keys.into_iter().collect())
+                    .boxify(),
+                Err(err) => future::err(err).boxify(),
+            },
+            // Like a non-publishing Mercurial server, list the draft roots
+            "phases" => match self.repo.phases {
+                Some(ref phases) => {
+                    let repo = self.repo.clone();
+                    let phases = phases.clone();
+                    let draft = format!("{}", Phase::Draft.to_hg()).into_bytes();
+
+                    self.repo
+                        .hgrepo
+                        .get_heads()
+                        .collect()
+                        .from_err()
+                        .and_then(move |heads| repo.draft_boundary(phases, heads))
+                        .map(move |boundary| {
+                            boundary
+                                .draft_roots
+                                .iter()
+                                .map(|root| {
+                                    let root: Vec<u8> = root.to_hex().into();
+                                    (root, draft.clone())
+                                })
+                                .collect()
+                        })
+                        .map_err(repo_err)
+                        .boxify()
+                }
+                // Without phase tracking every changeset is public
+                None => future::ok(hashmap! {
+                    b"publishing".to_vec() => b"True".to_vec(),
+                }).boxify(),
+            },
+            "namespaces" => future::ok(hashmap! {
+                b"bookmarks".to_vec() => vec![],
+                b"namespaces".to_vec() => vec![],
+                b"phases".to_vec() => vec![],
+            }).boxify(),
+            // Like Mercurial, unknown namespaces have no keys
+            _ => future::ok(HashMap::new()).boxify(),
+        }
+    }
+
+    // @wireprotocommand('pushkey', 'namespace key old new')
+    fn pushkey(
+        &self,
+        namespace: String,
+        key: String,
+        old: NodeHash,
+        new: NodeHash,
+    ) -> HgCommandRes<bool> {
+        info!(
+            self.logger,
+            "pushkey {} {}: {} -> {}",
+            namespace,
+            key,
+            old,
+            new
+        );
+
+        // Like Mercurial, report failure for namespaces which can't be pushed to
+        if namespace != "bookmarks" {
+            return future::ok(false).boxify();
+        }
+
+        let pushrepo = match self.repo.pushrepo {
+            Some(ref pushrepo) => pushrepo.clone(),
+            None => return future::err(repo_err(ErrorKind::ReadOnlyRepo.into())).boxify(),
+        };
+
+        // The client sends an empty value, which we parse as the null hash, for a bookmark that
+        // is being created or deleted
+        let old = if old == NULL_HASH { None } else { Some(old) };
+        let new = if new == NULL_HASH { None } else { Some(new) };
+
+        pushrepo
+            .update_bookmark(key.into_bytes(), old, new)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    // @wireprotocommand('stream_out')
+    fn stream_out(&self) -> HgCommandRes<BoxStream<Vec<u8>, hgproto::Error>> {
+        info!(self.logger, "stream_out");
+
+        let stream =
+            create_stream_clone(self.repo.hgrepo.clone(), self.repo.repo_generation.clone())
+                .map_err(|err| hgproto::Error::with_chain(err, hgproto::ErrorKind::Repo))
+                .boxify();
+        future::ok(stream).boxify()
+    }
+
     // @wireprotocommand('unbundle', 'heads')
-    fn unbundle(&self, heads: Vec<String>, _stream: Bytes) -> HgCommandRes<()> {
+    fn unbundle(&self, heads: Vec<String>, stream: Bytes) -> HgCommandRes<Bytes> {
         info!(self.logger, "unbundle heads {:?}", heads);
-        future::ok(()).boxify()
+
+        let reply = match self.apply_bundle(heads, stream) {
+            Ok(res) => res,
+            Err(err) => Err(err).into_future().boxify(),
+        };
+
+        let logger = self.logger.clone();
+        reply.or_else(move |err| error_bundle(err, &logger)).boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use linear;
+    use merge_uneven;
+
+    use super::*;
+
+    fn boxed_repo<R>(repo: R) -> Arc<BoxedRepo>
+    where
+        R: Repo + Send + Sync,
+    {
+        fn repo_chain<E: error::Error + Send + 'static>(err: E) -> hgproto::Error {
+            hgproto::Error::with_chain(err, hgproto::ErrorKind::Repo)
+        }
+        Arc::new(BoxRepo::new_with_cvterr(repo, repo_chain))
+    }
+
+    fn node(hex: &str) -> NodeHash {
+        hex.parse().unwrap()
+    }
+
+    #[test]
+    fn linear_branch_stops_at_root() {
+        let repo = boxed_repo(linear::getrepo());
+        let top = node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+
+        let branch = linear_branch(repo, top).wait().unwrap();
+        assert_eq!(
+            branch,
+            BranchRes {
+                top,
+                node: node("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536"),
+                p0: None,
+                p1: None,
+            }
+        );
+    }
+
+    #[test]
+    fn linear_branch_stops_at_merge() {
+        let repo = boxed_repo(merge_uneven::getrepo());
+        let top = node("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce");
+
+        // The top is a merge, so it's the bottom of its own branch
+        let branch = linear_branch(repo, top).wait().unwrap();
+        assert_eq!(
+            branch,
+            BranchRes {
+                top,
+                node: top,
+                p0: Some(node("264f01429683b3dd8042cb3979e8bf37007118bc")),
+                p1: Some(node("16839021e338500b3cf7c9b871c8a07351697d68")),
+            }
+        );
+    }
+
+    #[test]
+    fn linear_branch_below_merge() {
+        let repo = boxed_repo(merge_uneven::getrepo());
+        let top = node("16839021e338500b3cf7c9b871c8a07351697d68");
+
+        let branch = linear_branch(repo, top).wait().unwrap();
+        assert_eq!(
+            branch,
+            BranchRes {
+                top,
+                node: node("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+                p0: None,
+                p1: None,
+            }
+        );
+    }
+
+    #[test]
+    fn unbundle_heads_check_force() {
+        // "force" in hex
+        let check = unbundle_heads_check(&["666f726365".to_string()]).unwrap();
+        assert!(check.is_none());
+    }
+
+    #[test]
+    fn unbundle_heads_check_hashed() {
+        let hash = "a5ffa77602a066db7d5cfb9fb5823a0895717c5a";
+        // "hashed" in hex, followed by the hash of the heads
+        let heads = vec!["686173686564".to_string(), hash.to_string()];
+        match unbundle_heads_check(&heads).unwrap() {
+            Some(HeadsCheck::Hashed(ref sha1)) => assert_eq!(sha1, &hash.parse::<Sha1>().unwrap()),
+            check => panic!("unexpected heads check: {:?}", check),
+        }
+    }
+
+    #[test]
+    fn unbundle_heads_check_exact() {
+        let heads = vec![
+            "a5ffa77602a066db7d5cfb9fb5823a0895717c5a".to_string(),
+            "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536".to_string(),
+        ];
+        let expected: Vec<NodeHash> = heads.iter().map(|head| head.parse().unwrap()).collect();
+        match unbundle_heads_check(&heads).unwrap() {
+            Some(HeadsCheck::Exact(ref nodes)) => assert_eq!(nodes, &expected),
+            check => panic!("unexpected heads check: {:?}", check),
+        }
+    }
+
+    #[test]
+    fn unbundle_heads_check_invalid() {
+        assert!(unbundle_heads_check(&["not a hash".to_string()]).is_err());
+        assert!(unbundle_heads_check(&["686173686564".to_string(), "zz".to_string()]).is_err());
+    }
+
+    #[test]
+    fn getbundle_cg_version_highest_common() {
+        let bundlecaps = vec![b"bundle2=HG20%0Achangegroup%3D01%2C02%2C03".to_vec()];
+        assert_eq!(
+            getbundle_cg_version(&bundlecaps, false).unwrap(),
+            CgVersion::Cg3
+        );
+        assert_eq!(
+            getbundle_cg_version(&bundlecaps, true).unwrap(),
+            CgVersion::Cg2
+        );
+    }
+
+    #[test]
+    fn getbundle_cg_version_unlisted() {
+        let bundlecaps = vec![b"HG20".to_vec(), b"bundle2=HG20".to_vec()];
+        assert_eq!(
+            getbundle_cg_version(&bundlecaps, false).unwrap(),
+            CgVersion::Cg1
+        );
+
+        let bundlecaps = vec![b"bundle2=HG20%0Achangegroup%3D04".to_vec()];
+        assert!(getbundle_cg_version(&bundlecaps, false).is_err());
+    }
+
+    #[test]
+    fn bundle2caps_pushkey() {
+        let caps = |accepts_pushes| {
+            let bundlecaps = vec![format!("bundle2={}", bundle2caps(accepts_pushes)).into_bytes()];
+            part_types::decode_bundlecaps(&bundlecaps).unwrap().unwrap()
+        };
+        assert!(caps(true).contains_key("pushkey"));
+        assert!(!caps(false).contains_key("pushkey"));
+    }
+
+    #[test]
+    fn lookup_hash_prefix() {
+        let repo = boxed_repo(linear::getrepo());
+        let lookup = |key: &str| lookup_hash(repo.clone(), key.to_string()).wait();
+
+        assert_eq!(
+            lookup("a5f").unwrap(),
+            node("a5ffa77602a066db7d5cfb9fb5823a0895717c5a")
+        );
+        assert_eq!(
+            lookup("3c15").unwrap(),
+            node("3c15267ebf11807f3d772eb891272b911ec68759")
+        );
+        // 3c15... and 3e0e...
+        assert!(lookup("3").is_err());
+        assert!(lookup("b").is_err());
     }
 }
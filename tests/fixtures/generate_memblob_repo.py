@@ -44,6 +44,7 @@ extern crate membookmarks;
 extern crate mercurial_types;
 extern crate memheads;
 extern crate memlinknodes;
+extern crate memphases;
 extern crate blobrepo;
 extern crate blobstore;
 extern crate ascii;
@@ -55,6 +56,7 @@ use membookmarks::MemBookmarks;
 use mercurial_types::NodeHash;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
+use memphases::MemPhases;
 use blobrepo::{BlobRepo, MemBlobState};
 use ascii::AsciiString;
 use blobstore::Blobstore;
@@ -66,6 +68,7 @@ pub fn getrepo() -> BlobRepo<MemBlobState> {
     let heads: MemHeads<NodeHash> = MemHeads::new();
     let blobs = Memblob::new();
     let linknodes = MemLinknodes::new();
+    let phases: MemPhases<NodeHash> = MemPhases::new();
 
 """
         )
@@ -96,7 +99,7 @@ pub fn getrepo() -> BlobRepo<MemBlobState> {
                 )
         rs.writelines(
             """
-    BlobRepo::new(MemBlobState::new(heads, bookmarks, blobs, linknodes))
+    BlobRepo::new(MemBlobState::new(heads, bookmarks, blobs, linknodes, phases))
 }
 """
         )
@@ -85,7 +85,7 @@ Add commit with a directory
   $ mkdir $TESTTMP/blobrepo
 #if files
   $ blobimport --blobstore files --linknodes repo $TESTTMP/blobrepo -d 2> out.txt
-  $ grep changeset < out.txt
+  $ grep ': changeset ' < out.txt
   D* 0: changeset 3903775176ed42b1458a6281db4a0ccf4d9f287a (glob)
   D* 1: changeset 4dabaf45f54add88ca2797dfdeb00a7d55144243 (glob)
   D* 2: changeset 533267b0e203537fa53d2aec834b062f0b2249cd (glob)
@@ -101,7 +101,7 @@ Heads output order is unpredictable, let's sort them by commit hash
 
 #else
   $ blobimport --blobstore rocksdb --linknodes repo $TESTTMP/blobrepo --postpone-compaction -d 2> out.txt
-  $ grep changeset < out.txt
+  $ grep ': changeset ' < out.txt
   D* 0: changeset 3903775176ed42b1458a6281db4a0ccf4d9f287a (glob)
   D* 1: changeset 4dabaf45f54add88ca2797dfdeb00a7d55144243 (glob)
   D* 2: changeset 533267b0e203537fa53d2aec834b062f0b2249cd (glob)
@@ -118,8 +118,6 @@ Heads output order is unpredictable, let's sort them by commit hash
   I* compaction started (glob)
   I* compaction finished (glob)
 #endif
-Temporary hack because blobimport doesn't import bookmarks yet
-  $ mkdir $TESTTMP/blobrepo/books
 #if files
   $ edenserver --addr 127.0.0.1:3000 --blobrepo-folder $TESTTMP/blobrepo --reponame repo --repotype files
 #else